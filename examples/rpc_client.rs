@@ -0,0 +1,201 @@
+//! Plays a game against whatever listens on `discord-ipc-0`, so the whole pipeline can be tried
+//! without installing one. Cycles through a few representative presences and clears at the end.
+//!
+//! ```sh
+//! cargo run --example rpc_client -- --client-id 1234 --interval 5
+//! ```
+
+use anyhow::{bail, Context, Result};
+use arrpc_rs::ipc::{
+    structs::{HandshakeMessage, IpcFrame, IpcMessage},
+    transport::{RpcConnection, StreamConnection},
+};
+use serde_json::{json, Value};
+use std::{
+    env,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::time::sleep;
+
+struct Args {
+    client_id: String,
+    interval: Duration,
+    socket: Option<String>,
+}
+
+impl Args {
+    fn parse() -> Result<Args> {
+        let mut parsed = Args {
+            client_id: "1045800378228281345".to_string(),
+            interval: Duration::from_secs(5),
+            socket: None,
+        };
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--client-id" => {
+                    parsed.client_id = args.next().context("--client-id requires an id")?;
+                }
+                "--interval" => {
+                    let secs = args.next().context("--interval requires seconds")?;
+                    parsed.interval = Duration::from_secs(secs.parse()?);
+                }
+                "--socket" => {
+                    parsed.socket = Some(args.next().context("--socket requires a path")?)
+                }
+                arg => bail!("Unknown argument: {}", arg),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+#[cfg(unix)]
+async fn connect(socket: Option<String>) -> Result<impl RpcConnection> {
+    let path = socket.unwrap_or_else(|| {
+        let directory = env::var("XDG_RUNTIME_DIR")
+            .or_else(|_| env::var("TMPDIR"))
+            .unwrap_or("/tmp".to_string());
+        format!("{}/discord-ipc-0", directory.trim_end_matches('/'))
+    });
+    let stream = match path.strip_prefix('@') {
+        Some(name) => connect_abstract(name),
+        None => tokio::net::UnixStream::connect(&path).await,
+    }
+    .with_context(|| format!("Failed to connect to {}", path))?;
+    Ok(StreamConnection::new(stream))
+}
+
+/// `@name` sockets are in the abstract namespace, see `ipc.abstract_sockets`
+#[cfg(target_os = "linux")]
+fn connect_abstract(name: &str) -> std::io::Result<tokio::net::UnixStream> {
+    use std::os::{linux::net::SocketAddrExt, unix::net};
+
+    let address = net::SocketAddr::from_abstract_name(name)?;
+    let stream = net::UnixStream::connect_addr(&address)?;
+    stream.set_nonblocking(true)?;
+    tokio::net::UnixStream::from_std(stream)
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn connect_abstract(_name: &str) -> std::io::Result<tokio::net::UnixStream> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(windows)]
+async fn connect(socket: Option<String>) -> Result<impl RpcConnection> {
+    let path = socket.unwrap_or(r"\\.\pipe\discord-ipc-0".to_string());
+    let pipe = tokio::net::windows::named_pipe::ClientOptions::new()
+        .open(&path)
+        .with_context(|| format!("Failed to connect to {}", path))?;
+    Ok(StreamConnection::new(pipe))
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default()
+}
+
+/// What a typical game goes through, from the menu to a match with a timer and buttons
+fn presences() -> Vec<(&'static str, Option<Value>)> {
+    let started = now_secs();
+    vec![
+        (
+            "plain text",
+            Some(json!({ "state": "In the menus", "details": "Picking a loadout" })),
+        ),
+        (
+            "assets",
+            Some(json!({
+                "state": "Exploring",
+                "details": "The Swamp",
+                "assets": {
+                    "large_image": "map_swamp",
+                    "large_text": "The Swamp",
+                    "small_image": "class_ranger",
+                    "small_text": "Ranger, level 12"
+                }
+            })),
+        ),
+        (
+            "timestamps and party",
+            Some(json!({
+                "state": "In a match",
+                "details": "Ranked, 3 - 1",
+                "timestamps": { "start": started },
+                "party": { "id": "party-1", "size": [2, 4] }
+            })),
+        ),
+        (
+            "buttons",
+            Some(json!({
+                "state": "Spectating",
+                "details": "Tournament finals",
+                "timestamps": { "start": started, "end": started + 600 },
+                "buttons": [
+                    { "label": "Watch", "url": "https://example.com/watch" },
+                    { "label": "Join the discord", "url": "https://example.com/discord" }
+                ]
+            })),
+        ),
+        ("clear", None),
+    ]
+}
+
+async fn command<C: RpcConnection>(conn: &mut C, nonce: usize, args: Value) -> Result<IpcFrame> {
+    let frame = IpcFrame {
+        args: Some(args),
+        data: None,
+        cmd: "SET_ACTIVITY".to_string(),
+        evt: None,
+        nonce: nonce.to_string(),
+    };
+    conn.send(&IpcMessage::Frame(Box::new(frame))).await?;
+    loop {
+        match conn.recv().await? {
+            IpcMessage::Frame(reply) if reply.nonce == nonce.to_string() => return Ok(*reply),
+            IpcMessage::Ping(data) => conn.send(&IpcMessage::Pong(data)).await?,
+            IpcMessage::Close(close) => bail!("Closed: {:?} {}", close.code, close.message),
+            _ => {}
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse()?;
+    let mut conn = connect(args.socket.clone()).await?;
+    conn.send(&IpcMessage::Handshake(HandshakeMessage {
+        version: 1,
+        client_id: args.client_id.clone(),
+    }))
+    .await?;
+    match conn.recv().await? {
+        IpcMessage::Frame(frame) if frame.evt.as_deref() == Some("READY") => {
+            let user = frame
+                .data
+                .as_ref()
+                .and_then(|data| data.pointer("/user/username"));
+            println!("READY as {}", user.unwrap_or(&Value::Null));
+        }
+        IpcMessage::Close(close) => bail!("Handshake refused: {:?} {}", close.code, close.message),
+        msg => bail!("Expected READY, got {:?}", msg),
+    }
+
+    let pid = std::process::id();
+    for (nonce, (name, activity)) in presences().into_iter().enumerate() {
+        let frame_args = match activity {
+            Some(activity) => json!({ "pid": pid, "activity": activity }),
+            None => json!({ "pid": pid }),
+        };
+        let reply = command(&mut conn, nonce, frame_args).await?;
+        match reply.evt.as_deref() {
+            Some("ERROR") => println!("{}: rejected, {}", name, reply.data.unwrap_or_default()),
+            _ => println!("{}: ok", name),
+        }
+        sleep(args.interval).await;
+    }
+    Ok(())
+}
@@ -0,0 +1,744 @@
+use crate::{
+    blocklist::Blocklist,
+    command::{CommandHandler, CommandRegistry},
+    config::{Config, SharedConfig},
+    error::{ArRpcError, Result},
+    expiry::Expiry,
+    history::History,
+    ipc::server::Transports,
+    middleware::{Middleware, MiddlewareStack},
+    privacy::PrivacyFilter,
+    record::Recorder,
+    rewrite::Rewriter,
+    rotation,
+    server::{Server, ServerHandle},
+    sink::{ActivitySink, DynSink},
+    stats::{SharedStats, StatsSnapshot},
+    status::{Status, StatusTracker},
+    structs::{ActivityEvent, CustomStatus, IpcActivityMessage},
+    validate,
+};
+use futures_util::{
+    stream::{self, BoxStream},
+    Stream, StreamExt,
+};
+use std::{
+    collections::HashMap,
+    future,
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+};
+use tokio::{
+    select,
+    sync::{
+        mpsc::{self, UnboundedSender},
+        watch, Mutex as AsyncMutex,
+    },
+    task::{self, JoinHandle},
+    time::{interval, MissedTickBehavior},
+};
+
+#[cfg(feature = "bridge")]
+use crate::{
+    bridge::BridgeServer,
+    state::{PersistedState, DEFAULT_MAX_AGE},
+};
+use std::time::Duration;
+
+#[cfg(feature = "process-detection")]
+use crate::detection::{Database, Precedence, Scanner};
+#[cfg(feature = "idle")]
+use crate::idle::{self, IdleFilter};
+use owo_colors::OwoColorize;
+use tracing::{debug, info, warn};
+
+#[cfg(all(unix, feature = "plugins"))]
+use crate::plugin::Plugin;
+
+/// How often activities are checked against `activity.max_age_secs`
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(5);
+
+type EventCallback = Box<dyn Fn(&ActivityEvent) + Send + Sync>;
+type Subscribers = Arc<Mutex<Vec<UnboundedSender<ActivityEvent>>>>;
+
+pub struct ArRpcBuilder {
+    #[cfg(feature = "bridge")]
+    bridge: bool,
+    transports: Transports,
+    config: Config,
+    #[cfg(feature = "bridge")]
+    state_file: Option<PathBuf>,
+    #[cfg(feature = "bridge")]
+    state_max_age: Duration,
+    callbacks: Vec<EventCallback>,
+    sinks: Vec<Arc<dyn DynSink>>,
+    middleware: MiddlewareStack,
+    commands: CommandRegistry,
+    record: Option<PathBuf>,
+    #[cfg(all(unix, feature = "plugins"))]
+    plugins: Vec<PathBuf>,
+}
+
+impl Default for ArRpcBuilder {
+    fn default() -> Self {
+        Self {
+            #[cfg(feature = "bridge")]
+            bridge: true,
+            transports: Transports::default(),
+            config: Config::default(),
+            #[cfg(feature = "bridge")]
+            state_file: None,
+            #[cfg(feature = "bridge")]
+            state_max_age: DEFAULT_MAX_AGE,
+            callbacks: Vec::new(),
+            sinks: Vec::new(),
+            middleware: MiddlewareStack::default(),
+            commands: CommandRegistry::default(),
+            record: None,
+            #[cfg(all(unix, feature = "plugins"))]
+            plugins: Vec::new(),
+        }
+    }
+}
+
+impl ArRpcBuilder {
+    #[cfg(feature = "bridge")]
+    /// Serve activities to web clients over the WebSocket bridge
+    pub fn bridge(mut self, enabled: bool) -> Self {
+        self.bridge = enabled;
+        self
+    }
+
+    /// Apply the settings of a config file, see [`Config`]
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Accept RPC clients on the `discord-ipc-N` socket or named pipe
+    pub fn ipc(mut self, enabled: bool) -> Self {
+        self.transports.ipc = enabled;
+        self
+    }
+
+    #[cfg(feature = "ws-rpc")]
+    /// Accept RPC clients over WebSocket on ports 6463-6472
+    pub fn ws_rpc(mut self, enabled: bool) -> Self {
+        self.transports.ws_rpc = enabled;
+        self
+    }
+
+    #[cfg(feature = "bridge")]
+    /// Persist bridge activities to this file on shutdown and restore them on start
+    pub fn state_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.state_file = Some(path.into());
+        self
+    }
+
+    #[cfg(feature = "bridge")]
+    pub fn state_max_age(mut self, max_age: Duration) -> Self {
+        self.state_max_age = max_age;
+        self
+    }
+
+    pub fn on_event<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&ActivityEvent) + Send + Sync + 'static,
+    {
+        self.callbacks.push(Box::new(callback));
+        self
+    }
+
+    /// Publish every activity to `sink` as well, after the bridge
+    pub fn sink<S: ActivitySink + 'static>(mut self, sink: S) -> Self {
+        self.sinks.push(Arc::new(sink));
+        self
+    }
+
+    pub fn middleware<M: Middleware + 'static>(self, middleware: M) -> Self {
+        self.middleware.register(middleware);
+        self
+    }
+
+    pub fn command<H: CommandHandler + 'static>(self, cmd: impl Into<String>, handler: H) -> Self {
+        self.commands.register(cmd, handler);
+        self
+    }
+
+    /// Append every message of an RPC client to this file, for [`record::replay`](crate::record::replay)
+    pub fn record(mut self, path: impl Into<PathBuf>) -> Self {
+        self.record = Some(path.into());
+        self
+    }
+
+    #[cfg(all(unix, feature = "plugins"))]
+    /// Load a shared library plugin when the server starts, see [`Plugin`]
+    pub fn plugin(mut self, path: impl Into<PathBuf>) -> Self {
+        self.plugins.push(path.into());
+        self
+    }
+
+    pub async fn start(self) -> Result<ArRpc> {
+        #[cfg(all(unix, feature = "plugins"))]
+        for path in &self.plugins {
+            let plugin = Arc::new(Plugin::load(path)?);
+            info!(
+                "{} {}",
+                "Loaded plugin".cyan(),
+                plugin.name().yellow().bold()
+            );
+            for cmd in plugin.commands() {
+                self.commands.register_arc(cmd.clone(), plugin.clone());
+            }
+            self.middleware.register_arc(plugin);
+        }
+
+        #[cfg(feature = "bridge")]
+        let bridge = if self.bridge {
+            let bridge = BridgeServer::try_bind(&self.config.bridge).await?;
+            if let Some(path) = &self.state_file {
+                match PersistedState::load(path, self.state_max_age).await {
+                    Ok(Some(state)) => bridge.restore(state).await,
+                    Ok(None) => {}
+                    Err(e) => warn!("Failed to restore state: {}", e),
+                }
+            }
+            Some(Arc::new(bridge))
+        } else {
+            None
+        };
+
+        #[cfg(feature = "process-detection")]
+        let detection = match self.config.detection.enabled {
+            true => {
+                let custom_path = match &self.config.detection.custom_path {
+                    Some(path) => path.clone(),
+                    None => Database::default_custom_path(),
+                };
+                let database =
+                    Arc::new(Database::open(&Database::default_cache_path(), &custom_path).await?);
+                let interval = self.config.detection.refresh_interval_secs;
+                let refresh = database.clone().spawn_refresh(
+                    self.config.detection.database_url.clone(),
+                    (interval > 0).then(|| Duration::from_secs(interval)),
+                );
+                Some((refresh, Scanner::spawn(database, &self.config.detection)))
+            }
+            false => None,
+        };
+
+        #[cfg(feature = "idle")]
+        let idle_config = self.config.idle.clone();
+        let status = StatusTracker::default();
+        let stats = SharedStats::default();
+        let mut sources: Vec<BoxStream<'static, ActivityEvent>> = Vec::new();
+        let mut servers = Vec::new();
+        let ipc_config = self.config.ipc.clone();
+        let (privacy_tx, mut privacy_mode) = watch::channel(self.config.activity.privacy_mode);
+        let config = Arc::new(RwLock::new(self.config));
+        // Not in the server's middleware, they apply to activities from every source
+        let rules = MiddlewareStack::default();
+        rules.register(Rewriter::new(config.clone()));
+        rules.register(Blocklist::new(config.clone()));
+        #[allow(unused_mut)]
+        let mut sinks: Vec<Arc<dyn DynSink>> = Vec::new();
+        #[cfg(feature = "bridge")]
+        if let Some(bridge) = &bridge {
+            sinks.push(bridge.clone());
+        }
+        sinks.extend(self.sinks);
+        let dispatcher = Dispatcher {
+            callbacks: Arc::new(self.callbacks),
+            subscribers: Subscribers::default(),
+            history: History::default(),
+            config: config.clone(),
+            sinks: Arc::new(sinks),
+            forwarding: Arc::default(),
+        };
+        if self.transports.ipc || self.transports.ws_rpc {
+            let mut server = Server::from_parts(self.middleware.clone(), self.commands.clone())
+                .with_transports(self.transports)
+                .with_ipc_config(ipc_config)
+                .with_config(config.clone())
+                .with_status(status.clone())
+                .with_stats(stats.clone());
+            if let Some(path) = &self.record {
+                server = server.with_recorder(Recorder::open(path).await?);
+            }
+            let (events, server) = server.try_bind().await?;
+            sources.push(events.boxed());
+            servers.push(server);
+        }
+        #[cfg(feature = "bridge")]
+        if let Some(events) = bridge.as_ref().and_then(|bridge| bridge.relayed_events()) {
+            let config = config.clone();
+            let middleware = self.middleware.clone();
+            sources.push(
+                events
+                    .filter_map(move |event| {
+                        future::ready(check_relayed(event, &config, &middleware))
+                    })
+                    .boxed(),
+            );
+        }
+        if !config.read().unwrap().rotation.presences.is_empty() {
+            let config = config.read().unwrap();
+            sources.push(rotation::rotate(&config.rotation, &config.activity).boxed());
+        }
+        #[cfg(feature = "process-detection")]
+        let detection = detection.map(|(refresh, (events, scanner))| {
+            sources.push(events.boxed());
+            (refresh, scanner)
+        });
+
+        let pump = if !sources.is_empty() {
+            let mut events = stream::select_all(sources);
+            let dispatcher = dispatcher.clone();
+            #[cfg(feature = "process-detection")]
+            let mut precedence = detection.as_ref().map(|_| Precedence::default());
+            let mut expiry = Expiry::default();
+            let mut check = interval(EXPIRY_INTERVAL);
+            check.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            #[allow(unused_mut)]
+            let mut away = None;
+            #[cfg(feature = "idle")]
+            let mut idle_filter = idle_config.enabled.then(|| {
+                away = Some(idle::watch(Duration::from_secs(idle_config.threshold_secs)));
+                IdleFilter::new(idle_config.action)
+            });
+            let mut privacy = PrivacyFilter::new(*privacy_mode.borrow());
+            Some(task::spawn(async move {
+                loop {
+                    let (events, stage) = select! {
+                        event = events.next() => match event {
+                            Some(event) => {
+                                expiry.observe(&event);
+                                (vec![event], Stage::Rules)
+                            }
+                            None => break,
+                        },
+                        _ = check.tick() => {
+                            let max_age = dispatcher.config.read().unwrap().activity.max_age_secs;
+                            match max_age {
+                                0 => continue,
+                                secs => (expiry.expired(Duration::from_secs(secs)), Stage::Precedence),
+                            }
+                        }
+                        Ok(()) = privacy_mode.changed() => {
+                            let enabled = *privacy_mode.borrow_and_update();
+                            (privacy.set_enabled(enabled), Stage::Idle)
+                        }
+                        idle = changed(&mut away) => {
+                            #[cfg(feature = "idle")]
+                            if let Some(filter) = &mut idle_filter {
+                                for event in filter.set_idle(idle) {
+                                    dispatcher.dispatch(privacy.reapply(event)).await;
+                                }
+                            }
+                            let _ = idle;
+                            continue;
+                        }
+                    };
+                    let mut output = events;
+                    if stage <= Stage::Rules {
+                        output = output
+                            .into_iter()
+                            .filter_map(|event| apply_middleware(event, &dispatcher.config, &rules))
+                            .collect();
+                    }
+                    #[cfg(feature = "process-detection")]
+                    if let Some(precedence) =
+                        precedence.as_mut().filter(|_| stage <= Stage::Precedence)
+                    {
+                        output = output
+                            .into_iter()
+                            .flat_map(|event| precedence.apply(event))
+                            .collect();
+                    }
+                    if stage <= Stage::Privacy {
+                        output = output
+                            .into_iter()
+                            .flat_map(|event| privacy.apply(event))
+                            .collect();
+                    }
+                    #[cfg(feature = "idle")]
+                    if let Some(filter) = &mut idle_filter {
+                        output = output
+                            .into_iter()
+                            .flat_map(|event| filter.apply(event))
+                            .collect();
+                    }
+                    for event in output {
+                        dispatcher.dispatch(event).await;
+                    }
+                }
+            }))
+        } else {
+            None
+        };
+
+        Ok(ArRpc {
+            #[cfg(feature = "bridge")]
+            bridge,
+            #[cfg(feature = "bridge")]
+            state_file: self.state_file,
+            dispatcher,
+            privacy_mode: privacy_tx,
+            middleware: self.middleware,
+            config,
+            status,
+            stats,
+            servers,
+            pump,
+            #[cfg(feature = "process-detection")]
+            detection,
+        })
+    }
+}
+
+pub struct ArRpc {
+    #[cfg(feature = "bridge")]
+    bridge: Option<Arc<BridgeServer>>,
+    #[cfg(feature = "bridge")]
+    state_file: Option<PathBuf>,
+    dispatcher: Dispatcher,
+    privacy_mode: watch::Sender<bool>,
+    middleware: MiddlewareStack,
+    config: Arc<RwLock<Config>>,
+    status: StatusTracker,
+    stats: SharedStats,
+    servers: Vec<ServerHandle>,
+    pump: Option<JoinHandle<()>>,
+    #[cfg(feature = "process-detection")]
+    detection: Option<(JoinHandle<()>, Scanner)>,
+}
+
+impl ArRpc {
+    pub fn builder() -> ArRpcBuilder {
+        ArRpcBuilder::default()
+    }
+
+    pub fn middleware(&self) -> MiddlewareStack {
+        self.middleware.clone()
+    }
+
+    pub fn config(&self) -> Config {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Swaps in a new config without touching existing connections
+    pub fn reload(&self, config: Config) {
+        let mut current = self.config.write().unwrap();
+        if current.ipc != config.ipc {
+            warn!("IPC settings only apply after a restart");
+        }
+        if current.bridge != config.bridge {
+            warn!("Bridge settings only apply after a restart");
+        }
+        if current.sinks != config.sinks {
+            warn!("Sink settings only apply after a restart");
+        }
+        if current.detection != config.detection {
+            warn!("Detection settings only apply after a restart");
+        }
+        if current.idle != config.idle {
+            warn!("Idle settings only apply after a restart");
+        }
+        if current.rotation != config.rotation {
+            warn!("Rotation settings only apply after a restart");
+        }
+        if current.activity.privacy_mode != config.activity.privacy_mode {
+            self.set_privacy_mode(config.activity.privacy_mode);
+        }
+        *current = config;
+    }
+
+    pub async fn status(&self) -> Status {
+        #[allow(unused_mut)]
+        let mut status = self.status.snapshot();
+        status.stats = self.stats.snapshot();
+        #[cfg(feature = "bridge")]
+        if let Some(bridge) = &self.bridge {
+            status.bridge_clients = bridge.clients().await;
+            status.activities = bridge.snapshot().await.activities;
+        }
+        status
+    }
+
+    /// Shows `status` next to the activities of IPC clients, or clears it
+    pub async fn set_custom_status(&self, status: Option<CustomStatus>) -> Result<()> {
+        let event = match status {
+            Some(status) => {
+                if status.text.is_empty() && status.emoji.is_none() {
+                    return Err(ArRpcError::InvalidActivity(
+                        "custom status needs a text or an emoji".to_string(),
+                    ));
+                }
+                let mut msg = status.to_message();
+                if let Some(activity) = &mut msg.activity {
+                    validate::sanitize(activity, &self.config.read().unwrap().activity)?;
+                }
+                ActivityEvent::Set(Box::new(msg))
+            }
+            None => ActivityEvent::Clear {
+                socket_id: CustomStatus::SOCKET_ID.to_string(),
+                pid: std::process::id() as usize,
+            },
+        };
+        self.dispatcher.dispatch(event).await;
+        Ok(())
+    }
+
+    /// Stops looking for games among the running processes and clears the detected ones, or
+    /// starts again
+    pub fn set_detection_paused(&self, paused: bool) -> Result<()> {
+        #[cfg(feature = "process-detection")]
+        if let Some((_, scanner)) = &self.detection {
+            scanner.set_paused(paused);
+            return Ok(());
+        }
+        let _ = paused;
+        Err(ArRpcError::DetectionDisabled)
+    }
+
+    /// Stops sending activities to the bridge and sinks and clears the ones they have, without
+    /// touching any connection. Resuming sends the latest activities again.
+    pub async fn set_paused(&self, paused: bool) {
+        self.dispatcher.set_paused(paused).await;
+        info!(
+            "{}",
+            match paused {
+                true => "Forwarding paused",
+                false => "Forwarding resumed",
+            }
+            .cyan()
+        );
+    }
+
+    pub async fn is_paused(&self) -> bool {
+        self.dispatcher.forwarding.lock().await.paused
+    }
+
+    /// Strips forwarded activities down to the application they are for, or shows them in full
+    /// again. Lasts until the next reload that changes `activity.privacy_mode`.
+    pub fn set_privacy_mode(&self, enabled: bool) {
+        if self.privacy_mode.send_replace(enabled) != enabled {
+            info!(
+                "{} {}",
+                "Privacy mode".cyan(),
+                match enabled {
+                    true => "on",
+                    false => "off",
+                }
+                .yellow()
+                .bold()
+            );
+        }
+    }
+
+    pub fn privacy_mode(&self) -> bool {
+        *self.privacy_mode.borrow()
+    }
+
+    pub fn stats(&self) -> StatsSnapshot {
+        self.stats.snapshot()
+    }
+
+    pub fn history(&self) -> &History {
+        &self.dispatcher.history
+    }
+
+    /// Every event produced after this call, until the server shuts down
+    pub fn events(&self) -> impl Stream<Item = ActivityEvent> + Send + Unpin {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.dispatcher.subscribers.lock().unwrap().push(tx);
+        Box::pin(stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|event| (event, rx))
+        }))
+    }
+
+    /// Resolves once the RPC pipeline stops producing activities
+    pub async fn wait(&mut self) {
+        match &mut self.pump {
+            Some(pump) => {
+                let _ = pump.await;
+                self.pump = None;
+            }
+            None => future::pending().await,
+        }
+    }
+
+    pub async fn shutdown(self) -> Result<()> {
+        if let Some(pump) = self.pump {
+            pump.abort();
+        }
+        #[cfg(feature = "process-detection")]
+        if let Some((refresh, scanner)) = self.detection {
+            refresh.abort();
+            scanner.shutdown();
+        }
+        for server in self.servers {
+            server.shutdown().await;
+        }
+        #[cfg(feature = "bridge")]
+        if let Some(bridge) = self.bridge {
+            if let Some(path) = &self.state_file {
+                if let Err(e) = bridge.snapshot().await.save(path).await {
+                    warn!("Failed to save state: {}", e);
+                }
+            }
+            bridge.close().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Relayed activities are checked and go through the middleware like the ones of IPC clients
+#[cfg(feature = "bridge")]
+fn check_relayed(
+    event: ActivityEvent,
+    config: &SharedConfig,
+    middleware: &MiddlewareStack,
+) -> Option<ActivityEvent> {
+    let ActivityEvent::Set(mut msg) = event else {
+        return Some(event);
+    };
+    if let Some(activity) = &mut msg.activity {
+        if let Err(e) = validate::sanitize(activity, &config.read().unwrap().activity) {
+            debug!("Rejecting relayed activity {}: {}", msg.socket_id, e);
+            return None;
+        }
+    }
+    apply_middleware(ActivityEvent::Set(msg), config, middleware)
+}
+
+/// Runs a new activity through `middleware` and cleans up what it changed, one the middleware
+/// cleared becomes a clear
+fn apply_middleware(
+    event: ActivityEvent,
+    config: &SharedConfig,
+    middleware: &MiddlewareStack,
+) -> Option<ActivityEvent> {
+    let ActivityEvent::Set(msg) = event else {
+        return Some(event);
+    };
+    let mut msg = middleware.apply(*msg)?;
+    if let Some(activity) = &mut msg.activity {
+        if let Err(e) = validate::sanitize(activity, &config.read().unwrap().activity) {
+            debug!("Dropping rewritten activity {}: {}", msg.socket_id, e);
+            return None;
+        }
+    }
+    Some(match msg.activity {
+        Some(_) => ActivityEvent::Set(Box::new(msg)),
+        None => ActivityEvent::Clear {
+            socket_id: msg.socket_id,
+            pid: msg.pid,
+        },
+    })
+}
+
+/// The first step of the pipeline that events still have to go through
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum Stage {
+    Rules,
+    Precedence,
+    Privacy,
+    Idle,
+}
+
+/// Resolves with the new idle state whenever it changes, never without a watcher
+async fn changed(away: &mut Option<watch::Receiver<bool>>) -> bool {
+    let Some(away) = away else {
+        return future::pending().await;
+    };
+    if away.changed().await.is_err() {
+        return future::pending().await;
+    }
+    *away.borrow_and_update()
+}
+
+/// Hands every event to whoever is interested in it
+#[derive(Clone)]
+struct Dispatcher {
+    callbacks: Arc<Vec<EventCallback>>,
+    subscribers: Subscribers,
+    history: History,
+    config: Arc<RwLock<Config>>,
+    sinks: Arc<Vec<Arc<dyn DynSink>>>,
+    /// Held while the sinks are sent something, so nothing gets through after a pause
+    forwarding: Arc<AsyncMutex<Forwarding>>,
+}
+
+/// What the sinks were last sent for every socket, to take it back while paused
+#[derive(Default)]
+struct Forwarding {
+    paused: bool,
+    current: HashMap<String, IpcActivityMessage>,
+}
+
+impl Dispatcher {
+    async fn dispatch(&self, event: ActivityEvent) {
+        let history_size = self.config.read().unwrap().activity.history_size;
+        self.history.record(&event, history_size);
+        for callback in self.callbacks.iter() {
+            callback(&event);
+        }
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.send(event.clone()).is_ok());
+        let msg = event.to_message();
+        let mut forwarding = self.forwarding.lock().await;
+        match &msg {
+            Some(msg) if msg.activity.is_some() => {
+                forwarding
+                    .current
+                    .insert(msg.socket_id.clone(), msg.clone());
+            }
+            _ => {
+                forwarding.current.remove(event.socket_id());
+            }
+        }
+        if forwarding.paused {
+            return;
+        }
+        for sink in self.sinks.iter() {
+            let sent = match (&event, &msg) {
+                (ActivityEvent::SessionEnd(summary), _) => sink.session_end(summary).await,
+                (_, Some(msg)) => sink.publish(msg).await,
+                (_, None) => Ok(()),
+            };
+            if let Err(e) = sent {
+                warn!("Failed to send activity to {}: {}", sink.name(), e);
+            }
+        }
+    }
+
+    /// Clears every activity on the sinks while paused, and sends each one again on resume
+    async fn set_paused(&self, paused: bool) {
+        let mut forwarding = self.forwarding.lock().await;
+        if forwarding.paused == paused {
+            return;
+        }
+        forwarding.paused = paused;
+        let messages: Vec<IpcActivityMessage> = forwarding
+            .current
+            .values()
+            .map(|msg| match paused {
+                true => IpcActivityMessage {
+                    activity: None,
+                    ..msg.clone()
+                },
+                false => msg.clone(),
+            })
+            .collect();
+        for msg in &messages {
+            for sink in self.sinks.iter() {
+                if let Err(e) = sink.publish(msg).await {
+                    warn!("Failed to send activity to {}: {}", sink.name(), e);
+                }
+            }
+        }
+    }
+}
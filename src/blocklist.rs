@@ -0,0 +1,95 @@
+use crate::{
+    config::{BlockAction, BlocklistConfig, SharedConfig},
+    middleware::Middleware,
+    pattern::{self, Regex},
+    structs::IpcActivityMessage,
+};
+use std::sync::{Arc, Mutex};
+use tracing::{debug, warn};
+
+/// Scrubs or clears activities with a text matching `activity.blocklist`, so private project
+/// names and the like never leave the machine. The patterns are compiled again whenever a
+/// reload changes them.
+pub struct Blocklist {
+    config: SharedConfig,
+    compiled: Mutex<(BlocklistConfig, Arc<Vec<Regex>>)>,
+}
+
+impl Blocklist {
+    pub fn new(config: SharedConfig) -> Self {
+        Self {
+            config,
+            compiled: Mutex::default(),
+        }
+    }
+
+    fn patterns(&self) -> (BlockAction, Arc<Vec<Regex>>) {
+        let config = self.config.read().unwrap();
+        let blocklist = &config.activity.blocklist;
+        let mut compiled = self.compiled.lock().unwrap();
+        if compiled.0 != *blocklist {
+            let words = blocklist.words.iter().map(|word| word_pattern(word));
+            let regexes = words
+                .chain(blocklist.patterns.iter().cloned())
+                .filter_map(|pattern| match Regex::new(&pattern) {
+                    Ok(regex) => Some(regex),
+                    Err(e) => {
+                        warn!("Ignoring blocked pattern {:?}: {}", pattern, e);
+                        None
+                    }
+                })
+                .collect();
+            *compiled = (blocklist.clone(), Arc::new(regexes));
+        }
+        (blocklist.action, compiled.1.clone())
+    }
+}
+
+impl Middleware for Blocklist {
+    fn on_activity(&self, mut msg: IpcActivityMessage) -> Option<IpcActivityMessage> {
+        let (action, patterns) = self.patterns();
+        let Some(activity) = &mut msg.activity else {
+            return Some(msg);
+        };
+        let assets = &mut activity.assets;
+        let mut blocked = 0;
+        for text in [&mut activity.state, &mut activity.details]
+            .into_iter()
+            .chain(assets.large_text.as_mut())
+            .chain(assets.small_text.as_mut())
+            .filter(|text| patterns.iter().any(|regex| regex.is_match(text)))
+        {
+            text.clear();
+            blocked += 1;
+        }
+        match (blocked, action) {
+            (0, _) => {}
+            (_, BlockAction::Scrub) => debug!(
+                "Scrubbed {} blocked texts from the activity of {}",
+                blocked, msg.socket_id
+            ),
+            (_, BlockAction::Suppress) => {
+                debug!(
+                    "Clearing the activity of {}, it contains blocked text",
+                    msg.socket_id
+                );
+                msg.activity = None;
+            }
+        }
+        Some(msg)
+    }
+}
+
+/// Word boundaries only work next to word characters, so `C++` still matches
+fn word_pattern(word: &str) -> String {
+    let boundary = |c: Option<char>| match c {
+        Some(c) if c.is_ascii_alphanumeric() || c == '_' => r"\b",
+        _ => "",
+    };
+    format!(
+        "(?i){}{}{}",
+        boundary(word.chars().next()),
+        pattern::escape(word),
+        boundary(word.chars().last())
+    )
+}
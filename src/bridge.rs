@@ -1,114 +1,679 @@
-use crate::structs::IpcActivityMessage;
-use anyhow::Result;
-use futures_util::{lock::Mutex, SinkExt, StreamExt};
+use crate::{
+    config::{Backpressure, BridgeConfig, PriorityPolicy, QueueConfig, SnapshotStrategy},
+    error::{ArRpcError, Result},
+    priority, process,
+    queue::{self, Coalesce},
+    sink::ActivitySink,
+    state::PersistedState,
+    status::BridgeClientStatus,
+    structs::{ActivityEvent, ActivitySource, IpcActivityMessage, SessionSummary},
+    validate,
+};
+use futures_util::{future, lock::Mutex, stream, Sink, SinkExt, Stream, StreamExt};
 use owo_colors::OwoColorize;
-use serde_json::to_string;
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use serde::Deserialize;
+use serde_json::{json, to_string, Map, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{self, Display},
+    future::Future,
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 use tokio::{
+    io::{AsyncRead, AsyncWrite},
     net::{TcpListener, TcpStream},
     select,
-    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    sync::mpsc::{self, error::TrySendError},
     task,
+    time::{self, timeout, Instant},
+};
+use tokio_tungstenite::{
+    accept_async, accept_hdr_async,
+    tungstenite::{
+        handshake::server::{Request, Response},
+        http::header::AUTHORIZATION,
+        protocol::{frame::coding::CloseCode, CloseFrame},
+        Message,
+    },
 };
-use tokio_tungstenite::{accept_async, tungstenite::Message};
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
 pub enum BridgeCommand {
-    Message(Box<IpcActivityMessage>),
+    /// Serialized once and shared by every client
+    Message {
+        socket_id: String,
+        data: Arc<str>,
+    },
+    /// Unlike activities, every one of these matters
+    Event(Arc<str>),
+    /// The client starts over on what it knows, like after asking for a snapshot
+    Reset,
     Close,
 }
 
-type ClientMap = Arc<Mutex<HashMap<SocketAddr, UnboundedSender<BridgeCommand>>>>;
+// A client only cares about the latest activity of each socket
+impl Coalesce for BridgeCommand {
+    fn coalesces_with(&self, other: &Self) -> bool {
+        match (self, other) {
+            (
+                BridgeCommand::Message { socket_id, .. },
+                BridgeCommand::Message {
+                    socket_id: other, ..
+                },
+            ) => socket_id == other,
+            _ => false,
+        }
+    }
+}
+
+// Sends never block, so the lock is only held to copy the senders out and never across an await
+type ClientMap = Arc<RwLock<HashMap<ClientAddress, Client>>>;
 type ActivityMap = Arc<Mutex<HashMap<String, IpcActivityMessage>>>;
 
+const RESTORED_PREFIX: &str = "restored-";
+const RELAYED_PREFIX: &str = "relayed-";
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long to wait after a failed accept, like running out of file descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+/// How long restored activities wait for their games to show up again
+const RESTORED_GRACE: Duration = Duration::from_secs(60);
+const MAX_LABEL_LEN: usize = 64;
+/// Bumped when the bridge messages change in a way clients have to know about
+const PROTOCOL_VERSION: u32 = 1;
+
 #[derive(Debug)]
+struct Client {
+    tx: queue::Sender<BridgeCommand>,
+    /// What the client says it is, from the `name` and `version` query parameters or an
+    /// `identify` message
+    name: Option<String>,
+    version: Option<String>,
+    /// Everything goes to clients that never subscribed
+    subscription: Option<Subscription>,
+    /// Sockets whose activity matched the subscription when it was last sent
+    matched: HashSet<String>,
+    /// What the client picked in its `hello`, if it sent one
+    features: Option<HashSet<Feature>>,
+}
+
+enum Route {
+    Send,
+    /// The activity no longer matches the subscription, the client gets a clear instead
+    Clear,
+    Skip,
+}
+
+impl Client {
+    fn wants(&self, feature: Feature) -> bool {
+        self.features
+            .as_ref()
+            .is_none_or(|features| features.contains(&feature))
+    }
+
+    /// Like `wants`, for features that change what the client gets and have to be asked for
+    fn picked(&self, feature: Feature) -> bool {
+        self.features
+            .as_ref()
+            .is_some_and(|features| features.contains(&feature))
+    }
+
+    fn route(&mut self, msg: &IpcActivityMessage) -> Route {
+        let Some(subscription) = &self.subscription else {
+            return Route::Send;
+        };
+        if subscription.accepts(msg) {
+            self.matched.insert(msg.socket_id.clone());
+            return match subscription.clears_only {
+                true => Route::Skip,
+                false => Route::Send,
+            };
+        }
+        match (self.matched.remove(&msg.socket_id), &msg.activity) {
+            (true, Some(_)) => Route::Clear,
+            (true, None) => Route::Send,
+            (false, _) => Route::Skip,
+        }
+    }
+
+    fn status(&self, addr: ClientAddress) -> BridgeClientStatus {
+        BridgeClientStatus {
+            address: addr.to_string(),
+            name: self.name.clone(),
+            version: self.version.clone(),
+        }
+    }
+}
+
+/// The part of the traffic a web client wants, empty lists allow everything. Clears are sent
+/// for activities that matched before.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Subscription {
+    pub application_ids: Vec<String>,
+    pub sources: Vec<ActivitySource>,
+    /// Like `2` for listening
+    pub activity_types: Vec<u64>,
+    /// Only the clears of matching activities, not the activities themselves
+    pub clears_only: bool,
+}
+
+impl Subscription {
+    fn accepts(&self, msg: &IpcActivityMessage) -> bool {
+        let Some(activity) = &msg.activity else {
+            return false;
+        };
+        fn allows<T: PartialEq>(list: &[T], value: &T) -> bool {
+            list.is_empty() || list.contains(value)
+        }
+        allows(&self.application_ids, &activity.application_id)
+            && allows(&self.sources, &msg.source)
+            && allows(&self.activity_types, &activity.r#type)
+    }
+}
+
+/// Messages web clients may send besides relayed activities
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    Identify {
+        name: String,
+        version: Option<String>,
+    },
+    Subscribe(Subscription),
+    Hello {
+        #[serde(default)]
+        version: u32,
+        #[serde(default)]
+        features: Vec<String>,
+    },
+    Snapshot,
+}
+
+/// What clients may ask for in their `hello`, clients that never send one get the old behavior
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Feature {
+    /// `session_end` events, if `bridge.session_events` is on
+    SessionEvents,
+    /// Answering `snapshot` requests with the current activities and a `snapshot_end`
+    Snapshot,
+    /// `patch` messages with what changed instead of whole activities
+    Diffs,
+}
+
+impl Feature {
+    const ALL: [Feature; 3] = [Feature::SessionEvents, Feature::Snapshot, Feature::Diffs];
+
+    fn name(self) -> &'static str {
+        match self {
+            Feature::SessionEvents => "session_events",
+            Feature::Snapshot => "snapshot",
+            Feature::Diffs => "diffs",
+        }
+    }
+}
+
+/// Turns activity updates into JSON merge patches against what the client got last, once it
+/// picked `diffs`
+#[derive(Default)]
+struct Diffs {
+    enabled: bool,
+    sent: HashMap<String, Value>,
+}
+
+impl Diffs {
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.sent.clear();
+        }
+    }
+
+    /// What to send for `command`, `None` if the client already has it
+    fn encode(&mut self, command: &BridgeCommand) -> Result<Option<String>> {
+        let (socket_id, data) = match command {
+            BridgeCommand::Message { socket_id, data } if self.enabled => (socket_id, data),
+            BridgeCommand::Message { data, .. } | BridgeCommand::Event(data) => {
+                return Ok(Some(data.to_string()))
+            }
+            BridgeCommand::Reset => {
+                self.sent.clear();
+                return Ok(None);
+            }
+            BridgeCommand::Close => return Ok(None),
+        };
+        let msg: Value = serde_json::from_str(data)?;
+        // Clears go out whole, so nothing is kept around for sockets that are gone
+        if msg["activity"].is_null() {
+            self.sent.remove(socket_id);
+            return Ok(Some(data.to_string()));
+        }
+        let text = match self.sent.get(socket_id) {
+            Some(previous) => match merge_patch(previous, &msg) {
+                Some(patch) => to_string(&json!({
+                    "type": "patch",
+                    "socket_id": socket_id,
+                    "patch": patch,
+                }))?,
+                None => return Ok(None),
+            },
+            None => data.to_string(),
+        };
+        self.sent.insert(socket_id.clone(), msg);
+        Ok(Some(text))
+    }
+}
+
+// Takes as long for any wrong token of the right length, so it can't be guessed byte by byte
+fn token_matches(token: &str, expected: &str) -> bool {
+    token.len() == expected.len()
+        && token
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// RFC 7386, `None` if there is no difference
+fn merge_patch(old: &Value, new: &Value) -> Option<Value> {
+    let (Value::Object(old), Value::Object(new)) = (old, new) else {
+        return (old != new).then(|| new.clone());
+    };
+    let mut patch = Map::new();
+    for (key, value) in new {
+        match old.get(key) {
+            Some(previous) => {
+                if let Some(changed) = merge_patch(previous, value) {
+                    patch.insert(key.clone(), changed);
+                }
+            }
+            None if value.is_null() => {}
+            None => {
+                patch.insert(key.clone(), value.clone());
+            }
+        }
+    }
+    for key in old.keys().filter(|key| !new.contains_key(*key)) {
+        patch.insert(key.clone(), Value::Null);
+    }
+    (!patch.is_empty()).then_some(Value::Object(patch))
+}
+
+/// Where a web client connected from, Unix socket peers have no address and get numbered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ClientAddress {
+    Tcp(SocketAddr),
+    Unix(u64),
+}
+
+impl Display for ClientAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientAddress::Tcp(addr) => addr.fmt(f),
+            ClientAddress::Unix(id) => write!(f, "unix#{}", id),
+        }
+    }
+}
+
+trait Listener: Send + 'static {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    fn accept(&mut self) -> impl Future<Output = io::Result<(Self::Stream, ClientAddress)>> + Send;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    async fn accept(&mut self) -> io::Result<(TcpStream, ClientAddress)> {
+        let (stream, addr) = TcpListener::accept(self).await?;
+        Ok((stream, ClientAddress::Tcp(addr)))
+    }
+}
+
+#[cfg(unix)]
+struct UnixBridgeListener {
+    listener: tokio::net::UnixListener,
+    next_id: u64,
+}
+
+#[cfg(unix)]
+impl Listener for UnixBridgeListener {
+    type Stream = tokio::net::UnixStream;
+
+    async fn accept(&mut self) -> io::Result<(Self::Stream, ClientAddress)> {
+        let (stream, _) = self.listener.accept().await?;
+        self.next_id += 1;
+        Ok((stream, ClientAddress::Unix(self.next_id)))
+    }
+}
+
+// Clones share the clients and activities, for connections that relay activities
+#[derive(Debug, Clone)]
 pub struct BridgeServer {
     client_map: ClientMap,
     activity_map: ActivityMap,
+    priority: Option<PriorityPolicy>,
+    session_events: bool,
+    /// Removed again on close
+    socket_path: Option<PathBuf>,
+    /// Relayed activities, with `bridge.accept_relay`, go through the pipeline like the ones of
+    /// IPC clients before they are forwarded
+    relay_tx: Option<mpsc::Sender<ActivityEvent>>,
+    relay_rx: Arc<std::sync::Mutex<Option<mpsc::Receiver<ActivityEvent>>>>,
 }
 
 impl BridgeServer {
-    pub async fn try_bind() -> Result<BridgeServer> {
-        let client_map = ClientMap::new(Mutex::new(HashMap::new()));
+    pub async fn try_bind(config: &BridgeConfig) -> Result<BridgeServer> {
+        let client_map = ClientMap::new(RwLock::new(HashMap::new()));
         let activity_map = ActivityMap::new(Mutex::new(HashMap::new()));
-        let listener = TcpListener::bind("127.0.0.1:1337").await?;
+        let listeners = Self::bind(&config.listen).await?;
+        if listeners.is_empty() && config.unix_socket.is_none() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "Nowhere to listen on").into());
+        }
+        #[cfg(unix)]
+        let unix_listener = match &config.unix_socket {
+            Some(path) => Some(Self::bind_unix(path).await?),
+            None => None,
+        };
+        #[cfg(not(unix))]
+        if config.unix_socket.is_some() {
+            warn!("The bridge can only listen on a Unix socket on Unix");
+        }
+        let exposed = config
+            .listen
+            .iter()
+            .any(|address| !address.ip().is_loopback());
+        if config.accept_relay && config.relay_token.is_none() && exposed {
+            warn!("Anyone who can reach the bridge may relay activities, set bridge.relay_token");
+        }
+        let (relay_tx, relay_rx) = match config.accept_relay {
+            true => {
+                let (tx, rx) = mpsc::channel(config.client_queue);
+                (Some(tx), Some(rx))
+            }
+            false => (None, None),
+        };
+        let server = Self {
+            client_map,
+            activity_map,
+            priority: config.priority.clone(),
+            session_events: config.session_events,
+            socket_path: config.unix_socket.clone().filter(|_| cfg!(unix)),
+            relay_tx,
+            relay_rx: Arc::new(std::sync::Mutex::new(relay_rx)),
+        };
+        for listener in listeners {
+            task::spawn(Self::accept_loop(listener, config.clone(), server.clone()));
+        }
+        #[cfg(unix)]
+        if let Some(listener) = unix_listener {
+            task::spawn(Self::accept_loop(listener, config.clone(), server.clone()));
+        }
+        Ok(server)
+    }
+
+    /// What web clients relay, with `bridge.accept_relay`. Relayed activities are only forwarded
+    /// once they come back through [`ActivitySink::publish`], and can be taken once.
+    pub fn relayed_events(&self) -> Option<impl Stream<Item = ActivityEvent> + Send + Unpin> {
+        let rx = self.relay_rx.lock().unwrap().take()?;
+        Some(Box::pin(stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|event| (event, rx))
+        })))
+    }
+
+    /// Permissions come from the directory, like `$XDG_RUNTIME_DIR`
+    #[cfg(unix)]
+    async fn bind_unix(path: &std::path::Path) -> Result<UnixBridgeListener> {
+        use tokio::net::{UnixListener, UnixStream};
+
+        let listener = match UnixListener::bind(path) {
+            Ok(listener) => listener,
+            Err(e) if e.kind() == ErrorKind::AddrInUse => {
+                // Nobody answering means a previous instance crashed without cleaning up
+                if UnixStream::connect(path).await.is_ok() {
+                    return Err(e.into());
+                }
+                std::fs::remove_file(path)?;
+                UnixListener::bind(path)?
+            }
+            Err(e) => return Err(e.into()),
+        };
         info!(
             "{} {}",
-            "Bridge Started on port".cyan(),
-            "1337".yellow().bold()
+            "Bridge Started on".cyan(),
+            path.display().yellow().bold()
         );
-        task::spawn(Self::accept_loop(
+        Ok(UnixBridgeListener {
             listener,
-            client_map.clone(),
-            activity_map.clone(),
-        ));
-        Ok(Self {
-            client_map,
-            activity_map,
+            next_id: 0,
         })
     }
 
+    /// Addresses that don't exist here, like `::1` without IPv6, are skipped as long as one
+    /// works. One that is taken fails, as clients would end up at whatever has it.
+    async fn bind(addresses: &[SocketAddr]) -> Result<Vec<TcpListener>> {
+        let mut listeners = Vec::new();
+        let mut last_error = None;
+        for address in addresses {
+            match TcpListener::bind(address).await {
+                Ok(listener) => {
+                    if !address.ip().is_loopback() {
+                        warn!("Bridge is reachable from other machines on {}", address);
+                    }
+                    info!("{} {}", "Bridge Started on".cyan(), address.yellow().bold());
+                    listeners.push(listener);
+                }
+                Err(e) if e.kind() == ErrorKind::AddrInUse => {
+                    return Err(ArRpcError::BridgePortBusy(address.port()));
+                }
+                Err(e) => {
+                    warn!("Failed to bind the bridge to {}: {}", address, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        match (listeners.is_empty(), last_error) {
+            (true, Some(e)) => Err(e.into()),
+            _ => Ok(listeners),
+        }
+    }
+
     async fn accept_loop(
-        listener: TcpListener,
-        client_map: ClientMap,
-        activity_map: ActivityMap,
+        mut listener: impl Listener,
+        config: BridgeConfig,
+        server: Self,
     ) -> Result<()> {
+        let client_map = &server.client_map;
         loop {
-            let (stream, addr) = listener.accept().await?;
-            let (tx, rx) = mpsc::unbounded_channel();
-            client_map.lock().await.insert(addr, tx);
+            // Listeners don't close on their own, so errors are all worth retrying
+            let (stream, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Failed to accept a web client: {}", e);
+                    time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            };
+            if client_map.read().unwrap().len() >= config.max_connections {
+                warn!(
+                    "Refusing web client {}, {} are connected already",
+                    addr, config.max_connections
+                );
+                task::spawn(Self::refuse(stream));
+                continue;
+            }
+            let (tx, rx) = queue::channel(QueueConfig {
+                capacity: config.client_queue,
+                backpressure: Backpressure::CoalesceLatest,
+            });
+            let client = Client {
+                tx,
+                name: None,
+                version: None,
+                subscription: None,
+                matched: HashSet::new(),
+                features: None,
+            };
+            client_map.write().unwrap().insert(addr, client);
 
-            info!("{}", "New Web Client connected!".green());
-            task::spawn(Self::handle_stream(
-                stream,
-                addr,
-                rx,
-                client_map.clone(),
-                activity_map.clone(),
-            ));
+            info!("{} {}", "New Web Client connected!".green(), addr);
+            let server = server.clone();
+            let config = config.clone();
+            task::spawn(async move {
+                let mut relayed = HashMap::new();
+                let result = server
+                    .handle_stream(stream, addr, rx, &config, &mut relayed)
+                    .await;
+                if let Err(e) = result {
+                    debug!("Web client {} closed: {}", server.describe(addr), e);
+                }
+                let client = server.client_map.write().unwrap().remove(&addr);
+                server.end_relay(relayed).await;
+                let client =
+                    client.map_or(addr.to_string(), |client| client.status(addr).to_string());
+                info!("{} {}", "Web Client Disconnected!".red(), client);
+            });
         }
     }
 
+    async fn refuse(stream: impl AsyncRead + AsyncWrite + Unpin) {
+        let refused = async {
+            let mut ws_stream = accept_async(stream).await?;
+            ws_stream
+                .close(Some(CloseFrame {
+                    code: CloseCode::Policy,
+                    reason: "Too many connections".into(),
+                }))
+                .await
+        };
+        if let Ok(Err(e)) = timeout(CLOSE_TIMEOUT, refused).await {
+            debug!("Failed to refuse web client: {}", e);
+        }
+    }
+
+    /// Activities the client relayed, with `bridge.accept_relay`, are collected in `relayed` with
+    /// their pids
+    // The handshake callback signature is dictated by tungstenite
+    #[allow(clippy::result_large_err)]
     async fn handle_stream(
-        stream: TcpStream,
-        addr: SocketAddr,
-        mut rx: UnboundedReceiver<BridgeCommand>,
-        client_map: ClientMap,
-        activity_map: ActivityMap,
+        &self,
+        stream: impl AsyncRead + AsyncWrite + Unpin,
+        addr: ClientAddress,
+        mut rx: queue::Receiver<BridgeCommand>,
+        config: &BridgeConfig,
+        relayed: &mut HashMap<String, usize>,
     ) -> Result<()> {
-        let ws_stream = accept_async(stream).await?;
+        let activity_map = &self.activity_map;
+        let stall_timeout = Duration::from_secs(config.stall_timeout_secs);
+        let mut query = String::new();
+        let mut browser = false;
+        let mut token = None;
+        let ws_stream = accept_hdr_async(stream, |request: &Request, response: Response| {
+            query = request.uri().query().unwrap_or_default().to_string();
+            browser = request.headers().contains_key("origin");
+            // Not in the query, which ends up in logs and shell histories
+            token = request
+                .headers()
+                .get(AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .map(str::to_string);
+            Ok(response)
+        })
+        .await?;
+        let mut name = None;
+        let mut version = None;
+        for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+            match key {
+                "name" => name = Some(value),
+                "version" => version = Some(value),
+                _ => {}
+            }
+        }
+        // Pages open in a browser can't relay, they always send an `Origin`. What socket ids of
+        // activities the client sends get prefixed with, if it may send any.
+        let relay = (config.accept_relay
+            && !browser
+            && config.relay_token.as_deref().is_none_or(|expected| {
+                token
+                    .as_deref()
+                    .is_some_and(|token| token_matches(token, expected))
+            }))
+        .then(|| format!("{}{}-", RELAYED_PREFIX, addr));
+        if config.accept_relay && relay.is_none() {
+            debug!("Web client {} may not relay activities", addr);
+        }
+        if let Some(name) = name {
+            self.identify(addr, name, version);
+        }
         let (mut write, mut read) = ws_stream.split();
 
-        // Catch up on activity
-        for (_, msg) in activity_map.lock().await.iter() {
-            if msg.activity.is_some() {
-                let msg = to_string(&msg)?;
-                write.send(Message::Text(msg)).await?;
-            }
+        // Catch up on activity, serialized up front so a slow client doesn't hold the map
+        let current: Vec<String> = priority::snapshot(
+            activity_map.lock().await.values(),
+            self.priority.as_ref(),
+            config.snapshot,
+        )
+        .into_iter()
+        .map(to_string)
+        .collect::<serde_json::Result<_>>()?;
+        for msg in current {
+            send_text(&mut write, msg, stall_timeout).await?;
         }
 
+        // Held back for `bridge.batch_window_ms`, with only the latest activity of each socket
+        let batch_window = Duration::from_millis(config.batch_window_ms);
+        let mut batch: Vec<BridgeCommand> = Vec::new();
+        let mut flush_at = None;
+        let mut diffs = Diffs::default();
         loop {
             select! {
                 msg = rx.recv() => {
                     if let Some(msg) = msg {
                         match msg {
-                            BridgeCommand::Message(msg) => {
-                                let msg = to_string(&msg)?;
-                                write.send(Message::Text(msg)).await?;
-                            }
                             BridgeCommand::Close => {
-                                for (_, msg) in activity_map.lock().await.iter_mut() {
-                                    msg.activity = None;
-                                    let msg = to_string(&msg)?;
-                                    write.send(Message::Text(msg)).await?;
+                                send_batch(&mut write, &mut batch, &mut diffs, stall_timeout).await?;
+                                let matched = self.client_map.read().unwrap().get(&addr).and_then(|client| {
+                                    client.subscription.as_ref().map(|_| client.matched.clone())
+                                });
+                                let cleared: Vec<String> = activity_map
+                                    .lock()
+                                    .await
+                                    .values()
+                                    .filter(|msg| matched.as_ref().is_none_or(|matched| matched.contains(&msg.socket_id)))
+                                    .map(|msg| IpcActivityMessage {
+                                        activity: None,
+                                        ..msg.clone()
+                                    })
+                                    .map(|msg| to_string(&msg))
+                                    .collect::<serde_json::Result<_>>()?;
+                                for msg in cleared {
+                                    send_text(&mut write, msg, stall_timeout).await?;
                                 }
                                 return Ok(())
                             },
+                            msg if batch_window.is_zero() => {
+                                if let Some(text) = diffs.encode(&msg)? {
+                                    send_text(&mut write, text, stall_timeout).await?;
+                                }
+                            }
+                            msg => {
+                                batch.retain(|queued| !msg.coalesces_with(queued));
+                                batch.push(msg);
+                                flush_at.get_or_insert_with(|| Instant::now() + batch_window);
+                            }
                         }
                     } else {
                         break;
                     }
                 }
+                _ = time::sleep_until(flush_at.unwrap_or_else(Instant::now)), if flush_at.is_some() => {
+                    flush_at = None;
+                    send_batch(&mut write, &mut batch, &mut diffs, stall_timeout).await?;
+                }
                 evt = read.next() => {
                     match evt {
                         None => break,
@@ -117,6 +682,10 @@ impl BridgeServer {
                                 Ok(msg) => {
                                     match msg {
                                         Message::Close(_) => break,
+                                        Message::Text(text) => {
+                                            self.client_message(&text, addr, config, relay.as_deref(), relayed).await?;
+                                            diffs.set_enabled(self.picked(addr, Feature::Diffs));
+                                        }
                                         e => {
                                             debug!("{}", e);
                                         }
@@ -132,31 +701,612 @@ impl BridgeServer {
                 }
             }
         }
-        client_map.lock().await.remove(&addr);
-        info!("{}", "Web Client Disconnected!".red());
         Ok(())
     }
 
-    pub async fn send_activity(&self, msg: IpcActivityMessage) -> Result<()> {
-        self.activity_map
-            .lock()
-            .await
-            .insert(msg.socket_id.clone(), msg.clone());
+    async fn client_message(
+        &self,
+        text: &str,
+        addr: ClientAddress,
+        config: &BridgeConfig,
+        relay: Option<&str>,
+        relayed: &mut HashMap<String, usize>,
+    ) -> Result<()> {
+        match (serde_json::from_str(text), relay) {
+            (Ok(ClientMessage::Identify { name, version }), _) => {
+                self.identify(addr, &name, version.as_deref());
+                Ok(())
+            }
+            (Ok(ClientMessage::Subscribe(subscription)), _) => {
+                self.subscribe(addr, subscription, config.snapshot).await
+            }
+            (Ok(ClientMessage::Hello { version, features }), _) => {
+                self.hello(addr, version, &features, config, relay.is_some())
+            }
+            (Ok(ClientMessage::Snapshot), _) => self.send_snapshot(addr, config.snapshot).await,
+            (Err(_), Some(prefix)) => self.relay_message(text, prefix, relayed).await,
+            (Err(e), None) => {
+                debug!("Ignoring message from web client {}: {}", addr, e);
+                Ok(())
+            }
+        }
+    }
+
+    fn identify(&self, addr: ClientAddress, name: &str, version: Option<&str>) {
+        let label = |text: &str| validate::truncate(&validate::clean_text(text), MAX_LABEL_LEN);
+        let status = {
+            let mut client_map = self.client_map.write().unwrap();
+            let Some(client) = client_map.get_mut(&addr) else {
+                return;
+            };
+            client.name = Some(label(name));
+            client.version = version.map(label);
+            client.status(addr)
+        };
+        info!(
+            "{} {}",
+            "Web client identified as".cyan(),
+            status.yellow().bold()
+        );
+    }
+
+    /// Brings the client in line with what it has so far: activities that stopped matching are
+    /// cleared and ones that started matching are sent
+    async fn subscribe(
+        &self,
+        addr: ClientAddress,
+        subscription: Subscription,
+        strategy: Option<SnapshotStrategy>,
+    ) -> Result<()> {
+        let activity_map = self.activity_map.lock().await;
+        let current = priority::snapshot(activity_map.values(), self.priority.as_ref(), strategy);
+        let mut client_map = self.client_map.write().unwrap();
+        let Some(client) = client_map.get_mut(&addr) else {
+            return Ok(());
+        };
+        let has: HashSet<String> = match &client.subscription {
+            None => current.iter().map(|msg| msg.socket_id.clone()).collect(),
+            Some(previous) if previous.clears_only => HashSet::new(),
+            Some(_) => client.matched.clone(),
+        };
+        client.matched.clear();
+        for msg in current {
+            let accepted = subscription.accepts(msg);
+            if accepted {
+                client.matched.insert(msg.socket_id.clone());
+            }
+            let msg = match (
+                accepted && !subscription.clears_only,
+                has.contains(&msg.socket_id),
+            ) {
+                (true, false) => msg.clone(),
+                (false, true) => IpcActivityMessage {
+                    activity: None,
+                    ..msg.clone()
+                },
+                _ => continue,
+            };
+            // One that is full is disconnected with the next update anyway
+            let _ = client.tx.try_send(BridgeCommand::Message {
+                socket_id: msg.socket_id.clone(),
+                data: to_string(&msg)?.into(),
+            });
+        }
+        info!(
+            "{} {}",
+            "Web client subscribed:".cyan(),
+            client.status(addr).yellow().bold()
+        );
+        client.subscription = Some(subscription);
+        Ok(())
+    }
+
+    /// Answers with the protocol version both sides speak, what this bridge can do and which of
+    /// the features the client asked for it gets
+    fn hello(
+        &self,
+        addr: ClientAddress,
+        version: u32,
+        requested: &[String],
+        config: &BridgeConfig,
+        relay: bool,
+    ) -> Result<()> {
+        let mut capabilities = vec!["identify", "subscribe"];
+        if relay {
+            capabilities.push("relay");
+        }
+        let available: Vec<Feature> = Feature::ALL
+            .into_iter()
+            .filter(|feature| *feature != Feature::SessionEvents || config.session_events)
+            .collect();
+        capabilities.extend(available.iter().map(|feature| feature.name()));
+        let features: HashSet<Feature> = available
+            .into_iter()
+            .filter(|feature| requested.iter().any(|name| name == feature.name()))
+            .collect();
+        let reply = json!({
+            "type": "hello",
+            "version": version.clamp(1, PROTOCOL_VERSION),
+            "capabilities": capabilities,
+            "features": features.iter().map(|feature| feature.name()).collect::<Vec<_>>(),
+        });
+        let mut client_map = self.client_map.write().unwrap();
+        let Some(client) = client_map.get_mut(&addr) else {
+            return Ok(());
+        };
+        debug!("Web client {} picked {:?}", client.status(addr), features);
+        client.features = Some(features);
+        let _ = client
+            .tx
+            .try_send(BridgeCommand::Event(to_string(&reply)?.into()));
+        Ok(())
+    }
+
+    /// What the client would get on connecting, as far as its subscription lets it through
+    async fn send_snapshot(
+        &self,
+        addr: ClientAddress,
+        strategy: Option<SnapshotStrategy>,
+    ) -> Result<()> {
+        let activity_map = self.activity_map.lock().await;
+        let current = priority::snapshot(activity_map.values(), self.priority.as_ref(), strategy);
+        let mut client_map = self.client_map.write().unwrap();
+        let Some(client) = client_map.get_mut(&addr) else {
+            return Ok(());
+        };
+        if !client.picked(Feature::Snapshot) {
+            debug!(
+                "Web client {} asked for a snapshot without the feature",
+                addr
+            );
+            return Ok(());
+        }
+        // Patches would be against whatever the client got before
+        let mut messages = vec![BridgeCommand::Reset];
+        for msg in current {
+            if client
+                .subscription
+                .as_ref()
+                .is_none_or(|subscription| subscription.accepts(msg) && !subscription.clears_only)
+            {
+                messages.push(BridgeCommand::Message {
+                    socket_id: msg.socket_id.clone(),
+                    data: to_string(msg)?.into(),
+                });
+            }
+        }
+        messages.push(BridgeCommand::Event(
+            to_string(&json!({ "type": "snapshot_end" }))?.into(),
+        ));
+        for msg in messages {
+            // One that is full is disconnected with the next update anyway
+            let _ = client.tx.try_send(msg);
+        }
+        Ok(())
+    }
+
+    fn picked(&self, addr: ClientAddress, feature: Feature) -> bool {
+        let client_map = self.client_map.read().unwrap();
+        client_map
+            .get(&addr)
+            .is_some_and(|client| client.picked(feature))
+    }
+
+    /// The address and, once it said so, what the client is
+    fn describe(&self, addr: ClientAddress) -> String {
+        match self.client_map.read().unwrap().get(&addr) {
+            Some(client) => client.status(addr).to_string(),
+            None => addr.to_string(),
+        }
+    }
+
+    async fn relay_message(
+        &self,
+        text: &str,
+        prefix: &str,
+        relayed: &mut HashMap<String, usize>,
+    ) -> Result<()> {
+        let mut msg: IpcActivityMessage = match serde_json::from_str(text) {
+            Ok(msg) => msg,
+            // Like the other side's own `session_end` events
+            Err(e) => {
+                debug!("Ignoring relayed message: {}", e);
+                return Ok(());
+            }
+        };
+        msg.socket_id = format!("{}{}", prefix, msg.socket_id);
+        let event = match msg.activity {
+            Some(_) => {
+                relayed.insert(msg.socket_id.clone(), msg.pid);
+                ActivityEvent::Set(Box::new(msg))
+            }
+            None => {
+                relayed.remove(&msg.socket_id);
+                ActivityEvent::Clear {
+                    socket_id: msg.socket_id,
+                    pid: msg.pid,
+                }
+            }
+        };
+        self.relay(event).await;
+        Ok(())
+    }
+
+    /// Clears what a relaying client sent once it is gone
+    async fn end_relay(&self, relayed: HashMap<String, usize>) {
+        for (socket_id, pid) in relayed {
+            self.relay(ActivityEvent::Clear { socket_id, pid }).await;
+        }
+    }
+
+    async fn relay(&self, event: ActivityEvent) {
+        let Some(tx) = &self.relay_tx else {
+            return;
+        };
+        if tx.send(event).await.is_err() {
+            debug!("Dropping relayed activity, nothing takes them");
+        }
+    }
+
+    /// Clears restored activities nobody took up
+    async fn drop_activities(&self, socket_ids: HashSet<String>) {
+        for socket_id in socket_ids {
+            let Some(msg) = self.activity_map.lock().await.get(&socket_id).cloned() else {
+                continue;
+            };
+            let cleared = IpcActivityMessage {
+                activity: None,
+                ..msg
+            };
+            if let Err(e) = self.send_activity(cleared).await {
+                debug!("Failed to clear activity: {}", e);
+            }
+            self.activity_map.lock().await.remove(&socket_id);
+        }
+    }
+
+    pub async fn clients(&self) -> Vec<BridgeClientStatus> {
         self.client_map
-            .lock()
-            .await
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(addr, client)| client.status(*addr))
+            .collect()
+    }
+
+    pub async fn snapshot(&self) -> PersistedState {
+        PersistedState::new(
+            self.activity_map
+                .lock()
+                .await
+                .values()
+                // Relayed ones are the other instance's to keep
+                .filter(|msg| msg.activity.is_some() && !msg.socket_id.starts_with(RELAYED_PREFIX))
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /// Restored activities of processes that are gone are dropped, the others are cleared after
+    /// `RESTORED_GRACE` unless their game sets a new one
+    pub async fn restore(&self, state: PersistedState) {
+        let mut activity_map = self.activity_map.lock().await;
+        let mut restored = 0;
+        for mut msg in state.activities {
+            if !process::is_alive(msg.pid) {
+                continue;
+            }
+            // Socket ids restart from zero, so keep restored ones out of the way of new connections
+            msg.socket_id = format!("{}{}", RESTORED_PREFIX, msg.socket_id);
+            activity_map.insert(msg.socket_id.clone(), msg);
+            restored += 1;
+        }
+        if restored == 0 {
+            return;
+        }
+        info!(
+            "{} {}",
+            "Restored activities:".cyan(),
+            restored.yellow().bold()
+        );
+        let server = self.clone();
+        task::spawn(async move {
+            time::sleep(RESTORED_GRACE).await;
+            let restored: HashSet<String> = server
+                .activity_map
+                .lock()
+                .await
+                .keys()
+                .filter(|socket_id| socket_id.starts_with(RESTORED_PREFIX))
+                .cloned()
+                .collect();
+            if !restored.is_empty() {
+                debug!("Clearing {} restored activities", restored.len());
+            }
+            server.drop_activities(restored).await;
+        });
+    }
+
+    /// Every activity is kept, but only the visible ones reach the web clients
+    pub async fn send_activity(&self, msg: IpcActivityMessage) -> Result<()> {
+        let mut activity_map = self.activity_map.lock().await;
+        let before: Vec<String> = self.visible_ids(&activity_map);
+        // A game reconnects with a new socket id, so its restored activity is outdated. Pids
+        // aren't unique enough for this, clients without a known process all have 0.
+        let application_id = msg
+            .activity
+            .as_ref()
+            .map(|activity| &activity.application_id);
+        let outdated: Vec<String> = activity_map
+            .iter()
+            .filter(|(socket_id, restored)| {
+                socket_id.starts_with(RESTORED_PREFIX)
+                    && restored.source == msg.source
+                    && restored
+                        .activity
+                        .as_ref()
+                        .map(|activity| &activity.application_id)
+                        == application_id
+            })
+            .map(|(socket_id, _)| socket_id.clone())
+            .collect();
+        let mut hidden: Vec<IpcActivityMessage> = outdated
             .iter()
-            .try_for_each(|(_, tx)| tx.send(BridgeCommand::Message(Box::new(msg.clone()))))?;
+            .filter_map(|socket_id| activity_map.remove(socket_id))
+            .collect();
+        let socket_id = msg.socket_id.clone();
+        activity_map.insert(socket_id.clone(), msg);
+        let after: Vec<String> = self.visible_ids(&activity_map);
+
+        hidden.extend(
+            before
+                .iter()
+                .filter(|id| !after.contains(id))
+                .filter_map(|id| activity_map.get(id))
+                .cloned(),
+        );
+        for mut msg in hidden {
+            if before.contains(&msg.socket_id) {
+                msg.activity = None;
+                self.broadcast(&msg).await?;
+            }
+        }
+        for id in &after {
+            if *id == socket_id || !before.contains(id) {
+                self.broadcast(&activity_map[id]).await?;
+            }
+        }
         Ok(())
     }
 
-    pub async fn close(&self) -> Result<()> {
-        info!("{}", "Shutting Down Bridge".magenta());
+    fn visible_ids(&self, activity_map: &HashMap<String, IpcActivityMessage>) -> Vec<String> {
+        priority::visible(activity_map.values(), self.priority.as_ref())
+            .into_iter()
+            .map(|msg| msg.socket_id.clone())
+            .collect()
+    }
+
+    pub async fn send_session_end(&self, summary: &SessionSummary) -> Result<()> {
+        if !self.session_events {
+            return Ok(());
+        }
+        let mut msg = serde_json::to_value(summary)?;
+        msg["type"] = json!("session_end");
+        let data: Arc<str> = to_string(&msg)?.into();
+        self.send_command(|client| {
+            client
+                .wants(Feature::SessionEvents)
+                .then(|| BridgeCommand::Event(data.clone()))
+        });
+        Ok(())
+    }
+
+    async fn broadcast(&self, msg: &IpcActivityMessage) -> Result<()> {
+        let data: Arc<str> = to_string(msg)?.into();
+        // Only serialized if some client needs it
+        let mut cleared: Option<Arc<str>> = None;
+        let mut clear = || -> Arc<str> {
+            cleared
+                .get_or_insert_with(|| {
+                    let msg = IpcActivityMessage {
+                        activity: None,
+                        ..msg.clone()
+                    };
+                    to_string(&msg).unwrap_or_default().into()
+                })
+                .clone()
+        };
+        self.send_command(|client| {
+            let data = match client.route(msg) {
+                Route::Send => data.clone(),
+                Route::Clear => clear(),
+                Route::Skip => return None,
+            };
+            Some(BridgeCommand::Message {
+                socket_id: msg.socket_id.clone(),
+                data,
+            })
+        });
+        Ok(())
+    }
+
+    fn send_command(&self, mut command: impl FnMut(&mut Client) -> Option<BridgeCommand>) {
+        self.client_map.write().unwrap().retain(|addr, client| {
+            let Some(command) = command(client) else {
+                return true;
+            };
+            // Full means the client fell behind on every socket, it would never catch up
+            match client.tx.try_send(command) {
+                Err(TrySendError::Full(_)) => {
+                    warn!(
+                        "Disconnecting web client {}, it stopped reading",
+                        client.status(*addr)
+                    );
+                    false
+                }
+                _ => true,
+            }
+        });
+    }
+
+    fn senders(&self) -> Vec<(ClientAddress, queue::Sender<BridgeCommand>)> {
         self.client_map
-            .lock()
-            .await
+            .read()
+            .unwrap()
             .iter()
-            .try_for_each(|(_, tx)| tx.send(BridgeCommand::Close))?;
+            .map(|(addr, client)| (*addr, client.tx.clone()))
+            .collect()
+    }
+
+    pub async fn close(&self) -> Result<()> {
+        info!("{}", "Shutting Down Bridge".magenta());
+        let clients = self.senders();
+        for (_, tx) in &clients {
+            // A client too far behind to take this is dropped with the rest of the process
+            let _ = tx.try_send(BridgeCommand::Close);
+        }
+        // The receiver is dropped once a client got its clear messages, give slow ones a moment
+        let flushed = future::join_all(clients.iter().map(|(_, tx)| tx.closed()));
+        if timeout(CLOSE_TIMEOUT, flushed).await.is_err() {
+            debug!("Timed out waiting for web clients to close");
+        }
+        if let Some(path) = &self.socket_path {
+            tokio::fs::remove_file(path).await?;
+        }
         Ok(())
     }
 }
+
+async fn send_batch<S>(
+    write: &mut S,
+    batch: &mut Vec<BridgeCommand>,
+    diffs: &mut Diffs,
+    stall_timeout: Duration,
+) -> Result<()>
+where
+    S: Sink<Message, Error = tokio_tungstenite::tungstenite::Error> + Unpin,
+{
+    for msg in batch.drain(..) {
+        if let Some(text) = diffs.encode(&msg)? {
+            send_text(write, text, stall_timeout).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn send_text<S>(write: &mut S, msg: String, stall_timeout: Duration) -> Result<()>
+where
+    S: Sink<Message, Error = tokio_tungstenite::tungstenite::Error> + Unpin,
+{
+    match timeout(stall_timeout, write.send(Message::Text(msg))).await {
+        Ok(sent) => Ok(sent?),
+        Err(_) => Err(ArRpcError::Io(ErrorKind::TimedOut.into())),
+    }
+}
+
+impl ActivitySink for BridgeServer {
+    async fn publish(&self, msg: &IpcActivityMessage) -> Result<()> {
+        self.send_activity(msg.clone()).await
+    }
+
+    async fn session_end(&self, summary: &SessionSummary) -> Result<()> {
+        self.send_session_end(summary).await
+    }
+
+    fn name(&self) -> String {
+        "bridge".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // What a web client does with a patch, RFC 7386
+    fn apply(target: &mut Value, patch: &Value) {
+        let Value::Object(patch) = patch else {
+            *target = patch.clone();
+            return;
+        };
+        if !target.is_object() {
+            *target = Value::Object(Map::new());
+        }
+        let target = target.as_object_mut().unwrap();
+        for (key, value) in patch {
+            if value.is_null() {
+                target.remove(key);
+            } else {
+                apply(target.entry(key.clone()).or_insert(Value::Null), value);
+            }
+        }
+    }
+
+    fn message(socket_id: &str, data: Value) -> BridgeCommand {
+        BridgeCommand::Message {
+            socket_id: socket_id.to_string(),
+            data: data.to_string().into(),
+        }
+    }
+
+    #[test]
+    fn removed_and_null_keys_become_null() {
+        let old = json!({ "state": "In menus", "details": "Solo" });
+        assert_eq!(
+            merge_patch(&old, &json!({ "state": "In menus" })),
+            Some(json!({ "details": null }))
+        );
+        assert_eq!(
+            merge_patch(&old, &json!({ "state": "In menus", "details": null })),
+            Some(json!({ "details": null }))
+        );
+        assert_eq!(merge_patch(&old, &old), None);
+    }
+
+    #[test]
+    fn nested_objects_only_carry_changes() {
+        let old = json!({ "assets": { "large_image": "map", "large_text": "Dust" }, "state": "" });
+        let new =
+            json!({ "assets": { "large_image": "map", "large_text": "Inferno" }, "state": "" });
+        assert_eq!(
+            merge_patch(&old, &new),
+            Some(json!({ "assets": { "large_text": "Inferno" } }))
+        );
+    }
+
+    #[test]
+    fn arrays_are_replaced_whole() {
+        let old = json!({ "buttons": ["Join", "Watch"] });
+        let new = json!({ "buttons": ["Join"] });
+        assert_eq!(
+            merge_patch(&old, &new),
+            Some(json!({ "buttons": ["Join"] }))
+        );
+    }
+
+    #[test]
+    fn patches_rebuild_the_new_message() {
+        let snapshots = [
+            json!({ "activity": { "state": "In menus", "party": { "size": [1, 4] } }, "pid": 1 }),
+            json!({ "activity": { "state": "In a match", "party": { "size": [2, 4] } }, "pid": 1 }),
+            json!({ "activity": { "state": "In a match", "buttons": ["Join"] }, "pid": 1 }),
+            json!({ "activity": { "details": "Ranked", "buttons": ["Join", "Watch"] }, "pid": 1 }),
+        ];
+        let mut diffs = Diffs::default();
+        diffs.set_enabled(true);
+        let mut client = Value::Null;
+        for snapshot in &snapshots {
+            let text = diffs
+                .encode(&message("1", snapshot.clone()))
+                .unwrap()
+                .unwrap();
+            let received: Value = serde_json::from_str(&text).unwrap();
+            match received["type"].as_str() {
+                Some("patch") => apply(&mut client, &received["patch"]),
+                _ => client = received,
+            }
+            assert_eq!(&client, snapshot);
+        }
+        let repeated = message("1", snapshots[3].clone());
+        assert_eq!(diffs.encode(&repeated).unwrap(), None);
+    }
+}
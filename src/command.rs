@@ -0,0 +1,37 @@
+use crate::ipc::structs::IpcFrame;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+pub trait CommandHandler: Send + Sync {
+    /// The returned value becomes the `data` of the response frame
+    fn handle(&self, socket_id: usize, frame: &IpcFrame) -> Option<Value>;
+}
+
+impl<F> CommandHandler for F
+where
+    F: Fn(usize, &IpcFrame) -> Option<Value> + Send + Sync,
+{
+    fn handle(&self, socket_id: usize, frame: &IpcFrame) -> Option<Value> {
+        self(socket_id, frame)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct CommandRegistry(Arc<RwLock<HashMap<String, Arc<dyn CommandHandler>>>>);
+
+impl CommandRegistry {
+    pub fn register<H: CommandHandler + 'static>(&self, cmd: impl Into<String>, handler: H) {
+        self.register_arc(cmd, Arc::new(handler));
+    }
+
+    pub fn register_arc(&self, cmd: impl Into<String>, handler: Arc<dyn CommandHandler>) {
+        self.0.write().unwrap().insert(cmd.into(), handler);
+    }
+
+    pub fn get(&self, cmd: &str) -> Option<Arc<dyn CommandHandler>> {
+        self.0.read().unwrap().get(cmd).cloned()
+    }
+}
@@ -0,0 +1,505 @@
+use crate::{error::Result, structs::IpcPartialActivity};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{
+    env,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+use tokio::fs;
+
+/// The config as the servers see it, swapped out on reload
+pub type SharedConfig = Arc<RwLock<Config>>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// One of `trace`, `debug`, `info`, `warn`, `error` or `off`
+    pub log_level: String,
+    /// Elide activity text and hash client IDs in logs, keeping everything else
+    pub redact_logs: bool,
+    pub ipc: IpcConfig,
+    pub bridge: BridgeConfig,
+    pub activity: ActivityConfig,
+    pub ready: ReadyConfig,
+    /// Where activities go besides the bridge, only read on start
+    pub sinks: Vec<SinkConfig>,
+    pub detection: DetectionConfig,
+    pub idle: IdleConfig,
+    pub rotation: RotationConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            log_level: "debug".to_string(),
+            redact_logs: false,
+            ipc: IpcConfig::default(),
+            bridge: BridgeConfig::default(),
+            activity: ActivityConfig::default(),
+            ready: ReadyConfig::default(),
+            sinks: Vec::new(),
+            detection: DetectionConfig::default(),
+            idle: IdleConfig::default(),
+            rotation: RotationConfig::default(),
+        }
+    }
+}
+
+/// Only read when binding, so changes need a restart
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IpcConfig {
+    /// How many `discord-ipc-N` sockets to bind in each directory, 10 takes every free one
+    pub sockets: usize,
+    /// Directories to bind additional sockets in, `None` uses the Flatpak and Snap defaults
+    pub extra_directories: Option<Vec<PathBuf>>,
+    pub on_discord_running: DiscordRunning,
+    /// Drop socket connections from processes of other users, only has an effect on Unix
+    pub reject_other_users: bool,
+    /// Bind in the default directory even if other users can write to it, like `/tmp` when
+    /// there is no `$XDG_RUNTIME_DIR`
+    pub allow_shared_directory: bool,
+    /// Also bind `@discord-ipc-N` in the abstract namespace, only on Linux
+    pub abstract_sockets: bool,
+    /// Mode bits for the socket files, usually written as an octal string like `"0600"`
+    #[serde(deserialize_with = "deserialize_mode")]
+    pub socket_mode: Option<u32>,
+    /// User name or uid to hand the socket files to
+    pub socket_owner: Option<String>,
+    /// Group name or gid to hand the socket files to
+    pub socket_group: Option<String>,
+    /// Messages from every connection to the server
+    pub message_queue: QueueConfig,
+    /// Events from the server to whoever consumes them, like the bridge
+    pub event_queue: QueueConfig,
+    /// Open connections per socket or port, further ones are closed right away
+    pub max_connections: usize,
+    /// How often to ping connections to detect dead ones, 0 disables it
+    pub ping_interval_secs: u64,
+    /// How long a ping may go without anything coming back before the connection is closed, 0
+    /// disables it. Connections of processes that are still running are kept anyway.
+    pub ping_timeout_secs: u64,
+    /// Browser origins besides Discord's own that may open WebSocket RPC connections
+    pub allowed_origins: Vec<String>,
+    /// Accept WebSocket RPC connections from any origin, meant for development
+    pub permissive: bool,
+}
+
+/// Checks applied to every activity, read for each update so reloads apply right away
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ActivityConfig {
+    /// Keep buttons linking to `discord://` besides `https://` ones
+    pub allow_discord_urls: bool,
+    pub button_overflow: ButtonOverflow,
+    /// Transitions kept for each socket, see [`History`](crate::history::History)
+    pub history_size: usize,
+    /// Clear activities of RPC clients that weren't updated for this long, 0 disables it
+    pub max_age_secs: u64,
+    pub overrides: Vec<OverrideRule>,
+    pub rewrites: Vec<RewriteRule>,
+    pub blocklist: BlocklistConfig,
+    /// Only forward the application of activities, see [`PrivacyFilter`](crate::privacy::PrivacyFilter)
+    pub privacy_mode: bool,
+}
+
+impl Default for ActivityConfig {
+    fn default() -> Self {
+        Self {
+            allow_discord_urls: false,
+            button_overflow: ButtonOverflow::default(),
+            history_size: 16,
+            max_age_secs: 0,
+            overrides: Vec::new(),
+            rewrites: Vec::new(),
+            blocklist: BlocklistConfig::default(),
+            privacy_mode: false,
+        }
+    }
+}
+
+/// Replaces texts of an application's activities with templates, see
+/// [`overrides`](crate::overrides) for the variables
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OverrideRule {
+    /// Application ID or process name, the latter case-insensitive
+    #[serde(rename = "match")]
+    pub app: String,
+    #[serde(default)]
+    pub details: Option<String>,
+    #[serde(default)]
+    pub state: Option<String>,
+    #[serde(default)]
+    pub large_text: Option<String>,
+    #[serde(default)]
+    pub small_text: Option<String>,
+}
+
+/// Replaces matches of a [`Regex`](crate::pattern::Regex) in the texts of activities
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RewriteRule {
+    /// Application ID or process name, `None` applies to every activity
+    #[serde(rename = "match", default)]
+    pub app: Option<String>,
+    pub pattern: String,
+    /// May refer to groups as `$1`
+    #[serde(default)]
+    pub replace: String,
+}
+
+/// What must never show up in the state, details or asset texts of an activity, applied after
+/// the rewrites
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BlocklistConfig {
+    /// Matched as whole words, ignoring case
+    pub words: Vec<String>,
+    /// Regular expressions, see [`Regex`](crate::pattern::Regex)
+    pub patterns: Vec<String>,
+    pub action: BlockAction,
+}
+
+/// What happens to an activity with a blocked text
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockAction {
+    /// Empty the texts that contain one
+    #[default]
+    Scrub,
+    /// Clear the whole activity
+    Suppress,
+}
+
+/// Sent to clients in the `READY` event, read on every handshake. Point these at a
+/// Discord-compatible backend like Spacebar to use it instead.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReadyConfig {
+    pub api_endpoint: String,
+    pub cdn_host: String,
+    pub environment: String,
+}
+
+impl Default for ReadyConfig {
+    fn default() -> Self {
+        Self {
+            api_endpoint: "//discord.com/api".to_string(),
+            cdn_host: "cdn.discordapp.com".to_string(),
+            environment: "production".to_string(),
+        }
+    }
+}
+
+/// See [`sink`](crate::sink) for what each of them does
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SinkConfig {
+    File {
+        path: PathBuf,
+    },
+    Webhook {
+        url: String,
+    },
+    /// Needs the `dbus` feature and a session bus
+    Dbus,
+    /// A desktop notification whenever an application starts sharing an activity, needs the
+    /// `dbus` feature and a notification daemon
+    Notify,
+    /// Needs the `mqtt` feature
+    Mqtt(MqttConfig),
+    /// Needs the `bridge` feature
+    Relay {
+        url: String,
+        /// Sent as `Authorization: Bearer <token>`, for a bridge with `relay_token`
+        #[serde(default)]
+        token: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MqttConfig {
+    /// `host` or `host:port`, the port defaults to 1883
+    pub broker: String,
+    /// `<topic>/state` gets the current activity and `<topic>/availability` whether arRPC runs
+    pub topic: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Where Home Assistant looks for discovery messages, `null` sends none
+    pub discovery_prefix: Option<String>,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            broker: "localhost".to_string(),
+            topic: "arrpc".to_string(),
+            username: None,
+            password: None,
+            discovery_prefix: Some("homeassistant".to_string()),
+        }
+    }
+}
+
+/// What to do with activities that have more buttons than Discord shows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ButtonOverflow {
+    /// Keep the first ones
+    #[default]
+    Truncate,
+    /// Answer with an error and keep the previous activity
+    Reject,
+}
+
+/// Only read when binding, so changes need a restart
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BridgeConfig {
+    /// Addresses to accept web clients on, `[::]` takes IPv4 too where the system allows it
+    pub listen: Vec<SocketAddr>,
+    /// Also accept web clients on a Unix socket at this path
+    pub unix_socket: Option<PathBuf>,
+    /// Connected web clients, further ones are closed right away
+    pub max_connections: usize,
+    /// Messages waiting for each client, only activities of different sockets take up room
+    pub client_queue: usize,
+    /// How long a single write to a client may take before it is disconnected
+    pub stall_timeout_secs: u64,
+    /// Collect updates for this long and only send the latest one of each socket, 0 sends them
+    /// right away
+    pub batch_window_ms: u64,
+    /// Only forward the activity that wins by this policy, `None` forwards all of them
+    pub priority: Option<PriorityPolicy>,
+    /// What a newly connected client catches up on, `None` is what would have been forwarded
+    pub snapshot: Option<SnapshotStrategy>,
+    /// Send a `session_end` message with a summary when an IPC connection closes
+    pub session_events: bool,
+    /// Take activity messages from web clients, like a `relay` sink of another instance, and
+    /// forward them to the others until that client disconnects
+    pub accept_relay: bool,
+    /// Only let clients that connect with `Authorization: Bearer <this>` relay
+    pub relay_token: Option<String>,
+}
+
+/// Which activity wins when several clients have one, ties go to the most recent
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PriorityPolicy {
+    MostRecent,
+    /// Application ids or process names, the first one wins and unlisted ones come last
+    Ranking(Vec<String>),
+    /// Games first, anything that is listening like a music player last
+    GameOverMusic,
+}
+
+/// Picks activities by the priority policy, or the most recent ones without one
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SnapshotStrategy {
+    All,
+    Top(usize),
+    /// The winner of each activity type
+    PerType,
+}
+
+impl Default for BridgeConfig {
+    fn default() -> Self {
+        Self {
+            listen: vec![
+                SocketAddr::from(([127, 0, 0, 1], 1337)),
+                SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], 1337)),
+            ],
+            unix_socket: None,
+            max_connections: 16,
+            client_queue: 32,
+            stall_timeout_secs: 30,
+            batch_window_ms: 0,
+            priority: None,
+            snapshot: None,
+            session_events: false,
+            accept_relay: false,
+            relay_token: None,
+        }
+    }
+}
+
+/// Only read on start, needs the `process-detection` feature
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DetectionConfig {
+    pub enabled: bool,
+    /// Where to fetch Discord's list of detectable applications from
+    pub database_url: String,
+    /// How often to check the list for updates, 0 only fetches it when there is no cached copy
+    pub refresh_interval_secs: u64,
+    /// How often to look at the running processes, longer intervals detect games later
+    pub scan_interval_secs: u64,
+    /// Executables never to show as played, like `wine*` or `*/dev-builds/*`, case-insensitive
+    pub ignore: Vec<String>,
+    /// A JSON list of extra entries merged over the official ones by `id`, `None` uses
+    /// `detectable.json` next to the config file
+    pub custom_path: Option<PathBuf>,
+}
+
+impl Default for DetectionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            database_url: "https://discord.com/api/v9/applications/detectable".to_string(),
+            refresh_interval_secs: 24 * 60 * 60,
+            scan_interval_secs: 10,
+            ignore: Vec::new(),
+            custom_path: None,
+        }
+    }
+}
+
+/// Only read on start, needs the `idle` feature
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IdleConfig {
+    pub enabled: bool,
+    /// How long without input until the user counts as away
+    pub threshold_secs: u64,
+    pub action: IdleAction,
+}
+
+impl Default for IdleConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            threshold_secs: 10 * 60,
+            action: IdleAction::default(),
+        }
+    }
+}
+
+/// What happens to forwarded activities while the user is away
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdleAction {
+    /// Hide them until the user is back
+    #[default]
+    Clear,
+    /// Send them again with `idle` set
+    Tag,
+}
+
+/// Presences shown one after another in a loop, only read on start
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RotationConfig {
+    /// The application whose assets the presences use
+    pub application_id: String,
+    pub presences: Vec<RotationEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RotationEntry {
+    /// How long this presence is shown before the next one
+    pub duration_secs: u64,
+    pub activity: IpcPartialActivity,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QueueConfig {
+    pub capacity: usize,
+    pub backpressure: Backpressure,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 64,
+            backpressure: Backpressure::default(),
+        }
+    }
+}
+
+/// What a full queue does with new items
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backpressure {
+    /// Wait for the consumer, nothing gets lost but a slow consumer stalls the producers
+    #[default]
+    Await,
+    /// Make room by dropping the oldest item
+    DropOldest,
+    /// Replace a queued activity update of the same client, otherwise wait
+    CoalesceLatest,
+}
+
+fn deserialize_mode<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Mode {
+        Number(u32),
+        Octal(String),
+    }
+    match Option::<Mode>::deserialize(deserializer)? {
+        Some(Mode::Number(mode)) => Ok(Some(mode)),
+        Some(Mode::Octal(mode)) => u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+            .map(Some)
+            .map_err(|_| serde::de::Error::custom(format!("invalid socket mode {:?}", mode))),
+        None => Ok(None),
+    }
+}
+
+/// What to do when the official client already listens on `discord-ipc-0`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiscordRunning {
+    /// Bind the next free socket anyway, games will most likely talk to Discord instead
+    #[default]
+    Warn,
+    /// Refuse to start
+    Abort,
+    /// Forward every connection to the official client, activities still reach the bridge. Only
+    /// covers `discord-ipc-1` and up, clients on Discord's own `discord-ipc-0` never get here.
+    Proxy,
+}
+
+impl Default for IpcConfig {
+    fn default() -> Self {
+        Self {
+            sockets: 1,
+            extra_directories: None,
+            on_discord_running: DiscordRunning::default(),
+            reject_other_users: false,
+            allow_shared_directory: false,
+            abstract_sockets: false,
+            socket_mode: None,
+            socket_owner: None,
+            socket_group: None,
+            message_queue: QueueConfig::default(),
+            event_queue: QueueConfig::default(),
+            max_connections: 64,
+            ping_interval_secs: 30,
+            ping_timeout_secs: 0,
+            allowed_origins: Vec::new(),
+            permissive: false,
+        }
+    }
+}
+
+impl Config {
+    pub fn default_path() -> PathBuf {
+        let config_directory = env::var("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|_| env::var("HOME").map(|home| Path::new(&home).join(".config")))
+            .unwrap_or_else(|_| env::temp_dir());
+        config_directory.join("arrpc").join("config.json")
+    }
+
+    /// A missing file is not an error, everything just stays at its default
+    pub async fn load(path: &Path) -> Result<Config> {
+        match fs::read(path).await {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
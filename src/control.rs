@@ -0,0 +1,260 @@
+use crate::{
+    error::{ArRpcError, Result},
+    paths,
+};
+use bytes::BytesMut;
+use owo_colors::OwoColorize;
+use serde_json::{json, Value};
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{UnixListener, UnixStream},
+    sync::{mpsc, oneshot},
+    task,
+};
+use tracing::{debug, info, warn};
+
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ControlCommand {
+    Reload,
+    Stop,
+    Status,
+    Clients,
+    SetCustomStatus,
+    ClearCustomStatus,
+    History,
+    Stats,
+    PauseDetection,
+    ResumeDetection,
+    EnablePrivacy,
+    DisablePrivacy,
+    Pause,
+    Resume,
+}
+
+impl ControlCommand {
+    fn route(method: &str, path: &str) -> Option<ControlCommand> {
+        match (method, path) {
+            ("POST", "/reload") => Some(ControlCommand::Reload),
+            ("POST", "/stop") => Some(ControlCommand::Stop),
+            ("GET", "/status") => Some(ControlCommand::Status),
+            ("GET", "/clients") => Some(ControlCommand::Clients),
+            ("GET", "/history") => Some(ControlCommand::History),
+            ("GET", "/stats") => Some(ControlCommand::Stats),
+            ("PUT", "/custom-status") => Some(ControlCommand::SetCustomStatus),
+            ("DELETE", "/custom-status") => Some(ControlCommand::ClearCustomStatus),
+            ("POST", "/detection/pause") => Some(ControlCommand::PauseDetection),
+            ("POST", "/detection/resume") => Some(ControlCommand::ResumeDetection),
+            ("POST", "/privacy/enable") => Some(ControlCommand::EnablePrivacy),
+            ("POST", "/privacy/disable") => Some(ControlCommand::DisablePrivacy),
+            ("POST", "/pause") => Some(ControlCommand::Pause),
+            ("POST", "/resume") => Some(ControlCommand::Resume),
+            _ => None,
+        }
+    }
+}
+
+/// A request that is waiting for whoever owns the server to act on it
+pub struct ControlRequest {
+    pub command: ControlCommand,
+    pub body: Option<Value>,
+    response: oneshot::Sender<std::result::Result<Value, String>>,
+}
+
+impl ControlRequest {
+    pub fn respond(self, result: std::result::Result<Value, String>) {
+        let _ = self.response.send(result);
+    }
+}
+
+/// JSON over HTTP on a Unix socket, so access is governed by file permissions
+pub struct ControlServer {
+    path: PathBuf,
+    rx: mpsc::Receiver<ControlRequest>,
+}
+
+impl ControlServer {
+    pub fn default_path() -> PathBuf {
+        paths::arrpc_dir().join("control.sock")
+    }
+
+    pub async fn try_bind(path: &Path) -> Result<ControlServer> {
+        if let Some(parent) = path.parent() {
+            paths::create_private_dir(parent)?;
+        }
+        let listener = match UnixListener::bind(path) {
+            Ok(listener) => listener,
+            Err(e) if e.kind() == ErrorKind::AddrInUse => {
+                // Nobody answering means a previous instance crashed without cleaning up
+                if UnixStream::connect(path).await.is_ok() {
+                    return Err(e.into());
+                }
+                std::fs::remove_file(path)?;
+                UnixListener::bind(path)?
+            }
+            Err(e) => return Err(e.into()),
+        };
+        info!(
+            "{} {}",
+            "Control API listening at".green(),
+            path.display().yellow().bold()
+        );
+
+        let (tx, rx) = mpsc::channel(8);
+        task::spawn(Self::accept_loop(listener, tx));
+        Ok(ControlServer {
+            path: path.to_path_buf(),
+            rx,
+        })
+    }
+
+    async fn accept_loop(listener: UnixListener, tx: mpsc::Sender<ControlRequest>) -> Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let tx = tx.clone();
+            task::spawn(async move {
+                if let Err(e) = Self::handle_stream(stream, tx).await {
+                    debug!("Control connection closed: {}", e);
+                }
+            });
+        }
+    }
+
+    async fn handle_stream(mut stream: UnixStream, tx: mpsc::Sender<ControlRequest>) -> Result<()> {
+        let mut buffer = BytesMut::with_capacity(1024);
+        let (method, path, header_len, content_length) = loop {
+            if stream.read_buf(&mut buffer).await? == 0 {
+                return Err(ArRpcError::Io(ErrorKind::UnexpectedEof.into()));
+            }
+            let mut headers = [httparse::EMPTY_HEADER; 32];
+            let mut request = httparse::Request::new(&mut headers);
+            match request.parse(&buffer) {
+                Ok(httparse::Status::Complete(header_len)) => {
+                    let content_length = request
+                        .headers
+                        .iter()
+                        .find(|header| header.name.eq_ignore_ascii_case("content-length"))
+                        .and_then(|header| std::str::from_utf8(header.value).ok())
+                        .and_then(|value| value.trim().parse::<usize>().ok())
+                        .unwrap_or(0);
+                    break (
+                        request.method.unwrap_or_default().to_string(),
+                        request.path.unwrap_or_default().to_string(),
+                        header_len,
+                        content_length,
+                    );
+                }
+                Ok(httparse::Status::Partial) if buffer.len() < MAX_REQUEST_SIZE => continue,
+                _ => {
+                    return write_response(&mut stream, 400, &json!({ "error": "Bad request" }))
+                        .await
+                }
+            }
+        };
+
+        if header_len + content_length > MAX_REQUEST_SIZE {
+            return write_response(&mut stream, 413, &json!({ "error": "Request too large" }))
+                .await;
+        }
+        while buffer.len() < header_len + content_length {
+            if stream.read_buf(&mut buffer).await? == 0 {
+                return Err(ArRpcError::Io(ErrorKind::UnexpectedEof.into()));
+            }
+        }
+        let body = &buffer[header_len..header_len + content_length];
+        let body = match body.is_empty() {
+            true => None,
+            false => match serde_json::from_slice(body) {
+                Ok(body) => Some(body),
+                Err(e) => {
+                    let error = json!({ "error": e.to_string() });
+                    return write_response(&mut stream, 400, &error).await;
+                }
+            },
+        };
+
+        let Some(command) = ControlCommand::route(&method, &path) else {
+            return write_response(&mut stream, 404, &json!({ "error": "Not found" })).await;
+        };
+        debug!("Control API request: {} {}", method, path);
+        let (response, rx) = oneshot::channel();
+        tx.send(ControlRequest {
+            command,
+            body,
+            response,
+        })
+        .await?;
+        match rx.await {
+            Ok(Ok(data)) => write_response(&mut stream, 200, &data).await,
+            Ok(Err(e)) => write_response(&mut stream, 500, &json!({ "error": e })).await,
+            Err(_) => Err(ArRpcError::ChannelClosed),
+        }
+    }
+
+    pub async fn recv(&mut self) -> Option<ControlRequest> {
+        self.rx.recv().await
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("Failed to remove control socket at {}", self.path.display());
+            warn!("Error: {:?}", e);
+        }
+    }
+}
+
+/// Sends a request to the control API of a running instance
+pub async fn request(path: &Path, method: &str, route: &str) -> Result<Value> {
+    let mut stream = UnixStream::connect(path).await?;
+    let head = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        method, route
+    );
+    stream.write_all(head.as_bytes()).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut parsed = httparse::Response::new(&mut headers);
+    let Ok(httparse::Status::Complete(header_len)) = parsed.parse(&response) else {
+        return Err(ArRpcError::Control("Malformed response".to_string()));
+    };
+    let body: Value = serde_json::from_slice(&response[header_len..])?;
+    match parsed.code {
+        Some(200) => Ok(body),
+        _ => Err(ArRpcError::Control(
+            body.get("error")
+                .and_then(Value::as_str)
+                .unwrap_or("Unknown error")
+                .to_string(),
+        )),
+    }
+}
+
+async fn write_response(stream: &mut UnixStream, status: u16, body: &Value) -> Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        413 => "Payload Too Large",
+        _ => "Internal Server Error",
+    };
+    let body = serde_json::to_vec(body)?;
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        reason,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&body).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
@@ -0,0 +1,192 @@
+// Some of it is only needed by the tray
+#[cfg_attr(not(feature = "tray"), allow(dead_code))]
+pub(crate) mod wire;
+
+pub mod notify;
+
+use crate::{error::Result, sink::ActivitySink, structs::IpcActivityMessage};
+use std::{collections::BTreeMap, sync::Arc};
+use tokio::{net::unix::OwnedReadHalf, task};
+use tracing::debug;
+use wire::{
+    signal, unknown_method, unknown_object, Answer, Connection, Encoder, Incoming, PROPERTIES,
+};
+
+pub const BUS_NAME: &str = "org.arrpc.Presence";
+pub const OBJECT_PATH: &str = "/org/arrpc/Presence";
+const INTERFACE: &str = "org.arrpc.Presence";
+
+/// Publishes the current activities on the session bus as the `Activities` property of
+/// `org.arrpc.Presence`, a JSON array of activity messages. Changes are announced with both the
+/// `Changed` signal and `PropertiesChanged`.
+pub struct DbusSink(Arc<Shared>);
+
+struct Shared {
+    connection: Connection,
+    activities: std::sync::Mutex<BTreeMap<String, IpcActivityMessage>>,
+}
+
+impl Shared {
+    fn activities_json(&self) -> String {
+        let activities = self.activities.lock().unwrap();
+        serde_json::to_string(&activities.values().collect::<Vec<_>>()).unwrap_or_default()
+    }
+}
+
+impl DbusSink {
+    /// Connects to the session bus and claims [`BUS_NAME`]
+    pub async fn connect() -> Result<DbusSink> {
+        let (connection, reader) = Connection::open(Some(BUS_NAME)).await?;
+        let shared = Arc::new(Shared {
+            connection,
+            activities: Default::default(),
+        });
+        task::spawn(Self::serve(reader, shared.clone()));
+        Ok(DbusSink(shared))
+    }
+
+    async fn serve(mut reader: OwnedReadHalf, shared: Arc<Shared>) {
+        let connection = &shared.connection;
+        loop {
+            let message = match Incoming::read(&mut reader).await {
+                Ok(message) => message,
+                Err(e) => {
+                    debug!("D-Bus connection closed: {}", e);
+                    return;
+                }
+            };
+            if !message.wants_reply() {
+                continue;
+            }
+            let reply = match answer(&message, &shared) {
+                Ok((signature, body)) => message.reply(connection.next_serial(), signature, &body),
+                Err((name, text)) => message.error(connection.next_serial(), name, text),
+            };
+            if let Err(e) = connection.send(reply).await {
+                debug!("Failed to answer D-Bus call: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+impl ActivitySink for DbusSink {
+    async fn publish(&self, msg: &IpcActivityMessage) -> Result<()> {
+        {
+            let mut activities = self.0.activities.lock().unwrap();
+            match msg.activity {
+                Some(_) => activities.insert(msg.socket_id.clone(), msg.clone()),
+                None => activities.remove(&msg.socket_id),
+            };
+        }
+        let json = self.0.activities_json();
+
+        let mut body = Encoder::default();
+        body.str(&json);
+        let connection = &self.0.connection;
+        let serial = connection.next_serial();
+        let changed = signal(serial, OBJECT_PATH, INTERFACE, "Changed", &body, "s");
+        connection.send(changed).await?;
+
+        let mut body = Encoder::default();
+        body.str(INTERFACE);
+        properties(&mut body, &json);
+        body.array(4, |_| {});
+        let changed = signal(
+            connection.next_serial(),
+            OBJECT_PATH,
+            PROPERTIES,
+            "PropertiesChanged",
+            &body,
+            "sa{sv}as",
+        );
+        connection.send(changed).await
+    }
+
+    fn name(&self) -> String {
+        "D-Bus".to_string()
+    }
+}
+
+fn properties(body: &mut Encoder, json: &str) {
+    body.array(8, |body| {
+        body.entry("Activities", "s", |body| body.str(json))
+    });
+}
+
+fn introspect(path: &str) -> Option<String> {
+    if path == OBJECT_PATH {
+        return Some(format!(
+            r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="{INTERFACE}">
+    <property name="Activities" type="s" access="read"/>
+    <signal name="Changed"><arg name="activities" type="s"/></signal>
+  </interface>
+  <interface name="{PROPERTIES}">
+    <method name="Get"><arg type="s" direction="in"/><arg type="s" direction="in"/><arg type="v" direction="out"/></method>
+    <method name="GetAll"><arg type="s" direction="in"/><arg type="a{{sv}}" direction="out"/></method>
+    <signal name="PropertiesChanged"><arg type="s"/><arg type="a{{sv}}"/><arg type="as"/></signal>
+  </interface>
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect"><arg type="s" direction="out"/></method>
+  </interface>
+  <interface name="org.freedesktop.DBus.Peer">
+    <method name="Ping"/>
+  </interface>
+</node>
+"#
+        ));
+    }
+    // Parents only list the next path component, so tools can walk down to the object
+    let rest = match path {
+        "/" => OBJECT_PATH.strip_prefix('/'),
+        path => OBJECT_PATH.strip_prefix(path)?.strip_prefix('/'),
+    }?;
+    let child = rest.split('/').next()?;
+    Some(format!(r#"<node><node name="{}"/></node>"#, child))
+}
+
+fn answer(message: &Incoming, shared: &Shared) -> Answer {
+    let mut body = Encoder::default();
+    let interface = message.interface.as_deref();
+    match (interface, message.member.as_str()) {
+        (Some("org.freedesktop.DBus.Peer") | None, "Ping") => Ok(("", body)),
+        (Some("org.freedesktop.DBus.Introspectable") | None, "Introspect") => {
+            let xml = introspect(&message.path).ok_or_else(|| unknown_object(&message.path))?;
+            body.str(&xml);
+            Ok(("s", body))
+        }
+        _ if message.path != OBJECT_PATH => Err(unknown_object(&message.path)),
+        (Some(PROPERTIES) | None, "Get") => {
+            let mut args = message.body();
+            let args = args
+                .str()
+                .and_then(|interface| Ok((interface, args.str()?)));
+            match args {
+                Ok((INTERFACE, "Activities")) => {
+                    body.signature("s");
+                    body.str(&shared.activities_json());
+                    Ok(("v", body))
+                }
+                _ => Err((
+                    "org.freedesktop.DBus.Error.UnknownProperty",
+                    "No such property".to_string(),
+                )),
+            }
+        }
+        (Some(PROPERTIES) | None, "GetAll") => {
+            match message.body().str() {
+                Ok(INTERFACE) => properties(&mut body, &shared.activities_json()),
+                _ => body.array(8, |_| {}),
+            }
+            Ok(("a{sv}", body))
+        }
+        (Some(PROPERTIES) | None, "Set") => Err((
+            "org.freedesktop.DBus.Error.PropertyReadOnly",
+            "Activities is read-only".to_string(),
+        )),
+        _ => Err(unknown_method(&message.member)),
+    }
+}
@@ -0,0 +1,143 @@
+//! Desktop notifications through `org.freedesktop.Notifications`
+
+use super::wire::{method_call, Connection, Encoder, Incoming, Target, ERROR};
+use crate::{
+    error::Result,
+    sink::ActivitySink,
+    structs::{ActivitySource, IpcActivityMessage, SessionSummary},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+use tokio::{net::unix::OwnedReadHalf, task};
+use tracing::{debug, warn};
+
+const NOTIFICATIONS: Target = (
+    "org.freedesktop.Notifications",
+    "/org/freedesktop/Notifications",
+    "org.freedesktop.Notifications",
+);
+
+/// Shows a desktop notification the first time a connection or a detected game sets an
+/// activity, so nothing starts broadcasting unnoticed
+pub struct NotifySink {
+    connection: Connection,
+    /// Application names by ID, from the cached detectable database
+    names: HashMap<String, String>,
+    /// Sockets that were already announced
+    announced: Mutex<HashSet<String>>,
+}
+
+impl NotifySink {
+    pub async fn connect() -> Result<NotifySink> {
+        let (connection, reader) = Connection::open(None).await?;
+        task::spawn(read_errors(reader));
+        Ok(NotifySink {
+            connection,
+            names: names().await,
+            announced: Default::default(),
+        })
+    }
+}
+
+impl ActivitySink for NotifySink {
+    async fn publish(&self, msg: &IpcActivityMessage) -> Result<()> {
+        let Some(activity) = &msg.activity else {
+            // Detected games are announced again when they are started again, connections once
+            if msg.source != ActivitySource::Rpc {
+                self.announced.lock().unwrap().remove(&msg.socket_id);
+            }
+            return Ok(());
+        };
+        // The custom status and rotation are set up by the user, nothing to point out
+        if !matches!(msg.source, ActivitySource::Rpc | ActivitySource::Process)
+            || !self.announced.lock().unwrap().insert(msg.socket_id.clone())
+        {
+            return Ok(());
+        }
+
+        let id = &activity.application_id;
+        let name = self
+            .names
+            .get(id)
+            .or(msg.process_name.as_ref())
+            .map_or("An application", String::as_str);
+        let text = match &msg.process_name {
+            Some(process) => format!("{} (pid {}), application {}", process, msg.pid, id),
+            None => format!("Application {}", id),
+        };
+        let mut body = Encoder::default();
+        body.str("arRPC");
+        body.u32(0);
+        body.str("user-available");
+        body.str(&format!("{} is now sharing rich presence", name));
+        body.str(&text);
+        body.array(4, |_| {});
+        body.array(8, |_| {});
+        body.i32(-1);
+        let connection = &self.connection;
+        let call = method_call(
+            connection.next_serial(),
+            NOTIFICATIONS,
+            "Notify",
+            &body,
+            "susssasa{sv}i",
+        );
+        connection.send(call).await
+    }
+
+    async fn session_end(&self, summary: &SessionSummary) -> Result<()> {
+        self.announced.lock().unwrap().remove(&summary.socket_id);
+        Ok(())
+    }
+
+    fn name(&self) -> String {
+        "notifications".to_string()
+    }
+}
+
+/// Answers only matter if showing a notification failed
+async fn read_errors(mut reader: OwnedReadHalf) {
+    loop {
+        match Incoming::read(&mut reader).await {
+            Ok(message) if message.kind == ERROR => {
+                warn!("Failed to show a notification: {}", message.error_message());
+            }
+            Ok(_) => {}
+            Err(e) => {
+                debug!("D-Bus connection closed: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(feature = "process-detection")]
+async fn names() -> HashMap<String, String> {
+    use crate::detection::{Database, Detectable};
+
+    let mut names = HashMap::new();
+    // Custom entries last, so their names win
+    for path in [
+        Database::default_cache_path(),
+        Database::default_custom_path(),
+    ] {
+        let Ok(data) = tokio::fs::read(&path).await else {
+            continue;
+        };
+        let entries: Vec<Detectable> = serde_json::from_slice(&data).unwrap_or_default();
+        names.extend(
+            entries
+                .into_iter()
+                .filter(|entry| !entry.name.is_empty())
+                .map(|entry| (entry.id, entry.name)),
+        );
+    }
+    names
+}
+
+#[cfg(not(feature = "process-detection"))]
+async fn names() -> HashMap<String, String> {
+    HashMap::new()
+}
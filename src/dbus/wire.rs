@@ -0,0 +1,540 @@
+//! Just enough of the D-Bus protocol for the objects arRPC exports
+
+use crate::error::{ArRpcError, Result};
+use std::{
+    env,
+    path::PathBuf,
+    sync::atomic::{AtomicU32, Ordering},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        unix::{OwnedReadHalf, OwnedWriteHalf},
+        UnixStream,
+    },
+    sync::Mutex,
+};
+
+pub const PROPERTIES: &str = "org.freedesktop.DBus.Properties";
+
+pub const METHOD_CALL: u8 = 1;
+pub const METHOD_RETURN: u8 = 2;
+pub const ERROR: u8 = 3;
+pub const SIGNAL: u8 = 4;
+pub const NO_REPLY_EXPECTED: u8 = 1;
+// Refuse to start instead of queueing behind another instance
+const DO_NOT_QUEUE: u32 = 4;
+const PRIMARY_OWNER: u32 = 1;
+const MAX_MESSAGE_SIZE: usize = 1 << 20;
+
+/// The sending half of a session bus connection
+pub struct Connection {
+    writer: Mutex<OwnedWriteHalf>,
+    serial: AtomicU32,
+}
+
+impl Connection {
+    /// Connects to the session bus and claims `name` if there is one, the reading half is left
+    /// to the caller
+    pub async fn open(name: Option<&str>) -> Result<(Connection, OwnedReadHalf)> {
+        let stream = UnixStream::connect(session_bus_path()?).await?;
+        let (mut reader, mut writer) = stream.into_split();
+        authenticate(&mut reader, &mut writer).await?;
+
+        writer
+            .write_all(&bus_call(1, "Hello", &Encoder::default(), ""))
+            .await?;
+        if let Some(name) = name {
+            let mut body = Encoder::default();
+            body.str(name);
+            body.u32(DO_NOT_QUEUE);
+            writer
+                .write_all(&bus_call(2, "RequestName", &body, "su"))
+                .await?;
+            let reply = loop {
+                let message = Incoming::read(&mut reader).await?;
+                if message.reply_serial == Some(2) {
+                    break message;
+                }
+            };
+            match reply.kind {
+                METHOD_RETURN if reply.body().u32()? == PRIMARY_OWNER => {}
+                METHOD_RETURN => {
+                    return Err(ArRpcError::Dbus(format!("{} is already taken", name)));
+                }
+                _ => return Err(ArRpcError::Dbus(reply.error_message())),
+            }
+        }
+        let connection = Connection {
+            writer: Mutex::new(writer),
+            serial: AtomicU32::new(3),
+        };
+        Ok((connection, reader))
+    }
+
+    pub fn next_serial(&self) -> u32 {
+        self.serial.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub async fn send(&self, message: Vec<u8>) -> Result<()> {
+        Ok(self.writer.lock().await.write_all(&message).await?)
+    }
+}
+
+fn session_bus_path() -> Result<PathBuf> {
+    let Ok(address) = env::var("DBUS_SESSION_BUS_ADDRESS") else {
+        return crate::paths::runtime_dir()
+            .map(|dir| dir.join("bus"))
+            .ok_or_else(|| ArRpcError::Dbus("No session bus address".to_string()));
+    };
+    // Like `unix:path=/run/user/1000/bus,guid=...`, possibly several separated by `;`
+    address
+        .split(';')
+        .filter_map(|address| address.strip_prefix("unix:"))
+        .flat_map(|options| options.split(','))
+        .find_map(|option| option.strip_prefix("path="))
+        .map(PathBuf::from)
+        .ok_or_else(|| ArRpcError::Dbus(format!("Unsupported bus address {}", address)))
+}
+
+async fn authenticate(reader: &mut OwnedReadHalf, writer: &mut OwnedWriteHalf) -> Result<()> {
+    let uid = unsafe { libc::getuid() }.to_string();
+    let hex: String = uid.bytes().map(|b| format!("{:02x}", b)).collect();
+    writer
+        .write_all(format!("\0AUTH EXTERNAL {}\r\n", hex).as_bytes())
+        .await?;
+    // Read byte by byte, whatever follows the line is already part of the binary protocol
+    let mut line = Vec::new();
+    while !line.ends_with(b"\r\n") {
+        line.push(reader.read_u8().await?);
+        if line.len() > 1024 {
+            return Err(ArRpcError::Dbus(
+                "Authentication reply too long".to_string(),
+            ));
+        }
+    }
+    if !line.starts_with(b"OK ") {
+        return Err(ArRpcError::Dbus(format!(
+            "Authentication failed: {}",
+            String::from_utf8_lossy(&line).trim()
+        )));
+    }
+    writer.write_all(b"BEGIN\r\n").await?;
+    Ok(())
+}
+
+#[derive(Default)]
+pub struct Encoder(Vec<u8>);
+
+impl Encoder {
+    pub fn align(&mut self, alignment: usize) {
+        while !self.0.len().is_multiple_of(alignment) {
+            self.0.push(0);
+        }
+    }
+
+    pub fn byte(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.align(4);
+        self.0.extend(value.to_le_bytes());
+    }
+
+    pub fn i32(&mut self, value: i32) {
+        self.u32(value as u32);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u32(value as u32);
+    }
+
+    pub fn str(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.0.extend(value.as_bytes());
+        self.0.push(0);
+    }
+
+    pub fn signature(&mut self, value: &str) {
+        self.byte(value.len() as u8);
+        self.0.extend(value.as_bytes());
+        self.0.push(0);
+    }
+
+    /// The length comes first but depends on the elements `f` writes
+    pub fn array(&mut self, alignment: usize, f: impl FnOnce(&mut Self)) {
+        self.u32(0);
+        let length_at = self.0.len() - 4;
+        self.align(alignment);
+        let start = self.0.len();
+        f(self);
+        let length = (self.0.len() - start) as u32;
+        self.0[length_at..length_at + 4].copy_from_slice(&length.to_le_bytes());
+    }
+
+    /// A `{sv}` entry of a property map, `f` writes a value of type `signature`
+    pub fn entry(&mut self, key: &str, signature: &str, f: impl FnOnce(&mut Self)) {
+        self.align(8);
+        self.str(key);
+        self.signature(signature);
+        f(self);
+    }
+}
+
+pub enum Field<'a> {
+    Path(&'a str),
+    Interface(&'a str),
+    Member(&'a str),
+    ErrorName(&'a str),
+    ReplySerial(u32),
+    Destination(&'a str),
+    Signature(&'a str),
+}
+
+impl Field<'_> {
+    fn encode(&self, e: &mut Encoder) {
+        e.align(8);
+        match self {
+            Field::Path(path) => {
+                e.byte(1);
+                e.signature("o");
+                e.str(path);
+            }
+            Field::Interface(interface) => {
+                e.byte(2);
+                e.signature("s");
+                e.str(interface);
+            }
+            Field::Member(member) => {
+                e.byte(3);
+                e.signature("s");
+                e.str(member);
+            }
+            Field::ErrorName(name) => {
+                e.byte(4);
+                e.signature("s");
+                e.str(name);
+            }
+            Field::ReplySerial(serial) => {
+                e.byte(5);
+                e.signature("u");
+                e.u32(*serial);
+            }
+            Field::Destination(destination) => {
+                e.byte(6);
+                e.signature("s");
+                e.str(destination);
+            }
+            Field::Signature(signature) => {
+                e.byte(8);
+                e.signature("g");
+                e.signature(signature);
+            }
+        }
+    }
+}
+
+pub fn message<'a>(
+    kind: u8,
+    serial: u32,
+    mut fields: Vec<Field<'a>>,
+    body: &Encoder,
+    signature: &'a str,
+) -> Vec<u8> {
+    if !signature.is_empty() {
+        fields.push(Field::Signature(signature));
+    }
+    let mut e = Encoder::default();
+    e.byte(b'l');
+    e.byte(kind);
+    e.byte(0);
+    e.byte(1);
+    e.u32(body.0.len() as u32);
+    e.u32(serial);
+    e.array(8, |e| fields.iter().for_each(|field| field.encode(e)));
+    e.align(8);
+    e.0.extend(&body.0);
+    e.0
+}
+
+/// Where a method call goes, as destination, path and interface
+pub type Target<'a> = (&'a str, &'a str, &'a str);
+
+pub const BUS: Target = (
+    "org.freedesktop.DBus",
+    "/org/freedesktop/DBus",
+    "org.freedesktop.DBus",
+);
+
+pub fn method_call(
+    serial: u32,
+    (destination, path, interface): Target,
+    member: &str,
+    body: &Encoder,
+    signature: &str,
+) -> Vec<u8> {
+    let fields = vec![
+        Field::Path(path),
+        Field::Interface(interface),
+        Field::Member(member),
+        Field::Destination(destination),
+    ];
+    message(METHOD_CALL, serial, fields, body, signature)
+}
+
+/// A call to the bus itself
+pub fn bus_call(serial: u32, member: &str, body: &Encoder, signature: &str) -> Vec<u8> {
+    method_call(serial, BUS, member, body, signature)
+}
+
+pub fn signal(
+    serial: u32,
+    path: &str,
+    interface: &str,
+    member: &str,
+    body: &Encoder,
+    signature: &str,
+) -> Vec<u8> {
+    let fields = vec![
+        Field::Path(path),
+        Field::Interface(interface),
+        Field::Member(member),
+    ];
+    message(SIGNAL, serial, fields, body, signature)
+}
+
+pub struct Decoder<'a> {
+    data: &'a [u8],
+    position: usize,
+    big_endian: bool,
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.position..self.position + len)
+            .ok_or_else(|| ArRpcError::Dbus("Truncated message".to_string()))?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn align(&mut self, alignment: usize) -> Result<()> {
+        let padding = (alignment - self.position % alignment) % alignment;
+        self.take(padding).map(|_| ())
+    }
+
+    pub fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        self.align(4)?;
+        let bytes = self.take(4)?.try_into().unwrap();
+        Ok(match self.big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        })
+    }
+
+    pub fn i32(&mut self) -> Result<i32> {
+        Ok(self.u32()? as i32)
+    }
+
+    fn text(&mut self, len: usize) -> Result<&'a str> {
+        let text = self.take(len)?;
+        self.take(1)?;
+        std::str::from_utf8(text).map_err(|_| ArRpcError::Dbus("Invalid UTF-8".to_string()))
+    }
+
+    pub fn str(&mut self) -> Result<&'a str> {
+        let len = self.u32()? as usize;
+        self.text(len)
+    }
+
+    pub fn signature(&mut self) -> Result<&'a str> {
+        let len = self.byte()? as usize;
+        self.text(len)
+    }
+
+    /// Skips over a variant holding a basic type, which is all anyone sends where arRPC
+    /// doesn't care about the value
+    pub fn skip_variant(&mut self) -> Result<()> {
+        match self.signature()? {
+            "y" => self.byte().map(|_| ()),
+            "n" | "q" => {
+                self.align(2)?;
+                self.take(2).map(|_| ())
+            }
+            "b" | "i" | "u" => self.u32().map(|_| ()),
+            "x" | "t" | "d" => {
+                self.align(8)?;
+                self.take(8).map(|_| ())
+            }
+            "s" | "o" => self.str().map(|_| ()),
+            "g" => self.signature().map(|_| ()),
+            other => Err(ArRpcError::Dbus(format!(
+                "Unexpected variant type {}",
+                other
+            ))),
+        }
+    }
+
+    /// An array of elements `f` reads, each aligned to `alignment`
+    pub fn array<T>(
+        &mut self,
+        alignment: usize,
+        mut f: impl FnMut(&mut Self) -> Result<T>,
+    ) -> Result<Vec<T>> {
+        let len = self.u32()? as usize;
+        self.align(alignment)?;
+        let end = self.position + len;
+        let mut values = Vec::new();
+        while self.position < end {
+            self.align(alignment)?;
+            values.push(f(self)?);
+        }
+        Ok(values)
+    }
+}
+
+#[derive(Default)]
+pub struct Incoming {
+    pub kind: u8,
+    pub flags: u8,
+    big_endian: bool,
+    serial: u32,
+    pub path: String,
+    pub interface: Option<String>,
+    pub member: String,
+    pub sender: Option<String>,
+    pub reply_serial: Option<u32>,
+    body: Vec<u8>,
+}
+
+impl Incoming {
+    pub async fn read(reader: &mut OwnedReadHalf) -> Result<Incoming> {
+        let mut data = vec![0; 16];
+        reader.read_exact(&mut data).await?;
+        let mut header = Decoder {
+            data: &data,
+            position: 0,
+            big_endian: data[0] == b'B',
+        };
+        header.take(4)?;
+        let body_len = header.u32()? as usize;
+        header.u32()?;
+        let fields_len = header.u32()? as usize;
+        let header_len = (16 + fields_len).div_ceil(8) * 8;
+        if header_len + body_len > MAX_MESSAGE_SIZE {
+            return Err(ArRpcError::Dbus("Message too large".to_string()));
+        }
+        data.resize(header_len + body_len, 0);
+        reader.read_exact(&mut data[16..]).await?;
+
+        let mut message = Incoming {
+            kind: data[1],
+            flags: data[2],
+            big_endian: data[0] == b'B',
+            ..Default::default()
+        };
+        let mut d = Decoder {
+            data: &data[..16 + fields_len],
+            position: 8,
+            big_endian: message.big_endian,
+        };
+        message.serial = d.u32()?;
+        d.u32()?;
+        while d.position < d.data.len() {
+            d.align(8)?;
+            let code = d.byte()?;
+            match d.signature()? {
+                "s" | "o" => {
+                    let value = d.str()?.to_string();
+                    match code {
+                        1 => message.path = value,
+                        2 => message.interface = Some(value),
+                        3 => message.member = value,
+                        7 => message.sender = Some(value),
+                        _ => {}
+                    }
+                }
+                "u" => {
+                    let value = d.u32()?;
+                    if code == 5 {
+                        message.reply_serial = Some(value);
+                    }
+                }
+                "g" => {
+                    d.signature()?;
+                }
+                other => {
+                    return Err(ArRpcError::Dbus(format!(
+                        "Unexpected header field type {}",
+                        other
+                    )));
+                }
+            }
+        }
+        message.body = data[header_len..].to_vec();
+        Ok(message)
+    }
+
+    pub fn body(&self) -> Decoder<'_> {
+        Decoder {
+            data: &self.body,
+            position: 0,
+            big_endian: self.big_endian,
+        }
+    }
+
+    /// Whether the caller waits for an answer
+    pub fn wants_reply(&self) -> bool {
+        self.kind == METHOD_CALL && self.flags & NO_REPLY_EXPECTED == 0
+    }
+
+    pub fn error_message(&self) -> String {
+        self.body()
+            .str()
+            .map(str::to_string)
+            .unwrap_or_else(|_| "Unknown error".to_string())
+    }
+
+    fn reply_fields(&self) -> Vec<Field<'_>> {
+        let mut fields = vec![Field::ReplySerial(self.serial)];
+        if let Some(sender) = &self.sender {
+            fields.push(Field::Destination(sender));
+        }
+        fields
+    }
+
+    pub fn reply(&self, serial: u32, signature: &str, body: &Encoder) -> Vec<u8> {
+        message(METHOD_RETURN, serial, self.reply_fields(), body, signature)
+    }
+
+    pub fn error(&self, serial: u32, name: &str, text: String) -> Vec<u8> {
+        let mut fields = self.reply_fields();
+        fields.push(Field::ErrorName(name));
+        let mut body = Encoder::default();
+        body.str(&text);
+        message(ERROR, serial, fields, &body, "s")
+    }
+}
+
+/// The signature and body of a reply, or the name and text of an error
+pub type Answer = std::result::Result<(&'static str, Encoder), (&'static str, String)>;
+
+pub fn unknown_object(path: &str) -> (&'static str, String) {
+    (
+        "org.freedesktop.DBus.Error.UnknownObject",
+        format!("No object at {}", path),
+    )
+}
+
+pub fn unknown_method(member: &str) -> (&'static str, String) {
+    (
+        "org.freedesktop.DBus.Error.UnknownMethod",
+        format!("No method {}", member),
+    )
+}
@@ -0,0 +1,230 @@
+use crate::{
+    config::Config,
+    error::{ArRpcError, Result},
+    structs::Assets,
+};
+use owo_colors::OwoColorize;
+use serde::{Deserialize, Serialize};
+use std::{
+    env,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::{fs, process::Command, task, time};
+use tracing::{debug, info, warn};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Detectable {
+    /// The application ID activities are shown with
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub executables: Vec<Executable>,
+    /// Only set by custom entries, shown instead of the application's own artwork
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub assets: Option<Assets>,
+}
+
+impl Detectable {
+    /// Custom entries only need to set what they change about an official one
+    fn merge(&mut self, custom: &Detectable) {
+        if !custom.name.is_empty() {
+            self.name = custom.name.clone();
+        }
+        if !custom.executables.is_empty() {
+            self.executables = custom.executables.clone();
+        }
+        if custom.assets.is_some() {
+            self.assets = custom.assets.clone();
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Executable {
+    /// A path suffix like `game.exe` or `bin/game`, always with forward slashes
+    pub name: String,
+    /// `win32`, `linux` or `darwin`
+    pub os: String,
+    #[serde(default)]
+    pub is_launcher: bool,
+    /// Has to appear in the command line as well, for games run by something like `java`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arguments: Option<String>,
+}
+
+/// Discord's list of detectable applications, cached on disk so detection also works offline,
+/// with the user's own entries merged over it
+pub struct Database {
+    entries: RwLock<Arc<[Detectable]>>,
+    official: RwLock<Arc<[Detectable]>>,
+    custom: Vec<Detectable>,
+    cache_path: PathBuf,
+}
+
+impl Database {
+    pub fn default_cache_path() -> PathBuf {
+        let cache_directory = env::var("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|_| env::var("HOME").map(|home| Path::new(&home).join(".cache")))
+            .unwrap_or_else(|_| env::temp_dir());
+        cache_directory.join("arrpc").join("detectable.json")
+    }
+
+    /// Next to the config file, only read if it exists
+    pub fn default_custom_path() -> PathBuf {
+        Config::default_path().with_file_name("detectable.json")
+    }
+
+    /// Starts out with the cached copy, if there is a usable one. Unlike a broken cache, a
+    /// broken custom file is an error.
+    pub async fn open(cache_path: &Path, custom_path: &Path) -> Result<Database> {
+        let custom = match fs::read(custom_path).await {
+            Ok(data) => serde_json::from_slice::<Vec<Detectable>>(&data).map_err(|e| {
+                ArRpcError::Database(format!("Invalid {}: {}", custom_path.display(), e))
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        if !custom.is_empty() {
+            info!(
+                "{} {}",
+                "Loaded custom detectable applications:".cyan(),
+                custom.len().yellow().bold()
+            );
+        }
+        let entries = match fs::read(cache_path).await {
+            Ok(data) => match serde_json::from_slice::<Vec<Detectable>>(&data) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    warn!("Ignoring broken cache {}: {}", cache_path.display(), e);
+                    Vec::new()
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                warn!("Failed to read {}: {}", cache_path.display(), e);
+                Vec::new()
+            }
+        };
+        let database = Database {
+            entries: RwLock::new(Vec::new().into()),
+            official: RwLock::new(Vec::new().into()),
+            custom,
+            cache_path: cache_path.to_path_buf(),
+        };
+        database.set_official(entries);
+        Ok(database)
+    }
+
+    /// Custom entries come first, so they win when several match the same process
+    pub fn entries(&self) -> Arc<[Detectable]> {
+        self.entries.read().unwrap().clone()
+    }
+
+    fn set_official(&self, mut official: Vec<Detectable>) {
+        let mut entries: Vec<Detectable> = Vec::with_capacity(official.len() + self.custom.len());
+        for custom in &self.custom {
+            match official.iter().position(|entry| entry.id == custom.id) {
+                Some(index) => {
+                    let mut entry = official.remove(index);
+                    entry.merge(custom);
+                    entries.push(entry);
+                }
+                None => entries.push(custom.clone()),
+            }
+        }
+        entries.extend(official.iter().cloned());
+        *self.official.write().unwrap() = official.into();
+        *self.entries.write().unwrap() = entries.into();
+    }
+
+    fn etag_path(&self) -> PathBuf {
+        self.cache_path.with_extension("etag")
+    }
+
+    /// Fetches `url` unless the cached copy is still current, returns whether anything changed.
+    /// There is no TLS in this crate, so `curl` does the fetching, which also takes care of
+    /// `ETag` and `If-Modified-Since`.
+    pub async fn refresh(&self, url: &str) -> Result<bool> {
+        if let Some(parent) = self.cache_path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let download = self.cache_path.with_extension("json.part");
+        let _ = fs::remove_file(&download).await;
+        let mut curl = Command::new("curl");
+        curl.args([
+            "--silent",
+            "--show-error",
+            "--fail",
+            "--location",
+            "--compressed",
+        ])
+        .args(["--max-time", "60"])
+        .arg("--etag-save")
+        .arg(self.etag_path())
+        .arg("--output")
+        .arg(&download);
+        // Only conditional once there is a copy to fall back on
+        if !self.official.read().unwrap().is_empty() {
+            curl.arg("--etag-compare")
+                .arg(self.etag_path())
+                .arg("--time-cond")
+                .arg(&self.cache_path);
+        }
+        let output = curl
+            .arg(url)
+            .output()
+            .await
+            .map_err(|e| ArRpcError::Database(format!("Failed to run curl: {}", e)))?;
+        if !output.status.success() {
+            return Err(ArRpcError::Database(format!(
+                "Fetching {} failed: {}",
+                url,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        // Nothing is written for a 304
+        let data = match fs::read(&download).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        let entries: Vec<Detectable> = serde_json::from_slice(&data)
+            .map_err(|e| ArRpcError::Database(format!("{} returned invalid data: {}", url, e)))?;
+        fs::rename(&download, &self.cache_path).await?;
+        self.set_official(entries);
+        Ok(true)
+    }
+
+    /// Refreshes right away and then every `interval`, `None` only fetches without a cached copy
+    pub fn spawn_refresh(
+        self: Arc<Self>,
+        url: String,
+        interval: Option<Duration>,
+    ) -> task::JoinHandle<()> {
+        task::spawn(async move {
+            if interval.is_none() && !self.official.read().unwrap().is_empty() {
+                return;
+            }
+            loop {
+                match self.refresh(&url).await {
+                    Ok(true) => info!(
+                        "{} {}",
+                        "Updated detectable applications:".cyan(),
+                        self.official.read().unwrap().len().yellow().bold()
+                    ),
+                    Ok(false) => debug!("Detectable applications are up to date"),
+                    Err(e) => warn!("{}", e),
+                }
+                let Some(interval) = interval else {
+                    return;
+                };
+                time::sleep(interval).await;
+            }
+        })
+    }
+}
@@ -0,0 +1,11 @@
+//! Shows games as played without them speaking RPC, by matching running processes against
+//! Discord's list of detectable applications
+
+pub mod database;
+pub mod precedence;
+pub mod process;
+pub mod scanner;
+
+pub use database::{Database, Detectable, Executable};
+pub use precedence::Precedence;
+pub use scanner::Scanner;
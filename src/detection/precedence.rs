@@ -0,0 +1,121 @@
+use crate::structs::{ActivityEvent, ActivitySource, IpcActivityMessage};
+use std::collections::HashMap;
+use tracing::debug;
+
+/// Hides detected activities while an RPC client of the same game, by pid or application ID,
+/// shows its own, and brings them back once it stops
+#[derive(Default)]
+pub struct Precedence {
+    /// Socket ID of every RPC client with an activity to its pids and application ID
+    rpc: HashMap<String, (Vec<usize>, String)>,
+    /// Socket ID of every detected activity to it and whether it is passed on
+    detected: HashMap<String, (Box<IpcActivityMessage>, bool)>,
+}
+
+impl Precedence {
+    /// The events to pass on instead of `event`
+    pub fn apply(&mut self, event: ActivityEvent) -> Vec<ActivityEvent> {
+        match ActivitySource::of(event.socket_id()) {
+            ActivitySource::Process => return self.apply_detected(event),
+            ActivitySource::Custom | ActivitySource::Rotation => return vec![event],
+            ActivitySource::Rpc => {}
+        }
+        match &event {
+            ActivityEvent::Set(msg) if msg.activity.is_some() => {
+                let pids = std::iter::once(msg.pid).chain(msg.peer.and_then(|peer| peer.pid));
+                let application_id = msg.activity.as_ref().unwrap().application_id.clone();
+                self.rpc
+                    .insert(msg.socket_id.clone(), (pids.collect(), application_id));
+                let mut events = self.hide();
+                events.push(event);
+                // Its previous activity might have been for another game
+                events.extend(self.show());
+                events
+            }
+            ActivityEvent::Set(_)
+            | ActivityEvent::Clear { .. }
+            | ActivityEvent::Disconnect { .. } => {
+                if self.rpc.remove(event.socket_id()).is_none() {
+                    return vec![event];
+                }
+                let mut events = vec![event];
+                events.extend(self.show());
+                events
+            }
+            _ => vec![event],
+        }
+    }
+
+    fn apply_detected(&mut self, event: ActivityEvent) -> Vec<ActivityEvent> {
+        match event {
+            ActivityEvent::Set(msg) => {
+                let shown = !self.conflicts(&msg);
+                if !shown {
+                    debug!(
+                        "Not showing {}, its RPC client takes precedence",
+                        msg.socket_id
+                    );
+                }
+                self.detected
+                    .insert(msg.socket_id.clone(), (msg.clone(), shown));
+                match shown {
+                    true => vec![ActivityEvent::Set(msg)],
+                    false => Vec::new(),
+                }
+            }
+            event => match self.detected.remove(event.socket_id()) {
+                // Already cleared when it was hidden
+                Some((_, false)) => Vec::new(),
+                _ => vec![event],
+            },
+        }
+    }
+
+    fn conflicts(&self, msg: &IpcActivityMessage) -> bool {
+        let application_id = msg
+            .activity
+            .as_ref()
+            .map(|activity| &activity.application_id);
+        self.rpc.values().any(|(pids, rpc_application_id)| {
+            pids.contains(&msg.pid) || Some(rpc_application_id) == application_id
+        })
+    }
+
+    fn hide(&mut self) -> Vec<ActivityEvent> {
+        let hidden: Vec<String> = self
+            .detected
+            .iter()
+            .filter(|(_, (msg, shown))| *shown && self.conflicts(msg))
+            .map(|(socket_id, _)| socket_id.clone())
+            .collect();
+        hidden
+            .into_iter()
+            .filter_map(|socket_id| {
+                let (msg, shown) = self.detected.get_mut(&socket_id)?;
+                *shown = false;
+                debug!("Hiding {}, its RPC client takes precedence", socket_id);
+                Some(ActivityEvent::Clear {
+                    socket_id,
+                    pid: msg.pid,
+                })
+            })
+            .collect()
+    }
+
+    fn show(&mut self) -> Vec<ActivityEvent> {
+        let shown: Vec<String> = self
+            .detected
+            .iter()
+            .filter(|(_, (msg, shown))| !*shown && !self.conflicts(msg))
+            .map(|(socket_id, _)| socket_id.clone())
+            .collect();
+        shown
+            .into_iter()
+            .filter_map(|socket_id| {
+                let (msg, shown) = self.detected.get_mut(&socket_id)?;
+                *shown = true;
+                Some(ActivityEvent::Set(msg.clone()))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,232 @@
+/// A running process as far as detection cares
+#[derive(Debug, Clone, PartialEq)]
+pub struct Process {
+    pub pid: usize,
+    /// The full path of the executable, or just its name where the OS doesn't tell more
+    pub path: String,
+    /// The command line including the executable, empty where it can't be read
+    pub args: Vec<String>,
+}
+
+/// Every process that can be seen, except for this one. Blocks for a while on busy systems.
+pub fn list() -> Vec<Process> {
+    let own_pid = std::process::id() as usize;
+    let mut processes = platform::list();
+    processes.retain(|process| process.pid != own_pid);
+    processes
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use super::*;
+    use std::fs;
+
+    pub fn list() -> Vec<Process> {
+        let Ok(entries) = fs::read_dir("/proc") else {
+            return Vec::new();
+        };
+        entries
+            .flatten()
+            .filter_map(|entry| entry.file_name().to_str()?.parse::<usize>().ok())
+            .filter_map(process)
+            .collect()
+    }
+
+    fn process(pid: usize) -> Option<Process> {
+        let args: Vec<String> = fs::read(format!("/proc/{}/cmdline", pid))
+            .ok()?
+            .split(|&b| b == 0)
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| String::from_utf8_lossy(arg).into_owned())
+            .collect();
+        // The link can't be read for processes of other users, kernel threads have neither
+        let path = fs::read_link(format!("/proc/{}/exe", pid))
+            .ok()
+            .map(|exe| exe.to_string_lossy().into_owned())
+            .or_else(|| args.first().cloned())?;
+        Some(Process { pid, path, args })
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use super::*;
+    use std::{ffi::c_void, mem, ptr};
+
+    pub fn list() -> Vec<Process> {
+        let count = unsafe { libc::proc_listallpids(ptr::null_mut(), 0) };
+        if count <= 0 {
+            return Vec::new();
+        }
+        // Room for processes started in the meantime
+        let mut pids: Vec<libc::pid_t> = vec![0; count as usize + 64];
+        let size = (pids.len() * mem::size_of::<libc::pid_t>()) as libc::c_int;
+        let count = unsafe { libc::proc_listallpids(pids.as_mut_ptr() as *mut c_void, size) };
+        pids.truncate(count.max(0) as usize);
+        pids.into_iter()
+            .filter(|&pid| pid > 0)
+            .filter_map(process)
+            .collect()
+    }
+
+    fn process(pid: libc::pid_t) -> Option<Process> {
+        let mut buffer = vec![0u8; libc::PROC_PIDPATHINFO_MAXSIZE as usize];
+        let len = unsafe {
+            libc::proc_pidpath(pid, buffer.as_mut_ptr() as *mut c_void, buffer.len() as u32)
+        };
+        if len <= 0 {
+            return None;
+        }
+        buffer.truncate(len as usize);
+        Some(Process {
+            pid: pid as usize,
+            path: String::from_utf8_lossy(&buffer).into_owned(),
+            args: args(pid).unwrap_or_default(),
+        })
+    }
+
+    // KERN_PROCARGS2 is argc, the executable path, padding and then the arguments, all NUL
+    // terminated and followed by the environment
+    fn args(pid: libc::pid_t) -> Option<Vec<String>> {
+        let mut max: libc::c_int = 0;
+        let mut size = mem::size_of::<libc::c_int>();
+        let mut mib = [libc::CTL_KERN, libc::KERN_ARGMAX];
+        let result = unsafe {
+            libc::sysctl(
+                mib.as_mut_ptr(),
+                2,
+                &mut max as *mut _ as *mut c_void,
+                &mut size,
+                ptr::null_mut(),
+                0,
+            )
+        };
+        if result != 0 || max <= 0 {
+            return None;
+        }
+        let mut buffer = vec![0u8; max as usize];
+        let mut size = buffer.len();
+        let mut mib = [libc::CTL_KERN, libc::KERN_PROCARGS2, pid];
+        let result = unsafe {
+            libc::sysctl(
+                mib.as_mut_ptr(),
+                3,
+                buffer.as_mut_ptr() as *mut c_void,
+                &mut size,
+                ptr::null_mut(),
+                0,
+            )
+        };
+        if result != 0 || size < mem::size_of::<libc::c_int>() {
+            return None;
+        }
+        buffer.truncate(size);
+        let (argc, rest) = buffer.split_at(mem::size_of::<libc::c_int>());
+        let argc = libc::c_int::from_ne_bytes(argc.try_into().ok()?).max(0) as usize;
+        let args = rest
+            .split(|&b| b == 0)
+            .skip(1)
+            .filter(|arg| !arg.is_empty())
+            .take(argc)
+            .map(|arg| String::from_utf8_lossy(arg).into_owned())
+            .collect();
+        Some(args)
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use super::*;
+    use std::{ffi::c_void, mem};
+
+    type Handle = *mut c_void;
+
+    const TH32CS_SNAPPROCESS: u32 = 0x2;
+    const PROCESS_QUERY_LIMITED_INFORMATION: u32 = 0x1000;
+    const INVALID_HANDLE_VALUE: Handle = -1isize as Handle;
+    const MAX_PATH: usize = 260;
+
+    #[repr(C)]
+    #[allow(non_snake_case)]
+    struct PROCESSENTRY32W {
+        dwSize: u32,
+        cntUsage: u32,
+        th32ProcessID: u32,
+        th32DefaultHeapID: usize,
+        th32ModuleID: u32,
+        cntThreads: u32,
+        th32ParentProcessID: u32,
+        pcPriClassBase: i32,
+        dwFlags: u32,
+        szExeFile: [u16; MAX_PATH],
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn CreateToolhelp32Snapshot(flags: u32, pid: u32) -> Handle;
+        fn Process32FirstW(snapshot: Handle, entry: *mut PROCESSENTRY32W) -> i32;
+        fn Process32NextW(snapshot: Handle, entry: *mut PROCESSENTRY32W) -> i32;
+        fn OpenProcess(access: u32, inherit: i32, pid: u32) -> Handle;
+        fn QueryFullProcessImageNameW(
+            process: Handle,
+            flags: u32,
+            name: *mut u16,
+            size: *mut u32,
+        ) -> i32;
+        fn CloseHandle(handle: Handle) -> i32;
+    }
+
+    fn wide(buffer: &[u16]) -> String {
+        let len = buffer.iter().position(|&c| c == 0).unwrap_or(buffer.len());
+        String::from_utf16_lossy(&buffer[..len])
+    }
+
+    // Reading the command line of another process needs its PEB, which isn't worth it yet
+    pub fn list() -> Vec<Process> {
+        let snapshot = unsafe { CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0) };
+        if snapshot == INVALID_HANDLE_VALUE {
+            return Vec::new();
+        }
+        let mut processes = Vec::new();
+        let mut entry: PROCESSENTRY32W = unsafe { mem::zeroed() };
+        entry.dwSize = mem::size_of::<PROCESSENTRY32W>() as u32;
+        let mut found = unsafe { Process32FirstW(snapshot, &mut entry) } != 0;
+        while found {
+            let pid = entry.th32ProcessID;
+            if pid != 0 {
+                processes.push(Process {
+                    pid: pid as usize,
+                    path: full_path(pid).unwrap_or_else(|| wide(&entry.szExeFile)),
+                    args: Vec::new(),
+                });
+            }
+            found = unsafe { Process32NextW(snapshot, &mut entry) } != 0;
+        }
+        unsafe { CloseHandle(snapshot) };
+        processes
+    }
+
+    fn full_path(pid: u32) -> Option<String> {
+        let process = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid) };
+        if process.is_null() {
+            return None;
+        }
+        let mut buffer = [0u16; 32 * 1024];
+        let mut size = buffer.len() as u32;
+        let ok = unsafe { QueryFullProcessImageNameW(process, 0, buffer.as_mut_ptr(), &mut size) };
+        unsafe { CloseHandle(process) };
+        match ok != 0 {
+            true => Some(wide(&buffer[..size as usize])),
+            false => None,
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+mod platform {
+    use super::*;
+
+    pub fn list() -> Vec<Process> {
+        Vec::new()
+    }
+}
@@ -0,0 +1,299 @@
+use super::{process::Process, Database, Detectable};
+use crate::{
+    config::DetectionConfig,
+    state::now_millis,
+    structs::{
+        ActivityEvent, ActivitySource, IpcActivity, IpcActivityMessage, IpcActivityMetadata,
+        Timestamps,
+    },
+};
+use futures_util::{stream, Stream};
+use owo_colors::OwoColorize;
+use std::{borrow::Cow, collections::HashMap, sync::Arc, time::Duration};
+use tokio::{
+    select,
+    sync::{mpsc, watch},
+    task::{self, JoinHandle},
+    time::{self, MissedTickBehavior},
+};
+use tracing::info;
+
+/// How Discord's list names the platform an executable is for
+pub const OS: &str = if cfg!(windows) {
+    "win32"
+} else if cfg!(target_os = "macos") {
+    "darwin"
+} else {
+    "linux"
+};
+
+/// What detected activities use as their socket ID, followed by the application ID
+pub const SOCKET_PREFIX: &str = ActivitySource::PROCESS_PREFIX;
+
+/// Looks for known games among the running processes and reports them as activities
+pub struct Scanner {
+    paused: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl Scanner {
+    pub fn spawn(
+        database: Arc<Database>,
+        config: &DetectionConfig,
+    ) -> (impl Stream<Item = ActivityEvent> + Send + Unpin, Scanner) {
+        let interval = Duration::from_secs(config.scan_interval_secs.max(1));
+        let ignore: Vec<String> = config.ignore.iter().map(|p| normalize(p)).collect();
+        let (tx, rx) = mpsc::channel(64);
+        let (paused, mut paused_rx) = watch::channel(false);
+        let task = task::spawn(async move {
+            // Application ID to the pid it was detected in
+            let mut detected: HashMap<String, usize> = HashMap::new();
+            let mut ticker = time::interval(interval);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                select! {
+                    _ = ticker.tick() => {}
+                    changed = paused_rx.changed() => {
+                        if changed.is_err() {
+                            return;
+                        }
+                    }
+                }
+                if *paused_rx.borrow_and_update() {
+                    for (id, pid) in detected.drain() {
+                        let event = ActivityEvent::Clear {
+                            socket_id: format!("{}{}", SOCKET_PREFIX, id),
+                            pid,
+                        };
+                        if tx.send(event).await.is_err() {
+                            return;
+                        }
+                    }
+                    continue;
+                }
+
+                let processes = task::spawn_blocking(super::process::list)
+                    .await
+                    .unwrap_or_default();
+                // An empty list means enumerating failed, not that everything exited
+                if processes.is_empty() {
+                    continue;
+                }
+                let entries = database.entries();
+                let mut running: HashMap<&str, (&Detectable, Cow<Process>)> = HashMap::new();
+                for process in &processes {
+                    if is_ignored(&ignore, &process.path) {
+                        continue;
+                    }
+                    let (process, os) = match wine_target(process) {
+                        Some(target) if !is_ignored(&ignore, &target.path) => {
+                            (Cow::Owned(target), "win32")
+                        }
+                        Some(_) => continue,
+                        None => (Cow::Borrowed(process), OS),
+                    };
+                    let path = normalize(&process.path);
+                    let args = process.args.get(1..).unwrap_or_default().join(" ");
+                    if let Some(entry) = entries
+                        .iter()
+                        .find(|entry| matches(entry, os, &path, &args))
+                    {
+                        running.entry(&entry.id).or_insert((entry, process));
+                    }
+                }
+
+                let exited: Vec<String> = detected
+                    .keys()
+                    .filter(|id| !running.contains_key(id.as_str()))
+                    .cloned()
+                    .collect();
+                for id in exited {
+                    let Some(pid) = detected.remove(&id) else {
+                        continue;
+                    };
+                    info!(
+                        "{} {} {}",
+                        "Process".cyan(),
+                        pid.yellow().bold(),
+                        "exited, clearing its activity".cyan()
+                    );
+                    let event = ActivityEvent::Clear {
+                        socket_id: format!("{}{}", SOCKET_PREFIX, id),
+                        pid,
+                    };
+                    if tx.send(event).await.is_err() {
+                        return;
+                    }
+                }
+
+                for (entry, process) in running.into_values() {
+                    // Still shown if the game is running again under another pid
+                    if let Some(pid) = detected.get_mut(&entry.id) {
+                        *pid = process.pid;
+                        continue;
+                    }
+                    info!(
+                        "{} {} {} {}",
+                        "Detected".cyan(),
+                        entry.name.yellow().bold(),
+                        "in pid".cyan(),
+                        process.pid.yellow().bold()
+                    );
+                    detected.insert(entry.id.clone(), process.pid);
+                    if tx
+                        .send(ActivityEvent::Set(Box::new(message(entry, &process))))
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+            }
+        });
+        let events = Box::pin(stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|event| (event, rx))
+        }));
+        (events, Scanner { paused, task })
+    }
+
+    /// Pausing clears every detected activity, resuming scans right away
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.send_replace(paused);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    pub fn shutdown(self) {
+        self.task.abort();
+    }
+}
+
+fn file_name(path: &str) -> &str {
+    path.rsplit(['/', '\\']).next().unwrap_or(path)
+}
+
+fn normalize(path: &str) -> String {
+    path.replace('\\', "/").to_lowercase()
+}
+
+/// `*` stands for any run of characters and `?` for a single one
+fn wildcard(pattern: &[char], text: &[char]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some(('*', rest)) => (0..=text.len()).any(|skip| wildcard(rest, &text[skip..])),
+        Some((&c, rest)) => match text.split_first() {
+            Some((&t, text)) => (c == '?' || c == t) && wildcard(rest, text),
+            None => false,
+        },
+    }
+}
+
+/// Patterns with a slash are matched against the whole path, others only against the file name
+fn is_ignored(ignore: &[String], path: &str) -> bool {
+    if ignore.is_empty() {
+        return false;
+    }
+    let path = normalize(path);
+    let path_chars: Vec<char> = path.chars().collect();
+    let name_chars: Vec<char> = file_name(&path).chars().collect();
+    ignore.iter().any(|pattern| {
+        let text = match pattern.contains('/') {
+            true => &path_chars,
+            false => &name_chars,
+        };
+        wildcard(&pattern.chars().collect::<Vec<_>>(), text)
+    })
+}
+
+/// Whether `name` is the end of `path`, in whole components and ignoring case. Installs in
+/// other directories still match `bin/game.exe` or `game.exe` that way.
+fn is_path_suffix(path: &str, name: &str) -> bool {
+    let Some(start) = path.len().checked_sub(name.len()) else {
+        return false;
+    };
+    path.is_char_boundary(start)
+        && path[start..].eq_ignore_ascii_case(name)
+        && (start == 0 || path.as_bytes()[start - 1] == b'/')
+}
+
+/// The Windows executable a Wine or Proton process runs, as the process itself is only the
+/// preloader. Wine puts the Windows path in `argv[0]`, `Z:` being the Unix root.
+fn wine_target(process: &Process) -> Option<Process> {
+    let exe = file_name(&process.path);
+    if !exe.starts_with("wine") {
+        return None;
+    }
+    // `wine game.exe` before Wine rewrote the command line
+    let index = process
+        .args
+        .iter()
+        .take(2)
+        .position(|arg| arg.to_ascii_lowercase().ends_with(".exe"))?;
+    let target = &process.args[index];
+    let path = match target.get(..3) {
+        Some(drive) if drive.eq_ignore_ascii_case("z:\\") => target[2..].replace('\\', "/"),
+        _ => target.replace('\\', "/"),
+    };
+    Some(Process {
+        pid: process.pid,
+        path,
+        args: process.args[index..].to_vec(),
+    })
+}
+
+/// `path` as [`normalize`] returns it, `args` is the command line without the executable
+fn matches(entry: &Detectable, os: &str, path: &str, args: &str) -> bool {
+    entry.executables.iter().any(|exe| {
+        // A leading `>` only matches the file name
+        let suffix = match exe.name.strip_prefix('>') {
+            Some(name) => file_name(path).eq_ignore_ascii_case(name),
+            None => is_path_suffix(path, &exe.name),
+        };
+        exe.os == os
+            && !exe.is_launcher
+            && suffix
+            && exe
+                .arguments
+                .as_ref()
+                .is_none_or(|arguments| args.contains(arguments.as_str()))
+    })
+}
+
+fn message(entry: &Detectable, process: &Process) -> IpcActivityMessage {
+    let created_at = now_millis();
+    IpcActivityMessage {
+        activity: Some(IpcActivity {
+            application_id: entry.id.clone(),
+            state: String::new(),
+            details: String::new(),
+            flags: 0,
+            r#type: IpcActivity::PLAYING,
+            url: None,
+            assets: entry.assets.clone().unwrap_or_default(),
+            buttons: Vec::new(),
+            metadata: IpcActivityMetadata {
+                button_urls: Vec::new(),
+            },
+            instance: false,
+            timestamps: Some(Timestamps {
+                start: created_at,
+                end: None,
+            }),
+            party: None,
+            emoji: None,
+            status_display_type: None,
+            state_url: None,
+            details_url: None,
+            created_at,
+        }),
+        socket_id: format!("{}{}", SOCKET_PREFIX, entry.id),
+        pid: process.pid,
+        source: ActivitySource::Process,
+        peer: None,
+        process_name: Some(file_name(&process.path).to_string()),
+        process_path: Some(process.path.clone()),
+        idle: false,
+    }
+}
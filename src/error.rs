@@ -0,0 +1,97 @@
+use crate::ipc::structs::IpcFrame;
+use std::{io, path::PathBuf, time::Duration};
+use thiserror::Error;
+use tokio::sync::mpsc;
+
+#[derive(Debug, Error)]
+pub enum ArRpcError {
+    #[error("Failed to bind to IPC server (ran out of paths)")]
+    SocketPathsExhausted,
+    #[error("{} can be written to by other users, set ipc.allow_shared_directory to bind there anyway", .0.display())]
+    SharedDirectory(PathBuf),
+    #[error("{} is not a private directory of this user", .0.display())]
+    UnsafeDirectory(PathBuf),
+    #[error("Bridge port {0} is already in use")]
+    BridgePortBusy(u16),
+    #[error("arRPC is already running{}", .0.map(|pid| format!(" (pid {})", pid)).unwrap_or_default())]
+    AlreadyRunning(Option<u32>),
+    #[error("The official Discord client is already running")]
+    DiscordRunning,
+    #[error("No RPC transport is enabled")]
+    NoTransports,
+    #[error("Invalid IPC message type: {0}")]
+    InvalidMessageType(i32),
+    #[error("Invalid handshake version: {0}")]
+    InvalidVersion(i32),
+    #[error("Origin not allowed: {0}")]
+    InvalidOrigin(String),
+    #[error("Unsupported encoding: {0}")]
+    InvalidEncoding(String),
+    #[error("Invalid client ID")]
+    InvalidClientId,
+    #[error("Handshake sent twice")]
+    DuplicateHandshake,
+    #[error("Frame sent before handshake was done")]
+    FrameBeforeHandshake,
+    #[error("Invalid activity: {0}")]
+    InvalidActivity(String),
+    #[error("Invalid pattern: {0}")]
+    Pattern(String),
+    /// Valid JSON but not a valid frame, carries what is needed to answer it
+    #[error("Invalid frame: {1}")]
+    InvalidFrame(Box<IpcFrame>, String),
+    #[error("IPC message of {0} bytes is too large")]
+    FrameTooLarge(usize),
+    #[error("No reply to ping within {0:?}")]
+    PingTimeout(Duration),
+    #[error("Client process is gone")]
+    ClientGone,
+    #[error("Failed to load plugin {0}")]
+    Plugin(String),
+    #[error("Detectable applications: {0}")]
+    Database(String),
+    #[error("Process detection is not enabled")]
+    DetectionDisabled,
+    #[error("Idle detection: {0}")]
+    Idle(String),
+    #[error("Service setup failed: {0}")]
+    Service(String),
+    #[error("D-Bus error: {0}")]
+    Dbus(String),
+    #[error("Webhook failed: {0}")]
+    Webhook(String),
+    #[error("MQTT error: {0}")]
+    Mqtt(String),
+    #[error("Relay failed: {0}")]
+    Relay(String),
+    #[error("Control API request failed: {0}")]
+    Control(String),
+    #[error("Replay failed: {0}")]
+    Replay(String),
+    #[error("Answered a plain HTTP request")]
+    HttpProbe,
+    #[error("Channel closed")]
+    ChannelClosed,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[cfg(any(feature = "bridge", feature = "ws-rpc"))]
+    #[error(transparent)]
+    WebSocket(Box<tokio_tungstenite::tungstenite::Error>),
+}
+
+#[cfg(any(feature = "bridge", feature = "ws-rpc"))]
+impl From<tokio_tungstenite::tungstenite::Error> for ArRpcError {
+    fn from(e: tokio_tungstenite::tungstenite::Error) -> Self {
+        ArRpcError::WebSocket(Box::new(e))
+    }
+}
+
+impl<T> From<mpsc::error::SendError<T>> for ArRpcError {
+    fn from(_: mpsc::error::SendError<T>) -> Self {
+        ArRpcError::ChannelClosed
+    }
+}
+
+pub type Result<T, E = ArRpcError> = std::result::Result<T, E>;
@@ -0,0 +1,56 @@
+use crate::structs::{ActivityEvent, ActivitySource};
+use owo_colors::OwoColorize;
+use std::{collections::HashMap, time::Duration};
+use tokio::time::Instant;
+use tracing::info;
+
+/// When the activity of every RPC client was last set, to clear the ones that are never
+/// updated or cleared again
+#[derive(Debug, Default)]
+pub struct Expiry {
+    /// Socket ID to pid and last update
+    updated: HashMap<String, (usize, Instant)>,
+}
+
+impl Expiry {
+    pub fn observe(&mut self, event: &ActivityEvent) {
+        if ActivitySource::of(event.socket_id()) != ActivitySource::Rpc {
+            return;
+        }
+        match event {
+            ActivityEvent::Set(msg) if msg.activity.is_some() => {
+                self.updated
+                    .insert(msg.socket_id.clone(), (msg.pid, Instant::now()));
+            }
+            ActivityEvent::Set(_)
+            | ActivityEvent::Clear { .. }
+            | ActivityEvent::Disconnect { .. } => {
+                self.updated.remove(event.socket_id());
+            }
+            _ => {}
+        }
+    }
+
+    /// Clears for the activities older than `max_age`, which are forgotten then
+    pub fn expired(&mut self, max_age: Duration) -> Vec<ActivityEvent> {
+        let expired: Vec<String> = self
+            .updated
+            .iter()
+            .filter(|(_, (_, updated))| updated.elapsed() >= max_age)
+            .map(|(socket_id, _)| socket_id.clone())
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|socket_id| {
+                let (pid, _) = self.updated.remove(&socket_id)?;
+                info!(
+                    "{} {}, not updated in {}s",
+                    "Clearing activity of".cyan(),
+                    socket_id.yellow().bold(),
+                    max_age.as_secs()
+                );
+                Some(ActivityEvent::Clear { socket_id, pid })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,73 @@
+use crate::{
+    state::now_millis,
+    structs::{ActivityEvent, IpcActivity},
+};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+/// Sockets whose history is kept, the one that changed least recently is forgotten first
+const MAX_SOCKETS: usize = 64;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Transition {
+    /// ms since the epoch
+    pub at: u64,
+    #[serde(flatten)]
+    pub change: Change,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Change {
+    Set { activity: Box<IpcActivity> },
+    Clear,
+    Disconnect,
+}
+
+/// The last few activity transitions of each socket, including ones that are gone
+#[derive(Debug, Clone, Default)]
+pub struct History(Arc<Mutex<BTreeMap<String, VecDeque<Transition>>>>);
+
+impl History {
+    pub fn record(&self, event: &ActivityEvent, capacity: usize) {
+        let change = match event {
+            ActivityEvent::Set(msg) => match &msg.activity {
+                Some(activity) => Change::Set {
+                    activity: Box::new(activity.clone()),
+                },
+                None => Change::Clear,
+            },
+            ActivityEvent::Clear { .. } => Change::Clear,
+            ActivityEvent::Disconnect { .. } => Change::Disconnect,
+            ActivityEvent::Handshake { .. } | ActivityEvent::SessionEnd(_) => return,
+        };
+        let mut sockets = self.0.lock().unwrap();
+        if !sockets.contains_key(event.socket_id()) && sockets.len() >= MAX_SOCKETS {
+            let stalest = sockets
+                .iter()
+                .min_by_key(|(_, transitions)| transitions.back().map(|transition| transition.at))
+                .map(|(socket_id, _)| socket_id.clone());
+            if let Some(socket_id) = stalest {
+                sockets.remove(&socket_id);
+            }
+        }
+        let transitions = sockets.entry(event.socket_id().to_string()).or_default();
+        transitions.push_back(Transition {
+            at: now_millis(),
+            change,
+        });
+        while transitions.len() > capacity {
+            transitions.pop_front();
+        }
+        if transitions.is_empty() {
+            sockets.remove(event.socket_id());
+        }
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, VecDeque<Transition>> {
+        self.0.lock().unwrap().clone()
+    }
+}
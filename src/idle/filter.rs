@@ -0,0 +1,78 @@
+use crate::{
+    config::IdleAction,
+    structs::{ActivityEvent, ActivitySource, IpcActivityMessage},
+};
+use std::collections::HashMap;
+
+/// Hides or tags the activities passing through while the user is away and brings them back
+/// once they return. The custom status is left alone, the user set it themselves.
+#[derive(Debug)]
+pub struct IdleFilter {
+    action: IdleAction,
+    idle: bool,
+    /// The latest activity of every socket, as it would be shown without the filter
+    current: HashMap<String, Box<IpcActivityMessage>>,
+}
+
+impl IdleFilter {
+    pub fn new(action: IdleAction) -> Self {
+        Self {
+            action,
+            idle: false,
+            current: HashMap::new(),
+        }
+    }
+
+    /// The events to pass on instead of `event`
+    pub fn apply(&mut self, event: ActivityEvent) -> Vec<ActivityEvent> {
+        if ActivitySource::of(event.socket_id()) == ActivitySource::Custom {
+            return vec![event];
+        }
+        match event {
+            ActivityEvent::Set(msg) if msg.activity.is_some() => {
+                self.current.insert(msg.socket_id.clone(), msg.clone());
+                match (self.idle, self.action) {
+                    (false, _) => vec![ActivityEvent::Set(msg)],
+                    (true, IdleAction::Clear) => Vec::new(),
+                    (true, IdleAction::Tag) => vec![ActivityEvent::Set(tagged(msg, true))],
+                }
+            }
+            ActivityEvent::Set(_) | ActivityEvent::Clear { .. } => {
+                self.current.remove(event.socket_id());
+                // Already cleared when the user went away, or never shown
+                match (self.idle, self.action) {
+                    (true, IdleAction::Clear) => Vec::new(),
+                    _ => vec![event],
+                }
+            }
+            ActivityEvent::Disconnect { .. } => {
+                self.current.remove(event.socket_id());
+                vec![event]
+            }
+            event => vec![event],
+        }
+    }
+
+    /// The events that hide, tag or restore the current activities
+    pub fn set_idle(&mut self, idle: bool) -> Vec<ActivityEvent> {
+        if self.idle == idle {
+            return Vec::new();
+        }
+        self.idle = idle;
+        self.current
+            .values()
+            .map(|msg| match (idle, self.action) {
+                (true, IdleAction::Clear) => ActivityEvent::Clear {
+                    socket_id: msg.socket_id.clone(),
+                    pid: msg.pid,
+                },
+                _ => ActivityEvent::Set(tagged(msg.clone(), idle)),
+            })
+            .collect()
+    }
+}
+
+fn tagged(mut msg: Box<IpcActivityMessage>, idle: bool) -> Box<IpcActivityMessage> {
+    msg.idle = idle;
+    msg
+}
@@ -0,0 +1,110 @@
+//! Notices when the user walks away from the computer, so forwarded activities can be hidden or
+//! marked as idle until they are back
+
+pub mod filter;
+#[cfg(all(unix, not(target_os = "macos")))]
+mod wayland;
+#[cfg(windows)]
+mod windows;
+#[cfg(all(unix, not(target_os = "macos")))]
+mod x11;
+
+pub use filter::IdleFilter;
+
+use owo_colors::OwoColorize;
+use std::{thread, time::Duration};
+use tokio::{sync::watch, task};
+use tracing::{info, warn};
+
+/// How often the idle time is looked at where the system can't announce it
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Whether the user has been away for at least `threshold`, updated until the receiver is
+/// dropped. Never changes where idle time can't be read.
+pub fn watch(threshold: Duration) -> watch::Receiver<bool> {
+    let (tx, rx) = watch::channel(false);
+    task::spawn(async move {
+        #[cfg(all(unix, not(target_os = "macos")))]
+        if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+            match wayland::watch(threshold, &tx).await {
+                Ok(()) => return,
+                Err(e) => warn!("Wayland: {}, falling back to X11", e),
+            }
+        }
+        // The X11 display can't move between threads, so it gets one of its own
+        thread::spawn(move || poll(threshold, tx));
+    });
+    rx
+}
+
+fn poll(threshold: Duration, tx: watch::Sender<bool>) {
+    let Some(mut source) = IdleTime::open() else {
+        warn!("Idle detection is not supported here");
+        return;
+    };
+    info!(
+        "{} {}",
+        "Watching idle time through".cyan(),
+        source.name().yellow().bold()
+    );
+    while !tx.is_closed() {
+        if let Some(idle) = source.get() {
+            send(&tx, idle >= threshold);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn send(tx: &watch::Sender<bool>, idle: bool) {
+    tx.send_if_modified(|current| {
+        if *current == idle {
+            return false;
+        }
+        info!(
+            "{}",
+            match idle {
+                true => "User is away".cyan(),
+                false => "User is back".cyan(),
+            }
+        );
+        *current = idle;
+        true
+    });
+}
+
+/// Something that can be asked how long there was no input
+enum IdleTime {
+    #[cfg(all(unix, not(target_os = "macos")))]
+    X11(x11::Display),
+    #[cfg(windows)]
+    Windows,
+}
+
+impl IdleTime {
+    fn open() -> Option<IdleTime> {
+        #[cfg(all(unix, not(target_os = "macos")))]
+        return x11::Display::open().map(IdleTime::X11);
+        #[cfg(windows)]
+        return Some(IdleTime::Windows);
+        #[allow(unreachable_code)]
+        None
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            #[cfg(all(unix, not(target_os = "macos")))]
+            IdleTime::X11(_) => "the X11 screensaver extension",
+            #[cfg(windows)]
+            IdleTime::Windows => "GetLastInputInfo",
+        }
+    }
+
+    fn get(&mut self) -> Option<Duration> {
+        match self {
+            #[cfg(all(unix, not(target_os = "macos")))]
+            IdleTime::X11(display) => display.idle_time(),
+            #[cfg(windows)]
+            IdleTime::Windows => windows::idle_time(),
+        }
+    }
+}
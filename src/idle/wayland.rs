@@ -0,0 +1,198 @@
+use super::send;
+use crate::error::{ArRpcError, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use owo_colors::OwoColorize;
+use std::{env, path::PathBuf, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
+    select,
+    sync::watch,
+};
+use tracing::info;
+
+// Object IDs are picked by the client, so they are fixed here
+const DISPLAY: u32 = 1;
+const REGISTRY: u32 = 2;
+const CALLBACK: u32 = 3;
+const SEAT: u32 = 4;
+const NOTIFIER: u32 = 5;
+const NOTIFICATION: u32 = 6;
+
+const MAX_MESSAGE_SIZE: usize = 4096;
+
+/// Asks the compositor to announce when there was no input for `threshold` through
+/// `ext-idle-notify-v1`. Only returns early if it can't.
+pub async fn watch(threshold: Duration, tx: &watch::Sender<bool>) -> Result<()> {
+    let mut conn = Connection::open().await?;
+    conn.request(DISPLAY, 1, &[Arg::Uint(REGISTRY)]).await?;
+    conn.request(DISPLAY, 0, &[Arg::Uint(CALLBACK)]).await?;
+
+    // The globals are all announced before the sync callback is done
+    let mut seat = None;
+    let mut notifier = None;
+    loop {
+        let mut event = conn.event().await?;
+        match (event.object, event.opcode) {
+            (REGISTRY, 0) => {
+                let name = event.uint()?;
+                match event.string()?.as_str() {
+                    "wl_seat" => seat = seat.or(Some(name)),
+                    "ext_idle_notifier_v1" => notifier = Some(name),
+                    _ => {}
+                }
+            }
+            (CALLBACK, 0) => break,
+            (DISPLAY, 0) => return Err(event.error()),
+            _ => {}
+        }
+    }
+    let (Some(seat), Some(notifier)) = (seat, notifier) else {
+        return Err(ArRpcError::Idle(
+            "The compositor doesn't support ext-idle-notify".to_string(),
+        ));
+    };
+
+    for (name, interface, id) in [
+        (seat, "wl_seat", SEAT),
+        (notifier, "ext_idle_notifier_v1", NOTIFIER),
+    ] {
+        let args = [
+            Arg::Uint(name),
+            Arg::Str(interface),
+            Arg::Uint(1),
+            Arg::Uint(id),
+        ];
+        conn.request(REGISTRY, 0, &args).await?;
+    }
+    let timeout = threshold.as_millis().min(u32::MAX as u128) as u32;
+    let args = [Arg::Uint(NOTIFICATION), Arg::Uint(timeout), Arg::Uint(SEAT)];
+    conn.request(NOTIFIER, 1, &args).await?;
+    info!(
+        "{} {}",
+        "Watching idle time through".cyan(),
+        "ext-idle-notify".yellow().bold()
+    );
+
+    loop {
+        let mut event = select! {
+            event = conn.event() => event?,
+            _ = tx.closed() => return Ok(()),
+        };
+        match (event.object, event.opcode) {
+            (NOTIFICATION, 0) => send(tx, true),
+            (NOTIFICATION, 1) => send(tx, false),
+            (DISPLAY, 0) => return Err(event.error()),
+            _ => {}
+        }
+    }
+}
+
+enum Arg<'a> {
+    Uint(u32),
+    Str(&'a str),
+}
+
+struct Connection(UnixStream);
+
+impl Connection {
+    async fn open() -> Result<Connection> {
+        let display = env::var_os("WAYLAND_DISPLAY").unwrap_or_else(|| "wayland-0".into());
+        let mut path = PathBuf::from(&display);
+        if path.is_relative() {
+            let runtime_dir = env::var_os("XDG_RUNTIME_DIR")
+                .ok_or_else(|| ArRpcError::Idle("XDG_RUNTIME_DIR is not set".to_string()))?;
+            path = PathBuf::from(runtime_dir).join(display);
+        }
+        Ok(Connection(UnixStream::connect(path).await?))
+    }
+
+    async fn request(&mut self, object: u32, opcode: u16, args: &[Arg<'_>]) -> Result<()> {
+        let mut body = BytesMut::new();
+        for arg in args {
+            match arg {
+                Arg::Uint(value) => body.put_u32_ne(*value),
+                Arg::Str(value) => {
+                    body.put_u32_ne(value.len() as u32 + 1);
+                    body.put_slice(value.as_bytes());
+                    body.put_u8(0);
+                    while !body.len().is_multiple_of(4) {
+                        body.put_u8(0);
+                    }
+                }
+            }
+        }
+        let mut message = BytesMut::with_capacity(8 + body.len());
+        message.put_u32_ne(object);
+        message.put_u32_ne(((8 + body.len()) as u32) << 16 | opcode as u32);
+        message.put_slice(&body);
+        self.0.write_all(&message).await?;
+        Ok(())
+    }
+
+    async fn event(&mut self) -> Result<Event> {
+        let mut header = [0; 8];
+        self.0.read_exact(&mut header).await?;
+        let object = u32::from_ne_bytes(header[0..4].try_into().unwrap());
+        let size_opcode = u32::from_ne_bytes(header[4..8].try_into().unwrap());
+        let size = (size_opcode >> 16) as usize;
+        if !(8..=MAX_MESSAGE_SIZE).contains(&size) {
+            return Err(ArRpcError::Idle(format!("Invalid message size {}", size)));
+        }
+        let mut args = vec![0; size - 8];
+        self.0.read_exact(&mut args).await?;
+        Ok(Event {
+            object,
+            opcode: size_opcode as u16,
+            args: args.into(),
+        })
+    }
+}
+
+struct Event {
+    object: u32,
+    opcode: u16,
+    args: Bytes,
+}
+
+impl Event {
+    fn truncated() -> ArRpcError {
+        ArRpcError::Idle("Truncated message".to_string())
+    }
+
+    fn uint(&mut self) -> Result<u32> {
+        if self.args.remaining() < 4 {
+            return Err(Self::truncated());
+        }
+        Ok(self.args.get_u32_ne())
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.uint()? as usize;
+        let padded = len.div_ceil(4) * 4;
+        if len == 0 || self.args.remaining() < padded {
+            return Err(Self::truncated());
+        }
+        let text = String::from_utf8_lossy(&self.args[..len - 1]).into_owned();
+        self.args.advance(padded);
+        Ok(text)
+    }
+
+    /// `wl_display.error`, which the compositor closes the connection after
+    fn error(&mut self) -> ArRpcError {
+        let message = (|| {
+            let object = self.uint()?;
+            let code = self.uint()?;
+            Ok::<_, ArRpcError>(format!(
+                "object {}, code {}: {}",
+                object,
+                code,
+                self.string()?
+            ))
+        })();
+        ArRpcError::Idle(format!(
+            "Wayland error on {}",
+            message.unwrap_or_else(|e| e.to_string())
+        ))
+    }
+}
@@ -0,0 +1,31 @@
+use std::{mem, time::Duration};
+
+#[repr(C)]
+#[allow(non_snake_case)]
+struct LASTINPUTINFO {
+    cbSize: u32,
+    dwTime: u32,
+}
+
+#[link(name = "user32")]
+extern "system" {
+    fn GetLastInputInfo(info: *mut LASTINPUTINFO) -> i32;
+}
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn GetTickCount() -> u32;
+}
+
+pub fn idle_time() -> Option<Duration> {
+    let mut info = LASTINPUTINFO {
+        cbSize: mem::size_of::<LASTINPUTINFO>() as u32,
+        dwTime: 0,
+    };
+    if unsafe { GetLastInputInfo(&mut info) } == 0 {
+        return None;
+    }
+    // Both are ticks since boot, which wrap around after 49 days
+    let idle = unsafe { GetTickCount() }.wrapping_sub(info.dwTime);
+    Some(Duration::from_millis(idle as u64))
+}
@@ -0,0 +1,83 @@
+use std::{
+    ffi::{c_char, c_int, c_ulong, c_void, CStr},
+    ptr,
+    time::Duration,
+};
+
+/// `XScreenSaverInfo` of libXss
+#[repr(C)]
+struct ScreenSaverInfo {
+    window: c_ulong,
+    state: c_int,
+    kind: c_int,
+    til_or_since: c_ulong,
+    /// ms since the last input
+    idle: c_ulong,
+    event_mask: c_ulong,
+}
+
+type OpenDisplayFn = unsafe extern "C" fn(*const c_char) -> *mut c_void;
+type DefaultRootWindowFn = unsafe extern "C" fn(*mut c_void) -> c_ulong;
+type AllocInfoFn = unsafe extern "C" fn() -> *mut ScreenSaverInfo;
+type QueryInfoFn = unsafe extern "C" fn(*mut c_void, c_ulong, *mut ScreenSaverInfo) -> c_int;
+
+/// A connection to the X server through libX11 and libXss, loaded at runtime so neither is
+/// needed to build or start
+pub struct Display {
+    display: *mut c_void,
+    root: c_ulong,
+    info: *mut ScreenSaverInfo,
+    query_info: QueryInfoFn,
+}
+
+impl Display {
+    pub fn open() -> Option<Display> {
+        std::env::var_os("DISPLAY")?;
+        let x11 = library(c"libX11.so.6")?;
+        let xss = library(c"libXss.so.1")?;
+        unsafe {
+            let open_display: OpenDisplayFn = std::mem::transmute(symbol(x11, c"XOpenDisplay")?);
+            let default_root_window: DefaultRootWindowFn =
+                std::mem::transmute(symbol(x11, c"XDefaultRootWindow")?);
+            let alloc_info: AllocInfoFn =
+                std::mem::transmute(symbol(xss, c"XScreenSaverAllocInfo")?);
+            let query_info: QueryInfoFn =
+                std::mem::transmute(symbol(xss, c"XScreenSaverQueryInfo")?);
+            let display = open_display(ptr::null());
+            if display.is_null() {
+                return None;
+            }
+            let info = alloc_info();
+            if info.is_null() {
+                return None;
+            }
+            Some(Display {
+                display,
+                root: default_root_window(display),
+                info,
+                query_info,
+            })
+        }
+    }
+
+    pub fn idle_time(&mut self) -> Option<Duration> {
+        let status = unsafe { (self.query_info)(self.display, self.root, self.info) };
+        match status {
+            0 => None,
+            // `c_ulong` is only 32 bits on some targets
+            #[allow(clippy::unnecessary_cast)]
+            _ => Some(Duration::from_millis(unsafe { (*self.info).idle } as u64)),
+        }
+    }
+}
+
+// Both libraries stay loaded for as long as the process runs
+fn library(name: &CStr) -> Option<*mut c_void> {
+    let handle = unsafe { libc::dlopen(name.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+    (!handle.is_null()).then_some(handle)
+}
+
+fn symbol(library: *mut c_void, name: &CStr) -> Option<*mut c_void> {
+    let symbol = unsafe { libc::dlsym(library, name.as_ptr()) };
+    (!symbol.is_null()).then_some(symbol)
+}
@@ -0,0 +1,67 @@
+use super::{
+    structs::{HandshakeMessage, IpcMessage},
+    transport::{RpcConnection, StreamConnection},
+};
+use std::time::Duration;
+use tokio::time::timeout;
+use tracing::debug;
+
+// The id of the fake user we answer READY with, anything else is a real client
+const ARRPC_USER_ID: &str = "1045800378228281345";
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Whether the official client is listening on `discord-ipc-0`
+pub async fn official_client_running() -> bool {
+    let Some(conn) = connect().await else {
+        return false;
+    };
+    matches!(timeout(PROBE_TIMEOUT, probe(conn)).await, Ok(Some(true)))
+}
+
+async fn probe<C: RpcConnection>(mut conn: C) -> Option<bool> {
+    conn.send(&IpcMessage::Handshake(HandshakeMessage {
+        version: 1,
+        client_id: "0".into(),
+    }))
+    .await
+    .ok()?;
+    match conn.recv().await {
+        Ok(IpcMessage::Frame(frame)) => {
+            let user_id = frame
+                .data
+                .as_ref()
+                .and_then(|data| data.pointer("/user/id"))
+                .and_then(|id| id.as_str());
+            Some(user_id != Some(ARRPC_USER_ID))
+        }
+        // Discord rejects the probe's client id, which is still an answer
+        Ok(IpcMessage::Close(_)) => Some(true),
+        Ok(_) => Some(false),
+        Err(e) => {
+            debug!("Probing discord-ipc-0 failed: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(all(unix, feature = "ipc-unix"))]
+/// Opens a connection to the official client on `discord-ipc-0`
+pub async fn connect() -> Option<impl RpcConnection> {
+    let path = format!("{}/discord-ipc-0", super::transport::default_directory());
+    let stream = tokio::net::UnixStream::connect(path).await.ok()?;
+    Some(StreamConnection::new(stream))
+}
+
+#[cfg(all(windows, feature = "ipc-windows"))]
+/// Opens a connection to the official client on `discord-ipc-0`
+pub async fn connect() -> Option<impl RpcConnection> {
+    let pipe = tokio::net::windows::named_pipe::ClientOptions::new()
+        .open(r"\\.\pipe\discord-ipc-0")
+        .ok()?;
+    Some(StreamConnection::new(pipe))
+}
+
+#[cfg(not(any(all(unix, feature = "ipc-unix"), all(windows, feature = "ipc-windows"))))]
+pub async fn connect() -> Option<StreamConnection<tokio::io::DuplexStream>> {
+    None
+}
@@ -1,2 +1,6 @@
+pub mod discord;
 pub mod server;
 pub mod structs;
+pub mod transport;
+#[cfg(feature = "ws-rpc")]
+pub mod ws;
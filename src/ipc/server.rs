@@ -1,160 +1,604 @@
-use super::structs::{CloseCodes, CloseMessage, IpcClientMap, IpcCommand, IpcFrame, IpcMessage};
-use anyhow::Result;
+use super::{
+    discord,
+    structs::{
+        CloseCodes, CloseMessage, IpcClientMap, IpcCommand, IpcFrame, IpcMessage, RpcErrorCode,
+    },
+    transport::{RpcConnection, RpcTransport},
+};
+use crate::{
+    config::IpcConfig,
+    error::{ArRpcError, Result},
+    process, queue, redact,
+    stats::SharedStats,
+    structs::PeerCredentials,
+};
 use owo_colors::OwoColorize;
+use serde_json::json;
 use std::{
-    collections::HashMap,
-    env,
-    io::ErrorKind,
-    sync::atomic::{self, AtomicUsize},
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{self, AtomicUsize},
+        Arc, RwLock,
+    },
+    time::Duration,
 };
 use tokio::{
-    io::AsyncWriteExt,
-    net::{UnixListener, UnixStream},
     select,
-    sync::{broadcast, mpsc, Mutex},
+    sync::{mpsc, watch},
     task,
+    time::{self, timeout, Instant, MissedTickBehavior},
 };
-use tracing::{debug, info, warn};
+use tracing::{debug, field, info, info_span, warn, Instrument, Span};
 
 static SOCKET_ID: AtomicUsize = AtomicUsize::new(0);
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long to wait after a failed accept, like running out of file descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+type PeerMap = Arc<RwLock<HashMap<usize, PeerCredentials>>>;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Transports {
+    /// Unix socket or Windows named pipe, depending on the platform
+    pub ipc: bool,
+    /// WebSocket RPC on ports 6463-6472
+    pub ws_rpc: bool,
+}
+
+impl Default for Transports {
+    fn default() -> Self {
+        Self {
+            ipc: true,
+            ws_rpc: cfg!(feature = "ws-rpc"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionOptions {
+    /// Forward connections to the official client instead of answering them
+    pub proxy: bool,
+    pub reject_other_users: bool,
+    /// Counted per listener
+    pub max_connections: usize,
+    pub ping_interval: Option<Duration>,
+    /// `None` never closes a connection for not answering pings
+    pub ping_timeout: Option<Duration>,
+}
+
+impl ConnectionOptions {
+    fn from_config(config: &IpcConfig) -> Self {
+        Self {
+            proxy: false,
+            reject_other_users: config.reject_other_users,
+            max_connections: config.max_connections,
+            ping_interval: (config.ping_interval_secs > 0)
+                .then(|| Duration::from_secs(config.ping_interval_secs)),
+            ping_timeout: (config.ping_timeout_secs > 0)
+                .then(|| Duration::from_secs(config.ping_timeout_secs)),
+        }
+    }
+}
+
+/// Frames of one connection still waiting for an answer, oldest first
+#[derive(Default)]
+struct PendingReplies(VecDeque<IpcFrame>);
+
+impl PendingReplies {
+    /// Returns `false` if a frame with the same nonce is still waiting
+    fn insert(&mut self, frame: &IpcFrame) -> bool {
+        if frame.nonce.is_empty() {
+            return true;
+        }
+        if self.0.iter().any(|pending| pending.nonce == frame.nonce) {
+            return false;
+        }
+        self.0.push_back(frame.reply(None));
+        true
+    }
+
+    /// Takes the frame `reply` answers, returning answers for the older ones it overtook
+    fn answer(&mut self, reply: &IpcFrame) -> Vec<IpcFrame> {
+        let Some(index) = self
+            .0
+            .iter()
+            .position(|pending| pending.nonce == reply.nonce)
+        else {
+            return Vec::new();
+        };
+        // The server handles frames in order, so older ones were dropped by a full queue or
+        // replaced by this one if it was an activity update
+        let overtaken: Vec<_> = self.0.drain(..=index).collect();
+        overtaken[..index]
+            .iter()
+            .map(
+                |pending| match pending.cmd == reply.cmd && reply.evt.is_none() {
+                    true => IpcFrame {
+                        nonce: pending.nonce.clone(),
+                        ..reply.clone()
+                    },
+                    false => {
+                        pending.error(RpcErrorCode::UnknownError, "Dropped, the server is busy")
+                    }
+                },
+            )
+            .collect()
+    }
+}
 
 pub struct IpcServer {
-    pub path: String,
-    _ipc_client_map: IpcClientMap,
-    rx_msg: mpsc::Receiver<(usize, IpcMessage)>,
+    ipc_client_map: IpcClientMap,
+    shutdown: watch::Sender<bool>,
+    peers: PeerMap,
+    addresses: Vec<String>,
+    rx_msg: queue::Receiver<(usize, IpcMessage)>,
 }
 
 impl IpcServer {
-    pub async fn try_bind() -> Result<IpcServer> {
-        let bind_directory = env::var("XDG_RUNTIME_DIR")
-            .or_else(|_| env::var("TMPDIR"))
-            .or_else(|_| env::var("TMP"))
-            .or_else(|_| env::var("TEMP"))
-            .unwrap_or("/tmp".to_string());
-
-        for i in 0u8..10 {
-            let path = format!("{}/discord-ipc-{}", bind_directory, i);
-            let listener = UnixListener::bind(path.clone());
-            match listener {
-                Ok(listener) => {
-                    info!(
-                        "{} {}",
-                        "Bound to IPC server at".green(),
-                        path.yellow().bold(),
-                    );
-                    let ipc_client_map = IpcClientMap::new(Mutex::new(HashMap::new()));
-                    let (tx_msg, rx_msg) = mpsc::channel(1);
-                    task::spawn(Self::accept_loop(listener, tx_msg, ipc_client_map.clone()));
-                    return Ok(IpcServer {
-                        path,
-                        rx_msg,
-                        _ipc_client_map: ipc_client_map,
-                    });
-                }
-                Err(e) => match e.kind() {
-                    ErrorKind::AddrInUse => {
-                        info!(
-                            "{} {}, {}",
-                            "Socket is not available at".yellow().bold(),
-                            path.red().bold(),
-                            "Trying next path...".cyan().bold(),
-                        );
-                        continue;
+    #[cfg(any(
+        all(unix, feature = "ipc-unix"),
+        all(windows, feature = "ipc-windows"),
+        feature = "ws-rpc"
+    ))]
+    pub async fn try_bind(
+        transports: Transports,
+        config: &IpcConfig,
+        stats: SharedStats,
+    ) -> Result<IpcServer> {
+        let ipc_client_map = IpcClientMap::new(RwLock::new(HashMap::new()));
+        let peers = PeerMap::default();
+        let (tx_msg, rx_msg) = queue::channel(config.message_queue);
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let mut addresses = Vec::new();
+
+        let mut options = ConnectionOptions::from_config(config);
+        if transports.ipc && discord::official_client_running().await {
+            use crate::config::DiscordRunning;
+            match config.on_discord_running {
+                DiscordRunning::Warn => warn!(
+                    "{}",
+                    "Discord is already running, games will connect to it instead of arRPC"
+                        .red()
+                        .bold()
+                ),
+                DiscordRunning::Abort => return Err(ArRpcError::DiscordRunning),
+                DiscordRunning::Proxy => {
+                    info!("{}", "Discord is already running, proxying to it".cyan());
+                    options.proxy = true;
+                }
+            }
+        }
+
+        #[cfg(all(unix, feature = "ipc-unix"))]
+        if transports.ipc {
+            use super::transport::{default_directory, sandbox_directories, UnixTransport};
+
+            let set_permissions = |transport: &UnixTransport| {
+                transport.set_permissions(
+                    config.socket_mode,
+                    config.socket_owner.as_deref(),
+                    config.socket_group.as_deref(),
+                )
+            };
+
+            // Whoever binds discord-ipc-0 in /tmp first gets the games of every user
+            let directory = default_directory();
+            if !config.allow_shared_directory && crate::paths::is_shared(directory.as_ref()) {
+                return Err(ArRpcError::SharedDirectory(directory.into()));
+            }
+            for transport in UnixTransport::try_bind_in(&directory, config.sockets)? {
+                set_permissions(&transport)?;
+                addresses.push(Self::spawn(
+                    transport,
+                    options,
+                    &tx_msg,
+                    &ipc_client_map,
+                    &peers,
+                    &stats,
+                    &shutdown_rx,
+                ));
+            }
+
+            let extra_directories = config
+                .extra_directories
+                .clone()
+                .unwrap_or_else(sandbox_directories);
+            for directory in extra_directories {
+                if let Err(e) = std::fs::create_dir_all(&directory) {
+                    warn!("Failed to create {}: {}", directory.display(), e);
+                    continue;
+                }
+                match UnixTransport::try_bind_in(&directory.to_string_lossy(), config.sockets) {
+                    Ok(transports) => {
+                        for transport in transports {
+                            if let Err(e) = set_permissions(&transport) {
+                                warn!("Failed to set permissions of {}: {}", transport.path(), e);
+                                continue;
+                            }
+                            addresses.push(Self::spawn(
+                                transport,
+                                options,
+                                &tx_msg,
+                                &ipc_client_map,
+                                &peers,
+                                &stats,
+                                &shutdown_rx,
+                            ));
+                        }
                     }
-                    _ => {
-                        info!("Error: {:?}", e);
-                        return Err(e.into());
+                    Err(e) => warn!("Failed to bind in {}: {}", directory.display(), e),
+                }
+            }
+
+            #[cfg(target_os = "linux")]
+            if config.abstract_sockets {
+                if !config.reject_other_users {
+                    warn!("Abstract sockets have no permissions, every user can connect to them");
+                }
+                match UnixTransport::try_bind_abstract(config.sockets) {
+                    Ok(transports) => {
+                        for transport in transports {
+                            addresses.push(Self::spawn(
+                                transport,
+                                options,
+                                &tx_msg,
+                                &ipc_client_map,
+                                &peers,
+                                &stats,
+                                &shutdown_rx,
+                            ));
+                        }
                     }
-                },
+                    Err(e) => warn!("Failed to bind abstract sockets: {}", e),
+                }
+            }
+            #[cfg(not(target_os = "linux"))]
+            if config.abstract_sockets {
+                warn!("Abstract sockets are only supported on Linux");
             }
         }
-        Err(anyhow::anyhow!(
-            "Failed to bind to IPC server (ran out of paths)"
-        ))
+
+        #[cfg(all(windows, feature = "ipc-windows"))]
+        if transports.ipc {
+            for transport in super::transport::NamedPipeTransport::try_bind(config.sockets)? {
+                addresses.push(Self::spawn(
+                    transport,
+                    options,
+                    &tx_msg,
+                    &ipc_client_map,
+                    &peers,
+                    &stats,
+                    &shutdown_rx,
+                ));
+            }
+        }
+
+        #[cfg(feature = "ws-rpc")]
+        if transports.ws_rpc {
+            let origins = match config.permissive {
+                true => {
+                    warn!("Accepting WebSocket RPC connections from any origin");
+                    super::ws::OriginPolicy::Any
+                }
+                false => super::ws::OriginPolicy::Allowlist(config.allowed_origins.clone().into()),
+            };
+            let transport = super::ws::WsTransport::try_bind(origins).await?;
+            addresses.push(Self::spawn(
+                transport,
+                options,
+                &tx_msg,
+                &ipc_client_map,
+                &peers,
+                &stats,
+                &shutdown_rx,
+            ));
+        }
+
+        if addresses.is_empty() {
+            return Err(ArRpcError::NoTransports);
+        }
+        Ok(IpcServer {
+            rx_msg,
+            ipc_client_map,
+            shutdown,
+            peers,
+            addresses,
+        })
+    }
+
+    /// Without a transport built in there is nothing to bind
+    #[cfg(not(any(
+        all(unix, feature = "ipc-unix"),
+        all(windows, feature = "ipc-windows"),
+        feature = "ws-rpc"
+    )))]
+    pub async fn try_bind(
+        _transports: Transports,
+        _config: &IpcConfig,
+        _stats: SharedStats,
+    ) -> Result<IpcServer> {
+        Err(ArRpcError::NoTransports)
+    }
+
+    /// Serves nothing but `transport`, like a [`DuplexTransport`](super::transport::DuplexTransport)
+    /// in tests. Never proxies to the official client.
+    pub fn from_transport<T: RpcTransport>(
+        transport: T,
+        config: &IpcConfig,
+        stats: SharedStats,
+    ) -> IpcServer {
+        let ipc_client_map = IpcClientMap::new(RwLock::new(HashMap::new()));
+        let peers = PeerMap::default();
+        let (tx_msg, rx_msg) = queue::channel(config.message_queue);
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let address = Self::spawn(
+            transport,
+            ConnectionOptions::from_config(config),
+            &tx_msg,
+            &ipc_client_map,
+            &peers,
+            &stats,
+            &shutdown_rx,
+        );
+        IpcServer {
+            rx_msg,
+            ipc_client_map,
+            shutdown,
+            peers,
+            addresses: vec![address],
+        }
+    }
+
+    fn spawn<T: RpcTransport>(
+        transport: T,
+        options: ConnectionOptions,
+        tx_msg: &queue::Sender<(usize, IpcMessage)>,
+        ipc_client_map: &IpcClientMap,
+        peers: &PeerMap,
+        stats: &SharedStats,
+        shutdown: &watch::Receiver<bool>,
+    ) -> String {
+        let address = transport.address().to_string();
+        info!(
+            "{} {}",
+            "Bound to IPC server at".green(),
+            address.yellow().bold(),
+        );
+        task::spawn(Self::accept_loop(
+            transport,
+            options,
+            tx_msg.clone(),
+            ipc_client_map.clone(),
+            peers.clone(),
+            stats.clone(),
+            shutdown.clone(),
+        ));
+        address
     }
 
-    pub async fn accept_loop(
-        listener: UnixListener,
-        tx_msg: mpsc::Sender<(usize, IpcMessage)>,
+    pub async fn accept_loop<T: RpcTransport>(
+        mut transport: T,
+        options: ConnectionOptions,
+        tx_msg: queue::Sender<(usize, IpcMessage)>,
         ipc_client_map: IpcClientMap,
+        peers: PeerMap,
+        stats: SharedStats,
+        mut shutdown: watch::Receiver<bool>,
     ) -> Result<()> {
+        let address = transport.address().to_string();
+        let open = Arc::new(AtomicUsize::new(0));
         loop {
-            let (stream, _) = listener.accept().await?;
-            let (tx_cmd, rx_cmd) = broadcast::channel(1);
-
-            ipc_client_map
-                .lock()
-                .await
-                .insert(SOCKET_ID.load(atomic::Ordering::SeqCst), tx_cmd);
-
-            task::spawn(Self::handle_stream(
-                stream,
-                SOCKET_ID.load(atomic::Ordering::SeqCst),
-                rx_cmd,
-                tx_msg.clone(),
-            ));
+            let conn = select! {
+                conn = transport.accept() => conn,
+                _ = shutdown.changed() => break,
+            };
+            let conn = match conn {
+                Ok(conn) => conn,
+                // Only the listener going away is fatal, the next connection may well work
+                Err(ArRpcError::Io(e)) => {
+                    warn!("Failed to accept an IPC connection on {}: {}", address, e);
+                    time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let socket_id = SOCKET_ID.fetch_add(1, atomic::Ordering::SeqCst);
+            if let Some(peer) = conn.peer() {
+                debug!(
+                    "IPC connection {} from pid {:?} (uid {})",
+                    socket_id, peer.pid, peer.uid
+                );
+                if options.reject_other_users && is_other_user(&peer) {
+                    warn!("Rejecting IPC connection from uid {}", peer.uid);
+                    continue;
+                }
+            }
+            if open.load(atomic::Ordering::SeqCst) >= options.max_connections {
+                warn!(
+                    "Refusing IPC connection {}, {} already has {} open",
+                    socket_id, address, options.max_connections
+                );
+                task::spawn(Self::refuse(conn));
+                continue;
+            }
+            open.fetch_add(1, atomic::Ordering::SeqCst);
+            stats.connection_opened();
+            if let Some(peer) = conn.peer() {
+                peers.write().unwrap().insert(socket_id, peer);
+            }
+            let open = open.clone();
+            let tx_msg = tx_msg.clone();
+            let ipc_client_map = ipc_client_map.clone();
+            let peers = peers.clone();
+            let stats = stats.clone();
+            let mut shutdown = shutdown.clone();
+            // Everything logged for this connection carries these, the client id once it is known
+            let span = info_span!(
+                "ipc",
+                socket_id,
+                pid = field::Empty,
+                client_id = field::Empty
+            );
+            if let Some(pid) = conn.peer().and_then(|peer| peer.pid) {
+                span.record("pid", pid);
+            }
+            let connection = async move {
+                let upstream = match options.proxy {
+                    true => discord::connect().await,
+                    false => None,
+                };
+                if options.proxy && upstream.is_none() {
+                    warn!(
+                        "Failed to reach Discord, serving IPC connection {} locally",
+                        socket_id
+                    );
+                }
+                let result = match upstream {
+                    // Nothing can tell a proxied connection to close, so just drop it on shutdown
+                    Some(upstream) => select! {
+                        result = Self::proxy_stream(conn, upstream, socket_id, tx_msg.clone(), &stats) => result,
+                        _ = shutdown.changed() => Ok(()),
+                    },
+                    // Proxied connections are answered by Discord, so only these get a command channel
+                    None => {
+                        let (tx_cmd, rx_cmd) = mpsc::unbounded_channel();
+                        ipc_client_map.write().unwrap().insert(socket_id, tx_cmd);
+                        Self::handle_stream(
+                            conn,
+                            socket_id,
+                            options,
+                            rx_cmd,
+                            tx_msg.clone(),
+                            &stats,
+                        )
+                        .await
+                    }
+                };
+                if let Err(e) = result {
+                    debug!("IPC connection {} closed: {}", socket_id, e);
+                }
+                ipc_client_map.write().unwrap().remove(&socket_id);
+                peers.write().unwrap().remove(&socket_id);
+                open.fetch_sub(1, atomic::Ordering::SeqCst);
+                stats.connection_closed();
+                let _ = tx_msg
+                    .send((
+                        socket_id,
+                        IpcMessage::Close(CloseMessage {
+                            code: CloseCodes::Normal,
+                            message: "".into(),
+                        }),
+                    ))
+                    .await;
+            };
+            task::spawn(connection.instrument(span));
+        }
+        transport.close().await
+    }
 
-            SOCKET_ID.fetch_add(1, atomic::Ordering::SeqCst);
+    /// Tells the client why before dropping it, as it would probably just retry otherwise
+    async fn refuse<C: RpcConnection>(mut conn: C) {
+        let refused = async {
+            conn.ready().await?;
+            // Clients only look for a reply once their handshake is out
+            conn.recv().await
+        };
+        match timeout(CLOSE_TIMEOUT, refused).await {
+            Ok(Ok(_)) => close(&mut conn, CloseCodes::RateLimited, "Too many connections").await,
+            Ok(Err(e)) => debug!("Failed to refuse IPC connection: {}", e),
+            Err(_) => debug!("Timed out refusing IPC connection"),
         }
     }
 
-    async fn handle_stream(
-        mut stream: UnixStream,
+    pub async fn handle_stream<C: RpcConnection>(
+        mut conn: C,
         socket_id: usize,
-        mut rx: broadcast::Receiver<IpcCommand>,
-        tx: mpsc::Sender<(usize, IpcMessage)>,
+        options: ConnectionOptions,
+        mut rx: mpsc::UnboundedReceiver<IpcCommand>,
+        tx: queue::Sender<(usize, IpcMessage)>,
+        stats: &SharedStats,
     ) -> Result<()> {
+        match conn.ready().await {
+            Err(ArRpcError::InvalidOrigin(origin)) => {
+                debug!("Invalid origin: {}", origin);
+                close(&mut conn, CloseCodes::InvalidOrigin, "").await;
+                return Err(ArRpcError::InvalidOrigin(origin));
+            }
+            Err(ArRpcError::InvalidEncoding(encoding)) => {
+                debug!("Invalid encoding: {}", encoding);
+                close(&mut conn, CloseCodes::InvalidEncoding, "").await;
+                return Err(ArRpcError::InvalidEncoding(encoding));
+            }
+            result => result?,
+        }
         let mut handshake_done = false;
+        let mut pings = options.ping_interval.map(|period| {
+            let mut pings = time::interval_at(Instant::now() + period, period);
+            // A burst of ticks after a suspend would count as missed pings right away
+            pings.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            pings
+        });
+        let mut pong_deadline = None;
+        let mut pending = PendingReplies::default();
         loop {
+            // Everything the previous iteration wrote goes out in one go
+            conn.flush().await?;
+            // Counts what the previous iteration sent and received, so the final close is missed
+            stats.traffic(conn.take_traffic());
+            let next_ping = async {
+                match &mut pings {
+                    Some(pings) => pings.tick().await,
+                    None => std::future::pending().await,
+                }
+            };
+            let pong_timeout = async move {
+                match pong_deadline {
+                    Some(deadline) => time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
             select! {
-                event = IpcMessage::try_decode(&mut stream) => {
-                    if let Ok(event) = event {
-                        match event {
+                event = conn.recv() => {
+                    // Anything coming back proves the connection alive, not just pongs
+                    pong_deadline = None;
+                    match event {
+                        Err(ArRpcError::InvalidFrame(frame, message)) => {
+                            debug!("Invalid IPC frame: {}", redact::text(&message));
+                            stats.decode_error();
+                            let error = frame.error(RpcErrorCode::InvalidPayload, message);
+                            conn.feed(&IpcMessage::Frame(Box::new(error))).await?;
+                        }
+                        Err(e @ (ArRpcError::Json(_) | ArRpcError::InvalidMessageType(_))) => {
+                            debug!("Failed to decode IPC message: {}", redact::text(&e.to_string()));
+                            stats.decode_error();
+                        }
+                        Err(e) => return Err(e),
+                        Ok(event) => match event {
                             IpcMessage::Handshake(handshake_msg) => {
                                 if handshake_done {
-                                    return Err(anyhow::anyhow!("Handshake sent twice"));
+                                    return Err(ArRpcError::DuplicateHandshake);
                                 }
 
                                 if handshake_msg.version != 1 {
                                     debug!("Invalid Handshake version: {}", handshake_msg.version);
-                                    stream
-                                        .write_all(
-                                            IpcMessage::Close(CloseMessage {
-                                                code: CloseCodes::InvalidVersion,
-                                                message: "".into(),
-                                            })
-                                            .try_encode()?
-                                            .as_ref(),
-                                        )
-                                        .await?;
-                                    return Err(anyhow::anyhow!("Invalid Handshake version"));
+                                    close(&mut conn, CloseCodes::InvalidVersion, "").await;
+                                    return Err(ArRpcError::InvalidVersion(handshake_msg.version));
                                 }
 
                                 if handshake_msg.client_id.is_empty() {
                                     debug!("Invalid Client ID: {}", handshake_msg.client_id);
-                                    stream
-                                        .write_all(
-                                            IpcMessage::Close(CloseMessage {
-                                                code: CloseCodes::InvalidClientID,
-                                                message: "".into(),
-                                            })
-                                            .try_encode()?
-                                            .as_ref(),
-                                        )
-                                        .await?;
-                                    return Err(anyhow::anyhow!("Invalid Client ID"));
+                                    close(&mut conn, CloseCodes::InvalidClientID, "").await;
+                                    return Err(ArRpcError::InvalidClientId);
                                 }
                                 handshake_done = true;
+                                Span::current().record("client_id", field::display(redact::id(&handshake_msg.client_id)));
                                 tx.send((socket_id, IpcMessage::Handshake(handshake_msg)))
                                     .await?;
                             }
 
                             IpcMessage::Ping(data) => {
-                                stream
-                                    .write_all(
-                                        IpcMessage::Pong(data.clone()).try_encode()?.as_ref(),
-                                    )
-                                    .await?;
+                                conn.feed(&IpcMessage::Pong(data.clone())).await?;
                                 tx.send((socket_id, IpcMessage::Ping(data))).await?;
                             }
 
@@ -164,35 +608,141 @@ impl IpcServer {
 
                             IpcMessage::Frame(data) => {
                                 if !handshake_done {
-                                    return Err(anyhow::anyhow!(
-                                        "Frame Sent before Handshake wasn't done"
-                                    ));
+                                    return Err(ArRpcError::FrameBeforeHandshake);
+                                }
+                                // The server task answers, once it actually handled the frame
+                                if !pending.insert(&data) {
+                                    let error = data.error(RpcErrorCode::InvalidPayload, "Duplicate nonce");
+                                    conn.feed(&IpcMessage::Frame(Box::new(error))).await?;
+                                    continue;
                                 }
-                                stream.write_all(IpcMessage::Frame(IpcFrame { args:None, data: None, cmd: "SET_ACTIVITY".to_string(), nonce: data.nonce.clone(), evt:None }).try_encode()?.as_ref()).await?;
                                 tx.send((socket_id, IpcMessage::Frame(data))).await?;
                             }
 
-                            IpcMessage::Close(msg) => {
-                                tx.send((socket_id, IpcMessage::Close(msg))).await?;
+                            IpcMessage::Close(_) => {
                                 break Ok(());
                             }
                         }
                     }
                 }
                 cmd = rx.recv() => {
-                    if let Ok(cmd) = cmd {
-                        stream.write_all(cmd.try_encode()?.as_ref()).await?;
-                        if matches!(cmd, IpcCommand::Close) {
+                    let Some(mut cmd) = cmd else {
+                        break Ok(());
+                    };
+                    loop {
+                        if let IpcCommand::Close = cmd {
+                            close(&mut conn, CloseCodes::Normal, "").await;
+                            return Ok(());
+                        }
+                        if let IpcCommand::Frame(reply) = &cmd {
+                            for dropped in pending.answer(reply) {
+                                conn.feed(&IpcMessage::Frame(Box::new(dropped))).await?;
+                            }
+                        }
+                        conn.feed(&cmd.to_message()).await?;
+                        // Whatever else is queued already goes out with the same flush
+                        match rx.try_recv() {
+                            Ok(next) => cmd = next,
+                            Err(_) => break,
+                        }
+                    }
+                }
+                _ = next_ping => {
+                    if let Some(pid) = conn.peer().and_then(|peer| peer.pid) {
+                        if !process::is_alive(pid) {
+                            return Err(ArRpcError::ClientGone);
+                        }
+                    }
+                    // Still waiting on the last one
+                    if pong_deadline.is_none() {
+                        conn.feed(&IpcMessage::Ping(json!({}))).await?;
+                        pong_deadline = options.ping_timeout.map(|timeout| Instant::now() + timeout);
+                    }
+                }
+                _ = pong_timeout => {
+                    pong_deadline = None;
+                    // SDKs that stop reading after the handshake never answer, but their game
+                    // is still running
+                    let peer = conn.peer().and_then(|peer| peer.pid);
+                    if peer.is_some_and(process::is_alive) {
+                        continue;
+                    }
+                    let timeout = options.ping_timeout.unwrap_or_default();
+                    close(&mut conn, CloseCodes::Abnormal, "Ping timed out").await;
+                    return Err(ArRpcError::PingTimeout(timeout));
+                }
+            }
+        }
+    }
+
+    /// Relays a connection to the official client, mirroring what the game sends to `tx`
+    pub async fn proxy_stream<C: RpcConnection, U: RpcConnection>(
+        mut conn: C,
+        mut upstream: U,
+        socket_id: usize,
+        tx: queue::Sender<(usize, IpcMessage)>,
+        stats: &SharedStats,
+    ) -> Result<()> {
+        conn.ready().await?;
+        loop {
+            stats.traffic(conn.take_traffic());
+            select! {
+                event = conn.recv() => {
+                    match event {
+                        Err(ArRpcError::InvalidFrame(frame, message)) => {
+                            debug!("Invalid IPC frame: {}", redact::text(&message));
+                            stats.decode_error();
+                            let error = frame.error(RpcErrorCode::InvalidPayload, message);
+                            conn.send(&IpcMessage::Frame(Box::new(error))).await?;
+                        }
+                        Err(e @ (ArRpcError::Json(_) | ArRpcError::InvalidMessageType(_))) => {
+                            debug!("Failed to decode IPC message: {}", redact::text(&e.to_string()));
+                            stats.decode_error();
+                        }
+                        Err(e) => return Err(e),
+                        Ok(IpcMessage::Close(close)) => {
+                            upstream.send(&IpcMessage::Close(close)).await?;
                             break Ok(());
                         }
+                        Ok(event) => {
+                            if let IpcMessage::Handshake(handshake) = &event {
+                                Span::current().record("client_id", field::display(redact::id(&handshake.client_id)));
+                            }
+                            upstream.send(&event).await?;
+                            if matches!(event, IpcMessage::Handshake(_) | IpcMessage::Frame(_)) {
+                                tx.send((socket_id, event)).await?;
+                            }
+                        }
+                    }
+                }
+                event = upstream.recv() => {
+                    let event = event?;
+                    conn.send(&event).await?;
+                    if matches!(event, IpcMessage::Close(_)) {
+                        break Ok(());
                     }
                 }
             }
         }
     }
 
+    /// Stops accepting, closes every connection and removes the socket files
+    pub async fn shutdown(mut self) {
+        let _ = self.shutdown.send(true);
+        let clients: Vec<_> = self.ipc_client_map.write().unwrap().drain().collect();
+        for (_, tx) in clients {
+            let _ = tx.send(IpcCommand::Close);
+        }
+        // Every accept loop and connection holds a sender, so this ends once all of them are done
+        let drained = async { while self.rx_msg.recv().await.is_some() {} };
+        if timeout(SHUTDOWN_TIMEOUT, drained).await.is_err() {
+            debug!("Timed out waiting for IPC connections to close");
+        }
+    }
+
     pub async fn send(&self, socket_id: usize, command: IpcCommand) -> Result<()> {
-        if let Some(sender) = self._ipc_client_map.lock().await.get(&socket_id) {
+        let sender = self.ipc_client_map.read().unwrap().get(&socket_id).cloned();
+        if let Some(sender) = sender {
             sender.send(command)?;
         } else {
             debug!("Failed to send IPC Command ({})", socket_id);
@@ -200,16 +750,43 @@ impl IpcServer {
         Ok(())
     }
 
+    pub fn addresses(&self) -> &[String] {
+        &self.addresses
+    }
+
+    pub async fn peer(&self, socket_id: usize) -> Option<PeerCredentials> {
+        self.peers.read().unwrap().get(&socket_id).copied()
+    }
+
     pub async fn recv(&mut self) -> Option<(usize, IpcMessage)> {
         self.rx_msg.recv().await
     }
 }
 
-impl Drop for IpcServer {
-    fn drop(&mut self) {
-        if let Err(e) = std::fs::remove_file(&self.path) {
-            warn!("Failed to remove IPC socket file at {}", &self.path);
-            warn!("Error: {:?}", e);
-        }
+/// Sends a close and lingers until the client hangs up, so it reliably sees the code. Gives up
+/// after a moment, as the client may not be reading at all.
+async fn close<C: RpcConnection>(conn: &mut C, code: CloseCodes, message: &str) {
+    let close = IpcMessage::Close(CloseMessage {
+        code,
+        message: message.into(),
+    });
+    let closed = async {
+        conn.send(&close).await?;
+        conn.linger().await
+    };
+    match timeout(CLOSE_TIMEOUT, closed).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => debug!("Failed to close IPC connection: {}", e),
+        Err(_) => debug!("IPC client did not hang up in time"),
     }
 }
+
+#[cfg(unix)]
+fn is_other_user(peer: &PeerCredentials) -> bool {
+    peer.uid != unsafe { libc::getuid() }
+}
+
+#[cfg(not(unix))]
+fn is_other_user(_peer: &PeerCredentials) -> bool {
+    false
+}
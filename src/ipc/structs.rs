@@ -1,34 +1,45 @@
-use anyhow::Result;
-use bytes::{Buf, BufMut, BytesMut};
+use crate::{
+    error::{ArRpcError, Result},
+    redact,
+    structs::IpcPartialActivity,
+};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use serde::{Deserialize, Serialize};
-use serde_json::{from_slice, to_vec, Value};
-use std::{collections::HashMap, sync::Arc};
+use serde_json::{from_slice, json, to_vec, to_writer, Value};
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
 use tokio::{
-    io::AsyncReadExt,
-    net::UnixStream,
-    sync::{broadcast, Mutex},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
 };
 use tracing::debug;
 
-use crate::structs::IpcPartialActivity;
+/// Discord's own limit, anything longer is treated as garbage instead of buffered
+pub const MAX_FRAME_LEN: usize = 64 * 1024;
 
-pub type IpcClientMap = Arc<Mutex<HashMap<usize, broadcast::Sender<IpcCommand>>>>;
+/// Only locked for plain map operations, never across an await
+pub type IpcClientMap = Arc<RwLock<HashMap<usize, mpsc::UnboundedSender<IpcCommand>>>>;
 
 #[derive(Debug, Clone)]
 pub enum IpcCommand {
-    Frame(IpcFrame),
+    Frame(Box<IpcFrame>),
     Close,
 }
 
 impl IpcCommand {
     pub fn try_encode(&self) -> Result<BytesMut> {
+        self.to_message().try_encode()
+    }
+
+    pub fn to_message(&self) -> IpcMessage {
         match self {
-            IpcCommand::Frame(data) => IpcMessage::Frame(data.clone()).try_encode(),
-            IpcCommand::Close => Ok(IpcMessage::Close(CloseMessage {
+            IpcCommand::Frame(data) => IpcMessage::Frame(data.clone()),
+            IpcCommand::Close => IpcMessage::Close(CloseMessage {
                 code: CloseCodes::Normal,
                 message: "".into(),
-            })
-            .try_encode()?),
+            }),
         }
     }
 }
@@ -36,7 +47,7 @@ impl IpcCommand {
 #[derive(Debug)]
 pub enum IpcMessage {
     Handshake(HandshakeMessage),
-    Frame(IpcFrame),
+    Frame(Box<IpcFrame>),
     Close(CloseMessage),
     Ping(Value),
     Pong(Value),
@@ -45,61 +56,117 @@ pub enum IpcMessage {
 impl IpcMessage {
     pub fn try_encode(&self) -> Result<BytesMut> {
         let mut buffer = BytesMut::new();
+        self.encode_into(&mut buffer)?;
+        Ok(buffer)
+    }
 
-        // Honestly, I don't have a better way to do this
+    /// Appends the encoded message to `buffer`, serializing straight into it
+    pub fn encode_into(&self, buffer: &mut BytesMut) -> Result<()> {
+        let start = buffer.len();
+        buffer.put_i32_le(self.opcode());
+        // The length is only known once the payload is written
+        buffer.put_i32_le(0);
+        let mut writer = (&mut *buffer).writer();
+        let written = match self {
+            IpcMessage::Handshake(data) => to_writer(&mut writer, data),
+            IpcMessage::Frame(data) => to_writer(&mut writer, data),
+            IpcMessage::Close(data) => to_writer(&mut writer, data),
+            IpcMessage::Ping(data) => to_writer(&mut writer, data),
+            IpcMessage::Pong(data) => to_writer(&mut writer, data),
+        };
+        if let Err(e) = written {
+            buffer.truncate(start);
+            return Err(e.into());
+        }
+        let data_len = (buffer.len() - start - 8) as i32;
+        buffer[start + 4..start + 8].copy_from_slice(&data_len.to_le_bytes());
+        Ok(())
+    }
+
+    /// The header and the payload apart, so they can be written without joining them
+    pub fn encode_parts(&self) -> Result<([u8; 8], Bytes)> {
+        let payload = match self {
+            IpcMessage::Handshake(data) => to_vec(data),
+            IpcMessage::Frame(data) => to_vec(data),
+            IpcMessage::Close(data) => to_vec(data),
+            IpcMessage::Ping(data) => to_vec(data),
+            IpcMessage::Pong(data) => to_vec(data),
+        }?;
+        let mut header = [0; 8];
+        header[..4].copy_from_slice(&self.opcode().to_le_bytes());
+        header[4..].copy_from_slice(&(payload.len() as i32).to_le_bytes());
+        Ok((header, payload.into()))
+    }
+
+    fn opcode(&self) -> i32 {
         match self {
-            IpcMessage::Handshake(data) => {
-                buffer.put_i32_le(0);
-                let data = to_vec(data)?;
-                buffer.put_i32_le(data.len() as i32);
-                buffer.put_slice(data.as_slice());
-            }
-            IpcMessage::Frame(data) => {
-                buffer.put_i32_le(1);
-                let data = to_vec(data)?;
-                buffer.put_i32_le(data.len() as i32);
-                buffer.put_slice(data.as_slice());
-            }
-            IpcMessage::Close(data) => {
-                buffer.put_i32_le(2);
-                let data = to_vec(data)?;
-                buffer.put_i32_le(data.len() as i32);
-                buffer.put_slice(data.as_slice());
-            }
-            IpcMessage::Ping(data) => {
-                buffer.put_i32_le(3);
-                let data = to_vec(data)?;
-                buffer.put_i32_le(data.len() as i32);
-                buffer.put_slice(data.as_slice());
-            }
-            IpcMessage::Pong(data) => {
-                buffer.put_i32_le(4);
-                let data = to_vec(data)?;
-                buffer.put_i32_le(data.len() as i32);
-                buffer.put_slice(data.as_slice());
-            }
+            IpcMessage::Handshake(_) => 0,
+            IpcMessage::Frame(_) => 1,
+            IpcMessage::Close(_) => 2,
+            IpcMessage::Ping(_) => 3,
+            IpcMessage::Pong(_) => 4,
         }
-        Ok(buffer)
     }
 
-    pub async fn try_decode(stream: &mut UnixStream) -> Result<IpcMessage> {
-        let mut info_buffer = BytesMut::with_capacity(8);
-        stream.read_buf(&mut info_buffer).await?;
+    /// Writes the encoded message to any byte stream, like a socket, named pipe or test duplex
+    pub async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.try_encode()?).await?;
+        Ok(())
+    }
+
+    /// Reads exactly one message from any byte stream. Not cancel safe, use
+    /// [`try_decode_buf`](Self::try_decode_buf) when racing reads against something else.
+    pub async fn try_decode<R: AsyncRead + Unpin>(stream: &mut R) -> Result<IpcMessage> {
+        let mut info_buffer = BytesMut::zeroed(8);
+        stream.read_exact(&mut info_buffer).await?;
         let msg_type = info_buffer.get_i32_le();
-        let data_len = info_buffer.get_i32_le();
-        let mut data_buffer = BytesMut::with_capacity(data_len as usize);
-        stream.read_buf(&mut data_buffer).await?;
+        let data_len = payload_len(&info_buffer)?;
+        let mut data_buffer = BytesMut::zeroed(data_len);
+        stream.read_exact(&mut data_buffer).await?;
+        Self::decode_payload(msg_type, &data_buffer)
+    }
+
+    /// Decodes the message at the start of `data` and returns how many bytes it took up, or
+    /// `None` if more data is needed. Nothing but bytes in, for fuzzers and other codecs.
+    pub fn decode_slice(data: &[u8]) -> Result<Option<(IpcMessage, usize)>> {
+        if data.len() < 8 {
+            return Ok(None);
+        }
+        let msg_type = (&data[0..4]).get_i32_le();
+        let data_len = payload_len(&data[4..8])?;
+        let Some(payload) = data.get(8..8 + data_len) else {
+            return Ok(None);
+        };
+        Self::decode_payload(msg_type, payload).map(|msg| Some((msg, 8 + data_len)))
+    }
+
+    /// Takes one complete message out of `buffer`, or returns `None` if more data is needed
+    pub fn try_decode_buf(buffer: &mut BytesMut) -> Result<Option<IpcMessage>> {
+        if buffer.len() < 8 {
+            return Ok(None);
+        }
+        let data_len = payload_len(&buffer[4..8])?;
+        if buffer.len() < 8 + data_len {
+            buffer.reserve(8 + data_len - buffer.len());
+            return Ok(None);
+        }
+        let mut frame = buffer.split_to(8 + data_len);
+        let msg_type = frame.get_i32_le();
+        frame.advance(4);
+        Self::decode_payload(msg_type, &frame).map(Some)
+    }
+
+    fn decode_payload(msg_type: i32, data_buffer: &[u8]) -> Result<IpcMessage> {
         match msg_type {
             0 => {
-                let data = from_slice(&data_buffer)?;
+                let data = from_slice(data_buffer)?;
                 Ok(IpcMessage::Handshake(data))
             }
-            1 => {
-                let data = from_slice(&data_buffer)?;
-                Ok(IpcMessage::Frame(data))
-            }
+            1 => Ok(IpcMessage::Frame(Box::new(IpcFrame::from_slice(
+                data_buffer,
+            )?))),
             2 => {
-                if let Ok(data) = from_slice(&data_buffer) {
+                if let Ok(data) = from_slice(data_buffer) {
                     Ok(IpcMessage::Close(data))
                 } else {
                     Ok(IpcMessage::Close(CloseMessage {
@@ -109,28 +176,44 @@ impl IpcMessage {
                 }
             }
             3 => {
-                let data = from_slice(&data_buffer)?;
+                let data = from_slice(data_buffer)?;
                 Ok(IpcMessage::Ping(data))
             }
             4 => {
-                let data = from_slice(&data_buffer)?;
+                let data = from_slice(data_buffer)?;
                 Ok(IpcMessage::Pong(data))
             }
             x => {
-                debug!("Invalid IPC Data: ({}) {:?}", x, data_buffer);
-                Err(anyhow::anyhow!("Invalid IPC Message Type"))
+                if redact::enabled() {
+                    debug!("Invalid IPC Data: ({}) {} bytes", x, data_buffer.len());
+                } else {
+                    debug!("Invalid IPC Data: ({}) {:?}", x, data_buffer);
+                }
+                Err(ArRpcError::InvalidMessageType(x))
             }
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// Checked before anything is allocated for the payload, the length comes from the client
+fn payload_len(mut header: &[u8]) -> Result<usize> {
+    let len = header.get_i32_le().max(0) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(ArRpcError::FrameTooLarge(len));
+    }
+    Ok(len)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum CloseCodes {
     Normal = 1000,
     Unsupported = 1003,
     Abnormal = 1006,
     InvalidClientID = 4000,
+    InvalidOrigin = 4001,
+    RateLimited = 4002,
     InvalidVersion = 4004,
+    InvalidEncoding = 4005,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -148,18 +231,75 @@ pub struct CloseMessage {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IpcFrameArgs {
-    pub activity: IpcPartialActivity,
+    #[serde(default)]
+    pub activity: Option<IpcPartialActivity>,
     pub pid: usize,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IpcFrame {
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub args: Option<IpcFrameArgs>,
+    pub args: Option<Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub data: Option<Value>,
     pub cmd: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub evt: Option<String>,
+    // Discord sends a null nonce with events
+    #[serde(default, deserialize_with = "null_as_empty")]
     pub nonce: String,
 }
+
+impl IpcFrame {
+    /// Like `serde_json::from_slice`, but keeps the `cmd` and `nonce` of frames that don't
+    /// match the schema so they can still be answered
+    pub fn from_slice(data: &[u8]) -> Result<IpcFrame> {
+        let value: Value = from_slice(data)?;
+        match IpcFrame::deserialize(&value) {
+            Ok(frame) => Ok(frame),
+            Err(e) => {
+                let field = |name| value.get(name).and_then(Value::as_str).unwrap_or_default();
+                let frame = IpcFrame {
+                    args: None,
+                    data: None,
+                    cmd: field("cmd").to_string(),
+                    evt: None,
+                    nonce: field("nonce").to_string(),
+                };
+                Err(ArRpcError::InvalidFrame(Box::new(frame), e.to_string()))
+            }
+        }
+    }
+
+    /// The answer to this frame, correlated by its nonce
+    pub fn reply(&self, data: Option<Value>) -> IpcFrame {
+        IpcFrame {
+            args: None,
+            data,
+            cmd: self.cmd.clone(),
+            evt: None,
+            nonce: self.nonce.clone(),
+        }
+    }
+
+    /// An `ERROR` event answering this frame, the way the official client reports failures
+    pub fn error(&self, code: RpcErrorCode, message: impl Into<String>) -> IpcFrame {
+        IpcFrame {
+            evt: Some("ERROR".to_string()),
+            ..self.reply(Some(
+                json!({ "code": code as i32, "message": message.into() }),
+            ))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RpcErrorCode {
+    UnknownError = 1000,
+    InvalidPayload = 4000,
+    InvalidCommand = 4002,
+}
+
+fn null_as_empty<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Ok(Option::<String>::deserialize(deserializer)?.unwrap_or_default())
+}
@@ -0,0 +1,559 @@
+use super::structs::IpcMessage;
+use crate::{
+    error::{ArRpcError, Result},
+    stats::Traffic,
+    structs::PeerCredentials,
+};
+use bytes::{Buf, Bytes, BytesMut};
+use std::{collections::VecDeque, fmt::Display, future::Future, io::IoSlice};
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream},
+    sync::mpsc,
+};
+
+#[cfg(all(unix, feature = "ipc-unix"))]
+pub use self::unix::{default_directory, sandbox_directories, UnixTransport};
+#[cfg(all(windows, feature = "ipc-windows"))]
+pub use self::windows::NamedPipeTransport;
+
+pub trait RpcConnection: Send + 'static {
+    /// Finishes any transport level handshake, called once before the first `recv`
+    fn ready(&mut self) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+    /// Must be cancel safe, as it is raced against outgoing commands
+    fn recv(&mut self) -> impl Future<Output = Result<IpcMessage>> + Send;
+    fn send(&mut self, msg: &IpcMessage) -> impl Future<Output = Result<()>> + Send {
+        async {
+            self.feed(msg).await?;
+            self.flush().await
+        }
+    }
+    /// Like `send`, but may hold on to the message until the next `flush`
+    fn feed(&mut self, msg: &IpcMessage) -> impl Future<Output = Result<()>> + Send;
+    fn flush(&mut self) -> impl Future<Output = Result<()>> + Send;
+    /// Only known for Unix sockets
+    fn peer(&self) -> Option<PeerCredentials> {
+        None
+    }
+    /// Called after sending a close, flushes and waits for the peer to hang up so it gets to
+    /// read the close before the connection is dropped
+    fn linger(&mut self) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+    /// What went over the wire since the last call
+    fn take_traffic(&mut self) -> Traffic {
+        Traffic::default()
+    }
+}
+
+pub trait RpcTransport: Send + 'static {
+    type Connection: RpcConnection;
+
+    fn accept(&mut self) -> impl Future<Output = Result<Self::Connection>> + Send;
+    /// Where the transport is reachable, for logging
+    fn address(&self) -> impl Display;
+    /// Releases whatever the transport left on the system, like socket files
+    fn close(self) -> impl Future<Output = Result<()>> + Send
+    where
+        Self: Sized,
+    {
+        async { Ok(()) }
+    }
+}
+
+/// Any byte stream speaking the length prefixed IPC framing
+pub struct StreamConnection<S> {
+    stream: S,
+    buffer: BytesMut,
+    /// Fed but not yet flushed
+    pending: Frames,
+    peer: Option<PeerCredentials>,
+    traffic: Traffic,
+}
+
+impl<S> StreamConnection<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            buffer: BytesMut::with_capacity(1024),
+            pending: Frames::default(),
+            peer: None,
+            traffic: Traffic::default(),
+        }
+    }
+
+    pub fn with_peer(mut self, peer: Option<PeerCredentials>) -> Self {
+        self.peer = peer;
+        self
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+}
+
+impl<S> RpcConnection for StreamConnection<S>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    async fn recv(&mut self) -> Result<IpcMessage> {
+        loop {
+            if let Some(msg) = IpcMessage::try_decode_buf(&mut self.buffer)? {
+                return Ok(msg);
+            }
+            // read_buf only touches the buffer once data arrived, so this stays cancel safe
+            let read = self.stream.read_buf(&mut self.buffer).await?;
+            if read == 0 {
+                return Err(ArRpcError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+            }
+            self.traffic.read += read as u64;
+        }
+    }
+
+    async fn feed(&mut self, msg: &IpcMessage) -> Result<()> {
+        let (header, payload) = msg.encode_parts()?;
+        self.pending.0.push_back(Bytes::copy_from_slice(&header));
+        self.pending.0.push_back(payload);
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.traffic.written += self.pending.remaining() as u64;
+        // Vectored where the stream supports it, so payloads are never copied behind a header
+        self.stream.write_all_buf(&mut self.pending).await?;
+        Ok(())
+    }
+
+    fn peer(&self) -> Option<PeerCredentials> {
+        self.peer
+    }
+
+    async fn linger(&mut self) -> Result<()> {
+        self.stream.shutdown().await?;
+        let mut discard = [0; 1024];
+        while self.stream.read(&mut discard).await? != 0 {}
+        Ok(())
+    }
+
+    fn take_traffic(&mut self) -> Traffic {
+        std::mem::take(&mut self.traffic)
+    }
+}
+
+/// Headers and payloads waiting to be written, in order. None of them are empty.
+#[derive(Default)]
+struct Frames(VecDeque<Bytes>);
+
+impl Buf for Frames {
+    fn remaining(&self) -> usize {
+        self.0.iter().map(Bytes::len).sum()
+    }
+
+    fn chunk(&self) -> &[u8] {
+        self.0.front().map_or(&[], |chunk| chunk)
+    }
+
+    fn chunks_vectored<'a>(&'a self, dst: &mut [IoSlice<'a>]) -> usize {
+        let mut filled = 0;
+        for (slice, chunk) in dst.iter_mut().zip(&self.0) {
+            *slice = IoSlice::new(chunk);
+            filled += 1;
+        }
+        filled
+    }
+
+    fn advance(&mut self, mut cnt: usize) {
+        while let Some(front) = self.0.front_mut() {
+            if cnt < front.len() {
+                front.advance(cnt);
+                return;
+            }
+            cnt -= front.len();
+            self.0.pop_front();
+        }
+    }
+}
+
+/// Connections made through a [`DuplexConnector`], so the protocol can be tested without
+/// touching the filesystem or real sockets
+pub struct DuplexTransport {
+    rx: mpsc::UnboundedReceiver<DuplexStream>,
+}
+
+#[derive(Clone)]
+pub struct DuplexConnector {
+    tx: mpsc::UnboundedSender<DuplexStream>,
+}
+
+impl DuplexTransport {
+    pub fn new() -> (DuplexTransport, DuplexConnector) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (DuplexTransport { rx }, DuplexConnector { tx })
+    }
+}
+
+impl DuplexConnector {
+    /// The client end of a new connection, the transport accepts the other one
+    pub fn connect(&self) -> Result<DuplexStream> {
+        let (client, server) = io::duplex(64 * 1024);
+        self.tx
+            .send(server)
+            .map_err(|_| ArRpcError::ChannelClosed)?;
+        Ok(client)
+    }
+}
+
+impl RpcTransport for DuplexTransport {
+    type Connection = StreamConnection<DuplexStream>;
+
+    /// Fails once every connector is gone
+    async fn accept(&mut self) -> Result<Self::Connection> {
+        match self.rx.recv().await {
+            Some(stream) => Ok(StreamConnection::new(stream)),
+            None => Err(ArRpcError::ChannelClosed),
+        }
+    }
+
+    fn address(&self) -> impl Display {
+        "memory"
+    }
+}
+
+#[cfg(all(unix, feature = "ipc-unix"))]
+mod unix {
+    use super::{RpcTransport, StreamConnection};
+    use crate::{
+        error::{ArRpcError, Result},
+        structs::PeerCredentials,
+    };
+    use owo_colors::OwoColorize;
+    use std::{
+        env,
+        ffi::CString,
+        fmt::Display,
+        fs::{self, Permissions},
+        io::{self, ErrorKind},
+        os::unix::fs::{chown, PermissionsExt},
+        path::PathBuf,
+    };
+    use tokio::net::{UnixListener, UnixStream};
+    use tracing::{info, warn};
+
+    // sun_path is 108 bytes on Linux but only 104 on macOS and the BSDs
+    #[cfg(target_os = "linux")]
+    const MAX_SOCKET_PATH: usize = 108;
+    #[cfg(not(target_os = "linux"))]
+    const MAX_SOCKET_PATH: usize = 104;
+
+    #[cfg(not(target_os = "macos"))]
+    pub fn default_directory() -> String {
+        if let Some(directory) = crate::paths::runtime_dir() {
+            return directory.to_string_lossy().into_owned();
+        }
+        env::var("TMPDIR")
+            .or_else(|_| env::var("TMP"))
+            .or_else(|_| env::var("TEMP"))
+            .unwrap_or("/tmp".to_string())
+    }
+
+    // Discord uses the per-user $TMPDIR (/var/folders/.../T/) on macOS. It comes with a trailing
+    // slash and isn't set at all for processes spawned by launchd, so ask the system for it then.
+    #[cfg(target_os = "macos")]
+    pub fn default_directory() -> String {
+        let directory = env::var("XDG_RUNTIME_DIR")
+            .or_else(|_| env::var("TMPDIR"))
+            .ok()
+            .or_else(darwin_user_temp_dir)
+            .unwrap_or("/tmp".to_string());
+        match directory.trim_end_matches('/') {
+            "" => "/".to_string(),
+            directory => directory.to_string(),
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    fn darwin_user_temp_dir() -> Option<String> {
+        let mut buffer = vec![0u8; libc::PATH_MAX as usize];
+        let len = unsafe {
+            libc::confstr(
+                libc::_CS_DARWIN_USER_TEMP_DIR,
+                buffer.as_mut_ptr() as *mut libc::c_char,
+                buffer.len(),
+            )
+        };
+        if len == 0 || len > buffer.len() {
+            return None;
+        }
+        buffer.truncate(len - 1);
+        String::from_utf8(buffer).ok()
+    }
+
+    pub struct UnixTransport {
+        path: String,
+        listener: UnixListener,
+        /// Nothing to remove or set permissions on
+        is_abstract: bool,
+        closed: bool,
+    }
+
+    /// Where sandboxed games expect the socket, only used if the sandbox directory already exists
+    pub fn sandbox_directories() -> Vec<PathBuf> {
+        let Some(runtime_directory) = crate::paths::runtime_dir() else {
+            return Vec::new();
+        };
+        [
+            "app/com.discordapp.Discord",
+            "app/com.discordapp.DiscordCanary",
+            "snap.discord",
+            "snap.discord-canary",
+        ]
+        .iter()
+        .map(|directory| runtime_directory.join(directory))
+        .filter(|directory| directory.is_dir())
+        .collect()
+    }
+
+    impl UnixTransport {
+        pub fn try_bind(count: usize) -> Result<Vec<UnixTransport>> {
+            Self::try_bind_in(&default_directory(), count)
+        }
+
+        /// Binds the first `count` free `discord-ipc-N` sockets in `bind_directory`
+        pub fn try_bind_in(bind_directory: &str, count: usize) -> Result<Vec<UnixTransport>> {
+            Self::try_bind_each(count, false, |i| {
+                format!("{}/discord-ipc-{}", bind_directory, i)
+            })
+        }
+
+        /// Binds the first `count` free `discord-ipc-N` names in the abstract namespace, which
+        /// has no files and is shared by everything in the same network namespace
+        #[cfg(target_os = "linux")]
+        pub fn try_bind_abstract(count: usize) -> Result<Vec<UnixTransport>> {
+            Self::try_bind_each(count, true, |i| format!("discord-ipc-{}", i))
+        }
+
+        fn try_bind_each(
+            count: usize,
+            is_abstract: bool,
+            name: impl Fn(u8) -> String,
+        ) -> Result<Vec<UnixTransport>> {
+            let mut transports = Vec::new();
+            for i in 0u8..10 {
+                if transports.len() >= count {
+                    break;
+                }
+                let path = name(i);
+                if path.len() >= MAX_SOCKET_PATH {
+                    warn!("Socket path {} is too long to bind", path);
+                    return Err(ArRpcError::SocketPathsExhausted);
+                }
+                let bound = match is_abstract {
+                    true => bind_abstract(&path),
+                    false => UnixListener::bind(&path),
+                };
+                let path = match is_abstract {
+                    true => format!("@{}", path),
+                    false => path,
+                };
+                match bound {
+                    Ok(listener) => transports.push(UnixTransport {
+                        path,
+                        listener,
+                        is_abstract,
+                        closed: false,
+                    }),
+                    Err(e) => match e.kind() {
+                        ErrorKind::AddrInUse => {
+                            info!(
+                                "{} {}, {}",
+                                "Socket is not available at".yellow().bold(),
+                                path.red().bold(),
+                                "Trying next path...".cyan().bold(),
+                            );
+                            continue;
+                        }
+                        _ => {
+                            info!("Error: {:?}", e);
+                            return Err(e.into());
+                        }
+                    },
+                }
+            }
+            if transports.is_empty() {
+                return Err(ArRpcError::SocketPathsExhausted);
+            }
+            Ok(transports)
+        }
+
+        /// Abstract sockets are written as `@name`
+        pub fn path(&self) -> &str {
+            &self.path
+        }
+
+        /// Owner and group are names or numeric ids
+        pub fn set_permissions(
+            &self,
+            mode: Option<u32>,
+            owner: Option<&str>,
+            group: Option<&str>,
+        ) -> Result<()> {
+            if self.is_abstract {
+                return Ok(());
+            }
+            if let Some(mode) = mode {
+                fs::set_permissions(&self.path, Permissions::from_mode(mode))?;
+            }
+            if owner.is_some() || group.is_some() {
+                let uid = owner.map(lookup_user).transpose()?;
+                let gid = group.map(lookup_group).transpose()?;
+                chown(&self.path, uid, gid)?;
+            }
+            Ok(())
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn bind_abstract(name: &str) -> io::Result<UnixListener> {
+        use std::os::{linux::net::SocketAddrExt, unix::net};
+
+        let address = net::SocketAddr::from_abstract_name(name)?;
+        let listener = net::UnixListener::bind_addr(&address)?;
+        listener.set_nonblocking(true)?;
+        UnixListener::from_std(listener)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn bind_abstract(_name: &str) -> io::Result<UnixListener> {
+        Err(ErrorKind::Unsupported.into())
+    }
+
+    fn lookup_user(name: &str) -> Result<u32> {
+        if let Ok(uid) = name.parse() {
+            return Ok(uid);
+        }
+        let name_c = CString::new(name).map_err(|_| unknown_id("user", name))?;
+        let passwd = unsafe { libc::getpwnam(name_c.as_ptr()) };
+        if passwd.is_null() {
+            return Err(unknown_id("user", name));
+        }
+        Ok(unsafe { (*passwd).pw_uid })
+    }
+
+    fn lookup_group(name: &str) -> Result<u32> {
+        if let Ok(gid) = name.parse() {
+            return Ok(gid);
+        }
+        let name_c = CString::new(name).map_err(|_| unknown_id("group", name))?;
+        let group = unsafe { libc::getgrnam(name_c.as_ptr()) };
+        if group.is_null() {
+            return Err(unknown_id("group", name));
+        }
+        Ok(unsafe { (*group).gr_gid })
+    }
+
+    fn unknown_id(kind: &str, name: &str) -> ArRpcError {
+        io::Error::new(ErrorKind::NotFound, format!("unknown {} {}", kind, name)).into()
+    }
+
+    impl RpcTransport for UnixTransport {
+        type Connection = StreamConnection<UnixStream>;
+
+        async fn accept(&mut self) -> Result<Self::Connection> {
+            let (stream, _) = self.listener.accept().await?;
+            let peer = stream.peer_cred().ok().map(|cred| PeerCredentials {
+                pid: cred.pid().and_then(|pid| usize::try_from(pid).ok()),
+                uid: cred.uid(),
+            });
+            Ok(StreamConnection::new(stream).with_peer(peer))
+        }
+
+        fn address(&self) -> impl Display {
+            &self.path
+        }
+
+        async fn close(mut self) -> Result<()> {
+            if !self.is_abstract {
+                tokio::fs::remove_file(&self.path).await?;
+            }
+            self.closed = true;
+            Ok(())
+        }
+    }
+
+    // Only a fallback for transports that never got closed
+    impl Drop for UnixTransport {
+        fn drop(&mut self) {
+            if self.closed || self.is_abstract {
+                return;
+            }
+            if let Err(e) = std::fs::remove_file(&self.path) {
+                warn!("Failed to remove IPC socket file at {}", &self.path);
+                warn!("Error: {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(all(windows, feature = "ipc-windows"))]
+mod windows {
+    use super::{RpcTransport, StreamConnection};
+    use crate::error::{ArRpcError, Result};
+    use owo_colors::OwoColorize;
+    use std::{fmt::Display, io::ErrorKind};
+    use tokio::net::windows::named_pipe::{NamedPipeServer, ServerOptions};
+    use tracing::info;
+
+    pub struct NamedPipeTransport {
+        path: String,
+        next: NamedPipeServer,
+    }
+
+    impl NamedPipeTransport {
+        /// Binds the first `count` free `discord-ipc-N` pipes
+        pub fn try_bind(count: usize) -> Result<Vec<NamedPipeTransport>> {
+            let mut transports = Vec::new();
+            for i in 0u8..10 {
+                if transports.len() >= count {
+                    break;
+                }
+                let path = format!(r"\\.\pipe\discord-ipc-{}", i);
+                match ServerOptions::new().first_pipe_instance(true).create(&path) {
+                    Ok(next) => transports.push(NamedPipeTransport { path, next }),
+                    Err(e) => match e.kind() {
+                        ErrorKind::PermissionDenied | ErrorKind::AddrInUse => {
+                            info!(
+                                "{} {}, {}",
+                                "Pipe is not available at".yellow().bold(),
+                                path.red().bold(),
+                                "Trying next path...".cyan().bold(),
+                            );
+                            continue;
+                        }
+                        _ => return Err(e.into()),
+                    },
+                }
+            }
+            if transports.is_empty() {
+                return Err(ArRpcError::SocketPathsExhausted);
+            }
+            Ok(transports)
+        }
+    }
+
+    impl RpcTransport for NamedPipeTransport {
+        type Connection = StreamConnection<NamedPipeServer>;
+
+        async fn accept(&mut self) -> Result<Self::Connection> {
+            self.next.connect().await?;
+            // A pipe instance serves exactly one client, so queue up the next one right away
+            let next = ServerOptions::new().create(&self.path)?;
+            let connected = std::mem::replace(&mut self.next, next);
+            Ok(StreamConnection::new(connected))
+        }
+
+        fn address(&self) -> impl Display {
+            &self.path
+        }
+    }
+}
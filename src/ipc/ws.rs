@@ -0,0 +1,319 @@
+use super::{
+    structs::{CloseCodes, CloseMessage, HandshakeMessage, IpcFrame, IpcMessage},
+    transport::{RpcConnection, RpcTransport},
+};
+use crate::{
+    error::{ArRpcError, Result},
+    stats::Traffic,
+};
+use futures_util::{SinkExt, StreamExt};
+use owo_colors::OwoColorize;
+use std::{fmt::Display, io::ErrorKind, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time,
+};
+use tokio_tungstenite::{
+    accept_hdr_async,
+    tungstenite::{
+        handshake::server::{Request, Response},
+        protocol::{frame::coding::CloseCode, CloseFrame},
+        Message,
+    },
+    WebSocketStream,
+};
+use tracing::info;
+
+// Same range the official client scans through
+const PORT_RANGE: std::ops::RangeInclusive<u16> = 6463..=6472;
+// What the official client answers plain HTTP requests with, SDKs scanning for the port look for it
+const PROBE_RESPONSE: &str = r#"{"code":0,"message":"404: Not Found"}"#;
+const MAX_HEADER_SIZE: usize = 8 * 1024;
+/// How long a connection may take to send its request, so silent ones don't hold a slot forever
+const UPGRADE_TIMEOUT: Duration = Duration::from_secs(5);
+const DISCORD_ORIGINS: [&str; 3] = [
+    "https://discord.com",
+    "https://ptb.discord.com",
+    "https://canary.discord.com",
+];
+
+/// Which pages a browser may connect from, clients without an `Origin` header are always let in
+#[derive(Debug, Clone)]
+pub enum OriginPolicy {
+    Any,
+    /// Discord's own origins and these
+    Allowlist(Arc<[String]>),
+}
+
+impl OriginPolicy {
+    pub fn allows(&self, origin: &str) -> bool {
+        match self {
+            OriginPolicy::Any => true,
+            OriginPolicy::Allowlist(allowed) => {
+                DISCORD_ORIGINS.contains(&origin) || allowed.iter().any(|allowed| allowed == origin)
+            }
+        }
+    }
+}
+
+pub struct WsTransport {
+    port: u16,
+    listener: TcpListener,
+    origins: OriginPolicy,
+}
+
+impl WsTransport {
+    pub async fn try_bind(origins: OriginPolicy) -> Result<WsTransport> {
+        for port in PORT_RANGE {
+            match TcpListener::bind(("127.0.0.1", port)).await {
+                Ok(listener) => {
+                    return Ok(WsTransport {
+                        port,
+                        listener,
+                        origins,
+                    })
+                }
+                Err(e) if e.kind() == ErrorKind::AddrInUse => {
+                    info!(
+                        "{} {}, {}",
+                        "Port is not available:".yellow().bold(),
+                        port.red().bold(),
+                        "Trying next port...".cyan().bold(),
+                    );
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Err(ArRpcError::SocketPathsExhausted)
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl RpcTransport for WsTransport {
+    type Connection = WsConnection;
+
+    async fn accept(&mut self) -> Result<WsConnection> {
+        let (stream, _) = self.listener.accept().await?;
+        Ok(WsConnection::Pending(Some(stream), self.origins.clone()))
+    }
+
+    fn address(&self) -> impl Display {
+        format!("ws://127.0.0.1:{}", self.port)
+    }
+}
+
+pub enum WsConnection {
+    Pending(Option<TcpStream>, OriginPolicy),
+    Open {
+        stream: Box<WebSocketStream<TcpStream>>,
+        handshake: Option<HandshakeMessage>,
+        /// Only counts message payloads, not WebSocket framing
+        traffic: Traffic,
+    },
+}
+
+impl RpcConnection for WsConnection {
+    // The handshake callback signature is dictated by tungstenite
+    #[allow(clippy::result_large_err)]
+    async fn ready(&mut self) -> Result<()> {
+        let WsConnection::Pending(stream, origins) = self else {
+            return Ok(());
+        };
+        let Some(stream) = stream.take() else {
+            return Err(ArRpcError::Io(ErrorKind::NotConnected.into()));
+        };
+
+        let origins = origins.clone();
+        let mut query = String::new();
+        let mut origin = None;
+        let upgrade = async {
+            if let Some(header_len) = plain_http(&stream).await? {
+                answer_probe(stream, header_len).await?;
+                return Err(ArRpcError::HttpProbe);
+            }
+            let stream = accept_hdr_async(stream, |request: &Request, response: Response| {
+                query = request.uri().query().unwrap_or_default().to_string();
+                origin = request
+                    .headers()
+                    .get("origin")
+                    .and_then(|origin| origin.to_str().ok())
+                    .map(str::to_string);
+                Ok(response)
+            })
+            .await?;
+            Ok(stream)
+        };
+        let stream = match time::timeout(UPGRADE_TIMEOUT, upgrade).await {
+            Ok(stream) => stream?,
+            Err(_) => return Err(ArRpcError::Io(ErrorKind::TimedOut.into())),
+        };
+
+        // There is no handshake message over WebSocket, the client identifies itself in the URL
+        let mut handshake = HandshakeMessage {
+            version: 0,
+            client_id: String::new(),
+        };
+        let mut encoding = None;
+        for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+            match key {
+                "v" => handshake.version = value.parse().unwrap_or_default(),
+                "client_id" => handshake.client_id = value.to_string(),
+                "encoding" => encoding = Some(value.to_string()),
+                _ => {}
+            }
+        }
+
+        *self = WsConnection::Open {
+            stream: Box::new(stream),
+            handshake: Some(handshake),
+            traffic: Traffic::default(),
+        };
+        // The connection is left open so the caller can send the close code
+        if let Some(origin) = origin.filter(|origin| !origins.allows(origin)) {
+            return Err(ArRpcError::InvalidOrigin(origin));
+        }
+        match encoding {
+            Some(encoding) if encoding != "json" => Err(ArRpcError::InvalidEncoding(encoding)),
+            _ => Ok(()),
+        }
+    }
+
+    async fn recv(&mut self) -> Result<IpcMessage> {
+        let WsConnection::Open {
+            stream,
+            handshake,
+            traffic,
+        } = self
+        else {
+            return Err(ArRpcError::Io(ErrorKind::NotConnected.into()));
+        };
+        if let Some(handshake) = handshake.take() {
+            return Ok(IpcMessage::Handshake(handshake));
+        }
+        loop {
+            let Some(msg) = stream.next().await else {
+                return Err(ArRpcError::Io(ErrorKind::UnexpectedEof.into()));
+            };
+            let msg = msg?;
+            traffic.read += msg.len() as u64;
+            match msg {
+                Message::Text(data) => {
+                    return Ok(IpcMessage::Frame(Box::new(IpcFrame::from_slice(
+                        data.as_bytes(),
+                    )?)))
+                }
+                Message::Binary(data) => {
+                    return Ok(IpcMessage::Frame(Box::new(IpcFrame::from_slice(&data)?)))
+                }
+                Message::Close(_) => {
+                    return Ok(IpcMessage::Close(CloseMessage {
+                        code: CloseCodes::Normal,
+                        message: "".into(),
+                    }))
+                }
+                Message::Pong(data) => {
+                    return Ok(IpcMessage::Pong(
+                        serde_json::from_slice(&data).unwrap_or_default(),
+                    ))
+                }
+                // Pings are answered by tungstenite itself
+                _ => continue,
+            }
+        }
+    }
+
+    async fn feed(&mut self, msg: &IpcMessage) -> Result<()> {
+        let WsConnection::Open {
+            stream, traffic, ..
+        } = self
+        else {
+            return Err(ArRpcError::Io(ErrorKind::NotConnected.into()));
+        };
+        let msg = match msg {
+            IpcMessage::Frame(frame) => Message::Text(serde_json::to_string(frame)?),
+            IpcMessage::Close(close) => Message::Close(Some(CloseFrame {
+                code: CloseCode::from(close.code as u16),
+                reason: close.message.clone().into(),
+            })),
+            IpcMessage::Ping(data) => Message::Ping(serde_json::to_vec(data)?),
+            IpcMessage::Pong(data) => Message::Pong(serde_json::to_vec(data)?),
+            IpcMessage::Handshake(_) => return Ok(()),
+        };
+        traffic.written += msg.len() as u64;
+        stream.feed(msg).await?;
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        match self {
+            WsConnection::Open { stream, .. } => Ok(stream.flush().await?),
+            WsConnection::Pending(..) => Ok(()),
+        }
+    }
+
+    // tungstenite answers the peer's close reply itself and ends the stream after it
+    async fn linger(&mut self) -> Result<()> {
+        if let WsConnection::Open { stream, .. } = self {
+            while let Some(msg) = stream.next().await {
+                msg?;
+            }
+        }
+        Ok(())
+    }
+
+    fn take_traffic(&mut self) -> Traffic {
+        match self {
+            WsConnection::Open { traffic, .. } => std::mem::take(traffic),
+            WsConnection::Pending(..) => Traffic::default(),
+        }
+    }
+}
+
+/// Peeks at the request, returning its header length if it is not a WebSocket upgrade
+async fn plain_http(stream: &TcpStream) -> Result<Option<usize>> {
+    let mut buffer = vec![0; MAX_HEADER_SIZE];
+    let mut peeked = 0;
+    loop {
+        let len = stream.peek(&mut buffer).await?;
+        if len == 0 {
+            return Err(ArRpcError::Io(ErrorKind::UnexpectedEof.into()));
+        }
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut request = httparse::Request::new(&mut headers);
+        match request.parse(&buffer[..len]) {
+            Ok(httparse::Status::Complete(header_len)) => {
+                let upgrade = request.headers.iter().any(|header| {
+                    header.name.eq_ignore_ascii_case("upgrade")
+                        && header.value.eq_ignore_ascii_case(b"websocket")
+                });
+                return Ok((!upgrade).then_some(header_len));
+            }
+            // Peeking again returns right away, so give the rest of the request a moment
+            Ok(httparse::Status::Partial) if len < buffer.len() => {
+                if len == peeked {
+                    time::sleep(Duration::from_millis(10)).await;
+                }
+                peeked = len;
+            }
+            // Not something we can answer, tungstenite will reject it
+            _ => return Ok(None),
+        }
+    }
+}
+
+async fn answer_probe(mut stream: TcpStream, header_len: usize) -> Result<()> {
+    let mut request = vec![0; header_len];
+    stream.read_exact(&mut request).await?;
+    let response = format!(
+        "HTTP/1.1 404 Not Found\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        PROBE_RESPONSE.len(),
+        PROBE_RESPONSE
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
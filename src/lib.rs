@@ -1,4 +1,56 @@
+mod arrpc;
+pub mod blocklist;
+#[cfg(feature = "bridge")]
 pub mod bridge;
+pub mod command;
+pub mod config;
+#[cfg(all(unix, feature = "rest-api"))]
+pub mod control;
+#[cfg(all(unix, feature = "dbus"))]
+pub mod dbus;
+#[cfg(feature = "process-detection")]
+pub mod detection;
+pub mod error;
+pub mod expiry;
+pub mod history;
+#[cfg(feature = "idle")]
+pub mod idle;
 pub mod ipc;
+#[cfg(unix)]
+pub mod lock;
+pub mod middleware;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod overrides;
+#[cfg(unix)]
+pub mod paths;
+pub mod pattern;
+#[cfg(all(unix, feature = "plugins"))]
+pub mod plugin;
+pub mod priority;
+pub mod privacy;
+pub mod process;
+pub mod queue;
+pub mod record;
+pub mod redact;
+#[cfg(feature = "bridge")]
+pub mod relay;
+pub mod rewrite;
+pub mod rotation;
+#[cfg(windows)]
+pub mod scm;
 pub mod server;
+pub mod service;
+pub mod sink;
+pub mod state;
+pub mod stats;
+pub mod status;
 pub mod structs;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+#[cfg(all(unix, feature = "tray"))]
+pub mod tray;
+pub mod validate;
+
+pub use arrpc::{ArRpc, ArRpcBuilder};
+pub use error::{ArRpcError, Result};
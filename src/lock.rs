@@ -0,0 +1,81 @@
+use crate::{
+    error::{ArRpcError, Result},
+    paths,
+};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Read, Seek, Write},
+    os::{fd::AsRawFd, unix::fs::MetadataExt},
+    path::{Path, PathBuf},
+};
+use tracing::warn;
+
+/// Held for as long as this is the running instance, doubles as the pid file
+pub struct InstanceLock {
+    path: PathBuf,
+    file: File,
+}
+
+impl InstanceLock {
+    pub fn default_path() -> PathBuf {
+        paths::arrpc_dir().join("arrpc.pid")
+    }
+
+    pub fn acquire(path: &Path) -> Result<InstanceLock> {
+        if let Some(parent) = path.parent() {
+            paths::create_private_dir(parent)?;
+        }
+        let file = loop {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?;
+            // The kernel drops the lock with the process, so a crash never leaves a stale one behind
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == -1 {
+                let e = std::io::Error::last_os_error();
+                if e.kind() != ErrorKind::WouldBlock {
+                    return Err(e.into());
+                }
+                let mut pid = String::new();
+                file.read_to_string(&mut pid)?;
+                return Err(ArRpcError::AlreadyRunning(pid.trim().parse().ok()));
+            }
+            // The previous holder unlinks the file on exit, so we might have locked a file that's gone
+            let ours = file.metadata()?;
+            match fs::metadata(path) {
+                Ok(current) if current.ino() == ours.ino() && current.dev() == ours.dev() => {
+                    break file
+                }
+                Ok(_) => continue,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        let lock = InstanceLock {
+            path: path.to_path_buf(),
+            file,
+        };
+        lock.write_pid()?;
+        Ok(lock)
+    }
+
+    /// Call again after forking, the lock carries over but the pid doesn't
+    pub fn write_pid(&self) -> Result<()> {
+        let mut file = &self.file;
+        file.set_len(0)?;
+        file.rewind()?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(())
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Failed to remove pid file at {}", self.path.display());
+            warn!("Error: {:?}", e);
+        }
+    }
+}
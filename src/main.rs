@@ -1,37 +1,646 @@
-use anyhow::Result;
-use arrpc_rs::{bridge::BridgeServer, server::Server};
+use anyhow::{bail, Context, Result};
+#[cfg(windows)]
+use arrpc_rs::scm;
+use arrpc_rs::{
+    config::{Config, SinkConfig},
+    record, redact,
+    relay::RelaySink,
+    service,
+    sink::{FileSink, StdoutSink, WebhookSink},
+    state::PersistedState,
+    ArRpc,
+};
+#[cfg(unix)]
+use arrpc_rs::{lock::InstanceLock, ArRpcError};
 use owo_colors::OwoColorize;
-use tokio::{select, signal};
-use tracing::{info, Level};
-use tracing_subscriber::{fmt::time, FmtSubscriber};
-
-#[tokio::main]
-async fn main() -> Result<()> {
-    let subscriber = FmtSubscriber::builder()
-        .with_timer(time::ChronoLocal::new("%H:%M:%S".into()))
-        .with_max_level(Level::DEBUG)
-        .finish();
-    tracing::subscriber::set_global_default(subscriber)?;
+#[cfg(unix)]
+use std::time::Duration;
+use std::{
+    env,
+    fs::OpenOptions,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+#[cfg(not(unix))]
+use tokio::signal;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::{runtime::Runtime, select};
+use tracing::{info, level_filters::LevelFilter, warn};
+use tracing_subscriber::{fmt, fmt::time, layer::SubscriberExt, reload, util::SubscriberInitExt};
+
+#[cfg(all(unix, feature = "rest-api"))]
+use arrpc_rs::control::{self, ControlCommand, ControlRequest, ControlServer};
+#[cfg(all(unix, feature = "rest-api"))]
+use serde_json::json;
+
+#[cfg(all(unix, feature = "dbus"))]
+use arrpc_rs::dbus::{notify::NotifySink, DbusSink};
+#[cfg(feature = "mqtt")]
+use arrpc_rs::mqtt::MqttSink;
+#[cfg(all(unix, feature = "tray"))]
+use arrpc_rs::tray::{Tray, TrayCommand, TrayState};
+#[cfg(all(unix, feature = "tray"))]
+use tokio::time::{interval, Interval};
+
+type LogHandle = reload::Handle<LevelFilter, tracing_subscriber::Registry>;
+
+#[derive(Default)]
+struct Args {
+    config_path: Option<PathBuf>,
+    log_file: Option<PathBuf>,
+    permissive: bool,
+    stdout_json: bool,
+    record: Option<PathBuf>,
+    replay: Option<PathBuf>,
+    replay_speed: Option<f64>,
+    #[cfg(unix)]
+    daemon: bool,
+    #[cfg(unix)]
+    replace: bool,
+    #[cfg(windows)]
+    service: bool,
+    #[cfg(all(unix, feature = "rest-api"))]
+    stop: bool,
+    install_service: bool,
+    uninstall: bool,
+    #[cfg(all(unix, feature = "plugins"))]
+    plugins: Vec<String>,
+    #[cfg(all(unix, feature = "tray"))]
+    tray: bool,
+}
+
+impl Args {
+    fn parse() -> Result<Args> {
+        let mut parsed = Args::default();
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => {
+                    parsed.config_path =
+                        Some(args.next().context("--config requires a path")?.into());
+                }
+                "--log-file" => {
+                    parsed.log_file =
+                        Some(args.next().context("--log-file requires a path")?.into());
+                }
+                "--permissive" => parsed.permissive = true,
+                "--stdout-json" => parsed.stdout_json = true,
+                "--record" => {
+                    parsed.record = Some(args.next().context("--record requires a path")?.into());
+                }
+                "replay" => {
+                    parsed.replay = Some(args.next().context("replay requires a path")?.into());
+                }
+                "--speed" => {
+                    let speed = args.next().context("--speed requires a factor")?;
+                    parsed.replay_speed = Some(speed.parse().context("Invalid --speed")?);
+                }
+                #[cfg(unix)]
+                "--daemon" => parsed.daemon = true,
+                #[cfg(unix)]
+                "--replace" => parsed.replace = true,
+                #[cfg(windows)]
+                "--service" => parsed.service = true,
+                #[cfg(all(unix, feature = "rest-api"))]
+                "stop" => parsed.stop = true,
+                "install-service" => parsed.install_service = true,
+                "--uninstall" => parsed.uninstall = true,
+                #[cfg(all(unix, feature = "plugins"))]
+                "--plugin" => {
+                    parsed
+                        .plugins
+                        .push(args.next().context("--plugin requires a path")?);
+                }
+                #[cfg(all(unix, feature = "tray"))]
+                "--tray" => parsed.tray = true,
+                arg => bail!("Unknown argument: {}", arg),
+            }
+        }
+        if parsed.replay_speed.is_some_and(|speed| speed <= 0.0) {
+            bail!("--speed has to be positive");
+        }
+        Ok(parsed)
+    }
+
+    /// What the installed service should be started with
+    fn service_args(&self) -> Result<Vec<String>> {
+        let mut args = Vec::new();
+        for (flag, path) in [
+            ("--config", &self.config_path),
+            ("--log-file", &self.log_file),
+        ] {
+            if let Some(path) = path {
+                args.push(flag.to_string());
+                args.push(std::path::absolute(path)?.to_string_lossy().into_owned());
+            }
+        }
+        if self.permissive {
+            args.push("--permissive".to_string());
+        }
+        #[cfg(all(unix, feature = "plugins"))]
+        for path in &self.plugins {
+            args.push("--plugin".to_string());
+            args.push(std::path::absolute(path)?.to_string_lossy().into_owned());
+        }
+        #[cfg(all(unix, feature = "tray"))]
+        if self.tray {
+            args.push("--tray".to_string());
+        }
+        Ok(args)
+    }
+}
+
+fn main() -> Result<()> {
+    #[allow(unused_mut)]
+    let mut args = Args::parse()?;
+
+    #[cfg(all(unix, feature = "rest-api"))]
+    if args.stop {
+        let response = Runtime::new()?.block_on(control::request(
+            &ControlServer::default_path(),
+            "POST",
+            "/stop",
+        ));
+        response.context("Failed to reach a running arRPC instance")?;
+        println!("Stopped");
+        return Ok(());
+    }
+
+    if args.install_service {
+        let installed = match args.uninstall {
+            true => service::uninstall()?,
+            false => service::install(&env::current_exe()?, &args.service_args()?)?,
+        };
+        println!(
+            "{} {}",
+            if args.uninstall {
+                "Removed"
+            } else {
+                "Installed"
+            },
+            installed
+        );
+        return Ok(());
+    }
+
+    #[cfg(unix)]
+    let lock = acquire_lock(args.replace)?;
+
+    #[cfg(unix)]
+    if args.daemon {
+        // A daemon has no terminal to log to, so it always gets a file
+        args.log_file
+            .get_or_insert_with(|| PersistedState::default_path().with_file_name("arrpc.log"));
+        daemonize()?;
+        lock.write_pid()?;
+    }
+
+    #[cfg(windows)]
+    if args.service {
+        // Like a daemon, a service has nowhere to log to but a file
+        args.log_file
+            .get_or_insert_with(|| PersistedState::default_path().with_file_name("arrpc.log"));
+        scm::run(move || {
+            let log_handle = init_logging(args.log_file.as_deref(), args.stdout_json)?;
+            Runtime::new()?.block_on(run(args, log_handle))
+        })?;
+        return Ok(());
+    }
+
+    let log_handle = init_logging(args.log_file.as_deref(), args.stdout_json)?;
+    Runtime::new()?.block_on(run(args, log_handle))
+}
+
+fn init_logging(log_file: Option<&Path>, stdout_json: bool) -> Result<LogHandle> {
+    let (filter, log_handle) = reload::Layer::new(LevelFilter::DEBUG);
+    let timer = time::ChronoLocal::new("%H:%M:%S".into());
+    let (stdout, stderr, file) = match log_file {
+        Some(path) => {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Failed to open {}", path.display()))?;
+            let layer = fmt::layer()
+                .with_timer(timer)
+                .with_ansi(false)
+                .with_writer(Mutex::new(file));
+            (None, None, Some(layer))
+        }
+        // Stdout carries the activities then, so logs go out of the way
+        None if stdout_json => (
+            None,
+            Some(fmt::layer().with_timer(timer).with_writer(std::io::stderr)),
+            None,
+        ),
+        None => (Some(fmt::layer().with_timer(timer)), None, None),
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(stdout)
+        .with(stderr)
+        .with(file)
+        .init();
+    Ok(log_handle)
+}
+
+async fn run(args: Args, log_handle: LogHandle) -> Result<()> {
     info!("{}", "arRPC Started".magenta().bold());
-    let bridge = BridgeServer::try_bind().await?;
-    let mut server = Server::try_bind().await?;
+    let mut builder = ArRpc::builder().state_file(PersistedState::default_path());
+    #[cfg(all(unix, feature = "plugins"))]
+    for path in args.plugins {
+        builder = builder.plugin(path);
+    }
+    if args.stdout_json {
+        builder = builder.sink(StdoutSink::default());
+    }
+    if let Some(path) = &args.record {
+        builder = builder.record(path);
+    }
+    let config_path = args.config_path.unwrap_or_else(Config::default_path);
+    let config = load_config(&config_path, &log_handle, args.permissive).await?;
+    for sink in &config.sinks {
+        builder = match sink {
+            SinkConfig::File { path } => builder.sink(
+                FileSink::open(path)
+                    .await
+                    .with_context(|| format!("Failed to open {}", path.display()))?,
+            ),
+            SinkConfig::Webhook { url } => builder.sink(WebhookSink::new(url)?),
+            #[cfg(all(unix, feature = "dbus"))]
+            SinkConfig::Dbus => builder.sink(
+                DbusSink::connect()
+                    .await
+                    .context("Failed to publish on D-Bus")?,
+            ),
+            #[cfg(all(unix, feature = "dbus"))]
+            SinkConfig::Notify => builder.sink(
+                NotifySink::connect()
+                    .await
+                    .context("Failed to connect to the session bus for notifications")?,
+            ),
+            #[cfg(not(all(unix, feature = "dbus")))]
+            SinkConfig::Dbus | SinkConfig::Notify => bail!("D-Bus support is not built in"),
+            #[cfg(feature = "mqtt")]
+            SinkConfig::Mqtt(config) => builder.sink(MqttSink::new(config.clone())),
+            #[cfg(not(feature = "mqtt"))]
+            SinkConfig::Mqtt(_) => bail!("MQTT support is not built in"),
+            SinkConfig::Relay { url, token } => builder.sink(RelaySink::new(url, token.clone())?),
+        };
+    }
+    let mut arrpc = builder.config(config).start().await?;
+    #[cfg(windows)]
+    scm::started();
+    if let Some(path) = args.replay {
+        let address = arrpc
+            .status()
+            .await
+            .addresses
+            .into_iter()
+            .find(|address| !address.starts_with("ws://"))
+            .context("Replaying needs the IPC socket")?;
+        let speed = args.replay_speed.unwrap_or(1.0);
+        tokio::spawn(async move {
+            match record::replay(&path, &address, speed).await {
+                Ok(()) => info!("{}", "Replay finished".cyan()),
+                Err(e) => warn!("Failed to replay {}: {}", path.display(), e),
+            }
+        });
+    }
+    #[allow(unused_mut)]
+    let mut events = Events::new().await?;
+    #[cfg(all(unix, feature = "tray"))]
+    if args.tray {
+        events.show_tray().await;
+    }
     loop {
         select! {
-            activity = server.recv() => {
-                if let Some(activity) = activity {
-                    bridge.send_activity(activity).await?;
-                } else {
+            _ = arrpc.wait() => break,
+            event = events.next() => match event {
+                Event::Shutdown => {
+                    // Just to make sure the ^C doesn't gets printed
+                    if !args.stdout_json {
+                        print!("\r");
+                    }
+                    info!("Shutting Down");
                     break;
-                };
+                }
+                Event::Reload => {
+                    if let Err(e) = reload(&arrpc, &config_path, &log_handle, args.permissive).await {
+                        warn!("{:#}", e);
+                    }
+                }
+                Event::Dump => {
+                    for line in arrpc.status().await.to_string().lines() {
+                        info!("{}", line);
+                    }
+                }
+                #[cfg(all(unix, feature = "rest-api"))]
+                Event::Control(request) => match request.command {
+                    ControlCommand::Reload => {
+                        let result = reload(&arrpc, &config_path, &log_handle, args.permissive).await;
+                        request.respond(result.map(|_| json!({})).map_err(|e| format!("{:#}", e)));
+                    }
+                    ControlCommand::Status => {
+                        let status = serde_json::to_value(arrpc.status().await);
+                        request.respond(status.map_err(|e| e.to_string()));
+                    }
+                    ControlCommand::Clients => {
+                        let status = arrpc.status().await;
+                        request.respond(Ok(json!({ "ipc": status.clients, "bridge": status.bridge_clients })));
+                    }
+                    ControlCommand::Stats => {
+                        let stats = serde_json::to_value(arrpc.stats());
+                        request.respond(stats.map_err(|e| e.to_string()));
+                    }
+                    ControlCommand::History => {
+                        let history = serde_json::to_value(arrpc.history().snapshot());
+                        request.respond(history.map_err(|e| e.to_string()));
+                    }
+                    ControlCommand::SetCustomStatus => {
+                        let result = match serde_json::from_value(request.body.clone().unwrap_or_default()) {
+                            Ok(status) => arrpc.set_custom_status(Some(status)).await.map_err(|e| e.to_string()),
+                            Err(e) => Err(e.to_string()),
+                        };
+                        request.respond(result.map(|_| json!({})));
+                    }
+                    ControlCommand::ClearCustomStatus => {
+                        let result = arrpc.set_custom_status(None).await;
+                        request.respond(result.map(|_| json!({})).map_err(|e| e.to_string()));
+                    }
+                    ControlCommand::PauseDetection | ControlCommand::ResumeDetection => {
+                        let paused = request.command == ControlCommand::PauseDetection;
+                        let result = arrpc.set_detection_paused(paused);
+                        request.respond(result.map(|_| json!({ "paused": paused })).map_err(|e| e.to_string()));
+                    }
+                    ControlCommand::EnablePrivacy | ControlCommand::DisablePrivacy => {
+                        let enabled = request.command == ControlCommand::EnablePrivacy;
+                        arrpc.set_privacy_mode(enabled);
+                        request.respond(Ok(json!({ "privacy_mode": enabled })));
+                    }
+                    ControlCommand::Pause | ControlCommand::Resume => {
+                        let paused = request.command == ControlCommand::Pause;
+                        arrpc.set_paused(paused).await;
+                        request.respond(Ok(json!({ "paused": paused })));
+                    }
+                    ControlCommand::Stop => {
+                        request.respond(Ok(json!({})));
+                        info!("Shutting Down");
+                        break;
+                    }
+                },
+                #[cfg(all(unix, feature = "tray"))]
+                Event::Tray(command) => {
+                    match command {
+                        TrayCommand::SetPaused(paused) => arrpc.set_paused(paused).await,
+                        TrayCommand::SetPrivacyMode(enabled) => arrpc.set_privacy_mode(enabled),
+                        TrayCommand::Quit => {
+                            info!("Shutting Down");
+                            break;
+                        }
+                    }
+                    events.refresh_tray(&arrpc).await;
+                }
+                #[cfg(all(unix, feature = "tray"))]
+                Event::RefreshTray => events.refresh_tray(&arrpc).await,
+            },
+        }
+    }
+    arrpc.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn acquire_lock(replace: bool) -> Result<InstanceLock> {
+    let path = InstanceLock::default_path();
+    match InstanceLock::acquire(&path) {
+        Err(ArRpcError::AlreadyRunning(Some(pid))) if replace => {
+            eprintln!("Replacing the running instance (pid {})", pid);
+            if let Ok(pid) = libc::pid_t::try_from(pid) {
+                unsafe { libc::kill(pid, libc::SIGTERM) };
             }
-            _ = signal::ctrl_c() => {
-                // Just to make sure the ^C doesn't gets printed
-                print!("\r");
-                info!("Shutting Down");
-                bridge.close().await?;
-                break;
+            // Give it a moment to shut down cleanly and release the lock
+            for _ in 0..50 {
+                std::thread::sleep(Duration::from_millis(100));
+                match InstanceLock::acquire(&path) {
+                    Err(ArRpcError::AlreadyRunning(_)) => continue,
+                    result => return Ok(result?),
+                }
             }
+            bail!("arRPC (pid {}) did not shut down in time", pid)
+        }
+        Err(e @ ArRpcError::AlreadyRunning(_)) => Err(anyhow::Error::new(e)
+            .context("Refusing to start a second instance, pass --replace to take over")),
+        result => Ok(result?),
+    }
+}
+
+// Double fork so the daemon is reparented to init and can never reacquire a terminal
+#[cfg(unix)]
+fn daemonize() -> Result<()> {
+    unsafe fn fork() -> Result<()> {
+        match libc::fork() {
+            -1 => Err(std::io::Error::last_os_error().into()),
+            0 => Ok(()),
+            _ => libc::_exit(0),
+        }
+    }
+
+    unsafe {
+        fork()?;
+        if libc::setsid() == -1 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        fork()?;
+        let null = libc::open(c"/dev/null".as_ptr(), libc::O_RDWR);
+        if null == -1 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+            libc::dup2(null, fd);
         }
+        libc::close(null);
     }
     Ok(())
 }
+
+async fn load_config(path: &Path, log_handle: &LogHandle, permissive: bool) -> Result<Config> {
+    let mut config = Config::load(path)
+        .await
+        .with_context(|| format!("Failed to load {}", path.display()))?;
+    let level: LevelFilter = config
+        .log_level
+        .parse()
+        .with_context(|| format!("Invalid log level: {}", config.log_level))?;
+    log_handle.modify(|filter| *filter = level)?;
+    redact::set_enabled(config.redact_logs);
+    config.ipc.permissive |= permissive;
+    Ok(config)
+}
+
+async fn reload(
+    arrpc: &ArRpc,
+    path: &Path,
+    log_handle: &LogHandle,
+    permissive: bool,
+) -> Result<()> {
+    let config = load_config(path, log_handle, permissive).await?;
+    arrpc.reload(config);
+    info!("{} {}", "Reloaded".cyan(), path.display().yellow().bold());
+    Ok(())
+}
+
+enum Event {
+    Shutdown,
+    #[cfg_attr(not(unix), allow(dead_code))]
+    Reload,
+    #[cfg_attr(not(unix), allow(dead_code))]
+    Dump,
+    #[cfg(all(unix, feature = "rest-api"))]
+    Control(ControlRequest),
+    #[cfg(all(unix, feature = "tray"))]
+    Tray(TrayCommand),
+    #[cfg(all(unix, feature = "tray"))]
+    RefreshTray,
+}
+
+struct Events {
+    signals: Signals,
+    #[cfg(all(unix, feature = "rest-api"))]
+    control: Option<ControlServer>,
+    /// The tray and how often it is brought up to date
+    #[cfg(all(unix, feature = "tray"))]
+    tray: Option<(Tray, Interval)>,
+}
+
+impl Events {
+    async fn new() -> Result<Self> {
+        Ok(Self {
+            signals: Signals::new()?,
+            #[cfg(all(unix, feature = "rest-api"))]
+            control: match ControlServer::try_bind(&ControlServer::default_path()).await {
+                Ok(control) => Some(control),
+                Err(e) => {
+                    warn!("Failed to start the control API: {}", e);
+                    None
+                }
+            },
+            #[cfg(all(unix, feature = "tray"))]
+            tray: None,
+        })
+    }
+
+    async fn next(&mut self) -> Event {
+        #[cfg(all(unix, feature = "rest-api"))]
+        let control = async {
+            match &mut self.control {
+                Some(control) => match control.recv().await {
+                    Some(request) => Event::Control(request),
+                    None => std::future::pending().await,
+                },
+                None => std::future::pending().await,
+            }
+        };
+        #[cfg(not(all(unix, feature = "rest-api")))]
+        let control = std::future::pending();
+        #[cfg(all(unix, feature = "tray"))]
+        let tray = async {
+            match &mut self.tray {
+                Some((tray, refresh)) => select! {
+                    Some(command) = tray.recv() => Event::Tray(command),
+                    _ = refresh.tick() => Event::RefreshTray,
+                },
+                None => std::future::pending().await,
+            }
+        };
+        #[cfg(not(all(unix, feature = "tray")))]
+        let tray = std::future::pending();
+        select! {
+            event = self.signals.next() => event,
+            event = control => event,
+            event = tray => event,
+        }
+    }
+}
+
+#[cfg(all(unix, feature = "tray"))]
+impl Events {
+    async fn show_tray(&mut self) {
+        match Tray::connect().await {
+            Ok(tray) => self.tray = Some((tray, interval(Duration::from_secs(2)))),
+            Err(e) => warn!("Failed to show the tray icon: {}", e),
+        }
+    }
+
+    async fn refresh_tray(&self, arrpc: &ArRpc) {
+        let Some((tray, _)) = &self.tray else {
+            return;
+        };
+        let status = arrpc.status().await;
+        let state = TrayState {
+            clients: status.clients.len(),
+            activities: status.activities.len(),
+            paused: arrpc.is_paused().await,
+            privacy_mode: arrpc.privacy_mode(),
+        };
+        if let Err(e) = tray.update(state).await {
+            warn!("Failed to update the tray icon: {}", e);
+        }
+    }
+}
+
+// Service managers stop us with SIGTERM rather than SIGINT, SIGHUP asks for a reload and
+// SIGUSR1 for a status dump
+#[cfg(unix)]
+struct Signals {
+    interrupt: Signal,
+    terminate: Signal,
+    hangup: Signal,
+    user_defined1: Signal,
+}
+
+#[cfg(unix)]
+impl Signals {
+    fn new() -> Result<Self> {
+        Ok(Self {
+            interrupt: signal(SignalKind::interrupt())?,
+            terminate: signal(SignalKind::terminate())?,
+            hangup: signal(SignalKind::hangup())?,
+            user_defined1: signal(SignalKind::user_defined1())?,
+        })
+    }
+
+    async fn next(&mut self) -> Event {
+        select! {
+            _ = self.interrupt.recv() => Event::Shutdown,
+            _ = self.terminate.recv() => Event::Shutdown,
+            _ = self.hangup.recv() => Event::Reload,
+            _ = self.user_defined1.recv() => Event::Dump,
+        }
+    }
+}
+
+#[cfg(not(unix))]
+struct Signals;
+
+#[cfg(not(unix))]
+impl Signals {
+    fn new() -> Result<Self> {
+        Ok(Self)
+    }
+
+    async fn next(&mut self) -> Event {
+        #[cfg(windows)]
+        let stopped = scm::stop_requested();
+        #[cfg(not(windows))]
+        let stopped = std::future::pending::<()>();
+        select! {
+            Ok(()) = signal::ctrl_c() => Event::Shutdown,
+            _ = stopped => Event::Shutdown,
+        }
+    }
+}
@@ -0,0 +1,41 @@
+use crate::structs::IpcActivityMessage;
+use std::sync::{Arc, RwLock};
+
+pub trait Middleware: Send + Sync {
+    /// Returning `None` drops the message before it reaches any consumer
+    fn on_activity(&self, msg: IpcActivityMessage) -> Option<IpcActivityMessage>;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(IpcActivityMessage) -> Option<IpcActivityMessage> + Send + Sync,
+{
+    fn on_activity(&self, msg: IpcActivityMessage) -> Option<IpcActivityMessage> {
+        self(msg)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct MiddlewareStack(Arc<RwLock<Vec<Arc<dyn Middleware>>>>);
+
+impl MiddlewareStack {
+    pub fn register<M: Middleware + 'static>(&self, middleware: M) {
+        self.register_arc(Arc::new(middleware));
+    }
+
+    pub fn register_arc(&self, middleware: Arc<dyn Middleware>) {
+        self.0.write().unwrap().push(middleware);
+    }
+
+    pub fn clear(&self) {
+        self.0.write().unwrap().clear();
+    }
+
+    pub fn apply(&self, msg: IpcActivityMessage) -> Option<IpcActivityMessage> {
+        self.0
+            .read()
+            .unwrap()
+            .iter()
+            .try_fold(msg, |msg, middleware| middleware.on_activity(msg))
+    }
+}
@@ -0,0 +1,312 @@
+//! Publishes the current activity to an MQTT broker, with discovery messages so Home Assistant
+//! picks it up without any setup
+
+use crate::{
+    config::MqttConfig,
+    error::{ArRpcError, Result},
+    sink::ActivitySink,
+    structs::IpcActivityMessage,
+};
+use owo_colors::OwoColorize;
+use serde_json::json;
+use std::{collections::BTreeMap, sync::Mutex, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{tcp::OwnedReadHalf, TcpStream},
+    select,
+    sync::watch,
+    task,
+    time::{self, timeout, Instant},
+};
+use tracing::{info, warn};
+
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const MAX_PACKET_SIZE: usize = 1 << 16;
+
+/// Keeps `<topic>/state` up to date with the latest activity as retained JSON, reconnecting
+/// whenever the connection drops. `<topic>/availability` is `online` while connected and the
+/// broker makes it `offline` once arRPC is gone.
+pub struct MqttSink {
+    broker: String,
+    activities: Mutex<BTreeMap<String, IpcActivityMessage>>,
+    state: watch::Sender<Vec<u8>>,
+}
+
+impl MqttSink {
+    /// Connects in the background, so a broker that is down doesn't hold up the start
+    pub fn new(config: MqttConfig) -> MqttSink {
+        let (tx, rx) = watch::channel(state(&BTreeMap::new()));
+        let broker = config.broker.clone();
+        task::spawn(run(config, rx));
+        MqttSink {
+            broker,
+            activities: Default::default(),
+            state: tx,
+        }
+    }
+}
+
+impl ActivitySink for MqttSink {
+    async fn publish(&self, msg: &IpcActivityMessage) -> Result<()> {
+        let payload = {
+            let mut activities = self.activities.lock().unwrap();
+            match msg.activity {
+                Some(_) => activities.insert(msg.socket_id.clone(), msg.clone()),
+                None => activities.remove(&msg.socket_id),
+            };
+            state(&activities)
+        };
+        self.state.send_replace(payload);
+        Ok(())
+    }
+
+    fn name(&self) -> String {
+        format!("MQTT {}", self.broker)
+    }
+}
+
+/// The latest activity for templates like `value_json.details`, and how many there are
+fn state(activities: &BTreeMap<String, IpcActivityMessage>) -> Vec<u8> {
+    let latest = activities
+        .values()
+        .filter_map(|msg| Some((msg, msg.activity.as_ref()?)))
+        .max_by_key(|(_, activity)| activity.created_at);
+    let state = match latest {
+        Some((msg, activity)) => json!({
+            "active": true,
+            "name": msg.process_name.as_deref().unwrap_or(&activity.application_id),
+            "application_id": activity.application_id,
+            "details": activity.details,
+            "state": activity.state,
+            "source": msg.source,
+            "started_at": activity.timestamps.as_ref().map(|timestamps| timestamps.start),
+            "activities": activities.len(),
+        }),
+        None => json!({ "active": false, "activities": 0 }),
+    };
+    serde_json::to_vec(&state).unwrap_or_default()
+}
+
+async fn run(config: MqttConfig, mut state: watch::Receiver<Vec<u8>>) {
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        let started = Instant::now();
+        match session(&config, &mut state).await {
+            Ok(()) => return,
+            Err(e) => {
+                // A connection that held for a while starts the backoff over
+                if started.elapsed() > MAX_BACKOFF {
+                    backoff = Duration::from_secs(1);
+                }
+                warn!(
+                    "MQTT broker {}: {}, retrying in {}s",
+                    config.broker,
+                    e,
+                    backoff.as_secs()
+                );
+            }
+        }
+        time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// One connection to the broker, until it drops or the sink is gone
+async fn session(config: &MqttConfig, state: &mut watch::Receiver<Vec<u8>>) -> Result<()> {
+    let address = match config.broker.contains(':') {
+        true => config.broker.clone(),
+        false => format!("{}:1883", config.broker),
+    };
+    let timed_out = || ArRpcError::Mqtt("Timed out connecting".to_string());
+    let stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(address))
+        .await
+        .map_err(|_| timed_out())??;
+    let (mut reader, mut writer) = stream.into_split();
+    let availability = format!("{}/availability", config.topic);
+    writer.write_all(&connect(config, &availability)).await?;
+    let (kind, body) = timeout(CONNECT_TIMEOUT, read_packet(&mut reader))
+        .await
+        .map_err(|_| timed_out())??;
+    match (kind >> 4, body.get(1)) {
+        (2, Some(0)) => {}
+        (2, Some(code)) => return Err(ArRpcError::Mqtt(refused(*code))),
+        _ => return Err(ArRpcError::Mqtt("Expected CONNACK".to_string())),
+    }
+    info!(
+        "{} {}",
+        "Connected to MQTT broker".cyan(),
+        config.broker.yellow().bold()
+    );
+
+    if let Some(prefix) = &config.discovery_prefix {
+        for (topic, payload) in discovery(&config.topic, prefix) {
+            writer.write_all(&publish(&topic, &payload)).await?;
+        }
+    }
+    writer.write_all(&publish(&availability, b"online")).await?;
+    let state_topic = format!("{}/state", config.topic);
+    let payload = state.borrow_and_update().clone();
+    writer.write_all(&publish(&state_topic, &payload)).await?;
+
+    // Nothing is subscribed to, so only ping responses come back
+    let mut reading = task::spawn(async move {
+        loop {
+            if let Err(e) = read_packet(&mut reader).await {
+                return e;
+            }
+        }
+    });
+    let mut ping = time::interval_at(Instant::now() + KEEP_ALIVE / 2, KEEP_ALIVE / 2);
+    let result = async {
+        loop {
+            select! {
+                changed = state.changed() => {
+                    if changed.is_err() {
+                        writer.write_all(&publish(&availability, b"offline")).await?;
+                        writer.write_all(&packet(0xe0, &[])).await?;
+                        return Ok(());
+                    }
+                    let payload = state.borrow_and_update().clone();
+                    writer.write_all(&publish(&state_topic, &payload)).await?;
+                }
+                _ = ping.tick() => writer.write_all(&packet(0xc0, &[])).await?,
+                e = &mut reading => return Err(e.unwrap_or(ArRpcError::ChannelClosed)),
+            }
+        }
+    }
+    .await;
+    reading.abort();
+    result
+}
+
+/// Home Assistant entities for whether something is played and what it is
+fn discovery(topic: &str, prefix: &str) -> Vec<(String, Vec<u8>)> {
+    let node = topic.replace('/', "_");
+    let state_topic = format!("{}/state", topic);
+    let availability_topic = format!("{}/availability", topic);
+    let device = json!({
+        "identifiers": [node],
+        "name": "arRPC",
+        "model": "arrpc-rs",
+        "sw_version": env!("CARGO_PKG_VERSION"),
+    });
+    let playing = json!({
+        "name": "Playing",
+        "unique_id": format!("{}_playing", node),
+        "icon": "mdi:gamepad-variant",
+        "state_topic": state_topic,
+        "value_template": "{{ 'ON' if value_json.active else 'OFF' }}",
+        "availability_topic": availability_topic,
+        "device": device,
+    });
+    let activity = json!({
+        "name": "Activity",
+        "unique_id": format!("{}_activity", node),
+        "icon": "mdi:controller",
+        "state_topic": state_topic,
+        "value_template": "{{ value_json.name if value_json.active else 'None' }}",
+        "json_attributes_topic": state_topic,
+        "availability_topic": availability_topic,
+        "device": device,
+    });
+    [
+        ("binary_sensor", "playing", playing),
+        ("sensor", "activity", activity),
+    ]
+    .into_iter()
+    .map(|(component, object, config)| {
+        let topic = format!("{}/{}/{}/{}/config", prefix, component, node, object);
+        (topic, serde_json::to_vec(&config).unwrap_or_default())
+    })
+    .collect()
+}
+
+fn refused(code: u8) -> String {
+    match code {
+        1 => "Unsupported protocol version".to_string(),
+        2 => "Client ID rejected".to_string(),
+        3 => "Broker unavailable".to_string(),
+        4 => "Bad username or password".to_string(),
+        5 => "Not authorized".to_string(),
+        code => format!("Connection refused with code {}", code),
+    }
+}
+
+/// A fixed header with the remaining length, then `body`
+fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![kind];
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        match len {
+            0 => {
+                packet.push(byte);
+                break;
+            }
+            _ => packet.push(byte | 0x80),
+        }
+    }
+    packet.extend(body);
+    packet
+}
+
+fn string(buf: &mut Vec<u8>, value: &[u8]) {
+    buf.extend((value.len() as u16).to_be_bytes());
+    buf.extend(value);
+}
+
+/// MQTT 3.1.1 with a clean session and a retained `offline` as the last will
+fn connect(config: &MqttConfig, will_topic: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    string(&mut body, b"MQTT");
+    body.push(4);
+    let mut flags = 0x02 | 0x04 | 0x20;
+    if config.username.is_some() {
+        flags |= 0x80;
+    }
+    if config.password.is_some() {
+        flags |= 0x40;
+    }
+    body.push(flags);
+    body.extend((KEEP_ALIVE.as_secs() as u16).to_be_bytes());
+    string(
+        &mut body,
+        format!("arrpc-{}", std::process::id()).as_bytes(),
+    );
+    string(&mut body, will_topic.as_bytes());
+    string(&mut body, b"offline");
+    for value in [&config.username, &config.password].into_iter().flatten() {
+        string(&mut body, value.as_bytes());
+    }
+    packet(0x10, &body)
+}
+
+/// Retained at QoS 0, only the latest value matters
+fn publish(topic: &str, payload: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(2 + topic.len() + payload.len());
+    string(&mut body, topic.as_bytes());
+    body.extend(payload);
+    packet(0x31, &body)
+}
+
+async fn read_packet(reader: &mut OwnedReadHalf) -> Result<(u8, Vec<u8>)> {
+    let kind = reader.read_u8().await?;
+    let mut len = 0;
+    for shift in [0, 7, 14, 21] {
+        let byte = reader.read_u8().await?;
+        len |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 != 0 {
+            continue;
+        }
+        if len > MAX_PACKET_SIZE {
+            return Err(ArRpcError::Mqtt(format!("Packet of {} bytes", len)));
+        }
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body).await?;
+        return Ok((kind, body));
+    }
+    Err(ArRpcError::Mqtt("Invalid packet length".to_string()))
+}
@@ -0,0 +1,97 @@
+//! Per-application rules that reshape activities of clients the user can't change, like
+//! `"details": "Now playing: {details}"`
+
+use crate::{
+    config::OverrideRule,
+    state::now_millis,
+    structs::{IpcActivity, IpcActivityMessage},
+};
+
+/// Applies every rule matching the application ID or process name of `msg`, in order. The
+/// variables always refer to the activity as the client sent it.
+pub fn apply(msg: &mut IpcActivityMessage, rules: &[OverrideRule]) {
+    let Some(activity) = &mut msg.activity else {
+        return;
+    };
+    let original = activity.clone();
+    let process_name = msg.process_name.as_deref().unwrap_or_default();
+    for rule in rules
+        .iter()
+        .filter(|rule| is_app(&rule.app, &original, process_name))
+    {
+        let render = |template: &str| render(template, &original, process_name);
+        if let Some(template) = &rule.details {
+            activity.details = render(template);
+        }
+        if let Some(template) = &rule.state {
+            activity.state = render(template);
+        }
+        if let Some(template) = &rule.large_text {
+            activity.assets.large_text = Some(render(template));
+        }
+        if let Some(template) = &rule.small_text {
+            activity.assets.small_text = Some(render(template));
+        }
+    }
+}
+
+/// Whether `app` is the application ID or, ignoring case, the process name
+pub(crate) fn is_app(app: &str, activity: &IpcActivity, process_name: &str) -> bool {
+    app == activity.application_id || app.eq_ignore_ascii_case(process_name)
+}
+
+/// Replaces `{variable}` in `template`, `{{` and `}}` stand for single braces and unknown
+/// variables are kept as they are
+pub fn render(template: &str, activity: &IpcActivity, process_name: &str) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find(['{', '}']) {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if rest.starts_with("{{") || rest.starts_with("}}") {
+            output.push_str(&rest[..1]);
+            rest = &rest[2..];
+            continue;
+        }
+        let Some(end) = rest.find('}').filter(|_| rest.starts_with('{')) else {
+            output.push_str(&rest[..1]);
+            rest = &rest[1..];
+            continue;
+        };
+        let name = &rest[1..end];
+        match variable(name, activity, process_name) {
+            Some(value) => output.push_str(&value),
+            None => output.push_str(&rest[..=end]),
+        }
+        rest = &rest[end + 1..];
+    }
+    output.push_str(rest);
+    output
+}
+
+fn variable(name: &str, activity: &IpcActivity, process_name: &str) -> Option<String> {
+    let assets = &activity.assets;
+    Some(match name {
+        "state" => activity.state.clone(),
+        "details" => activity.details.clone(),
+        "large_text" => assets.large_text.clone().unwrap_or_default(),
+        "small_text" => assets.small_text.clone().unwrap_or_default(),
+        "application_id" => activity.application_id.clone(),
+        "process_name" => process_name.to_string(),
+        "elapsed" => activity
+            .timestamps
+            .as_ref()
+            .map(|timestamps| elapsed(now_millis().saturating_sub(timestamps.start) / 1000))
+            .unwrap_or_default(),
+        _ => return None,
+    })
+}
+
+/// Like `1:02:03`, or `2:03` under an hour
+fn elapsed(secs: u64) -> String {
+    let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    match hours {
+        0 => format!("{}:{:02}", minutes, secs),
+        _ => format!("{}:{:02}:{:02}", hours, minutes, secs),
+    }
+}
@@ -0,0 +1,57 @@
+//! Per-user runtime directories, so several users on one machine don't share arRPC's files
+
+use crate::error::{ArRpcError, Result};
+use std::{
+    env,
+    fs::{self, DirBuilder},
+    io::ErrorKind,
+    os::unix::fs::{DirBuilderExt, MetadataExt},
+    path::{Path, PathBuf},
+};
+
+fn uid() -> u32 {
+    unsafe { libc::getuid() }
+}
+
+/// `$XDG_RUNTIME_DIR`, or `/run/user/<uid>` when started outside of a login session (cron, ssh
+/// without pam_systemd) as long as it belongs to us
+pub fn runtime_dir() -> Option<PathBuf> {
+    if let Some(directory) = env::var_os("XDG_RUNTIME_DIR") {
+        return Some(directory.into());
+    }
+    let directory = PathBuf::from(format!("/run/user/{}", uid()));
+    let metadata = fs::metadata(&directory).ok()?;
+    (metadata.is_dir() && metadata.uid() == uid()).then_some(directory)
+}
+
+/// Where the lock and the control socket go: `<runtime dir>/arrpc`, falling back to
+/// `arrpc-<uid>` in the temp directory so users don't take each other's
+pub fn arrpc_dir() -> PathBuf {
+    runtime_dir()
+        .map(|directory| directory.join("arrpc"))
+        .unwrap_or_else(|| env::temp_dir().join(format!("arrpc-{}", uid())))
+}
+
+/// Whether anyone may create files in `directory`, like `/tmp`. Sockets there can be taken by
+/// other users before we get to bind them.
+pub fn is_shared(directory: &Path) -> bool {
+    fs::metadata(directory).is_ok_and(|metadata| metadata.mode() & 0o002 != 0)
+}
+
+/// Creates `directory` only accessible to us, or makes sure an existing one is ours and not
+/// writable by others. In a shared temp directory it could have been set up by anyone.
+pub fn create_private_dir(directory: &Path) -> Result<()> {
+    if let Some(parent) = directory.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match DirBuilder::new().mode(0o700).create(directory) {
+        Ok(()) => return Ok(()),
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e.into()),
+    }
+    let metadata = fs::metadata(directory)?;
+    if !metadata.is_dir() || metadata.uid() != uid() || metadata.mode() & 0o002 != 0 {
+        return Err(ArRpcError::UnsafeDirectory(directory.to_path_buf()));
+    }
+    Ok(())
+}
@@ -0,0 +1,485 @@
+//! A small backtracking regex engine for the rewrite rules in the config, covering what is
+//! useful on activity texts: literals, `.`, `[a-z]` and `[^...]` classes, `\d`, `\w`, `\s`
+//! and their negations (ASCII only), `\b`, `^`, `$`, groups with `(...)` and `(?:...)`,
+//! `|`, the quantifiers `*`, `+`, `?` and `{n,m}` with lazy `?` variants, and a leading
+//! `(?i)` to ignore case.
+
+use crate::error::{ArRpcError, Result};
+use std::cell::Cell;
+
+/// Backtracking steps a single search may take before it gives up, so patterns like `(a|a)*`
+/// can't stall the pipeline
+const MAX_STEPS: usize = 100_000;
+
+#[derive(Debug, Clone)]
+enum Node {
+    Char(char),
+    Any,
+    Class {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    Start,
+    End,
+    WordBoundary,
+    Group(Box<Node>, Option<usize>),
+    Concat(Vec<Node>),
+    Alt(Vec<Node>),
+    Repeat {
+        node: Box<Node>,
+        min: usize,
+        max: Option<usize>,
+        greedy: bool,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Regex {
+    root: Node,
+    groups: usize,
+    ignore_case: bool,
+}
+
+type Captures = Vec<Option<(usize, usize)>>;
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Regex> {
+        let (ignore_case, pattern) = match pattern.strip_prefix("(?i)") {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        let mut parser = Parser {
+            chars: pattern.chars().collect(),
+            pos: 0,
+            groups: 0,
+        };
+        let root = parser.alternation()?;
+        if parser.pos < parser.chars.len() {
+            return Err(parser.error("unmatched )"));
+        }
+        Ok(Regex {
+            root,
+            groups: parser.groups + 1,
+            ignore_case,
+        })
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        self.find_at(&text, 0).is_some()
+    }
+
+    /// Replaces every match, `$0` to `$9` and `${N}` in `replacement` insert groups and `$$` a
+    /// dollar sign
+    pub fn replace_all(&self, text: &str, replacement: &str) -> String {
+        let chars: Vec<char> = text.chars().collect();
+        let mut output = String::with_capacity(text.len());
+        let mut pos = 0;
+        let mut copied = 0;
+        while pos <= chars.len() {
+            let Some(caps) = self.find_at(&chars, pos) else {
+                break;
+            };
+            let (start, end) = caps[0].unwrap();
+            output.extend(&chars[copied..start]);
+            expand(replacement, &chars, &caps, &mut output);
+            copied = end;
+            // Empty matches would be found again at the same spot
+            pos = match end > start {
+                true => end,
+                false => {
+                    output.extend(chars.get(end));
+                    copied = end + 1;
+                    end + 1
+                }
+            };
+        }
+        if copied < chars.len() {
+            output.extend(&chars[copied..]);
+        }
+        output
+    }
+
+    fn find_at(&self, text: &[char], from: usize) -> Option<Captures> {
+        let matcher = Matcher {
+            text,
+            ignore_case: self.ignore_case,
+            steps: Cell::new(0),
+        };
+        (from..=text.len()).find_map(|start| {
+            let mut caps = vec![None; self.groups];
+            let mut end = None;
+            let found = matcher.node(&self.root, start, &mut caps, &mut |pos, _| {
+                end = Some(pos);
+                true
+            });
+            if matcher.steps.get() > MAX_STEPS {
+                return None;
+            }
+            found.then(|| {
+                caps[0] = Some((start, end.unwrap()));
+                caps
+            })
+        })
+    }
+}
+
+/// A pattern matching `text` literally
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if r"\.+*?()|[]{}^$".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn expand(replacement: &str, text: &[char], caps: &Captures, output: &mut String) {
+    let mut chars = replacement.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            output.push(c);
+            continue;
+        }
+        let group = match chars.peek() {
+            Some('$') => {
+                chars.next();
+                output.push('$');
+                continue;
+            }
+            Some(d) if d.is_ascii_digit() => chars.next().and_then(|d| d.to_digit(10)),
+            Some('{') => {
+                let digits: String = chars.clone().skip(1).take_while(|c| *c != '}').collect();
+                match digits.parse::<u32>() {
+                    Ok(group) if chars.clone().nth(digits.len() + 1) == Some('}') => {
+                        chars.nth(digits.len() + 1);
+                        Some(group)
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        match group {
+            Some(group) => {
+                if let Some(Some((start, end))) = caps.get(group as usize) {
+                    output.extend(&text[*start..*end]);
+                }
+            }
+            None => output.push('$'),
+        }
+    }
+}
+
+struct Matcher<'a> {
+    text: &'a [char],
+    ignore_case: bool,
+    steps: Cell<usize>,
+}
+
+type Next<'k> = &'k mut dyn FnMut(usize, &mut Captures) -> bool;
+
+impl Matcher<'_> {
+    fn node(&self, node: &Node, pos: usize, caps: &mut Captures, next: Next) -> bool {
+        self.steps.set(self.steps.get() + 1);
+        if self.steps.get() > MAX_STEPS {
+            return false;
+        }
+        let current = self.text.get(pos).copied();
+        match node {
+            Node::Char(c) => current.is_some_and(|t| self.eq(t, *c)) && next(pos + 1, caps),
+            Node::Any => current.is_some_and(|t| t != '\n') && next(pos + 1, caps),
+            Node::Class { ranges, negated } => {
+                current.is_some_and(|t| self.in_class(t, ranges) != *negated) && next(pos + 1, caps)
+            }
+            Node::Start => pos == 0 && next(pos, caps),
+            Node::End => pos == self.text.len() && next(pos, caps),
+            Node::WordBoundary => {
+                let before = pos > 0 && is_word(self.text[pos - 1]);
+                let after = current.is_some_and(is_word);
+                before != after && next(pos, caps)
+            }
+            Node::Group(inner, index) => self.node(inner, pos, caps, &mut |end, caps| {
+                let Some(index) = *index else {
+                    return next(end, caps);
+                };
+                let previous = caps[index];
+                caps[index] = Some((pos, end));
+                if next(end, caps) {
+                    return true;
+                }
+                caps[index] = previous;
+                false
+            }),
+            Node::Concat(nodes) => self.concat(nodes, pos, caps, next),
+            Node::Alt(alternatives) => alternatives
+                .iter()
+                .any(|alternative| self.node(alternative, pos, caps, &mut *next)),
+            Node::Repeat {
+                node,
+                min,
+                max,
+                greedy,
+            } => self.repeat(node, (*min, *max, *greedy), 0, pos, caps, next),
+        }
+    }
+
+    fn concat(&self, nodes: &[Node], pos: usize, caps: &mut Captures, next: Next) -> bool {
+        match nodes.split_first() {
+            None => next(pos, caps),
+            Some((first, rest)) => self.node(first, pos, caps, &mut |pos, caps| {
+                self.concat(rest, pos, caps, &mut *next)
+            }),
+        }
+    }
+
+    fn repeat(
+        &self,
+        node: &Node,
+        bounds: (usize, Option<usize>, bool),
+        count: usize,
+        pos: usize,
+        caps: &mut Captures,
+        next: Next,
+    ) -> bool {
+        let (min, max, greedy) = bounds;
+        if count < min {
+            return self.node(node, pos, caps, &mut |end, caps| {
+                self.repeat(node, bounds, count + 1, end, caps, &mut *next)
+            });
+        }
+        let more = |caps: &mut Captures, next: Next| {
+            max.is_none_or(|max| count < max)
+                && self.node(node, pos, caps, &mut |end, caps| {
+                    // Another round that matched nothing would go on forever
+                    end > pos && self.repeat(node, bounds, count + 1, end, caps, &mut *next)
+                })
+        };
+        match greedy {
+            true => more(caps, &mut *next) || next(pos, caps),
+            false => next(pos, caps) || more(caps, next),
+        }
+    }
+
+    fn eq(&self, a: char, b: char) -> bool {
+        a == b || (self.ignore_case && a.to_lowercase().eq(b.to_lowercase()))
+    }
+
+    fn in_class(&self, c: char, ranges: &[(char, char)]) -> bool {
+        let within = |c: char| {
+            ranges
+                .iter()
+                .any(|(start, end)| (*start..=*end).contains(&c))
+        };
+        within(c)
+            || (self.ignore_case && (c.to_lowercase().any(within) || c.to_uppercase().any(within)))
+    }
+}
+
+fn is_word(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+const DIGIT: &[(char, char)] = &[('0', '9')];
+const WORD: &[(char, char)] = &[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')];
+const SPACE: &[(char, char)] = &[('\t', '\r'), (' ', ' ')];
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    groups: usize,
+}
+
+impl Parser {
+    fn error(&self, message: &str) -> ArRpcError {
+        ArRpcError::Pattern(format!("{} at position {}", message, self.pos))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let found = self.peek() == Some(c);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn alternation(&mut self) -> Result<Node> {
+        let mut alternatives = vec![self.concat()?];
+        while self.eat('|') {
+            alternatives.push(self.concat()?);
+        }
+        Ok(match alternatives.len() {
+            1 => alternatives.pop().unwrap(),
+            _ => Node::Alt(alternatives),
+        })
+    }
+
+    fn concat(&mut self) -> Result<Node> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            nodes.push(self.quantifier(atom)?);
+        }
+        Ok(Node::Concat(nodes))
+    }
+
+    fn atom(&mut self) -> Result<Node> {
+        let c = self.peek().unwrap();
+        self.pos += 1;
+        Ok(match c {
+            '.' => Node::Any,
+            '^' => Node::Start,
+            '$' => Node::End,
+            '(' => {
+                let index = match self.eat('?') {
+                    true if self.eat(':') => None,
+                    true => return Err(self.error("unsupported group")),
+                    false => {
+                        self.groups += 1;
+                        Some(self.groups)
+                    }
+                };
+                let inner = self.alternation()?;
+                if !self.eat(')') {
+                    return Err(self.error("unclosed group"));
+                }
+                Node::Group(Box::new(inner), index)
+            }
+            '[' => self.class()?,
+            '\\' => match self.escape()? {
+                Escape::Char(c) => Node::Char(c),
+                Escape::Class(ranges, negated) => Node::Class {
+                    ranges: ranges.to_vec(),
+                    negated,
+                },
+                Escape::WordBoundary => Node::WordBoundary,
+            },
+            '*' | '+' | '?' => return Err(self.error("nothing to repeat")),
+            c => Node::Char(c),
+        })
+    }
+
+    fn escape(&mut self) -> Result<Escape> {
+        let Some(c) = self.peek() else {
+            return Err(self.error("trailing backslash"));
+        };
+        self.pos += 1;
+        Ok(match c {
+            'd' => Escape::Class(DIGIT, false),
+            'D' => Escape::Class(DIGIT, true),
+            'w' => Escape::Class(WORD, false),
+            'W' => Escape::Class(WORD, true),
+            's' => Escape::Class(SPACE, false),
+            'S' => Escape::Class(SPACE, true),
+            'b' => Escape::WordBoundary,
+            'n' => Escape::Char('\n'),
+            't' => Escape::Char('\t'),
+            'r' => Escape::Char('\r'),
+            c if c.is_ascii_alphanumeric() => return Err(self.error("unknown escape")),
+            c => Escape::Char(c),
+        })
+    }
+
+    fn class(&mut self) -> Result<Node> {
+        let negated = self.eat('^');
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let Some(c) = self.peek() else {
+                return Err(self.error("unclosed class"));
+            };
+            self.pos += 1;
+            let start = match c {
+                ']' if !first => break,
+                '\\' => match self.escape()? {
+                    Escape::Char(c) => c,
+                    Escape::Class(class, false) => {
+                        ranges.extend_from_slice(class);
+                        first = false;
+                        continue;
+                    }
+                    _ => return Err(self.error("unsupported escape in class")),
+                },
+                c => c,
+            };
+            first = false;
+            let end = match (self.peek(), self.chars.get(self.pos + 1)) {
+                (Some('-'), Some(&end)) if end != ']' => {
+                    self.pos += 2;
+                    match end {
+                        '\\' => match self.escape()? {
+                            Escape::Char(c) => c,
+                            _ => return Err(self.error("invalid range")),
+                        },
+                        end => end,
+                    }
+                }
+                _ => start,
+            };
+            if end < start {
+                return Err(self.error("invalid range"));
+            }
+            ranges.push((start, end));
+        }
+        Ok(Node::Class { ranges, negated })
+    }
+
+    fn quantifier(&mut self, atom: Node) -> Result<Node> {
+        let (min, max) = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => match self.bounds() {
+                Some(bounds) => bounds,
+                // Like most engines, a brace that isn't a quantifier stands for itself
+                None => return Ok(atom),
+            },
+            _ => return Ok(atom),
+        };
+        if matches!(atom, Node::Start | Node::End | Node::WordBoundary) {
+            return Err(self.error("nothing to repeat"));
+        }
+        self.pos += 1;
+        let greedy = !self.eat('?');
+        Ok(Node::Repeat {
+            node: Box::new(atom),
+            min,
+            max,
+            greedy,
+        })
+    }
+
+    /// `{n}`, `{n,}` or `{n,m}`, leaving the position on the closing brace
+    fn bounds(&mut self) -> Option<(usize, Option<usize>)> {
+        let rest: String = self.chars[self.pos + 1..].iter().collect();
+        let inner = &rest[..rest.find('}')?];
+        let (min, max) = match inner.split_once(',') {
+            None => {
+                let n = inner.parse().ok()?;
+                (n, Some(n))
+            }
+            Some((min, "")) => (min.parse().ok()?, None),
+            Some((min, max)) => (min.parse().ok()?, Some(max.parse().ok()?)),
+        };
+        if max.is_some_and(|max| max < min) {
+            return None;
+        }
+        self.pos += inner.chars().count() + 1;
+        Some((min, max))
+    }
+}
+
+enum Escape {
+    Char(char),
+    Class(&'static [(char, char)], bool),
+    WordBoundary,
+}
@@ -0,0 +1,158 @@
+use crate::{
+    command::CommandHandler,
+    error::{ArRpcError, Result},
+    ipc::structs::IpcFrame,
+    middleware::Middleware,
+    structs::IpcActivityMessage,
+};
+use serde_json::Value;
+use std::{
+    ffi::{c_char, c_void, CStr, CString},
+    path::Path,
+    ptr,
+};
+use tracing::warn;
+
+// Plugins are shared libraries exporting a small C ABI where everything is passed as JSON.
+// Strings returned by the plugin as `*mut c_char` are given back to `arrpc_plugin_free`.
+//
+//   const char *arrpc_plugin_name(void);                          (optional)
+//   char *arrpc_plugin_on_activity(const char *message);          (optional, NULL drops it)
+//   const char *arrpc_plugin_commands(void);                      (optional, JSON array)
+//   char *arrpc_plugin_handle_command(const char *frame);         (optional, NULL for no data)
+//   void arrpc_plugin_free(char *data);
+type NameFn = unsafe extern "C" fn() -> *const c_char;
+type TransformFn = unsafe extern "C" fn(*const c_char) -> *mut c_char;
+type FreeFn = unsafe extern "C" fn(*mut c_char);
+
+pub struct Plugin {
+    name: String,
+    handle: *mut c_void,
+    on_activity: Option<TransformFn>,
+    handle_command: Option<TransformFn>,
+    commands: Vec<String>,
+    free: FreeFn,
+}
+
+// Plugins are required to be thread safe, the daemon calls into them from any worker thread
+unsafe impl Send for Plugin {}
+unsafe impl Sync for Plugin {}
+
+impl Plugin {
+    pub fn load(path: &Path) -> Result<Plugin> {
+        let error = |message: &str| ArRpcError::Plugin(format!("{}: {}", path.display(), message));
+        let c_path = CString::new(path.as_os_str().as_encoded_bytes())
+            .map_err(|_| error("Invalid plugin path"))?;
+
+        let handle = unsafe { libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        if handle.is_null() {
+            return Err(error(&last_dl_error()));
+        }
+
+        let symbol = |name: &CStr| {
+            let symbol = unsafe { libc::dlsym(handle, name.as_ptr()) };
+            (!symbol.is_null()).then_some(symbol)
+        };
+
+        let Some(free) = symbol(c"arrpc_plugin_free") else {
+            unsafe { libc::dlclose(handle) };
+            return Err(error("Missing arrpc_plugin_free"));
+        };
+        let free = unsafe { std::mem::transmute::<*mut c_void, FreeFn>(free) };
+        let on_activity = symbol(c"arrpc_plugin_on_activity")
+            .map(|f| unsafe { std::mem::transmute::<*mut c_void, TransformFn>(f) });
+        let handle_command = symbol(c"arrpc_plugin_handle_command")
+            .map(|f| unsafe { std::mem::transmute::<*mut c_void, TransformFn>(f) });
+
+        let name = symbol(c"arrpc_plugin_name")
+            .map(|f| unsafe { std::mem::transmute::<*mut c_void, NameFn>(f)() })
+            .and_then(borrowed_string)
+            .unwrap_or_else(|| path.display().to_string());
+
+        let commands = symbol(c"arrpc_plugin_commands")
+            .map(|f| unsafe { std::mem::transmute::<*mut c_void, NameFn>(f)() })
+            .and_then(borrowed_string)
+            .map(|commands| serde_json::from_str(&commands))
+            .transpose()?
+            .unwrap_or_default();
+
+        Ok(Plugin {
+            name,
+            handle,
+            on_activity,
+            handle_command,
+            commands,
+            free,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn commands(&self) -> &[String] {
+        &self.commands
+    }
+
+    fn call(&self, function: TransformFn, input: &str) -> Option<String> {
+        let input = CString::new(input).ok()?;
+        let output = unsafe { function(input.as_ptr()) };
+        if output.is_null() {
+            return None;
+        }
+        let result = borrowed_string(output);
+        unsafe { (self.free)(output) };
+        result
+    }
+}
+
+impl Middleware for Plugin {
+    fn on_activity(&self, msg: IpcActivityMessage) -> Option<IpcActivityMessage> {
+        let Some(on_activity) = self.on_activity else {
+            return Some(msg);
+        };
+        let Ok(input) = serde_json::to_string(&msg) else {
+            return Some(msg);
+        };
+        let output = self.call(on_activity, &input)?;
+        match serde_json::from_str(&output) {
+            Ok(msg) => Some(msg),
+            Err(e) => {
+                warn!("Plugin {} returned an invalid activity: {}", self.name, e);
+                Some(msg)
+            }
+        }
+    }
+}
+
+impl CommandHandler for Plugin {
+    fn handle(&self, _socket_id: usize, frame: &IpcFrame) -> Option<Value> {
+        let input = serde_json::to_string(frame).ok()?;
+        let output = self.call(self.handle_command?, &input)?;
+        serde_json::from_str(&output)
+            .map_err(|e| warn!("Plugin {} returned invalid data: {}", self.name, e))
+            .ok()
+    }
+}
+
+impl Drop for Plugin {
+    fn drop(&mut self) {
+        unsafe { libc::dlclose(self.handle) };
+        self.handle = ptr::null_mut();
+    }
+}
+
+fn borrowed_string(ptr: *const c_char) -> Option<String> {
+    if ptr.is_null() {
+        return None;
+    }
+    Some(
+        unsafe { CStr::from_ptr(ptr) }
+            .to_string_lossy()
+            .into_owned(),
+    )
+}
+
+fn last_dl_error() -> String {
+    borrowed_string(unsafe { libc::dlerror() }).unwrap_or_else(|| "Unknown error".into())
+}
@@ -0,0 +1,219 @@
+use crate::{
+    config::{PriorityPolicy, SnapshotStrategy},
+    structs::{IpcActivity, IpcActivityMessage},
+};
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::HashSet,
+};
+
+/// The activities the bridge shows, every one of them without a policy
+pub fn visible<'a>(
+    activities: impl IntoIterator<Item = &'a IpcActivityMessage>,
+    policy: Option<&PriorityPolicy>,
+) -> Vec<&'a IpcActivityMessage> {
+    let activities = activities.into_iter().filter(|msg| msg.activity.is_some());
+    match policy {
+        Some(policy) => activities
+            .max_by(|a, b| compare(a, b, policy))
+            .into_iter()
+            .collect(),
+        None => activities.collect(),
+    }
+}
+
+/// The activities a newly connected client catches up on, ordered so that the winner comes last
+pub fn snapshot<'a>(
+    activities: impl IntoIterator<Item = &'a IpcActivityMessage>,
+    policy: Option<&PriorityPolicy>,
+    strategy: Option<SnapshotStrategy>,
+) -> Vec<&'a IpcActivityMessage> {
+    // Matches what `visible` lets through
+    let strategy = strategy.unwrap_or(match policy {
+        Some(_) => SnapshotStrategy::Top(1),
+        None => SnapshotStrategy::All,
+    });
+    let policy = policy.unwrap_or(&PriorityPolicy::MostRecent);
+    let mut activities: Vec<_> = activities
+        .into_iter()
+        .filter(|msg| msg.activity.is_some())
+        .collect();
+    activities.sort_by(|a, b| compare(a, b, policy));
+    match strategy {
+        SnapshotStrategy::All => {}
+        SnapshotStrategy::Top(n) => {
+            activities.drain(..activities.len().saturating_sub(n));
+        }
+        SnapshotStrategy::PerType => {
+            let mut seen = HashSet::new();
+            activities.reverse();
+            activities
+                .retain(|msg| seen.insert(msg.activity.as_ref().map(|activity| activity.r#type)));
+            activities.reverse();
+        }
+    }
+    activities
+}
+
+/// Orders `a` after `b` if it wins over it
+pub fn compare(
+    a: &IpcActivityMessage,
+    b: &IpcActivityMessage,
+    policy: &PriorityPolicy,
+) -> Ordering {
+    let by_policy = match policy {
+        PriorityPolicy::MostRecent => Ordering::Equal,
+        PriorityPolicy::Ranking(ranking) => {
+            let rank = |msg| Reverse(position(msg, ranking).unwrap_or(usize::MAX));
+            rank(a).cmp(&rank(b))
+        }
+        PriorityPolicy::GameOverMusic => class(a).cmp(&class(b)),
+    };
+    let created_at =
+        |msg: &IpcActivityMessage| msg.activity.as_ref().map(|activity| activity.created_at);
+    by_policy
+        .then_with(|| created_at(a).cmp(&created_at(b)))
+        // Keeps the winner stable when two activities arrive in the same millisecond
+        .then_with(|| b.socket_id.cmp(&a.socket_id))
+}
+
+fn position(msg: &IpcActivityMessage, ranking: &[String]) -> Option<usize> {
+    let application_id = msg
+        .activity
+        .as_ref()
+        .map(|activity| &activity.application_id);
+    ranking
+        .iter()
+        .position(|entry| Some(entry) == application_id || Some(entry) == msg.process_name.as_ref())
+}
+
+fn class(msg: &IpcActivityMessage) -> u8 {
+    match msg.activity.as_ref().map(|activity| activity.r#type) {
+        Some(IpcActivity::PLAYING | IpcActivity::STREAMING | IpcActivity::COMPETING) => 2,
+        Some(IpcActivity::LISTENING) => 0,
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const GAME: u64 = IpcActivity::PLAYING;
+    const MUSIC: u64 = IpcActivity::LISTENING;
+
+    fn msg(socket_id: &str, r#type: u64, created_at: u64) -> IpcActivityMessage {
+        serde_json::from_value(json!({
+            "activity": {
+                "application_id": format!("app-{}", socket_id),
+                "state": "",
+                "details": "",
+                "flags": 0,
+                "type": r#type,
+                "metadata": {},
+                "instance": false,
+                "created_at": created_at,
+            },
+            "socket_id": socket_id,
+            "pid": 1,
+        }))
+        .unwrap()
+    }
+
+    fn ids(activities: Vec<&IpcActivityMessage>) -> Vec<&str> {
+        activities
+            .iter()
+            .map(|msg| msg.socket_id.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn compare_table() {
+        let ranking = PriorityPolicy::Ranking(vec!["app-ranked".to_string()]);
+        let cases = [
+            (
+                PriorityPolicy::MostRecent,
+                msg("1", GAME, 2),
+                msg("2", GAME, 1),
+                Ordering::Greater,
+            ),
+            (
+                PriorityPolicy::MostRecent,
+                msg("1", GAME, 1),
+                msg("2", GAME, 2),
+                Ordering::Less,
+            ),
+            // Same millisecond, the lower socket id wins
+            (
+                PriorityPolicy::MostRecent,
+                msg("1", GAME, 1),
+                msg("2", GAME, 1),
+                Ordering::Greater,
+            ),
+            (
+                PriorityPolicy::GameOverMusic,
+                msg("1", MUSIC, 2),
+                msg("2", GAME, 1),
+                Ordering::Less,
+            ),
+            (
+                PriorityPolicy::GameOverMusic,
+                msg("1", GAME, 1),
+                msg("2", GAME, 2),
+                Ordering::Less,
+            ),
+            (
+                ranking.clone(),
+                msg("ranked", GAME, 1),
+                msg("2", GAME, 2),
+                Ordering::Greater,
+            ),
+            (
+                ranking,
+                msg("1", GAME, 2),
+                msg("2", GAME, 1),
+                Ordering::Greater,
+            ),
+        ];
+        for (policy, a, b, expected) in cases {
+            assert_eq!(compare(&a, &b, &policy), expected, "{:?}", policy);
+            assert_eq!(compare(&b, &a, &policy), expected.reverse(), "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn visible_picks_one_winner_with_a_policy() {
+        let activities = [
+            msg("music", MUSIC, 3),
+            msg("game", GAME, 1),
+            msg("old", GAME, 0),
+        ];
+        assert_eq!(ids(visible(&activities, None)), ["music", "game", "old"]);
+        let most_recent = Some(&PriorityPolicy::MostRecent);
+        assert_eq!(ids(visible(&activities, most_recent)), ["music"]);
+        let game_over_music = Some(&PriorityPolicy::GameOverMusic);
+        assert_eq!(ids(visible(&activities, game_over_music)), ["game"]);
+    }
+
+    #[test]
+    fn snapshot_strategies() {
+        let activities = [
+            msg("music", MUSIC, 3),
+            msg("game", GAME, 1),
+            msg("newer", GAME, 2),
+        ];
+        let policy = Some(&PriorityPolicy::GameOverMusic);
+        let cases: [(SnapshotStrategy, &[&str]); 4] = [
+            (SnapshotStrategy::All, &["music", "game", "newer"]),
+            (SnapshotStrategy::Top(0), &[]),
+            (SnapshotStrategy::Top(2), &["game", "newer"]),
+            // The older game loses to the newer one of the same type
+            (SnapshotStrategy::PerType, &["music", "newer"]),
+        ];
+        for (strategy, expected) in cases {
+            let snapshot = snapshot(&activities, policy, Some(strategy));
+            assert_eq!(ids(snapshot), expected, "{:?}", strategy);
+        }
+    }
+}
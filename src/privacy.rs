@@ -0,0 +1,87 @@
+use crate::structs::{ActivityEvent, ActivitySource, IpcActivity, IpcActivityMessage};
+use std::collections::HashMap;
+
+/// Cuts activities down to the application while privacy mode is on, and brings the full ones
+/// back once it is turned off. The custom status is left alone, the user set it themselves.
+#[derive(Debug, Default)]
+pub struct PrivacyFilter {
+    enabled: bool,
+    /// The latest activity of every socket, as it would be shown without the filter
+    current: HashMap<String, Box<IpcActivityMessage>>,
+}
+
+impl PrivacyFilter {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            current: HashMap::new(),
+        }
+    }
+
+    /// The events to pass on instead of `event`
+    pub fn apply(&mut self, event: ActivityEvent) -> Vec<ActivityEvent> {
+        if ActivitySource::of(event.socket_id()) == ActivitySource::Custom {
+            return vec![event];
+        }
+        match event {
+            ActivityEvent::Set(msg) if msg.activity.is_some() => {
+                self.current.insert(msg.socket_id.clone(), msg.clone());
+                vec![ActivityEvent::Set(self.shown(msg))]
+            }
+            ActivityEvent::Set(_)
+            | ActivityEvent::Clear { .. }
+            | ActivityEvent::Disconnect { .. } => {
+                self.current.remove(event.socket_id());
+                vec![event]
+            }
+            event => vec![event],
+        }
+    }
+
+    /// Strips an activity that another filter shows again, like once the user is back, without
+    /// taking it as the latest one
+    pub fn reapply(&self, event: ActivityEvent) -> ActivityEvent {
+        match event {
+            ActivityEvent::Set(msg)
+                if ActivitySource::of(&msg.socket_id) != ActivitySource::Custom =>
+            {
+                ActivityEvent::Set(self.shown(msg))
+            }
+            event => event,
+        }
+    }
+
+    /// The events that strip or restore the current activities
+    pub fn set_enabled(&mut self, enabled: bool) -> Vec<ActivityEvent> {
+        if self.enabled == enabled {
+            return Vec::new();
+        }
+        self.enabled = enabled;
+        self.current
+            .values()
+            .map(|msg| ActivityEvent::Set(self.shown(msg.clone())))
+            .collect()
+    }
+
+    fn shown(&self, mut msg: Box<IpcActivityMessage>) -> Box<IpcActivityMessage> {
+        if let Some(activity) = msg.activity.as_mut().filter(|_| self.enabled) {
+            strip(activity);
+        }
+        msg
+    }
+}
+
+/// Leaves only what Discord needs to show "Playing <app name>", with its artwork and time
+pub fn strip(activity: &mut IpcActivity) {
+    activity.state.clear();
+    activity.details.clear();
+    activity.emoji = None;
+    activity.assets.large_text = None;
+    activity.assets.small_text = None;
+    activity.buttons.clear();
+    activity.metadata.button_urls.clear();
+    activity.url = None;
+    activity.party = None;
+    activity.state_url = None;
+    activity.details_url = None;
+}
@@ -0,0 +1,43 @@
+#[cfg(target_os = "linux")]
+use std::fs;
+
+#[cfg(target_os = "linux")]
+pub fn name(pid: usize) -> Option<String> {
+    let comm = fs::read_to_string(format!("/proc/{}/comm", pid)).ok()?;
+    Some(comm.trim_end().to_string())
+}
+
+#[cfg(target_os = "linux")]
+pub fn path(pid: usize) -> Option<String> {
+    let exe = fs::read_link(format!("/proc/{}/exe", pid)).ok()?;
+    Some(exe.to_string_lossy().into_owned())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn name(_pid: usize) -> Option<String> {
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn path(_pid: usize) -> Option<String> {
+    None
+}
+
+#[cfg(unix)]
+pub fn is_alive(pid: usize) -> bool {
+    // 0 would signal our own process group, it stands for no known process
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    if pid == 0 {
+        return false;
+    }
+    // Signal 0 only checks whether the process exists, EPERM means it belongs to someone else
+    let alive = unsafe { libc::kill(pid, 0) } == 0;
+    alive || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+pub fn is_alive(pid: usize) -> bool {
+    pid != 0
+}
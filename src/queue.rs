@@ -0,0 +1,182 @@
+use crate::{
+    config::{Backpressure, QueueConfig},
+    ipc::structs::IpcMessage,
+    structs::ActivityEvent,
+};
+use std::{
+    collections::VecDeque,
+    fmt,
+    pin::pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::sync::{
+    mpsc::error::{SendError, TrySendError},
+    Notify,
+};
+
+/// Items that only matter as long as no newer one replaced them
+pub trait Coalesce {
+    fn coalesces_with(&self, other: &Self) -> bool;
+}
+
+impl Coalesce for ActivityEvent {
+    fn coalesces_with(&self, other: &Self) -> bool {
+        matches!(self, ActivityEvent::Set(_) | ActivityEvent::Clear { .. })
+            && matches!(other, ActivityEvent::Set(_) | ActivityEvent::Clear { .. })
+            && self.socket_id() == other.socket_id()
+    }
+}
+
+impl Coalesce for (usize, IpcMessage) {
+    fn coalesces_with(&self, other: &Self) -> bool {
+        let is_activity = |msg: &IpcMessage| matches!(msg, IpcMessage::Frame(frame) if frame.cmd == "SET_ACTIVITY");
+        self.0 == other.0 && is_activity(&self.1) && is_activity(&other.1)
+    }
+}
+
+struct Shared<T> {
+    queue: Mutex<VecDeque<T>>,
+    config: QueueConfig,
+    senders: AtomicUsize,
+    receiver_closed: AtomicBool,
+    item_ready: Notify,
+    space_ready: Notify,
+    receiver_gone: Notify,
+}
+
+/// A bounded mpsc channel which applies a [`Backpressure`] policy once it is full
+pub fn channel<T: Coalesce>(config: QueueConfig) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(VecDeque::new()),
+        config: QueueConfig {
+            capacity: config.capacity.max(1),
+            ..config
+        },
+        senders: AtomicUsize::new(1),
+        receiver_closed: AtomicBool::new(false),
+        item_ready: Notify::new(),
+        space_ready: Notify::new(),
+        receiver_gone: Notify::new(),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Coalesce> Sender<T> {
+    pub async fn send(&self, mut item: T) -> Result<(), SendError<T>> {
+        loop {
+            let mut space_ready = pin!(self.shared.space_ready.notified());
+            space_ready.as_mut().enable();
+            match self.try_send(item) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Closed(item)) => return Err(SendError(item)),
+                Err(TrySendError::Full(rejected)) => item = rejected,
+            }
+            space_ready.await;
+        }
+    }
+
+    /// Like `send`, but hands the item back instead of waiting for space
+    pub fn try_send(&self, item: T) -> Result<(), TrySendError<T>> {
+        if self.shared.receiver_closed.load(Ordering::SeqCst) {
+            return Err(TrySendError::Closed(item));
+        }
+        let mut queue = self.shared.queue.lock().unwrap();
+        let policy = self.shared.config.backpressure;
+        if policy == Backpressure::CoalesceLatest {
+            if let Some(queued) = queue.iter_mut().find(|queued| queued.coalesces_with(&item)) {
+                *queued = item;
+                return Ok(());
+            }
+        }
+        if queue.len() >= self.shared.config.capacity {
+            if policy != Backpressure::DropOldest {
+                return Err(TrySendError::Full(item));
+            }
+            queue.pop_front();
+        }
+        queue.push_back(item);
+        self.shared.item_ready.notify_one();
+        Ok(())
+    }
+}
+
+impl<T> Sender<T> {
+    /// Resolves once the receiver is gone
+    pub async fn closed(&self) {
+        loop {
+            let mut receiver_gone = pin!(self.shared.receiver_gone.notified());
+            receiver_gone.as_mut().enable();
+            if self.shared.receiver_closed.load(Ordering::SeqCst) {
+                return;
+            }
+            receiver_gone.await;
+        }
+    }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sender")
+            .field("config", &self.shared.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::SeqCst);
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.shared.item_ready.notify_one();
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Returns `None` once every sender is gone and the queue is empty
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            let mut item_ready = pin!(self.shared.item_ready.notified());
+            item_ready.as_mut().enable();
+            if let Some(item) = self.shared.queue.lock().unwrap().pop_front() {
+                self.shared.space_ready.notify_one();
+                return Some(item);
+            }
+            if self.shared.senders.load(Ordering::SeqCst) == 0 {
+                return None;
+            }
+            item_ready.await;
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.receiver_closed.store(true, Ordering::SeqCst);
+        self.shared.space_ready.notify_waiters();
+        self.shared.receiver_gone.notify_waiters();
+    }
+}
@@ -0,0 +1,151 @@
+use crate::{
+    error::{ArRpcError, Result},
+    ipc::structs::IpcMessage,
+    state::now_millis,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::HashMap, path::Path, sync::Arc, time::Duration};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{self, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    sync::Mutex,
+    task,
+    time::{sleep_until, Instant},
+};
+use tracing::{debug, warn};
+
+/// One line of a recording, the message as it would be sent over IPC
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecordedMessage {
+    /// ms since the epoch
+    pub at: u64,
+    pub socket_id: usize,
+    pub op: i32,
+    pub data: Value,
+}
+
+/// Appends every message decoded from an RPC client to a file, so it can be replayed later
+#[derive(Clone)]
+pub struct Recorder {
+    file: Arc<Mutex<File>>,
+}
+
+impl Recorder {
+    pub async fn open(path: &Path) -> Result<Recorder> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        Ok(Recorder {
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    pub async fn record(&self, socket_id: usize, msg: &IpcMessage) {
+        if let Err(e) = self.try_record(socket_id, msg).await {
+            warn!("Failed to record message of {}: {}", socket_id, e);
+        }
+    }
+
+    async fn try_record(&self, socket_id: usize, msg: &IpcMessage) -> Result<()> {
+        let encoded = msg.try_encode()?;
+        let recorded = RecordedMessage {
+            at: now_millis(),
+            socket_id,
+            op: i32::from_le_bytes(encoded[0..4].try_into().unwrap()),
+            data: serde_json::from_slice(&encoded[8..])?,
+        };
+        let mut line = serde_json::to_vec(&recorded)?;
+        line.push(b'\n');
+        self.file.lock().await.write_all(&line).await?;
+        Ok(())
+    }
+}
+
+/// Plays a recording back to the IPC socket or named pipe at `address`, with one connection per
+/// recorded socket. `speed` divides the time between messages, `2.0` plays twice as fast.
+pub async fn replay(path: &Path, address: &str, speed: f64) -> Result<()> {
+    let mut lines = BufReader::new(File::open(path).await?).lines();
+    let mut messages = Vec::new();
+    let mut number = 0;
+    while let Some(line) = lines.next_line().await? {
+        number += 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let msg: RecordedMessage = serde_json::from_str(&line)
+            .map_err(|e| ArRpcError::Replay(format!("line {}: {}", number, e)))?;
+        messages.push(msg);
+    }
+    let Some(first) = messages.first().map(|msg| msg.at) else {
+        return Ok(());
+    };
+
+    let started = Instant::now();
+    let mut connections: HashMap<usize, Writer> = HashMap::new();
+    for msg in messages {
+        let offset = msg.at.saturating_sub(first) as f64 / 1000.0 / speed.max(f64::MIN_POSITIVE);
+        sleep_until(started + Duration::from_secs_f64(offset.min(u32::MAX as f64))).await;
+        match msg.op {
+            // The server notices a closed connection by itself
+            2 => {
+                connections.remove(&msg.socket_id);
+                continue;
+            }
+            // Pings of the server are answered as they come in instead
+            4 => continue,
+            _ => {}
+        }
+        let writer = match connections.get(&msg.socket_id) {
+            Some(writer) => writer.clone(),
+            None => {
+                let writer = connect(address).await?;
+                connections.insert(msg.socket_id, writer.clone());
+                writer
+            }
+        };
+        let data = serde_json::to_vec(&msg.data)?;
+        let mut frame = msg.op.to_le_bytes().to_vec();
+        frame.extend_from_slice(&(data.len() as i32).to_le_bytes());
+        frame.extend_from_slice(&data);
+        writer.lock().await.write_all(&frame).await?;
+    }
+    Ok(())
+}
+
+type Writer = Arc<Mutex<Box<dyn AsyncWrite + Send + Unpin>>>;
+
+/// Answers are read and thrown away so the server never blocks on a full socket, except for
+/// pings which have to be answered
+async fn connect(address: &str) -> Result<Writer> {
+    #[cfg(unix)]
+    let stream = tokio::net::UnixStream::connect(address).await?;
+    #[cfg(windows)]
+    let stream = tokio::net::windows::named_pipe::ClientOptions::new().open(address)?;
+    let (mut read, write) = io::split(stream);
+    let writer: Writer = Arc::new(Mutex::new(Box::new(write)));
+    let pong = writer.clone();
+    task::spawn(async move {
+        loop {
+            match IpcMessage::try_decode(&mut read).await {
+                Ok(IpcMessage::Ping(data)) => {
+                    let mut writer = pong.lock().await;
+                    if IpcMessage::Pong(data).write_to(&mut *writer).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    debug!("Replayed connection closed: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+    Ok(writer)
+}
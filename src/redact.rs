@@ -0,0 +1,70 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    fmt::{self, Debug, Display},
+    hash::{Hash, Hasher},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turns redaction of log output on or off for the whole process
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Free form text like activity details, elided down to its length
+pub fn text<T: AsRef<str> + ?Sized>(text: &T) -> Redacted<'_> {
+    Redacted {
+        value: text.as_ref(),
+        hash: false,
+    }
+}
+
+/// Identifiers like client IDs, replaced by a hash so lines can still be correlated
+pub fn id<T: AsRef<str> + ?Sized>(id: &T) -> Redacted<'_> {
+    Redacted {
+        value: id.as_ref(),
+        hash: true,
+    }
+}
+
+/// Formats as the value itself unless redaction is enabled
+pub struct Redacted<'a> {
+    value: &'a str,
+    hash: bool,
+}
+
+impl Redacted<'_> {
+    fn fmt_redacted(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.hash {
+            // Fixed keys, so the same ID hashes the same across lines and restarts
+            let mut hasher = DefaultHasher::new();
+            self.value.hash(&mut hasher);
+            write!(f, "#{:08x}", hasher.finish() as u32)
+        } else {
+            write!(f, "<{} chars>", self.value.chars().count())
+        }
+    }
+}
+
+impl Display for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match enabled() {
+            true => self.fmt_redacted(f),
+            false => Display::fmt(self.value, f),
+        }
+    }
+}
+
+impl Debug for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match enabled() {
+            true => self.fmt_redacted(f),
+            false => Debug::fmt(self.value, f),
+        }
+    }
+}
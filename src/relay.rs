@@ -0,0 +1,197 @@
+//! Forwards activities to the bridge of another instance, for games running on a different
+//! machine than the Discord client
+
+use crate::{
+    error::{ArRpcError, Result},
+    sink::ActivitySink,
+    structs::IpcActivityMessage,
+};
+use futures_util::{SinkExt, StreamExt};
+use owo_colors::OwoColorize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    select,
+    sync::mpsc::{self, error::TrySendError},
+    task,
+    time::{self, timeout, Instant},
+};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{
+        client::IntoClientRequest,
+        http::{header::AUTHORIZATION, HeaderValue},
+        Message,
+    },
+};
+use tracing::{info, warn};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const QUEUE_SIZE: usize = 64;
+
+/// Sends every message to a bridge that takes them, see `bridge.accept_relay`. The current
+/// activities are sent again on every reconnect, as the other side drops them when the
+/// connection does.
+pub struct RelaySink {
+    url: String,
+    shared: Arc<Shared>,
+    tx: mpsc::Sender<IpcActivityMessage>,
+}
+
+struct Shared {
+    current: Mutex<BTreeMap<String, IpcActivityMessage>>,
+    /// Set when the queue overflowed, so everything is sent again instead
+    resync: AtomicBool,
+}
+
+impl RelaySink {
+    /// Only `ws://` URLs are supported, connecting happens in the background. `token` is what
+    /// the other side has as `bridge.relay_token`.
+    pub fn new(url: &str, token: Option<String>) -> Result<RelaySink> {
+        if !url.starts_with("ws://") {
+            return Err(ArRpcError::Relay(format!("{} is not a ws:// URL", url)));
+        }
+        let token = token
+            .map(|token| HeaderValue::from_str(&format!("Bearer {}", token)))
+            .transpose()
+            .map_err(|_| ArRpcError::Relay("The token can't be sent in a header".to_string()))?;
+        let shared = Arc::new(Shared {
+            current: Default::default(),
+            resync: AtomicBool::new(false),
+        });
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        task::spawn(run(url.to_string(), token, shared.clone(), rx));
+        Ok(RelaySink {
+            url: url.to_string(),
+            shared,
+            tx,
+        })
+    }
+}
+
+impl ActivitySink for RelaySink {
+    async fn publish(&self, msg: &IpcActivityMessage) -> Result<()> {
+        {
+            let mut current = self.shared.current.lock().unwrap();
+            match msg.activity {
+                Some(_) => current.insert(msg.socket_id.clone(), msg.clone()),
+                None => current.remove(&msg.socket_id),
+            };
+        }
+        if let Err(TrySendError::Full(_)) = self.tx.try_send(msg.clone()) {
+            self.shared.resync.store(true, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn name(&self) -> String {
+        format!("relay {}", self.url)
+    }
+}
+
+async fn run(
+    url: String,
+    token: Option<HeaderValue>,
+    shared: Arc<Shared>,
+    mut rx: mpsc::Receiver<IpcActivityMessage>,
+) {
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        let started = Instant::now();
+        match session(&url, token.as_ref(), &shared, &mut rx).await {
+            Ok(()) => return,
+            Err(e) => {
+                // A connection that held for a while starts the backoff over
+                if started.elapsed() > MAX_BACKOFF {
+                    backoff = Duration::from_secs(1);
+                }
+                warn!(
+                    "Relay to {}: {}, retrying in {}s",
+                    url,
+                    e,
+                    backoff.as_secs()
+                );
+            }
+        }
+        time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// What the other side has to be sent to be up to date, given what it has. Everything queued
+/// so far is covered by it.
+fn resync(
+    shared: &Shared,
+    rx: &mut mpsc::Receiver<IpcActivityMessage>,
+    remote: &HashMap<String, IpcActivityMessage>,
+) -> Vec<IpcActivityMessage> {
+    while rx.try_recv().is_ok() {}
+    shared.resync.store(false, Ordering::Relaxed);
+    let current = shared.current.lock().unwrap();
+    let cleared = remote
+        .iter()
+        .filter(|(socket_id, _)| !current.contains_key(*socket_id))
+        .map(|(_, msg)| IpcActivityMessage {
+            activity: None,
+            ..msg.clone()
+        });
+    cleared.chain(current.values().cloned()).collect()
+}
+
+/// One connection, until it drops or the sink is gone
+async fn session(
+    url: &str,
+    token: Option<&HeaderValue>,
+    shared: &Shared,
+    rx: &mut mpsc::Receiver<IpcActivityMessage>,
+) -> Result<()> {
+    let mut request = url.into_client_request()?;
+    if let Some(token) = token {
+        request.headers_mut().insert(AUTHORIZATION, token.clone());
+    }
+    let (stream, _) = timeout(CONNECT_TIMEOUT, connect_async(request))
+        .await
+        .map_err(|_| ArRpcError::Relay("Timed out connecting".to_string()))??;
+    let (mut write, mut read) = stream.split();
+    info!("{} {}", "Relaying to".cyan(), url.yellow().bold());
+    // Activities the other side has from this connection
+    let mut remote = HashMap::new();
+    let mut messages = resync(shared, rx, &remote);
+    loop {
+        for msg in messages.drain(..) {
+            let text = serde_json::to_string(&msg)?;
+            match msg.activity {
+                Some(_) => remote.insert(msg.socket_id.clone(), msg),
+                None => remote.remove(&msg.socket_id),
+            };
+            write.send(Message::Text(text)).await?;
+        }
+        select! {
+            msg = rx.recv() => {
+                let Some(msg) = msg else {
+                    let _ = write.send(Message::Close(None)).await;
+                    return Ok(());
+                };
+                messages = match shared.resync.load(Ordering::Relaxed) {
+                    true => resync(shared, rx, &remote),
+                    false => vec![msg],
+                };
+            }
+            // The other side sends its own activities too, they don't matter here
+            msg = read.next() => match msg {
+                Some(Ok(Message::Close(_))) | None => {
+                    return Err(ArRpcError::Relay("Connection closed".to_string()));
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+            },
+        }
+    }
+}
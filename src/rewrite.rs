@@ -0,0 +1,75 @@
+use crate::{
+    config::{RewriteRule, SharedConfig},
+    middleware::Middleware,
+    overrides::is_app,
+    pattern::Regex,
+    structs::IpcActivityMessage,
+};
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+type Compiled = Arc<Vec<(RewriteRule, Regex)>>;
+
+/// Applies `activity.rewrites` to the state, details and asset texts of every activity. The
+/// rules are compiled again whenever a reload changes them.
+pub struct Rewriter {
+    config: SharedConfig,
+    compiled: Mutex<(Vec<RewriteRule>, Compiled)>,
+}
+
+impl Rewriter {
+    pub fn new(config: SharedConfig) -> Self {
+        Self {
+            config,
+            compiled: Mutex::default(),
+        }
+    }
+
+    fn rules(&self) -> Compiled {
+        let config = self.config.read().unwrap();
+        let rules = &config.activity.rewrites;
+        let mut compiled = self.compiled.lock().unwrap();
+        if compiled.0 != *rules {
+            let regexes = rules
+                .iter()
+                .filter_map(|rule| match Regex::new(&rule.pattern) {
+                    Ok(regex) => Some((rule.clone(), regex)),
+                    Err(e) => {
+                        warn!("Ignoring rewrite rule {:?}: {}", rule.pattern, e);
+                        None
+                    }
+                })
+                .collect();
+            *compiled = (rules.clone(), Arc::new(regexes));
+        }
+        compiled.1.clone()
+    }
+}
+
+impl Middleware for Rewriter {
+    fn on_activity(&self, mut msg: IpcActivityMessage) -> Option<IpcActivityMessage> {
+        let Some(activity) = &mut msg.activity else {
+            return Some(msg);
+        };
+        let rules = self.rules();
+        let process_name = msg.process_name.as_deref().unwrap_or_default();
+        for (rule, regex) in rules.iter() {
+            if rule
+                .app
+                .as_deref()
+                .is_some_and(|app| !is_app(app, activity, process_name))
+            {
+                continue;
+            }
+            let assets = &mut activity.assets;
+            for text in [&mut activity.state, &mut activity.details]
+                .into_iter()
+                .chain(assets.large_text.as_mut())
+                .chain(assets.small_text.as_mut())
+            {
+                *text = regex.replace_all(text, &rule.replace);
+            }
+        }
+        Some(msg)
+    }
+}
@@ -0,0 +1,72 @@
+//! Cycles through presences from the config, like the scripts people run against pypresence
+
+use crate::{
+    config::{ActivityConfig, RotationConfig},
+    state::now_millis,
+    structs::{ActivityEvent, ActivitySource, IpcActivityMessage, IpcPartialActivityMessage},
+    validate,
+};
+use futures_util::{stream, Stream};
+use owo_colors::OwoColorize;
+use std::{future, time::Duration};
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+/// What the presences of the rotation use as their socket ID
+pub const SOCKET_ID: &str = "rotation";
+
+/// Shows every presence for its duration, starting over after the last one. Presences that
+/// don't pass validation are skipped.
+pub fn rotate(
+    config: &RotationConfig,
+    activity_config: &ActivityConfig,
+) -> impl Stream<Item = ActivityEvent> + Send + Unpin {
+    let pid = std::process::id() as usize;
+    let application_id = Some(config.application_id.clone());
+    let presences: Vec<(Duration, IpcActivityMessage)> = config
+        .presences
+        .iter()
+        .enumerate()
+        .filter_map(|(i, entry)| {
+            let mut msg = IpcPartialActivityMessage::to_full_message(
+                Some(entry.activity.clone()),
+                pid,
+                SOCKET_ID.to_string(),
+                &application_id,
+            );
+            msg.source = ActivitySource::Rotation;
+            let activity = msg.activity.as_mut()?;
+            if let Err(e) = validate::sanitize(activity, activity_config) {
+                warn!("Skipping presence {} of the rotation: {}", i + 1, e);
+                return None;
+            }
+            Some((Duration::from_secs(entry.duration_secs.max(1)), msg))
+        })
+        .collect();
+    info!(
+        "{} {} {}",
+        "Rotating through".cyan(),
+        presences.len().yellow().bold(),
+        "presences".cyan()
+    );
+
+    Box::pin(stream::unfold(
+        (presences, 0, None),
+        |(presences, index, wait)| async move {
+            // A single presence never changes, so it is only sent once
+            if presences.is_empty() || (index > 0 && presences.len() == 1) {
+                return future::pending().await;
+            }
+            if let Some(wait) = wait {
+                sleep(wait).await;
+            }
+            let (duration, msg) = &presences[index % presences.len()];
+            let mut msg = Box::new(msg.clone());
+            if let Some(activity) = &mut msg.activity {
+                activity.created_at = now_millis();
+            }
+            let wait = Some(*duration);
+            Some((ActivityEvent::Set(msg), (presences, index + 1, wait)))
+        },
+    ))
+}
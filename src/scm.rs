@@ -0,0 +1,182 @@
+//! Running as a Windows service, so arRPC starts with the machine and is stopped through the
+//! service control manager instead of needing a logged-in console session
+
+use crate::error::{ArRpcError, Result};
+use std::{
+    ffi::c_void,
+    fmt::Display,
+    io, iter, ptr,
+    sync::{
+        atomic::{AtomicIsize, Ordering},
+        Mutex, OnceLock,
+    },
+};
+use tokio::sync::watch;
+use tracing::error;
+
+/// What `arrpc_rs install-service` registers the service as
+pub const SERVICE_NAME: &str = "arRPC";
+
+const SERVICE_WIN32_OWN_PROCESS: u32 = 0x10;
+const SERVICE_STOPPED: u32 = 1;
+const SERVICE_START_PENDING: u32 = 2;
+const SERVICE_STOP_PENDING: u32 = 3;
+const SERVICE_RUNNING: u32 = 4;
+const SERVICE_ACCEPT_STOP: u32 = 0x1;
+const SERVICE_ACCEPT_SHUTDOWN: u32 = 0x4;
+const SERVICE_CONTROL_STOP: u32 = 1;
+const SERVICE_CONTROL_INTERROGATE: u32 = 4;
+const SERVICE_CONTROL_SHUTDOWN: u32 = 5;
+const NO_ERROR: u32 = 0;
+const ERROR_CALL_NOT_IMPLEMENTED: u32 = 120;
+const ERROR_SERVICE_SPECIFIC_ERROR: u32 = 1066;
+/// How long the SCM should wait for the sockets to be bound before it considers us hung
+const START_WAIT_HINT_MS: u32 = 30_000;
+/// How long the SCM should wait for connections to close before it considers us hung
+const STOP_WAIT_HINT_MS: u32 = 10_000;
+
+#[repr(C)]
+#[allow(non_snake_case)]
+struct SERVICE_STATUS {
+    dwServiceType: u32,
+    dwCurrentState: u32,
+    dwControlsAccepted: u32,
+    dwWin32ExitCode: u32,
+    dwServiceSpecificExitCode: u32,
+    dwCheckPoint: u32,
+    dwWaitHint: u32,
+}
+
+#[repr(C)]
+#[allow(non_snake_case)]
+struct SERVICE_TABLE_ENTRYW {
+    lpServiceName: *mut u16,
+    lpServiceProc: Option<unsafe extern "system" fn(u32, *mut *mut u16)>,
+}
+
+type HandlerFn = unsafe extern "system" fn(u32, u32, *mut c_void, *mut c_void) -> u32;
+
+#[link(name = "advapi32")]
+extern "system" {
+    fn StartServiceCtrlDispatcherW(table: *const SERVICE_TABLE_ENTRYW) -> i32;
+    fn RegisterServiceCtrlHandlerExW(
+        name: *const u16,
+        handler: HandlerFn,
+        context: *mut c_void,
+    ) -> isize;
+    fn SetServiceStatus(handle: isize, status: *const SERVICE_STATUS) -> i32;
+}
+
+// The SCM calls back into plain functions, so what they need is kept here
+static SERVICE: Mutex<Option<Box<dyn FnOnce() -> bool + Send>>> = Mutex::new(None);
+static STATUS_HANDLE: AtomicIsize = AtomicIsize::new(0);
+static STOP: OnceLock<watch::Sender<bool>> = OnceLock::new();
+
+/// Hands the process to the service control manager and runs `service` as the service, until it
+/// returns. It should call [`started`] once it is listening and return once [`stop_requested`]
+/// resolves. Fails when the process wasn't started by the SCM.
+pub fn run<E: Display>(
+    service: impl FnOnce() -> std::result::Result<(), E> + Send + 'static,
+) -> Result<()> {
+    *SERVICE.lock().unwrap() = Some(Box::new(move || match service() {
+        Ok(()) => true,
+        Err(e) => {
+            error!("{}", e);
+            false
+        }
+    }));
+    let mut name = wide(SERVICE_NAME);
+    let table = [
+        SERVICE_TABLE_ENTRYW {
+            lpServiceName: name.as_mut_ptr(),
+            lpServiceProc: Some(service_main),
+        },
+        SERVICE_TABLE_ENTRYW {
+            lpServiceName: ptr::null_mut(),
+            lpServiceProc: None,
+        },
+    ];
+    // Only returns once the service stopped
+    if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+        return Err(ArRpcError::Service(format!(
+            "Failed to connect to the service control manager: {}",
+            io::Error::last_os_error()
+        )));
+    }
+    Ok(())
+}
+
+/// Reports the service as running, until then it is starting. Does nothing outside of a service.
+pub fn started() {
+    if STATUS_HANDLE.load(Ordering::SeqCst) != 0 {
+        set_status(SERVICE_RUNNING, NO_ERROR);
+    }
+}
+
+/// Resolves once the service control manager asks the service to stop, never outside of one
+pub async fn stop_requested() {
+    let mut stop = stop().subscribe();
+    let _ = stop.wait_for(|stop| *stop).await;
+}
+
+fn stop() -> &'static watch::Sender<bool> {
+    STOP.get_or_init(|| watch::channel(false).0)
+}
+
+unsafe extern "system" fn service_main(_argc: u32, _argv: *mut *mut u16) {
+    let name = wide(SERVICE_NAME);
+    let handle = RegisterServiceCtrlHandlerExW(name.as_ptr(), handler, ptr::null_mut());
+    if handle == 0 {
+        return;
+    }
+    STATUS_HANDLE.store(handle, Ordering::SeqCst);
+    // Running only once the sockets are bound, a failed bind ends up as a failed start
+    set_status(SERVICE_START_PENDING, NO_ERROR);
+    let service = SERVICE.lock().unwrap().take();
+    let exit_code = match service.is_some_and(|service| service()) {
+        true => NO_ERROR,
+        false => ERROR_SERVICE_SPECIFIC_ERROR,
+    };
+    set_status(SERVICE_STOPPED, exit_code);
+}
+
+unsafe extern "system" fn handler(
+    control: u32,
+    _event_type: u32,
+    _event_data: *mut c_void,
+    _context: *mut c_void,
+) -> u32 {
+    match control {
+        SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+            set_status(SERVICE_STOP_PENDING, NO_ERROR);
+            stop().send_replace(true);
+            NO_ERROR
+        }
+        SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+        _ => ERROR_CALL_NOT_IMPLEMENTED,
+    }
+}
+
+fn set_status(state: u32, exit_code: u32) {
+    let status = SERVICE_STATUS {
+        dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+        dwCurrentState: state,
+        dwControlsAccepted: match state {
+            SERVICE_RUNNING => SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN,
+            _ => 0,
+        },
+        dwWin32ExitCode: exit_code,
+        dwServiceSpecificExitCode: (exit_code == ERROR_SERVICE_SPECIFIC_ERROR) as u32,
+        dwCheckPoint: matches!(state, SERVICE_START_PENDING | SERVICE_STOP_PENDING) as u32,
+        dwWaitHint: match state {
+            SERVICE_START_PENDING => START_WAIT_HINT_MS,
+            SERVICE_STOP_PENDING => STOP_WAIT_HINT_MS,
+            _ => 0,
+        },
+    };
+    unsafe { SetServiceStatus(STATUS_HANDLE.load(Ordering::SeqCst), &status) };
+}
+
+fn wide(text: &str) -> Vec<u16> {
+    text.encode_utf16().chain(iter::once(0)).collect()
+}
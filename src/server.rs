@@ -1,73 +1,425 @@
 use crate::{
+    command::{CommandHandler, CommandRegistry},
+    config::{IpcConfig, SharedConfig},
+    error::Result,
     ipc::{
-        server::IpcServer,
-        structs::{IpcCommand, IpcFrame, IpcMessage},
+        server::{IpcServer, Transports},
+        structs::{IpcCommand, IpcFrame, IpcFrameArgs, IpcMessage, RpcErrorCode},
     },
-    structs::{IpcActivityMessage, IpcPartialActivityMessage},
+    middleware::{Middleware, MiddlewareStack},
+    overrides, process, queue,
+    record::Recorder,
+    redact,
+    state::now_millis,
+    stats::SharedStats,
+    status::{ClientStatus, StatusTracker},
+    structs::{ActivityEvent, IpcPartialActivityMessage, SessionSummary},
+    validate,
 };
-use anyhow::Result;
+use futures_util::{stream, Stream};
+use owo_colors::OwoColorize;
 use serde_json::json;
-use tokio::{sync::mpsc, task};
+use std::collections::HashMap;
+use tokio::{
+    select,
+    sync::watch,
+    task::{self, JoinHandle},
+    time::Instant,
+};
+use tracing::{debug, info};
+
+/// What is known about a connection that got past its handshake
+struct Session {
+    started: Instant,
+    started_at: u64,
+    pid: Option<usize>,
+    process_name: Option<String>,
+    activity_updates: u64,
+}
 
-pub struct Server;
+#[derive(Default)]
+pub struct Server {
+    middleware: MiddlewareStack,
+    commands: CommandRegistry,
+    transports: Transports,
+    ipc_config: IpcConfig,
+    config: SharedConfig,
+    status: StatusTracker,
+    stats: SharedStats,
+    recorder: Option<Recorder>,
+}
 
 impl Server {
-    pub async fn try_bind() -> Result<mpsc::Receiver<IpcActivityMessage>> {
-        let mut ipc = IpcServer::try_bind().await?;
-        let (tx, rx) = mpsc::channel(1);
-        task::spawn(async move {
-            let mut client_id = None;
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_parts(middleware: MiddlewareStack, commands: CommandRegistry) -> Self {
+        Self {
+            middleware,
+            commands,
+            transports: Transports::default(),
+            ipc_config: IpcConfig::default(),
+            config: SharedConfig::default(),
+            status: StatusTracker::default(),
+            stats: SharedStats::default(),
+            recorder: None,
+        }
+    }
+
+    pub fn with_transports(mut self, transports: Transports) -> Self {
+        self.transports = transports;
+        self
+    }
+
+    pub fn with_ipc_config(mut self, ipc_config: IpcConfig) -> Self {
+        self.ipc_config = ipc_config;
+        self
+    }
+
+    /// Read on every activity update, unlike the IPC config
+    pub fn with_config(mut self, config: SharedConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn with_status(mut self, status: StatusTracker) -> Self {
+        self.status = status;
+        self
+    }
+
+    pub fn with_stats(mut self, stats: SharedStats) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Writes every message from a client to `recorder` before handling it
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    pub fn with_middleware<M: Middleware + 'static>(self, middleware: M) -> Self {
+        self.middleware.register(middleware);
+        self
+    }
+
+    pub fn with_command<H: CommandHandler + 'static>(
+        self,
+        cmd: impl Into<String>,
+        handler: H,
+    ) -> Self {
+        self.commands.register(cmd, handler);
+        self
+    }
+
+    /// Handle to the middleware stack, which can still be changed once the server is bound
+    pub fn middleware(&self) -> MiddlewareStack {
+        self.middleware.clone()
+    }
+
+    pub fn commands(&self) -> CommandRegistry {
+        self.commands.clone()
+    }
+
+    pub fn status(&self) -> StatusTracker {
+        self.status.clone()
+    }
+
+    pub async fn try_bind(
+        self,
+    ) -> Result<(
+        impl Stream<Item = ActivityEvent> + Send + Unpin,
+        ServerHandle,
+    )> {
+        let middleware = self.middleware;
+        let commands = self.commands;
+        let status = self.status;
+        let config = self.config;
+        let stats = self.stats;
+        let recorder = self.recorder;
+        let mut ipc = IpcServer::try_bind(self.transports, &self.ipc_config, stats.clone()).await?;
+        status.update(|status| status.addresses.extend(ipc.addresses().iter().cloned()));
+        let (tx, rx) = queue::channel(self.ipc_config.event_queue);
+        let (shutdown, mut shutdown_rx) = watch::channel(false);
+        let task = task::spawn(async move {
+            let mut client_ids = HashMap::new();
+            let mut pids = HashMap::new();
+            let mut sessions: HashMap<usize, Session> = HashMap::new();
             loop {
-                if let Some((socket_id, msg)) = ipc.recv().await {
-                    match msg {
-                        IpcMessage::Frame(frame) => {
-                            if let Some(args) = frame.args {
-                                tx.send(IpcPartialActivityMessage::to_full_message(
-                                    Some(args.activity),
-                                    args.pid,
-                                    socket_id.to_string(),
-                                    &client_id,
-                                ))
-                                .await
-                                .unwrap();
-                            };
+                let (socket_id, msg) = select! {
+                    msg = ipc.recv() => match msg {
+                        Some(msg) => msg,
+                        None => break,
+                    },
+                    _ = shutdown_rx.changed() => break,
+                };
+                if let Some(recorder) = &recorder {
+                    recorder.record(socket_id, &msg).await;
+                }
+                if let IpcMessage::Frame(frame) = &msg {
+                    stats.frame(&frame.cmd);
+                }
+                match msg {
+                    IpcMessage::Frame(frame) if frame.cmd != "SET_ACTIVITY" => {
+                        let Some(handler) = commands.get(&frame.cmd) else {
+                            debug!("Unhandled IPC command: {}", frame.cmd);
+                            let message = format!("Unknown command: {}", frame.cmd);
+                            reply(
+                                &ipc,
+                                socket_id,
+                                frame.error(RpcErrorCode::InvalidCommand, message),
+                            )
+                            .await;
+                            continue;
+                        };
+                        let data = handler.handle(socket_id, &frame);
+                        reply(&ipc, socket_id, frame.reply(data)).await;
+                    }
+
+                    IpcMessage::Frame(mut frame) => {
+                        let args = frame
+                            .args
+                            .take()
+                            .ok_or_else(|| "Missing args".to_string())
+                            .and_then(|args| {
+                                serde_json::from_value::<IpcFrameArgs>(args)
+                                    .map_err(|e| e.to_string())
+                            });
+                        let args = match args {
+                            Ok(args) => args,
+                            Err(e) => {
+                                debug!(
+                                    "Invalid SET_ACTIVITY from {}: {}",
+                                    socket_id,
+                                    redact::text(&e.to_string())
+                                );
+                                reply(
+                                    &ipc,
+                                    socket_id,
+                                    frame.error(RpcErrorCode::InvalidPayload, e),
+                                )
+                                .await;
+                                continue;
+                            }
+                        };
+                        let mut msg = IpcPartialActivityMessage::to_full_message(
+                            args.activity,
+                            args.pid,
+                            socket_id.to_string(),
+                            &client_ids.get(&socket_id).cloned(),
+                        );
+                        msg.peer = ipc.peer(socket_id).await;
+                        // The pid in the payload is whatever the client claims, the socket's
+                        // peer is the process that actually connected
+                        if let Some(pid) = msg.peer.and_then(|peer| peer.pid) {
+                            if pid != msg.pid {
+                                debug!(
+                                    "Connection {} claims pid {}, but is pid {}",
+                                    socket_id, msg.pid, pid
+                                );
+                            }
+                            msg.pid = pid;
                         }
+                        msg.process_name = process::name(msg.pid);
+                        msg.process_path = process::path(msg.pid);
+                        let activity_config = config.read().unwrap().activity.clone();
+                        overrides::apply(&mut msg, &activity_config.overrides);
+                        if let Some(activity) = &mut msg.activity {
+                            if let Err(e) = validate::sanitize(activity, &activity_config) {
+                                debug!("Rejecting activity from {}: {}", socket_id, e);
+                                let error =
+                                    frame.error(RpcErrorCode::InvalidPayload, e.to_string());
+                                reply(&ipc, socket_id, error).await;
+                                continue;
+                            }
+                        }
+                        // Answered with what the client asked for, even if a middleware changes it
+                        let data = serde_json::to_value(&msg.activity).ok();
+                        reply(&ipc, socket_id, frame.reply(data)).await;
+                        let Some(mut msg) = middleware.apply(msg) else {
+                            continue;
+                        };
+                        // Again, a middleware can make texts too long or put line breaks in
+                        if let Some(activity) = &mut msg.activity {
+                            if let Err(e) = validate::sanitize(activity, &activity_config) {
+                                debug!(
+                                    "Dropping activity from {} after the middleware: {}",
+                                    socket_id, e
+                                );
+                                continue;
+                            }
+                        }
+                        stats.activity(msg.activity.is_some());
+                        if let Some(session) = sessions.get_mut(&socket_id) {
+                            session.activity_updates += 1;
+                            session.pid = Some(msg.pid);
+                            if msg.process_name.is_some() {
+                                session.process_name = msg.process_name.clone();
+                            }
+                        }
+                        status.update(|status| {
+                            if let Some(client) = status.clients.get_mut(&socket_id) {
+                                client.pid = Some(msg.pid);
+                            }
+                        });
+                        let event = if msg.activity.is_some() {
+                            pids.insert(socket_id, msg.pid);
+                            ActivityEvent::Set(Box::new(msg))
+                        } else {
+                            pids.remove(&socket_id);
+                            ActivityEvent::Clear {
+                                socket_id: msg.socket_id,
+                                pid: msg.pid,
+                            }
+                        };
+                        if tx.send(event).await.is_err() {
+                            break;
+                        }
+                    }
 
-                        IpcMessage::Handshake(data) => {
-                            client_id.replace(data.client_id);
-                            ipc.send(
+                    IpcMessage::Handshake(data) => {
+                        client_ids.insert(socket_id, data.client_id.clone());
+                        let peer = ipc.peer(socket_id).await;
+                        let pid = peer.and_then(|peer| peer.pid);
+                        sessions.insert(
+                            socket_id,
+                            Session {
+                                started: Instant::now(),
+                                started_at: now_millis(),
+                                pid,
+                                // The process may be gone by the time the connection closes
+                                process_name: pid.and_then(process::name),
+                                activity_updates: 0,
+                            },
+                        );
+                        status.update(|status| {
+                            status.clients.insert(
                                 socket_id,
-                                IpcCommand::Frame(IpcFrame {
-                                    cmd: "DISPATCH".to_string(),
-                                    evt: Some("READY".to_string()),
-                                    args: None,
-                                    data: Some(json!({
-                                      "v": 1,
-                                      "user": {
-                                        "id": "1045800378228281345",
-                                        "username": "arRPC",
-                                        "discriminator": "0000",
-                                        "avatar": "cfefa4d9839fb4bdf030f91c2a13e95c",
-                                        "flags": 0,
-                                        "premium_type": 0,
-                                      },
-                                      "config": {
-                                        "api_endpoint": "//discord.com/api",
-                                        "cdn_host": "cdn.discordapp.com",
-                                        "environment": "production"
-                                      }
-                                    })),
-                                    nonce: "".to_string(),
-                                }),
-                            )
+                                ClientStatus {
+                                    client_id: Some(data.client_id.clone()),
+                                    pid: None,
+                                    peer,
+                                },
+                            );
+                        });
+                        if tx
+                            .send(ActivityEvent::Handshake {
+                                socket_id: socket_id.to_string(),
+                                client_id: data.client_id,
+                            })
                             .await
-                            .unwrap();
+                            .is_err()
+                        {
+                            break;
                         }
-                        _ => {}
+                        let ready = config.read().unwrap().ready.clone();
+                        reply(
+                            &ipc,
+                            socket_id,
+                            IpcFrame {
+                                cmd: "DISPATCH".to_string(),
+                                evt: Some("READY".to_string()),
+                                args: None,
+                                data: Some(json!({
+                                  "v": 1,
+                                  "user": {
+                                    "id": "1045800378228281345",
+                                    "username": "arRPC",
+                                    "discriminator": "0000",
+                                    "avatar": "cfefa4d9839fb4bdf030f91c2a13e95c",
+                                    "flags": 0,
+                                    "premium_type": 0,
+                                  },
+                                  "config": ready,
+                                })),
+                                nonce: "".to_string(),
+                            },
+                        )
+                        .await;
                     }
+
+                    IpcMessage::Close(_) => {
+                        let client_id = client_ids.remove(&socket_id);
+                        status.update(|status| {
+                            status.clients.remove(&socket_id);
+                        });
+                        // Nobody is left to clear the activity of a closed socket
+                        if let Some(pid) = pids.remove(&socket_id) {
+                            let _ = tx
+                                .send(ActivityEvent::Clear {
+                                    socket_id: socket_id.to_string(),
+                                    pid,
+                                })
+                                .await;
+                        }
+                        if let (Some(session), Some(client_id)) =
+                            (sessions.remove(&socket_id), client_id)
+                        {
+                            let summary = SessionSummary {
+                                socket_id: socket_id.to_string(),
+                                client_id,
+                                pid: session.pid,
+                                process_name: session.process_name,
+                                started_at: session.started_at,
+                                duration_ms: session.started.elapsed().as_millis() as u64,
+                                activity_updates: session.activity_updates,
+                            };
+                            info!(
+                                "{} {} after {}s, {} activity updates",
+                                "Session ended:".cyan(),
+                                summary
+                                    .process_name
+                                    .as_deref()
+                                    .map(redact::text)
+                                    .unwrap_or_else(|| redact::id(&summary.client_id))
+                                    .yellow()
+                                    .bold(),
+                                summary.duration_ms / 1000,
+                                summary.activity_updates
+                            );
+                            let _ = tx.send(ActivityEvent::SessionEnd(Box::new(summary))).await;
+                        }
+                        if tx
+                            .send(ActivityEvent::Disconnect {
+                                socket_id: socket_id.to_string(),
+                            })
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                    _ => {}
                 }
             }
+            ipc.shutdown().await;
         });
-        Ok(rx)
+        let events = Box::pin(stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|event| (event, rx))
+        }));
+        Ok((events, ServerHandle { shutdown, task }))
+    }
+}
+
+pub struct ServerHandle {
+    shutdown: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl ServerHandle {
+    /// Waits until every connection is closed and the socket files are gone
+    pub async fn shutdown(self) {
+        let _ = self.shutdown.send(true);
+        let _ = self.task.await;
+    }
+}
+
+async fn reply(ipc: &IpcServer, socket_id: usize, frame: IpcFrame) {
+    if let Err(e) = ipc
+        .send(socket_id, IpcCommand::Frame(Box::new(frame)))
+        .await
+    {
+        debug!("Failed to answer IPC connection {}: {}", socket_id, e);
     }
 }
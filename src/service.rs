@@ -0,0 +1,196 @@
+use crate::error::{ArRpcError, Result};
+use std::{path::Path, process::Command};
+
+/// Installs and starts whatever runs `exe` with `args` on login on this platform: a systemd user
+/// unit on Linux, a launchd agent on macOS and a `Run` registry value on Windows. Returns where
+/// it was installed.
+pub fn install(exe: &Path, args: &[String]) -> Result<String> {
+    platform::install(exe, args)
+}
+
+/// Stops and removes what [`install`] set up
+pub fn uninstall() -> Result<String> {
+    platform::uninstall()
+}
+
+fn run(program: &str, args: &[&str]) -> Result<()> {
+    let status = Command::new(program).args(args).status()?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(ArRpcError::Service(format!(
+            "{} {} failed with {}",
+            program,
+            args.join(" "),
+            status
+        ))),
+    }
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+mod platform {
+    use super::*;
+    use std::{env, fs, path::PathBuf};
+
+    const UNIT: &str = "arrpc.service";
+
+    fn unit_path() -> Result<PathBuf> {
+        let config_directory = env::var("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|_| env::var("HOME").map(|home| Path::new(&home).join(".config")))
+            .map_err(|_| ArRpcError::Service("Neither XDG_CONFIG_HOME nor HOME is set".into()))?;
+        Ok(config_directory.join("systemd").join("user").join(UNIT))
+    }
+
+    // systemd splits ExecStart on spaces unless quoted and expands `%` specifiers
+    fn quote(arg: &str) -> String {
+        let arg = arg
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('%', "%%");
+        format!("\"{}\"", arg)
+    }
+
+    pub fn install(exe: &Path, args: &[String]) -> Result<String> {
+        let path = unit_path()?;
+        let command: Vec<String> = std::iter::once(exe.to_string_lossy().into_owned())
+            .chain(args.iter().cloned())
+            .map(|arg| quote(&arg))
+            .collect();
+        let unit = format!(
+            "[Unit]\n\
+             Description=arRPC, Discord RPC for web clients\n\
+             \n\
+             [Service]\n\
+             ExecStart={}\n\
+             ExecReload=/bin/kill -HUP $MAINPID\n\
+             Restart=on-failure\n\
+             \n\
+             [Install]\n\
+             WantedBy=default.target\n",
+            command.join(" ")
+        );
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, unit)?;
+        run("systemctl", &["--user", "daemon-reload"])?;
+        run("systemctl", &["--user", "enable", "--now", UNIT])?;
+        Ok(path.display().to_string())
+    }
+
+    pub fn uninstall() -> Result<String> {
+        let path = unit_path()?;
+        if !path.exists() {
+            return Err(ArRpcError::Service(format!(
+                "{} is not installed",
+                path.display()
+            )));
+        }
+        run("systemctl", &["--user", "disable", "--now", UNIT])?;
+        fs::remove_file(&path)?;
+        run("systemctl", &["--user", "daemon-reload"])?;
+        Ok(path.display().to_string())
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use super::*;
+    use std::{env, fs, path::PathBuf};
+
+    const LABEL: &str = "org.arrpc";
+
+    fn plist_path() -> Result<PathBuf> {
+        let home = env::var("HOME").map_err(|_| ArRpcError::Service("HOME is not set".into()))?;
+        Ok(Path::new(&home)
+            .join("Library")
+            .join("LaunchAgents")
+            .join(format!("{}.plist", LABEL)))
+    }
+
+    fn escape(arg: &str) -> String {
+        arg.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+    }
+
+    pub fn install(exe: &Path, args: &[String]) -> Result<String> {
+        let path = plist_path()?;
+        let arguments: String = std::iter::once(exe.to_string_lossy().into_owned())
+            .chain(args.iter().cloned())
+            .map(|arg| format!("        <string>{}</string>\n", escape(&arg)))
+            .collect();
+        let plist = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{}</string>
+    <key>ProgramArguments</key>
+    <array>
+{}    </array>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <dict>
+        <key>SuccessfulExit</key>
+        <false/>
+    </dict>
+</dict>
+</plist>
+"#,
+            LABEL, arguments
+        );
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, plist)?;
+        run("launchctl", &["load", "-w", &path.to_string_lossy()])?;
+        Ok(path.display().to_string())
+    }
+
+    pub fn uninstall() -> Result<String> {
+        let path = plist_path()?;
+        if !path.exists() {
+            return Err(ArRpcError::Service(format!(
+                "{} is not installed",
+                path.display()
+            )));
+        }
+        run("launchctl", &["unload", "-w", &path.to_string_lossy()])?;
+        fs::remove_file(&path)?;
+        Ok(path.display().to_string())
+    }
+}
+
+// Started on login in the user's session rather than as a service, which would run as
+// LocalSystem in session 0 with its own profile and without the user's input or processes. See
+// `scm` for running under the SCM anyway.
+#[cfg(windows)]
+mod platform {
+    use super::*;
+
+    const KEY: &str = r"HKCU\Software\Microsoft\Windows\CurrentVersion\Run";
+    const VALUE: &str = "arRPC";
+
+    pub fn install(exe: &Path, args: &[String]) -> Result<String> {
+        let command: Vec<String> = std::iter::once(exe.to_string_lossy().into_owned())
+            .chain(args.iter().cloned())
+            .map(|arg| format!("\"{}\"", arg))
+            .collect();
+        let command = command.join(" ");
+        run(
+            "reg",
+            &[
+                "add", KEY, "/v", VALUE, "/t", "REG_SZ", "/d", &command, "/f",
+            ],
+        )?;
+        Ok(format!(r"{}\{}", KEY, VALUE))
+    }
+
+    pub fn uninstall() -> Result<String> {
+        run("reg", &["delete", KEY, "/v", VALUE, "/f"])?;
+        Ok(format!(r"{}\{}", KEY, VALUE))
+    }
+}
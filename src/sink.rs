@@ -0,0 +1,187 @@
+use crate::{
+    error::{ArRpcError, Result},
+    structs::{IpcActivityMessage, SessionSummary},
+};
+use futures_util::future::BoxFuture;
+use std::{future::Future, path::Path, time::Duration};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{self, AsyncReadExt, AsyncWriteExt, Stdout},
+    net::TcpStream,
+    sync::Mutex,
+    time::timeout,
+};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Somewhere activities are published to, like the WebSocket bridge. Sinks are called one after
+/// the other for every event, so a slow one holds up the rest.
+pub trait ActivitySink: Send + Sync {
+    /// Called for every update, `msg.activity` is `None` once the activity is cleared
+    fn publish(&self, msg: &IpcActivityMessage) -> impl Future<Output = Result<()>> + Send;
+
+    fn session_end(&self, _summary: &SessionSummary) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Shown when publishing fails
+    fn name(&self) -> String {
+        std::any::type_name::<Self>().to_string()
+    }
+}
+
+/// [`ActivitySink`] can't be made into a trait object because of its async methods
+pub(crate) trait DynSink: Send + Sync {
+    fn publish<'a>(&'a self, msg: &'a IpcActivityMessage) -> BoxFuture<'a, Result<()>>;
+    fn session_end<'a>(&'a self, summary: &'a SessionSummary) -> BoxFuture<'a, Result<()>>;
+    fn name(&self) -> String;
+}
+
+impl<S: ActivitySink> DynSink for S {
+    fn publish<'a>(&'a self, msg: &'a IpcActivityMessage) -> BoxFuture<'a, Result<()>> {
+        Box::pin(ActivitySink::publish(self, msg))
+    }
+
+    fn session_end<'a>(&'a self, summary: &'a SessionSummary) -> BoxFuture<'a, Result<()>> {
+        Box::pin(ActivitySink::session_end(self, summary))
+    }
+
+    fn name(&self) -> String {
+        ActivitySink::name(self)
+    }
+}
+
+/// Appends every message to a file as one line of JSON
+pub struct FileSink {
+    path: String,
+    file: Mutex<File>,
+}
+
+impl FileSink {
+    pub async fn open(path: &Path) -> Result<FileSink> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        Ok(FileSink {
+            path: path.display().to_string(),
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl ActivitySink for FileSink {
+    async fn publish(&self, msg: &IpcActivityMessage) -> Result<()> {
+        let mut line = serde_json::to_vec(msg)?;
+        line.push(b'\n');
+        self.file.lock().await.write_all(&line).await?;
+        Ok(())
+    }
+
+    fn name(&self) -> String {
+        format!("file {}", self.path)
+    }
+}
+
+/// Prints every message to stdout as one line of JSON, for piping into other tools
+pub struct StdoutSink(Mutex<Stdout>);
+
+impl Default for StdoutSink {
+    fn default() -> Self {
+        Self(Mutex::new(io::stdout()))
+    }
+}
+
+impl ActivitySink for StdoutSink {
+    async fn publish(&self, msg: &IpcActivityMessage) -> Result<()> {
+        let mut line = serde_json::to_vec(msg)?;
+        line.push(b'\n');
+        let mut stdout = self.0.lock().await;
+        stdout.write_all(&line).await?;
+        stdout.flush().await?;
+        Ok(())
+    }
+
+    fn name(&self) -> String {
+        "stdout".to_string()
+    }
+}
+
+/// POSTs every message as JSON, only plain `http://` URLs are supported
+pub struct WebhookSink {
+    url: String,
+    host: String,
+    path: String,
+}
+
+impl WebhookSink {
+    pub fn new(url: &str) -> Result<WebhookSink> {
+        let Some(rest) = url.strip_prefix("http://") else {
+            return Err(ArRpcError::Webhook(format!(
+                "{} is not an http:// URL",
+                url
+            )));
+        };
+        let (host, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        if host.is_empty() {
+            return Err(ArRpcError::Webhook(format!("{} has no host", url)));
+        }
+        Ok(WebhookSink {
+            url: url.to_string(),
+            host: host.to_string(),
+            path: path.to_string(),
+        })
+    }
+
+    async fn post(&self, body: &[u8]) -> Result<()> {
+        let address = match self.host.contains(':') {
+            true => self.host.clone(),
+            false => format!("{}:80", self.host),
+        };
+        let mut stream = TcpStream::connect(address).await?;
+        let head = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.path,
+            self.host,
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(body).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        // Only the status code matters, e.g. `HTTP/1.1 204 No Content`
+        let status = response
+            .split(|&b| b == b' ')
+            .nth(1)
+            .and_then(|status| std::str::from_utf8(status).ok())
+            .unwrap_or_default();
+        match status.starts_with('2') {
+            true => Ok(()),
+            false => Err(ArRpcError::Webhook(format!(
+                "{} answered {}",
+                self.url, status
+            ))),
+        }
+    }
+}
+
+impl ActivitySink for WebhookSink {
+    async fn publish(&self, msg: &IpcActivityMessage) -> Result<()> {
+        let body = serde_json::to_vec(msg)?;
+        match timeout(WEBHOOK_TIMEOUT, self.post(&body)).await {
+            Ok(result) => result,
+            Err(_) => Err(ArRpcError::Webhook(format!("{} timed out", self.url))),
+        }
+    }
+
+    fn name(&self) -> String {
+        format!("webhook {}", self.url)
+    }
+}
@@ -0,0 +1,80 @@
+use crate::{error::Result, structs::IpcActivityMessage};
+use serde::{Deserialize, Serialize};
+use std::{
+    env,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::fs;
+use tracing::debug;
+
+// Anything older than this is most likely from a session the user already forgot about
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PersistedState {
+    pub saved_at: u64,
+    pub activities: Vec<IpcActivityMessage>,
+}
+
+impl PersistedState {
+    pub fn new(activities: Vec<IpcActivityMessage>) -> Self {
+        Self {
+            saved_at: now_millis(),
+            activities,
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    pub fn default_path() -> PathBuf {
+        env::var("HOME")
+            .map(|home| Path::new(&home).join("Library/Application Support"))
+            .unwrap_or_else(|_| env::temp_dir())
+            .join("arrpc")
+            .join("state.json")
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    pub fn default_path() -> PathBuf {
+        let state_directory = env::var("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .or_else(|_| env::var("HOME").map(|home| Path::new(&home).join(".local/state")))
+            .unwrap_or_else(|_| env::temp_dir());
+        state_directory.join("arrpc").join("state.json")
+    }
+
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::write(path, serde_json::to_vec(self)?).await?;
+        Ok(())
+    }
+
+    pub async fn load(path: &Path, max_age: Duration) -> Result<Option<PersistedState>> {
+        let data = match fs::read(path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        // The state is only meant to survive a single restart
+        fs::remove_file(path).await?;
+
+        let mut state: PersistedState = serde_json::from_slice(&data)?;
+        let age = Duration::from_millis(now_millis().saturating_sub(state.saved_at));
+        if age > max_age {
+            debug!("Discarding stale state from {}s ago", age.as_secs());
+            return Ok(None);
+        }
+
+        state.activities.retain(|msg| msg.activity.is_some());
+        Ok(Some(state))
+    }
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_millis() as u64)
+        .unwrap_or_default()
+}
@@ -0,0 +1,118 @@
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// Bytes a connection read and wrote
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Traffic {
+    pub read: u64,
+    pub written: u64,
+}
+
+/// Counters since startup, updated by whichever module sees the event
+#[derive(Debug, Default)]
+pub struct Stats {
+    frames: Mutex<BTreeMap<String, u64>>,
+    decode_errors: AtomicU64,
+    activities_set: AtomicU64,
+    activities_cleared: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    connections_opened: AtomicU64,
+    connections_closed: AtomicU64,
+}
+
+pub type SharedStats = Arc<Stats>;
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StatsSnapshot {
+    /// Received frames by command
+    pub frames: BTreeMap<String, u64>,
+    pub decode_errors: u64,
+    pub activities_set: u64,
+    pub activities_cleared: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub connections_opened: u64,
+    pub connections_closed: u64,
+}
+
+impl Stats {
+    pub fn frame(&self, cmd: &str) {
+        *self
+            .frames
+            .lock()
+            .unwrap()
+            .entry(cmd.to_string())
+            .or_default() += 1;
+    }
+
+    pub fn decode_error(&self) {
+        self.decode_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn activity(&self, set: bool) {
+        match set {
+            true => self.activities_set.fetch_add(1, Ordering::Relaxed),
+            false => self.activities_cleared.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    pub fn traffic(&self, traffic: Traffic) {
+        self.bytes_in.fetch_add(traffic.read, Ordering::Relaxed);
+        self.bytes_out.fetch_add(traffic.written, Ordering::Relaxed);
+    }
+
+    pub fn connection_opened(&self) {
+        self.connections_opened.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.connections_closed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            frames: self.frames.lock().unwrap().clone(),
+            decode_errors: self.decode_errors.load(Ordering::Relaxed),
+            activities_set: self.activities_set.load(Ordering::Relaxed),
+            activities_cleared: self.activities_cleared.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            connections_opened: self.connections_opened.load(Ordering::Relaxed),
+            connections_closed: self.connections_closed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Display for StatsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let frames: Vec<String> = self
+            .frames
+            .iter()
+            .map(|(cmd, count)| format!("{} {}", count, cmd))
+            .collect();
+        writeln!(f, "Frames: {}", frames.join(", "))?;
+        writeln!(
+            f,
+            "Connections: {} opened, {} closed",
+            self.connections_opened, self.connections_closed
+        )?;
+        writeln!(
+            f,
+            "Activity updates: {} set, {} cleared",
+            self.activities_set, self.activities_cleared
+        )?;
+        write!(
+            f,
+            "Traffic: {} bytes in, {} bytes out, {} decode errors",
+            self.bytes_in, self.bytes_out, self.decode_errors
+        )
+    }
+}
@@ -0,0 +1,98 @@
+use crate::{
+    redact,
+    stats::StatsSnapshot,
+    structs::{IpcActivityMessage, PeerCredentials},
+};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+    sync::{Arc, Mutex},
+};
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ClientStatus {
+    pub client_id: Option<String>,
+    pub pid: Option<usize>,
+    pub peer: Option<PeerCredentials>,
+}
+
+/// Name and version are what the client says it is, if anything
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BridgeClientStatus {
+    pub address: String,
+    pub name: Option<String>,
+    pub version: Option<String>,
+}
+
+impl Display for BridgeClientStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.address)?;
+        match (&self.name, &self.version) {
+            (Some(name), Some(version)) => write!(f, " ({} {})", name, version),
+            (Some(name), None) => write!(f, " ({})", name),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Status {
+    pub addresses: Vec<String>,
+    pub clients: BTreeMap<usize, ClientStatus>,
+    pub bridge_clients: Vec<BridgeClientStatus>,
+    pub activities: Vec<IpcActivityMessage>,
+    pub stats: StatsSnapshot,
+}
+
+/// Shared between the servers, which keep the parts they know about up to date
+#[derive(Debug, Clone, Default)]
+pub struct StatusTracker(Arc<Mutex<Status>>);
+
+impl StatusTracker {
+    pub fn snapshot(&self) -> Status {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub(crate) fn update(&self, f: impl FnOnce(&mut Status)) {
+        f(&mut self.0.lock().unwrap())
+    }
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Listening on: {}", self.addresses.join(", "))?;
+        writeln!(f, "IPC clients ({}):", self.clients.len())?;
+        for (socket_id, client) in &self.clients {
+            write!(f, "  #{}", socket_id)?;
+            if let Some(client_id) = &client.client_id {
+                write!(f, " client_id {}", redact::id(client_id))?;
+            }
+            if let Some(pid) = client.pid {
+                write!(f, " pid {}", pid)?;
+            }
+            if let Some(peer) = &client.peer {
+                write!(f, " uid {}", peer.uid)?;
+            }
+            writeln!(f)?;
+        }
+        writeln!(f, "Bridge clients ({}):", self.bridge_clients.len())?;
+        for client in &self.bridge_clients {
+            writeln!(f, "  {}", client)?;
+        }
+        writeln!(f, "Activities ({}):", self.activities.len())?;
+        for msg in &self.activities {
+            if let Some(activity) = &msg.activity {
+                writeln!(
+                    f,
+                    "  {} {}: {} / {}",
+                    msg.socket_id,
+                    redact::id(&activity.application_id),
+                    redact::text(&activity.details),
+                    redact::text(&activity.state)
+                )?;
+            }
+        }
+        write!(f, "{}", self.stats)
+    }
+}
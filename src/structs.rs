@@ -1,6 +1,7 @@
+use crate::state::now_millis;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct Assets {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub large_image: Option<String>,
@@ -21,30 +22,91 @@ impl Assets {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Button {
     pub label: String,
     pub url: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Timestamps {
     pub start: u64,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub end: Option<u64>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl Timestamps {
+    /// SDKs send seconds or ms since the epoch, the bridge always gets ms. Like arRPC, anything
+    /// with at least three digits less than now is taken as seconds.
+    pub fn to_millis(self) -> Timestamps {
+        let digits = |value: u64| value.checked_ilog10().unwrap_or_default();
+        let now = digits(now_millis());
+        let millis = |value: u64| match now.saturating_sub(digits(value)) > 2 {
+            true => value.saturating_mul(1000),
+            false => value,
+        };
+        Timestamps {
+            start: millis(self.start),
+            end: self.end.map(millis),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Party {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// Current and maximum size
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<[u64; 2]>,
+}
+
+/// Unicode emoji only have a name, custom ones also an id
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Emoji {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub animated: Option<bool>,
+}
+
+/// What SDKs send, which leave out whatever the game didn't set
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct IpcPartialActivity {
+    #[serde(default)]
     pub state: String,
+    #[serde(default)]
     pub details: String,
-    #[serde(skip_serializing_if = "Assets::is_empty")]
+    #[serde(default, skip_serializing_if = "Assets::is_empty")]
     pub assets: Assets,
-    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub buttons: Vec<Button>,
+    #[serde(default)]
     pub instance: bool,
+    /// Bit field of `INSTANCE = 1`, `JOIN = 2`, `SPECTATE = 4` and so on, derived from
+    /// `instance` when missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flags: Option<u64>,
+    /// `0` playing, `1` streaming, `2` listening, `3` watching and `5` competing
+    #[serde(default)]
+    pub r#type: u64,
+    /// The Twitch or YouTube stream of a streaming activity
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub timestamps: Option<Timestamps>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub party: Option<Party>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub emoji: Option<Emoji>,
+    /// Which line the member list shows, `0` the name, `1` the state and `2` the details
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_display_type: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details_url: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -57,7 +119,7 @@ pub struct IpcPartialActivityMessage {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IpcActivityMetadata {
-    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub button_urls: Vec<String>,
 }
 
@@ -68,14 +130,78 @@ pub struct IpcActivity {
     pub details: String,
     pub flags: u64,
     pub r#type: u64,
-    #[serde(skip_serializing_if = "Assets::is_empty")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(default, skip_serializing_if = "Assets::is_empty")]
     pub assets: Assets,
-    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub buttons: Vec<String>,
     pub metadata: IpcActivityMetadata,
     pub instance: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub timestamps: Option<Timestamps>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub party: Option<Party>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub emoji: Option<Emoji>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_display_type: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details_url: Option<String>,
+    /// When we received the activity in ms since the epoch, client clocks can't be trusted
+    #[serde(default)]
+    pub created_at: u64,
+}
+
+// Values of `type`
+impl IpcActivity {
+    pub const PLAYING: u64 = 0;
+    pub const STREAMING: u64 = 1;
+    pub const LISTENING: u64 = 2;
+    pub const WATCHING: u64 = 3;
+    pub const CUSTOM: u64 = 4;
+    pub const COMPETING: u64 = 5;
+}
+
+/// The process on the other end of a socket, as reported by the kernel
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PeerCredentials {
+    pub pid: Option<usize>,
+    pub uid: u32,
+}
+
+/// Where an activity came from, so bridge clients can style or filter detected games
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ActivitySource {
+    /// An RPC client over IPC or WebSocket
+    #[default]
+    Rpc,
+    /// Process detection
+    Process,
+    /// The custom status set through the control API
+    Custom,
+    /// The presences of `rotation` in the config
+    Rotation,
+}
+
+impl ActivitySource {
+    /// Socket IDs of detected activities start with this, followed by the application ID
+    pub const PROCESS_PREFIX: &'static str = "process-";
+
+    pub fn of(socket_id: &str) -> ActivitySource {
+        if socket_id.starts_with(Self::PROCESS_PREFIX) {
+            ActivitySource::Process
+        } else if socket_id == CustomStatus::SOCKET_ID {
+            ActivitySource::Custom
+        } else if socket_id == crate::rotation::SOCKET_ID {
+            ActivitySource::Rotation
+        } else {
+            ActivitySource::Rpc
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -83,6 +209,64 @@ pub struct IpcActivityMessage {
     pub activity: Option<IpcActivity>,
     pub socket_id: String,
     pub pid: usize,
+    #[serde(default)]
+    pub source: ActivitySource,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer: Option<PeerCredentials>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub process_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub process_path: Option<String>,
+    /// Set while the user is away, if `idle.action` is `tag`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub idle: bool,
+}
+
+/// The text and emoji shown under the user's name, set through the control API
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CustomStatus {
+    #[serde(default)]
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub emoji: Option<Emoji>,
+}
+
+impl CustomStatus {
+    pub const SOCKET_ID: &'static str = "custom-status";
+
+    pub fn to_message(self) -> IpcActivityMessage {
+        let pid = std::process::id() as usize;
+        IpcActivityMessage {
+            activity: Some(IpcActivity {
+                application_id: String::new(),
+                state: self.text,
+                details: String::new(),
+                flags: 0,
+                r#type: IpcActivity::CUSTOM,
+                url: None,
+                assets: Assets::default(),
+                buttons: Vec::new(),
+                metadata: IpcActivityMetadata {
+                    button_urls: Vec::new(),
+                },
+                instance: false,
+                timestamps: None,
+                party: None,
+                emoji: self.emoji,
+                status_display_type: None,
+                state_url: None,
+                details_url: None,
+                created_at: now_millis(),
+            }),
+            socket_id: Self::SOCKET_ID.to_string(),
+            pid,
+            source: ActivitySource::Custom,
+            peer: None,
+            process_name: None,
+            process_path: None,
+            idle: false,
+        }
+    }
 }
 
 impl IpcPartialActivityMessage {
@@ -98,8 +282,9 @@ impl IpcPartialActivityMessage {
                     application_id: client_id.clone().unwrap_or_default(),
                     state: activity.state,
                     details: activity.details,
-                    flags: activity.instance as u64,
-                    r#type: 0,
+                    flags: activity.flags.unwrap_or(activity.instance as u64),
+                    r#type: activity.r#type,
+                    url: activity.url,
                     assets: activity.assets,
                     buttons: activity
                         .buttons
@@ -114,7 +299,13 @@ impl IpcPartialActivityMessage {
                             .collect(),
                     },
                     instance: activity.instance,
-                    timestamps: activity.timestamps,
+                    timestamps: activity.timestamps.map(Timestamps::to_millis),
+                    party: activity.party,
+                    emoji: activity.emoji,
+                    status_display_type: activity.status_display_type,
+                    state_url: activity.state_url,
+                    details_url: activity.details_url,
+                    created_at: now_millis(),
                 };
 
                 Some(activity)
@@ -123,6 +314,74 @@ impl IpcPartialActivityMessage {
             },
             socket_id,
             pid,
+            source: ActivitySource::Rpc,
+            peer: None,
+            process_name: None,
+            process_path: None,
+            idle: false,
+        }
+    }
+}
+
+/// What an IPC connection did, from its handshake until it closed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionSummary {
+    pub socket_id: String,
+    pub client_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pid: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub process_name: Option<String>,
+    /// ms since the epoch
+    pub started_at: u64,
+    pub duration_ms: u64,
+    pub activity_updates: u64,
+}
+
+#[derive(Debug, Clone)]
+pub enum ActivityEvent {
+    Handshake {
+        socket_id: String,
+        client_id: String,
+    },
+    Set(Box<IpcActivityMessage>),
+    Clear {
+        socket_id: String,
+        pid: usize,
+    },
+    Disconnect {
+        socket_id: String,
+    },
+    /// Right before the `Disconnect` of a connection that got past its handshake
+    SessionEnd(Box<SessionSummary>),
+}
+
+impl ActivityEvent {
+    pub fn socket_id(&self) -> &str {
+        match self {
+            ActivityEvent::Handshake { socket_id, .. }
+            | ActivityEvent::Clear { socket_id, .. }
+            | ActivityEvent::Disconnect { socket_id } => socket_id,
+            ActivityEvent::Set(msg) => &msg.socket_id,
+            ActivityEvent::SessionEnd(summary) => &summary.socket_id,
+        }
+    }
+
+    /// The bridge message for this event, if it changes what should be displayed
+    pub fn to_message(&self) -> Option<IpcActivityMessage> {
+        match self {
+            ActivityEvent::Set(msg) => Some(*msg.clone()),
+            ActivityEvent::Clear { socket_id, pid } => Some(IpcActivityMessage {
+                activity: None,
+                socket_id: socket_id.clone(),
+                pid: *pid,
+                source: ActivitySource::of(socket_id),
+                peer: None,
+                process_name: None,
+                process_path: None,
+                idle: false,
+            }),
+            _ => None,
         }
     }
 }
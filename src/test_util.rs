@@ -0,0 +1,150 @@
+//! Helpers for testing against a running [`Server`](crate::server::Server) without a real game
+
+use crate::{
+    error::Result,
+    ipc::{
+        structs::{CloseMessage, HandshakeMessage, IpcFrame, IpcMessage},
+        transport::{DuplexConnector, RpcConnection, StreamConnection},
+    },
+};
+use serde_json::{json, Value};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
+
+/// Talks IPC to the server like a game would and panics on answers that don't fit, so tests
+/// read as a script of what the client does
+pub struct MockIpcClient<S> {
+    conn: StreamConnection<S>,
+    pid: usize,
+    nonce: u64,
+}
+
+#[cfg(unix)]
+impl MockIpcClient<tokio::net::UnixStream> {
+    /// Connects to a `discord-ipc-N` socket
+    pub async fn connect(path: impl AsRef<std::path::Path>) -> Result<Self> {
+        let stream = tokio::net::UnixStream::connect(path).await?;
+        Ok(Self::new(stream))
+    }
+}
+
+impl MockIpcClient<DuplexStream> {
+    /// Connects in memory to a [`DuplexTransport`](crate::ipc::transport::DuplexTransport)
+    pub fn connect_duplex(connector: &DuplexConnector) -> Result<Self> {
+        Ok(Self::new(connector.connect()?))
+    }
+}
+
+impl<S> MockIpcClient<S>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    pub fn new(stream: S) -> Self {
+        Self {
+            conn: StreamConnection::new(stream),
+            pid: std::process::id() as usize,
+            nonce: 0,
+        }
+    }
+
+    /// Reported as the pid of every activity, defaults to this process
+    pub fn with_pid(mut self, pid: usize) -> Self {
+        self.pid = pid;
+        self
+    }
+
+    pub async fn send(&mut self, msg: &IpcMessage) -> Result<()> {
+        self.conn.send(msg).await
+    }
+
+    pub async fn recv(&mut self) -> Result<IpcMessage> {
+        self.conn.recv().await
+    }
+
+    /// Sends the handshake and returns the `READY` dispatch that has to answer it
+    pub async fn handshake(&mut self, client_id: &str) -> Result<IpcFrame> {
+        self.send(&IpcMessage::Handshake(HandshakeMessage {
+            version: 1,
+            client_id: client_id.to_string(),
+        }))
+        .await?;
+        let frame = self.recv_frame().await?;
+        assert_eq!(frame.cmd, "DISPATCH", "expected READY, got {:?}", frame);
+        assert_eq!(
+            frame.evt.as_deref(),
+            Some("READY"),
+            "expected READY, got {:?}",
+            frame
+        );
+        Ok(frame)
+    }
+
+    /// Sends `cmd` with a fresh nonce and returns the answer, which has to carry that nonce
+    pub async fn command(&mut self, cmd: &str, args: Value) -> Result<IpcFrame> {
+        self.nonce += 1;
+        let nonce = self.nonce.to_string();
+        self.send(&IpcMessage::Frame(Box::new(IpcFrame {
+            args: Some(args),
+            data: None,
+            cmd: cmd.to_string(),
+            evt: None,
+            nonce: nonce.clone(),
+        })))
+        .await?;
+        let frame = self.recv_frame().await?;
+        assert_eq!(frame.nonce, nonce, "answer to another frame: {:?}", frame);
+        assert_eq!(frame.cmd, cmd, "answer to another command: {:?}", frame);
+        Ok(frame)
+    }
+
+    /// Like [`command`](Self::command), but the answer has to be a success
+    pub async fn expect_ack(&mut self, cmd: &str, args: Value) -> Result<IpcFrame> {
+        let frame = self.command(cmd, args).await?;
+        assert_ne!(
+            frame.evt.as_deref(),
+            Some("ERROR"),
+            "{} failed: {:?}",
+            cmd,
+            frame
+        );
+        Ok(frame)
+    }
+
+    /// Like [`command`](Self::command), but the answer has to be an `ERROR` event, whose data
+    /// is returned
+    pub async fn expect_error(&mut self, cmd: &str, args: Value) -> Result<Value> {
+        let frame = self.command(cmd, args).await?;
+        assert_eq!(
+            frame.evt.as_deref(),
+            Some("ERROR"),
+            "{} didn't fail: {:?}",
+            cmd,
+            frame
+        );
+        Ok(frame.data.unwrap_or_default())
+    }
+
+    pub async fn set_activity(&mut self, activity: Value) -> Result<IpcFrame> {
+        let args = json!({ "pid": self.pid, "activity": activity });
+        self.expect_ack("SET_ACTIVITY", args).await
+    }
+
+    pub async fn clear_activity(&mut self) -> Result<IpcFrame> {
+        let args = json!({ "pid": self.pid });
+        self.expect_ack("SET_ACTIVITY", args).await
+    }
+
+    /// The next message has to be a close, like after a rejected handshake
+    pub async fn expect_close(&mut self) -> Result<CloseMessage> {
+        match self.recv().await? {
+            IpcMessage::Close(close) => Ok(close),
+            msg => panic!("expected a close, got {:?}", msg),
+        }
+    }
+
+    async fn recv_frame(&mut self) -> Result<IpcFrame> {
+        match self.recv().await? {
+            IpcMessage::Frame(frame) => Ok(*frame),
+            msg => panic!("expected a frame, got {:?}", msg),
+        }
+    }
+}
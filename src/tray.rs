@@ -0,0 +1,622 @@
+//! A tray icon through StatusNotifierItem, which most Linux panels show, with its menu exported
+//! over `com.canonical.dbusmenu`
+
+use crate::{
+    dbus::wire::{
+        method_call, signal, unknown_method, unknown_object, Answer, Connection, Decoder, Encoder,
+        Incoming, Target, BUS, ERROR, PROPERTIES, SIGNAL,
+    },
+    error::{ArRpcError, Result},
+};
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc, Mutex,
+};
+use tokio::{net::unix::OwnedReadHalf, sync::mpsc, task};
+use tracing::{debug, warn};
+
+const ITEM_PATH: &str = "/StatusNotifierItem";
+const ITEM_INTERFACE: &str = "org.kde.StatusNotifierItem";
+const MENU_PATH: &str = "/MenuBar";
+const MENU_INTERFACE: &str = "com.canonical.dbusmenu";
+const WATCHER: Target = (
+    "org.kde.StatusNotifierWatcher",
+    "/StatusNotifierWatcher",
+    "org.kde.StatusNotifierWatcher",
+);
+
+// Menu item ids, the root is 0
+const STATUS: i32 = 1;
+const PAUSE: i32 = 2;
+const PRIVACY: i32 = 3;
+const QUIT: i32 = 4;
+const SEPARATORS: [i32; 2] = [5, 6];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrayCommand {
+    SetPaused(bool),
+    SetPrivacyMode(bool),
+    Quit,
+}
+
+/// What the tray shows
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrayState {
+    pub clients: usize,
+    pub activities: usize,
+    pub paused: bool,
+    pub privacy_mode: bool,
+}
+
+impl TrayState {
+    fn summary(&self) -> String {
+        let count = |n: usize, one: &str, many: &str| match n {
+            1 => format!("1 {}", one),
+            n => format!("{} {}", n, many),
+        };
+        let mut summary = format!(
+            "{}, {}",
+            count(self.clients, "client", "clients"),
+            count(self.activities, "activity", "activities")
+        );
+        if self.paused {
+            summary.push_str(" (paused)");
+        }
+        summary
+    }
+
+    fn icon(&self) -> &'static str {
+        match (self.paused, self.privacy_mode) {
+            (true, _) => "user-away",
+            (false, true) => "user-invisible",
+            (false, false) => "user-available",
+        }
+    }
+}
+
+/// A tray icon with a menu to pause forwarding, toggle privacy mode and quit. Clicking the
+/// toggles only sends a [`TrayCommand`], the state shown changes with [`Tray::update`].
+pub struct Tray {
+    shared: Arc<Shared>,
+    commands: mpsc::Receiver<TrayCommand>,
+}
+
+struct Shared {
+    connection: Connection,
+    name: String,
+    /// What is shown, and the menu revision that goes with it
+    state: Mutex<(TrayState, u32)>,
+    commands: mpsc::Sender<TrayCommand>,
+    /// The serial of the last registration, to tell its answer apart
+    registration: AtomicU32,
+}
+
+impl Shared {
+    async fn register(&self) -> Result<()> {
+        let serial = self.connection.next_serial();
+        self.registration.store(serial, Ordering::Relaxed);
+        let mut body = Encoder::default();
+        body.str(&self.name);
+        let call = method_call(serial, WATCHER, "RegisterStatusNotifierItem", &body, "s");
+        self.connection.send(call).await
+    }
+}
+
+impl Tray {
+    /// Exports the icon on the session bus and asks the panel to show it. Panels that start
+    /// later pick it up too.
+    pub async fn connect() -> Result<Tray> {
+        let name = format!("org.kde.StatusNotifierItem-{}-1", std::process::id());
+        let (connection, reader) = Connection::open(Some(&name)).await?;
+        let (tx, rx) = mpsc::channel(8);
+        let shared = Arc::new(Shared {
+            connection,
+            name,
+            state: Default::default(),
+            commands: tx,
+            registration: AtomicU32::new(0),
+        });
+
+        let mut body = Encoder::default();
+        body.str(&format!(
+            "type='signal',interface='org.freedesktop.DBus',member='NameOwnerChanged',arg0='{}'",
+            WATCHER.0
+        ));
+        let connection = &shared.connection;
+        let call = method_call(connection.next_serial(), BUS, "AddMatch", &body, "s");
+        connection.send(call).await?;
+        shared.register().await?;
+
+        task::spawn(serve(reader, shared.clone()));
+        Ok(Tray {
+            shared,
+            commands: rx,
+        })
+    }
+
+    pub async fn recv(&mut self) -> Option<TrayCommand> {
+        self.commands.recv().await
+    }
+
+    /// Shows `state`, the panel is only told if something changed
+    pub async fn update(&self, state: TrayState) -> Result<()> {
+        let revision = {
+            let mut current = self.shared.state.lock().unwrap();
+            if current.0 == state {
+                return Ok(());
+            }
+            *current = (state, current.1 + 1);
+            current.1
+        };
+        let connection = &self.shared.connection;
+        for member in ["NewIcon", "NewToolTip"] {
+            let serial = connection.next_serial();
+            let changed = signal(
+                serial,
+                ITEM_PATH,
+                ITEM_INTERFACE,
+                member,
+                &Encoder::default(),
+                "",
+            );
+            connection.send(changed).await?;
+        }
+        let mut body = Encoder::default();
+        body.u32(revision);
+        body.i32(0);
+        let serial = connection.next_serial();
+        let changed = signal(
+            serial,
+            MENU_PATH,
+            MENU_INTERFACE,
+            "LayoutUpdated",
+            &body,
+            "ui",
+        );
+        connection.send(changed).await
+    }
+}
+
+async fn serve(mut reader: OwnedReadHalf, shared: Arc<Shared>) {
+    let connection = &shared.connection;
+    loop {
+        let message = match Incoming::read(&mut reader).await {
+            Ok(message) => message,
+            Err(e) => {
+                debug!("D-Bus connection closed: {}", e);
+                return;
+            }
+        };
+        match message.kind {
+            SIGNAL if message.member == "NameOwnerChanged" && new_watcher(&message) => {
+                if let Err(e) = shared.register().await {
+                    debug!("Failed to register the tray icon: {}", e);
+                    return;
+                }
+            }
+            ERROR if message.reply_serial == Some(shared.registration.load(Ordering::Relaxed)) => {
+                warn!(
+                    "No panel to show the tray icon in: {}",
+                    message.error_message()
+                );
+            }
+            _ if message.wants_reply() => {
+                let reply = match answer(&message, &shared) {
+                    Ok((signature, body)) => {
+                        message.reply(connection.next_serial(), signature, &body)
+                    }
+                    Err((name, text)) => message.error(connection.next_serial(), name, text),
+                };
+                if let Err(e) = connection.send(reply).await {
+                    debug!("Failed to answer D-Bus call: {}", e);
+                    return;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Whether a panel just took over the watcher name
+fn new_watcher(message: &Incoming) -> bool {
+    let mut args = message.body();
+    let (Ok(name), Ok(_), Ok(owner)) = (args.str(), args.str(), args.str()) else {
+        return false;
+    };
+    name == WATCHER.0 && !owner.is_empty()
+}
+
+enum Value {
+    Str(String),
+    Bool(bool),
+    Int(i32),
+}
+
+impl Value {
+    fn str(value: &str) -> Value {
+        Value::Str(value.to_string())
+    }
+
+    fn signature(&self) -> &'static str {
+        match self {
+            Value::Str(_) => "s",
+            Value::Bool(_) => "b",
+            Value::Int(_) => "i",
+        }
+    }
+
+    fn write(&self, e: &mut Encoder) {
+        match self {
+            Value::Str(value) => e.str(value),
+            Value::Bool(value) => e.bool(*value),
+            Value::Int(value) => e.i32(*value),
+        }
+    }
+}
+
+struct Item {
+    id: i32,
+    properties: Vec<(&'static str, Value)>,
+}
+
+impl Item {
+    fn write_properties(&self, e: &mut Encoder) {
+        e.array(8, |e| {
+            for (key, value) in &self.properties {
+                e.entry(key, value.signature(), |e| value.write(e));
+            }
+        });
+    }
+}
+
+/// Everything in the menu, the root first and then its children in order
+fn items(state: &TrayState) -> Vec<Item> {
+    let toggle = |id, label: &str, on: bool| Item {
+        id,
+        properties: vec![
+            ("label", Value::str(label)),
+            ("toggle-type", Value::str("checkmark")),
+            ("toggle-state", Value::Int(on as i32)),
+        ],
+    };
+    let separator = |id| Item {
+        id,
+        properties: vec![("type", Value::str("separator"))],
+    };
+    vec![
+        Item {
+            id: 0,
+            properties: vec![("children-display", Value::str("submenu"))],
+        },
+        Item {
+            id: STATUS,
+            properties: vec![
+                ("label", Value::Str(state.summary())),
+                ("enabled", Value::Bool(false)),
+            ],
+        },
+        separator(SEPARATORS[0]),
+        toggle(PAUSE, "Pause forwarding", state.paused),
+        toggle(PRIVACY, "Privacy mode", state.privacy_mode),
+        separator(SEPARATORS[1]),
+        Item {
+            id: QUIT,
+            properties: vec![("label", Value::str("Quit"))],
+        },
+    ]
+}
+
+/// A `(ia{sv}av)` node, only the root has children
+fn layout(e: &mut Encoder, items: &[Item], item: &Item, depth: i32) {
+    e.align(8);
+    e.i32(item.id);
+    item.write_properties(e);
+    e.array(1, |e| {
+        if item.id != 0 || depth == 0 {
+            return;
+        }
+        for child in &items[1..] {
+            e.signature("(ia{sv}av)");
+            layout(e, items, child, depth - 1);
+        }
+    });
+}
+
+fn clicked(shared: &Shared, state: &TrayState, id: i32) {
+    let command = match id {
+        PAUSE => TrayCommand::SetPaused(!state.paused),
+        PRIVACY => TrayCommand::SetPrivacyMode(!state.privacy_mode),
+        QUIT => TrayCommand::Quit,
+        _ => return,
+    };
+    debug!("Tray menu: {:?}", command);
+    let _ = shared.commands.try_send(command);
+}
+
+const ITEM_PROPERTIES: [&str; 10] = [
+    "Category",
+    "Id",
+    "Title",
+    "Status",
+    "IconName",
+    "IconThemePath",
+    "ToolTip",
+    "Menu",
+    "ItemIsMenu",
+    "WindowId",
+];
+const MENU_PROPERTIES: [&str; 4] = ["Version", "TextDirection", "Status", "IconThemePath"];
+
+/// Writes property `name` as a variant, or nothing if there is no such property
+fn property(e: &mut Encoder, interface: &str, name: &str, state: &TrayState) -> bool {
+    fn text(e: &mut Encoder, value: &str) {
+        e.signature("s");
+        e.str(value);
+    }
+    match (interface, name) {
+        (ITEM_INTERFACE, "Category") => text(e, "ApplicationStatus"),
+        (ITEM_INTERFACE, "Id") => text(e, "arrpc"),
+        (ITEM_INTERFACE, "Title") => text(e, "arRPC"),
+        (ITEM_INTERFACE, "Status") => text(e, "Active"),
+        (ITEM_INTERFACE, "IconName") => text(e, state.icon()),
+        (ITEM_INTERFACE, "IconThemePath") => text(e, ""),
+        (ITEM_INTERFACE, "ToolTip") => {
+            // Icon name, icon pixmaps, title and text
+            e.signature("(sa(iiay)ss)");
+            e.align(8);
+            e.str("");
+            e.array(8, |_| {});
+            e.str("arRPC");
+            e.str(&state.summary());
+        }
+        (ITEM_INTERFACE, "Menu") => {
+            e.signature("o");
+            e.str(MENU_PATH);
+        }
+        (ITEM_INTERFACE, "ItemIsMenu") => {
+            e.signature("b");
+            e.bool(true);
+        }
+        (ITEM_INTERFACE, "WindowId") => {
+            e.signature("i");
+            e.i32(0);
+        }
+        (MENU_INTERFACE, "Version") => {
+            e.signature("u");
+            e.u32(3);
+        }
+        (MENU_INTERFACE, "TextDirection") => text(e, "ltr"),
+        (MENU_INTERFACE, "Status") => text(e, "normal"),
+        (MENU_INTERFACE, "IconThemePath") => {
+            e.signature("as");
+            e.array(4, |_| {});
+        }
+        _ => return false,
+    }
+    true
+}
+
+fn invalid_args(e: ArRpcError) -> (&'static str, String) {
+    ("org.freedesktop.DBus.Error.InvalidArgs", e.to_string())
+}
+
+fn properties(message: &Incoming, state: &TrayState) -> Answer {
+    let (interface, names) = match message.path.as_str() {
+        ITEM_PATH => (ITEM_INTERFACE, &ITEM_PROPERTIES[..]),
+        MENU_PATH => (MENU_INTERFACE, &MENU_PROPERTIES[..]),
+        path => return Err(unknown_object(path)),
+    };
+    let mut body = Encoder::default();
+    let mut args = message.body();
+    match message.member.as_str() {
+        "Get" => {
+            let requested = args.str().map_err(invalid_args)?;
+            let name = args.str().map_err(invalid_args)?;
+            match requested == interface && property(&mut body, interface, name, state) {
+                true => Ok(("v", body)),
+                false => Err((
+                    "org.freedesktop.DBus.Error.UnknownProperty",
+                    format!("No property {}", name),
+                )),
+            }
+        }
+        "GetAll" => {
+            let requested = args.str().map_err(invalid_args)?;
+            body.array(8, |e| {
+                if requested != interface {
+                    return;
+                }
+                for name in names {
+                    e.align(8);
+                    e.str(name);
+                    property(e, interface, name, state);
+                }
+            });
+            Ok(("a{sv}", body))
+        }
+        _ => Err((
+            "org.freedesktop.DBus.Error.PropertyReadOnly",
+            "All properties are read-only".to_string(),
+        )),
+    }
+}
+
+fn menu(message: &Incoming, shared: &Shared, state: &TrayState, revision: u32) -> Answer {
+    let items = items(state);
+    let find = |id: i32| {
+        items.iter().find(|item| item.id == id).ok_or_else(|| {
+            (
+                "org.freedesktop.DBus.Error.InvalidArgs",
+                format!("No menu item {}", id),
+            )
+        })
+    };
+    let mut body = Encoder::default();
+    let mut args = message.body();
+    match message.member.as_str() {
+        // The property names asked for are only a hint, so all of them are always sent
+        "GetLayout" => {
+            let parent = args.i32().map_err(invalid_args)?;
+            let depth = args.i32().map_err(invalid_args)?;
+            let parent = find(parent)?;
+            body.u32(revision);
+            layout(&mut body, &items, parent, depth);
+            Ok(("u(ia{sv}av)", body))
+        }
+        "GetGroupProperties" => {
+            let ids = args.array(4, Decoder::i32).map_err(invalid_args)?;
+            body.array(8, |e| {
+                let wanted = items
+                    .iter()
+                    .filter(|item| ids.is_empty() || ids.contains(&item.id));
+                for item in wanted {
+                    e.align(8);
+                    e.i32(item.id);
+                    item.write_properties(e);
+                }
+            });
+            Ok(("a(ia{sv})", body))
+        }
+        "GetProperty" => {
+            let id = args.i32().map_err(invalid_args)?;
+            let name = args.str().map_err(invalid_args)?;
+            let (_, value) = find(id)?
+                .properties
+                .iter()
+                .find(|(key, _)| *key == name)
+                .ok_or_else(|| {
+                    (
+                        "org.freedesktop.DBus.Error.InvalidArgs",
+                        format!("No property {}", name),
+                    )
+                })?;
+            body.signature(value.signature());
+            value.write(&mut body);
+            Ok(("v", body))
+        }
+        "Event" => {
+            let id = args.i32().map_err(invalid_args)?;
+            if args.str().map_err(invalid_args)? == "clicked" {
+                clicked(shared, state, id);
+            }
+            Ok(("", body))
+        }
+        "EventGroup" => {
+            let events = args
+                .array(8, |d| {
+                    let id = d.i32()?;
+                    let event = d.str()?;
+                    d.skip_variant()?;
+                    d.u32()?;
+                    Ok((id, event))
+                })
+                .map_err(invalid_args)?;
+            for (id, event) in events {
+                if event == "clicked" {
+                    clicked(shared, state, id);
+                }
+            }
+            body.array(4, |_| {});
+            Ok(("ai", body))
+        }
+        "AboutToShow" => {
+            body.bool(false);
+            Ok(("b", body))
+        }
+        "AboutToShowGroup" => {
+            body.array(4, |_| {});
+            body.array(4, |_| {});
+            Ok(("aiai", body))
+        }
+        member => Err(unknown_method(member)),
+    }
+}
+
+fn answer(message: &Incoming, shared: &Shared) -> Answer {
+    let (state, revision) = shared.state.lock().unwrap().clone();
+    let mut body = Encoder::default();
+    match (message.interface.as_deref(), message.member.as_str()) {
+        (Some("org.freedesktop.DBus.Peer") | None, "Ping") => Ok(("", body)),
+        (Some("org.freedesktop.DBus.Introspectable") | None, "Introspect") => {
+            let xml = introspect(&message.path).ok_or_else(|| unknown_object(&message.path))?;
+            body.str(&xml);
+            Ok(("s", body))
+        }
+        (Some(PROPERTIES), _) => properties(message, &state),
+        // Activating the icon shows the menu, as `ItemIsMenu` says
+        _ if message.path == ITEM_PATH => match message.member.as_str() {
+            "Activate" | "SecondaryActivate" | "ContextMenu" | "Scroll" => Ok(("", body)),
+            member => Err(unknown_method(member)),
+        },
+        _ if message.path == MENU_PATH => menu(message, shared, &state, revision),
+        _ => Err(unknown_object(&message.path)),
+    }
+}
+
+const STANDARD_INTERFACES: &str = r#"  <interface name="org.freedesktop.DBus.Properties">
+    <method name="Get"><arg type="s" direction="in"/><arg type="s" direction="in"/><arg type="v" direction="out"/></method>
+    <method name="GetAll"><arg type="s" direction="in"/><arg type="a{sv}" direction="out"/></method>
+  </interface>
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect"><arg type="s" direction="out"/></method>
+  </interface>
+  <interface name="org.freedesktop.DBus.Peer">
+    <method name="Ping"/>
+  </interface>"#;
+
+fn introspect(path: &str) -> Option<String> {
+    let interface = match path {
+        "/" => {
+            return Some(
+                r#"<node><node name="StatusNotifierItem"/><node name="MenuBar"/></node>"#
+                    .to_string(),
+            )
+        }
+        ITEM_PATH => format!(
+            r#"  <interface name="{ITEM_INTERFACE}">
+    <property name="Category" type="s" access="read"/>
+    <property name="Id" type="s" access="read"/>
+    <property name="Title" type="s" access="read"/>
+    <property name="Status" type="s" access="read"/>
+    <property name="IconName" type="s" access="read"/>
+    <property name="IconThemePath" type="s" access="read"/>
+    <property name="ToolTip" type="(sa(iiay)ss)" access="read"/>
+    <property name="Menu" type="o" access="read"/>
+    <property name="ItemIsMenu" type="b" access="read"/>
+    <property name="WindowId" type="i" access="read"/>
+    <method name="Activate"><arg name="x" type="i" direction="in"/><arg name="y" type="i" direction="in"/></method>
+    <method name="SecondaryActivate"><arg name="x" type="i" direction="in"/><arg name="y" type="i" direction="in"/></method>
+    <method name="ContextMenu"><arg name="x" type="i" direction="in"/><arg name="y" type="i" direction="in"/></method>
+    <method name="Scroll"><arg name="delta" type="i" direction="in"/><arg name="orientation" type="s" direction="in"/></method>
+    <signal name="NewIcon"/>
+    <signal name="NewToolTip"/>
+  </interface>"#
+        ),
+        MENU_PATH => format!(
+            r#"  <interface name="{MENU_INTERFACE}">
+    <property name="Version" type="u" access="read"/>
+    <property name="TextDirection" type="s" access="read"/>
+    <property name="Status" type="s" access="read"/>
+    <property name="IconThemePath" type="as" access="read"/>
+    <method name="GetLayout"><arg name="parentId" type="i" direction="in"/><arg name="recursionDepth" type="i" direction="in"/><arg name="propertyNames" type="as" direction="in"/><arg name="revision" type="u" direction="out"/><arg name="layout" type="(ia{{sv}}av)" direction="out"/></method>
+    <method name="GetGroupProperties"><arg name="ids" type="ai" direction="in"/><arg name="propertyNames" type="as" direction="in"/><arg name="properties" type="a(ia{{sv}})" direction="out"/></method>
+    <method name="GetProperty"><arg name="id" type="i" direction="in"/><arg name="name" type="s" direction="in"/><arg name="value" type="v" direction="out"/></method>
+    <method name="Event"><arg name="id" type="i" direction="in"/><arg name="eventId" type="s" direction="in"/><arg name="data" type="v" direction="in"/><arg name="timestamp" type="u" direction="in"/></method>
+    <method name="EventGroup"><arg name="events" type="a(isvu)" direction="in"/><arg name="idErrors" type="ai" direction="out"/></method>
+    <method name="AboutToShow"><arg name="id" type="i" direction="in"/><arg name="needUpdate" type="b" direction="out"/></method>
+    <method name="AboutToShowGroup"><arg name="ids" type="ai" direction="in"/><arg name="updatesNeeded" type="ai" direction="out"/><arg name="idErrors" type="ai" direction="out"/></method>
+    <signal name="LayoutUpdated"><arg name="revision" type="u"/><arg name="parent" type="i"/></signal>
+  </interface>"#
+        ),
+        _ => return None,
+    };
+    Some(format!(
+        r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+{interface}
+{STANDARD_INTERFACES}
+</node>
+"#
+    ))
+}
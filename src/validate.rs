@@ -0,0 +1,291 @@
+use crate::{
+    config::{ActivityConfig, ButtonOverflow},
+    error::{ArRpcError, Result},
+    redact,
+    structs::IpcActivity,
+};
+use tracing::warn;
+
+/// Discord's limit for state, details and asset texts
+pub const MAX_TEXT_LEN: usize = 128;
+/// Discord's limit for button labels
+pub const MAX_LABEL_LEN: usize = 32;
+/// Some Discord builds hide the whole presence if there are more
+pub const MAX_BUTTONS: usize = 2;
+
+/// Cleans up an activity before any middleware gets to see it and again after, so no consumer
+/// sees what a middleware broke. Fails if it has to be rejected as a whole.
+pub fn sanitize(activity: &mut IpcActivity, config: &ActivityConfig) -> Result<()> {
+    sanitize_buttons(activity, config);
+    if activity.buttons.len() > MAX_BUTTONS {
+        if config.button_overflow == ButtonOverflow::Reject {
+            return Err(ArRpcError::InvalidActivity(format!(
+                "{} buttons, at most {} are allowed",
+                activity.buttons.len(),
+                MAX_BUTTONS
+            )));
+        }
+        warn!(
+            "Dropping {} of {} buttons, only {} are shown",
+            activity.buttons.len() - MAX_BUTTONS,
+            activity.buttons.len(),
+            MAX_BUTTONS
+        );
+        activity.buttons.truncate(MAX_BUTTONS);
+        activity.metadata.button_urls.truncate(MAX_BUTTONS);
+    }
+    if let Some(url) = activity.url.take() {
+        if activity.r#type != IpcActivity::STREAMING {
+            warn!(
+                "Dropping URL {:?}, the activity is not streaming",
+                redact::text(&url)
+            );
+        } else if !url_allowed(&url, config) {
+            warn!(
+                "Dropping stream URL {:?}, it is not allowed",
+                redact::text(&url)
+            );
+        } else {
+            activity.url = Some(url);
+        }
+    }
+    let assets = &mut activity.assets;
+    for text in [&mut activity.state, &mut activity.details]
+        .into_iter()
+        .chain(assets.large_text.as_mut())
+        .chain(assets.small_text.as_mut())
+    {
+        *text = truncate(&clean_text(text), MAX_TEXT_LEN);
+    }
+    if let Some(emoji) = &mut activity.emoji {
+        emoji.name = clean_text(&emoji.name);
+    }
+    for label in &mut activity.buttons {
+        *label = truncate(&clean_text(label), MAX_LABEL_LEN);
+    }
+    Ok(())
+}
+
+/// Shortens `text` to at most `max_len` characters, ending in "…" if anything was cut. Never
+/// splits a character or the grapheme cluster it belongs to, like an emoji with a skin tone.
+pub fn truncate(text: &str, max_len: usize) -> String {
+    if text.chars().count() <= max_len {
+        return text.to_string();
+    }
+    let budget = max_len.saturating_sub(1);
+    let mut len = 0;
+    let mut end = 0;
+    for cluster in graphemes(text) {
+        let cluster_len = cluster.chars().count();
+        if len + cluster_len > budget {
+            break;
+        }
+        len += cluster_len;
+        end += cluster.len();
+    }
+    let mut truncated = text[..end].trim_end().to_string();
+    if max_len > 0 {
+        truncated.push('…');
+    }
+    truncated
+}
+
+// Good enough grapheme clusters without the Unicode tables: combining marks, variation
+// selectors, skin tones and tags extend a cluster, a joiner glues the next character on and
+// flags are pairs of regional indicators
+fn graphemes(text: &str) -> impl Iterator<Item = &str> {
+    let mut rest = text;
+    std::iter::from_fn(move || {
+        let mut chars = rest.char_indices().peekable();
+        let (_, first) = chars.next()?;
+        let mut end = first.len_utf8();
+        let mut prev = first;
+        while let Some(&(index, c)) = chars.peek() {
+            let flag = is_regional_indicator(prev)
+                && is_regional_indicator(c)
+                && rest[..index].chars().count() == 1;
+            if !(extends_cluster(c) || prev == '\u{200D}' || flag) {
+                break;
+            }
+            end = index + c.len_utf8();
+            prev = c;
+            chars.next();
+        }
+        let (cluster, remaining) = rest.split_at(end);
+        rest = remaining;
+        Some(cluster)
+    })
+}
+
+fn extends_cluster(c: char) -> bool {
+    matches!(
+        c,
+        '\u{0300}'..='\u{036F}'
+            | '\u{1160}'..='\u{11FF}'
+            | '\u{1AB0}'..='\u{1AFF}'
+            | '\u{1DC0}'..='\u{1DFF}'
+            | '\u{200C}'..='\u{200D}'
+            | '\u{20D0}'..='\u{20FF}'
+            | '\u{FE00}'..='\u{FE0F}'
+            | '\u{FE20}'..='\u{FE2F}'
+            | '\u{1F3FB}'..='\u{1F3FF}'
+            | '\u{E0020}'..='\u{E007F}'
+            | '\u{E0100}'..='\u{E01EF}'
+    )
+}
+
+fn is_regional_indicator(c: char) -> bool {
+    ('\u{1F1E6}'..='\u{1F1FF}').contains(&c)
+}
+
+/// Turns line breaks and tabs into spaces and drops other control and invisible characters
+pub fn clean_text(text: &str) -> String {
+    let mut cleaned = String::with_capacity(text.len());
+    let mut after_break = false;
+    for c in text.chars() {
+        match c {
+            // So that "\r\n" becomes a single space
+            '\n' | '\r' | '\t' | '\u{2028}' | '\u{2029}' => {
+                if !after_break {
+                    cleaned.push(' ');
+                }
+                after_break = true;
+            }
+            c if c.is_control() || is_invisible(c) => {}
+            c => {
+                cleaned.push(c);
+                after_break = false;
+            }
+        }
+    }
+    cleaned.trim().to_string()
+}
+
+// Zero width and bidi override characters. Joiners stay, emoji sequences are built from them.
+fn is_invisible(c: char) -> bool {
+    matches!(
+        c,
+        '\u{200B}'
+            | '\u{202A}'..='\u{202E}'
+            | '\u{2060}'..='\u{2064}'
+            | '\u{2066}'..='\u{2069}'
+            | '\u{FEFF}'
+    )
+}
+
+// The bridge hands button URLs straight to the web client, where they end up clickable
+fn sanitize_buttons(activity: &mut IpcActivity, config: &ActivityConfig) {
+    let labels = std::mem::take(&mut activity.buttons);
+    let urls = std::mem::take(&mut activity.metadata.button_urls);
+    for (label, url) in labels.into_iter().zip(urls) {
+        if url_allowed(&url, config) {
+            activity.buttons.push(label);
+            activity.metadata.button_urls.push(url);
+        } else {
+            warn!(
+                "Dropping button {:?}, its URL {:?} is not allowed",
+                redact::text(&label),
+                redact::text(&url)
+            );
+        }
+    }
+}
+
+fn url_allowed(url: &str, config: &ActivityConfig) -> bool {
+    // Browsers skip whitespace and control characters, so "java\tscript:" would still run
+    if url.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return false;
+    }
+    let Some((scheme, rest)) = url.split_once(':') else {
+        return false;
+    };
+    match scheme.to_ascii_lowercase().as_str() {
+        "https" => rest.starts_with("//") && rest.len() > 2,
+        "discord" => config.allow_discord_urls,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn activity(buttons: &[(&str, &str)]) -> IpcActivity {
+        let (labels, urls): (Vec<_>, Vec<_>) = buttons.iter().copied().unzip();
+        serde_json::from_value(json!({
+            "application_id": "1",
+            "state": "",
+            "details": "",
+            "flags": 0,
+            "type": 0,
+            "buttons": labels,
+            "metadata": { "button_urls": urls },
+            "instance": false,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn truncate_keeps_joined_emoji_whole() {
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        assert_eq!(truncate(&format!("abc{}", family), 5), "abc…");
+        assert_eq!(
+            truncate(&format!("a{}bc", family), 7),
+            format!("a{}…", family)
+        );
+    }
+
+    #[test]
+    fn truncate_keeps_combining_marks_with_their_letter() {
+        let text = "e\u{301}e\u{301}e\u{301}";
+        assert_eq!(truncate(text, 4), "e\u{301}…");
+        assert_eq!(truncate(text, 6), text);
+    }
+
+    #[test]
+    fn script_urls_drop_their_button() {
+        let mut activity = activity(&[
+            ("Play", "https://example.com"),
+            ("Evil", "javascript:alert(1)"),
+            ("Sneaky", "java\tscript:alert(1)"),
+        ]);
+        sanitize(&mut activity, &ActivityConfig::default()).unwrap();
+        assert_eq!(activity.buttons, ["Play"]);
+        assert_eq!(activity.metadata.button_urls, ["https://example.com"]);
+    }
+
+    #[test]
+    fn extra_buttons_are_truncated_or_rejected() {
+        let buttons = [
+            ("One", "https://example.com/1"),
+            ("Two", "https://example.com/2"),
+            ("Three", "https://example.com/3"),
+        ];
+        let mut truncated = activity(&buttons);
+        sanitize(&mut truncated, &ActivityConfig::default()).unwrap();
+        assert_eq!(truncated.buttons, ["One", "Two"]);
+        assert_eq!(truncated.metadata.button_urls.len(), MAX_BUTTONS);
+
+        let config = ActivityConfig {
+            button_overflow: ButtonOverflow::Reject,
+            ..ActivityConfig::default()
+        };
+        assert!(sanitize(&mut activity(&buttons), &config).is_err());
+    }
+
+    #[test]
+    fn stream_urls_need_a_streaming_activity() {
+        let url = "https://twitch.tv/example".to_string();
+        let mut playing = activity(&[]);
+        playing.url = Some(url.clone());
+        sanitize(&mut playing, &ActivityConfig::default()).unwrap();
+        assert_eq!(playing.url, None);
+
+        let mut streaming = activity(&[]);
+        streaming.r#type = IpcActivity::STREAMING;
+        streaming.url = Some(url.clone());
+        sanitize(&mut streaming, &ActivityConfig::default()).unwrap();
+        assert_eq!(streaming.url, Some(url));
+    }
+}
@@ -0,0 +1,58 @@
+use arrpc_rs::{
+    error::ArRpcError,
+    ipc::structs::{HandshakeMessage, IpcMessage, MAX_FRAME_LEN},
+};
+use bytes::BytesMut;
+use tokio::io;
+
+fn handshake() -> IpcMessage {
+    IpcMessage::Handshake(HandshakeMessage {
+        version: 1,
+        client_id: "1234".to_string(),
+    })
+}
+
+#[test]
+fn decode_slice_needs_whole_message() {
+    let encoded = handshake().try_encode().unwrap();
+    for len in 0..encoded.len() {
+        assert!(IpcMessage::decode_slice(&encoded[..len]).unwrap().is_none());
+    }
+    let mut data = encoded.to_vec();
+    data.extend_from_slice(b"trailing");
+    let (msg, used) = IpcMessage::decode_slice(&data).unwrap().unwrap();
+    assert_eq!(used, encoded.len());
+    assert!(matches!(msg, IpcMessage::Handshake(data) if data.client_id == "1234"));
+}
+
+#[test]
+fn decode_slice_rejects_unknown_opcodes() {
+    let mut data = 7i32.to_le_bytes().to_vec();
+    data.extend_from_slice(&2i32.to_le_bytes());
+    data.extend_from_slice(b"{}");
+    assert!(IpcMessage::decode_slice(&data).is_err());
+}
+
+#[test]
+fn oversized_messages_are_rejected_before_buffering() {
+    let mut data = 1i32.to_le_bytes().to_vec();
+    data.extend_from_slice(&(MAX_FRAME_LEN as i32 + 1).to_le_bytes());
+    assert!(matches!(
+        IpcMessage::decode_slice(&data),
+        Err(ArRpcError::FrameTooLarge(_))
+    ));
+    let mut buffer = BytesMut::from(&data[..]);
+    assert!(matches!(
+        IpcMessage::try_decode_buf(&mut buffer),
+        Err(ArRpcError::FrameTooLarge(_))
+    ));
+    assert!(buffer.capacity() < MAX_FRAME_LEN);
+}
+
+#[tokio::test]
+async fn round_trip_over_duplex() {
+    let (mut client, mut server) = io::duplex(1024);
+    handshake().write_to(&mut client).await.unwrap();
+    let msg = IpcMessage::try_decode(&mut server).await.unwrap();
+    assert!(matches!(msg, IpcMessage::Handshake(data) if data.version == 1));
+}
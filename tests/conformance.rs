@@ -0,0 +1,152 @@
+//! Replays frames captured from real SDKs and compares what reaches the bridge with what the
+//! Node arRPC sends for them. Fields only arrpc-rs adds, like `created_at`, are left out of the
+//! comparison, and so is Node's `socketId`, socket IDs are handed out differently. Over IPC the
+//! pid is the one of the connected process, this test, rather than the one the SDK claims.
+#![cfg(unix)]
+
+use arrpc_rs::{
+    ipc::{server::Transports, structs::IpcMessage},
+    server::Server,
+};
+use futures_util::{SinkExt, StreamExt};
+use serde::Deserialize;
+use serde_json::Value;
+use std::{env, path::Path, sync::Once, time::Duration};
+use tokio::{io::AsyncWriteExt, net::UnixStream, time::timeout};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+#[derive(Deserialize)]
+struct Fixture {
+    sdk: String,
+    transport: String,
+    /// The URL query of a WebSocket connection, which takes the place of the handshake
+    #[serde(default)]
+    query: String,
+    /// IPC messages are `{ "op": ..., "data": ... }`, WebSocket ones the text frames
+    messages: Vec<Value>,
+    bridge: Vec<Value>,
+}
+
+#[derive(Deserialize)]
+struct IpcFixtureMessage {
+    op: i32,
+    data: Value,
+}
+
+fn fixture(name: &str) -> Fixture {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/conformance")
+        .join(format!("{}.json", name));
+    let data = std::fs::read(&path).unwrap();
+    serde_json::from_slice(&data).unwrap()
+}
+
+/// Whether everything in `expected` is in `actual` too
+fn contains(actual: &Value, expected: &Value) -> bool {
+    match (actual, expected) {
+        (Value::Object(actual), Value::Object(expected)) => expected.iter().all(|(key, value)| {
+            actual
+                .get(key)
+                .is_some_and(|actual| contains(actual, value))
+        }),
+        (actual, expected) => actual == expected,
+    }
+}
+
+async fn send_ipc(stream: &mut UnixStream, msg: Value) {
+    let msg: IpcFixtureMessage = serde_json::from_value(msg).unwrap();
+    let data = serde_json::to_vec(&msg.data).unwrap();
+    let mut frame = msg.op.to_le_bytes().to_vec();
+    frame.extend_from_slice(&(data.len() as i32).to_le_bytes());
+    frame.extend_from_slice(&data);
+    stream.write_all(&frame).await.unwrap();
+    // Every message is answered, the handshake with READY
+    match IpcMessage::try_decode(stream).await.unwrap() {
+        IpcMessage::Frame(_) => {}
+        msg => panic!("expected an answer, got {:?}", msg),
+    }
+}
+
+async fn replay(name: &str) {
+    static DIRECTORY: Once = Once::new();
+    DIRECTORY.call_once(|| {
+        let directory = env::temp_dir().join(format!("arrpc-conformance-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        env::set_var("XDG_RUNTIME_DIR", directory);
+    });
+    let mut fixture = fixture(name);
+    if fixture.transport == "ipc" {
+        for msg in &mut fixture.bridge {
+            msg["pid"] = std::process::id().into();
+        }
+    }
+    let server = Server::new().with_transports(Transports {
+        ipc: fixture.transport == "ipc",
+        ws_rpc: fixture.transport == "ws",
+    });
+    let status = server.status();
+    let (mut events, handle) = server.try_bind().await.unwrap();
+    let address = status.snapshot().addresses[0].clone();
+
+    match fixture.transport.as_str() {
+        "ipc" => {
+            let mut stream = UnixStream::connect(address).await.unwrap();
+            for msg in fixture.messages {
+                send_ipc(&mut stream, msg).await;
+            }
+        }
+        "ws" => {
+            let url = format!("{}/?{}", address, fixture.query);
+            let (mut stream, _) = connect_async(url).await.unwrap();
+            let ready = stream.next().await.unwrap().unwrap();
+            assert!(ready.to_text().unwrap().contains("READY"), "{}", ready);
+            for msg in fixture.messages {
+                stream.send(Message::Text(msg.to_string())).await.unwrap();
+                stream.next().await.unwrap().unwrap();
+            }
+        }
+        transport => panic!("unknown transport {}", transport),
+    }
+
+    let mut bridge = Vec::new();
+    while bridge.len() < fixture.bridge.len() {
+        let event = timeout(Duration::from_secs(5), events.next())
+            .await
+            .expect("fewer bridge messages than expected")
+            .unwrap();
+        if let Some(msg) = event.to_message() {
+            bridge.push(serde_json::to_value(msg).unwrap());
+        }
+    }
+    for (index, (actual, expected)) in bridge.iter().zip(&fixture.bridge).enumerate() {
+        assert!(
+            contains(actual, expected),
+            "{}, message {}:\n{:#}\nis not like arRPC's\n{:#}",
+            fixture.sdk,
+            index,
+            actual,
+            expected
+        );
+    }
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn pypresence() {
+    replay("pypresence").await;
+}
+
+#[tokio::test]
+async fn discord_rpc() {
+    replay("discord-rpc").await;
+}
+
+#[tokio::test]
+async fn discord_game_sdk() {
+    replay("discord-game-sdk").await;
+}
+
+#[tokio::test]
+async fn websocket() {
+    replay("websocket").await;
+}
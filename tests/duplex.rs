@@ -0,0 +1,231 @@
+use arrpc_rs::{
+    config::IpcConfig,
+    ipc::{
+        server::IpcServer,
+        structs::{CloseCodes, HandshakeMessage, IpcCommand, IpcFrame, IpcMessage},
+        transport::{DuplexConnector, DuplexTransport},
+    },
+    stats::SharedStats,
+    test_util::MockIpcClient,
+};
+use serde_json::json;
+
+fn server(config: IpcConfig) -> (IpcServer, DuplexConnector) {
+    let (transport, connector) = DuplexTransport::new();
+    let server = IpcServer::from_transport(transport, &config, SharedStats::default());
+    (server, connector)
+}
+
+fn handshake(version: i32, client_id: &str) -> IpcMessage {
+    IpcMessage::Handshake(HandshakeMessage {
+        version,
+        client_id: client_id.to_string(),
+    })
+}
+
+#[tokio::test]
+async fn handshake_is_forwarded() {
+    let (mut server, connector) = server(IpcConfig::default());
+    let mut client = MockIpcClient::connect_duplex(&connector).unwrap();
+    client.send(&handshake(1, "1234")).await.unwrap();
+    let (socket_id, msg) = server.recv().await.unwrap();
+    assert!(matches!(msg, IpcMessage::Handshake(data) if data.client_id == "1234"));
+
+    let ready = IpcFrame {
+        args: None,
+        data: Some(json!({ "v": 1 })),
+        cmd: "DISPATCH".to_string(),
+        evt: Some("READY".to_string()),
+        nonce: String::new(),
+    };
+    server
+        .send(socket_id, IpcCommand::Frame(Box::new(ready)))
+        .await
+        .unwrap();
+    match client.recv().await.unwrap() {
+        IpcMessage::Frame(frame) => assert_eq!(frame.evt.as_deref(), Some("READY")),
+        msg => panic!("expected READY, got {:?}", msg),
+    }
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn invalid_version() {
+    let (server, connector) = server(IpcConfig::default());
+    let mut client = MockIpcClient::connect_duplex(&connector).unwrap();
+    client.send(&handshake(2, "1234")).await.unwrap();
+    let close = client.expect_close().await.unwrap();
+    assert_eq!(close.code, CloseCodes::InvalidVersion);
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn frame_before_handshake() {
+    let (server, connector) = server(IpcConfig::default());
+    let mut client = MockIpcClient::connect_duplex(&connector).unwrap();
+    let frame = IpcFrame {
+        args: Some(json!({ "pid": 1 })),
+        data: None,
+        cmd: "SET_ACTIVITY".to_string(),
+        evt: None,
+        nonce: "1".to_string(),
+    };
+    client
+        .send(&IpcMessage::Frame(Box::new(frame)))
+        .await
+        .unwrap();
+    assert!(client.recv().await.is_err());
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn too_many_connections() {
+    let (mut server, connector) = server(IpcConfig {
+        max_connections: 1,
+        ..IpcConfig::default()
+    });
+    let mut first = MockIpcClient::connect_duplex(&connector).unwrap();
+    first.send(&handshake(1, "1234")).await.unwrap();
+    // Makes sure the first one got accepted before the second connects
+    server.recv().await.unwrap();
+
+    let mut second = MockIpcClient::connect_duplex(&connector).unwrap();
+    second.send(&handshake(1, "1234")).await.unwrap();
+    let close = second.expect_close().await.unwrap();
+    assert_eq!(close.code, CloseCodes::RateLimited);
+    server.shutdown().await;
+}
+
+fn frame(cmd: &str, nonce: &str) -> IpcFrame {
+    IpcFrame {
+        args: Some(json!({ "pid": 1 })),
+        data: None,
+        cmd: cmd.to_string(),
+        evt: None,
+        nonce: nonce.to_string(),
+    }
+}
+
+async fn recv_frame(client: &mut MockIpcClient<tokio::io::DuplexStream>) -> IpcFrame {
+    match client.recv().await.unwrap() {
+        IpcMessage::Frame(frame) => *frame,
+        msg => panic!("expected a frame, got {:?}", msg),
+    }
+}
+
+/// Connects and hands the server's side of the handshake back
+async fn connected(
+    server: &mut IpcServer,
+    connector: &DuplexConnector,
+) -> (usize, MockIpcClient<tokio::io::DuplexStream>) {
+    let mut client = MockIpcClient::connect_duplex(connector).unwrap();
+    client.send(&handshake(1, "1234")).await.unwrap();
+    let (socket_id, _) = server.recv().await.unwrap();
+    (socket_id, client)
+}
+
+#[tokio::test]
+async fn overtaken_frames_are_answered_in_order() {
+    let (mut server, connector) = server(IpcConfig::default());
+    let (socket_id, mut client) = connected(&mut server, &connector).await;
+    for frame in [
+        frame("SUBSCRIBE", "1"),
+        frame("SET_ACTIVITY", "2"),
+        frame("SET_ACTIVITY", "3"),
+    ] {
+        client
+            .send(&IpcMessage::Frame(Box::new(frame)))
+            .await
+            .unwrap();
+        server.recv().await.unwrap();
+    }
+    // Only the newest activity update is answered, like after the queue dropped the others
+    let reply = frame("SET_ACTIVITY", "3").reply(Some(json!({ "state": "Ranked" })));
+    server
+        .send(socket_id, IpcCommand::Frame(Box::new(reply)))
+        .await
+        .unwrap();
+
+    let dropped = recv_frame(&mut client).await;
+    assert_eq!(
+        (dropped.nonce.as_str(), dropped.evt.as_deref()),
+        ("1", Some("ERROR"))
+    );
+    let replaced = recv_frame(&mut client).await;
+    assert_eq!(replaced.nonce, "2");
+    assert_eq!(replaced.data, Some(json!({ "state": "Ranked" })));
+    let answered = recv_frame(&mut client).await;
+    assert_eq!(answered.nonce, "3");
+    assert_eq!(answered.evt, None);
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn duplicate_nonces_are_rejected() {
+    let (mut server, connector) = server(IpcConfig::default());
+    let (socket_id, mut client) = connected(&mut server, &connector).await;
+    for _ in 0..2 {
+        let frame = frame("SET_ACTIVITY", "1");
+        client
+            .send(&IpcMessage::Frame(Box::new(frame)))
+            .await
+            .unwrap();
+    }
+    let rejected = recv_frame(&mut client).await;
+    assert_eq!(
+        (rejected.nonce.as_str(), rejected.evt.as_deref()),
+        ("1", Some("ERROR"))
+    );
+
+    // Only the first one made it to the server, and once answered the nonce is free again
+    let (_, msg) = server.recv().await.unwrap();
+    assert!(matches!(msg, IpcMessage::Frame(frame) if frame.nonce == "1"));
+    let reply = frame("SET_ACTIVITY", "1").reply(None);
+    server
+        .send(socket_id, IpcCommand::Frame(Box::new(reply)))
+        .await
+        .unwrap();
+    assert_eq!(recv_frame(&mut client).await.evt, None);
+    let again = frame("SET_ACTIVITY", "1");
+    client
+        .send(&IpcMessage::Frame(Box::new(again)))
+        .await
+        .unwrap();
+    let (_, msg) = server.recv().await.unwrap();
+    assert!(matches!(msg, IpcMessage::Frame(frame) if frame.nonce == "1"));
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn pending_frames_go_with_their_connection() {
+    let (mut server, connector) = server(IpcConfig::default());
+    let (first_id, mut first) = connected(&mut server, &connector).await;
+    let pending = frame("SET_ACTIVITY", "1");
+    first
+        .send(&IpcMessage::Frame(Box::new(pending)))
+        .await
+        .unwrap();
+    server.recv().await.unwrap();
+    drop(first);
+    let (socket_id, msg) = server.recv().await.unwrap();
+    assert_eq!(socket_id, first_id);
+    assert!(matches!(msg, IpcMessage::Close(_)));
+
+    // A late answer goes nowhere, and the next connection starts without anything pending
+    let late = frame("SET_ACTIVITY", "1").reply(None);
+    server
+        .send(first_id, IpcCommand::Frame(Box::new(late)))
+        .await
+        .unwrap();
+    let (second_id, mut second) = connected(&mut server, &connector).await;
+    assert_ne!(second_id, first_id);
+    let frame = frame("SET_ACTIVITY", "1");
+    second
+        .send(&IpcMessage::Frame(Box::new(frame)))
+        .await
+        .unwrap();
+    let (socket_id, msg) = server.recv().await.unwrap();
+    assert_eq!(socket_id, second_id);
+    assert!(matches!(msg, IpcMessage::Frame(frame) if frame.nonce == "1"));
+    server.shutdown().await;
+}
@@ -0,0 +1,101 @@
+#![cfg(unix)]
+
+use arrpc_rs::{
+    ipc::{
+        server::Transports,
+        structs::{CloseCodes, HandshakeMessage, IpcMessage},
+    },
+    server::{Server, ServerHandle},
+    structs::ActivityEvent,
+    test_util::MockIpcClient,
+};
+use futures_util::{Stream, StreamExt};
+use serde_json::json;
+use std::{env, sync::Once};
+use tokio::net::UnixStream;
+
+/// Every test binds its own `discord-ipc-N` in one shared directory
+async fn server() -> (
+    impl Stream<Item = ActivityEvent> + Unpin,
+    ServerHandle,
+    MockIpcClient<UnixStream>,
+) {
+    static DIRECTORY: Once = Once::new();
+    DIRECTORY.call_once(|| {
+        let directory = env::temp_dir().join(format!("arrpc-test-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        env::set_var("XDG_RUNTIME_DIR", directory);
+    });
+    let server = Server::new().with_transports(Transports {
+        ipc: true,
+        ws_rpc: false,
+    });
+    let status = server.status();
+    let (events, handle) = server.try_bind().await.unwrap();
+    let address = status.snapshot().addresses[0].clone();
+    let client = MockIpcClient::connect(address).await.unwrap();
+    (events, handle, client)
+}
+
+#[tokio::test]
+async fn handshake_and_activity() {
+    let (mut events, handle, mut client) = server().await;
+    let ready = client.handshake("1234").await.unwrap();
+    assert_eq!(ready.data.unwrap()["user"]["username"], "arRPC");
+    assert!(matches!(
+        events.next().await,
+        Some(ActivityEvent::Handshake { client_id, .. }) if client_id == "1234"
+    ));
+
+    let activity = json!({
+        "state": "In a match",
+        "details": "Ranked",
+        "assets": {},
+        "buttons": [],
+        "instance": false,
+    });
+    let ack = client.set_activity(activity).await.unwrap();
+    assert_eq!(ack.data.unwrap()["state"], "In a match");
+    match events.next().await {
+        Some(ActivityEvent::Set(msg)) => {
+            let activity = msg.activity.unwrap();
+            assert_eq!(activity.application_id, "1234");
+            assert_eq!(activity.details, "Ranked");
+        }
+        event => panic!("expected an activity, got {:?}", event),
+    }
+
+    client.clear_activity().await.unwrap();
+    assert!(matches!(
+        events.next().await,
+        Some(ActivityEvent::Clear { .. })
+    ));
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn invalid_payload() {
+    let (_events, handle, mut client) = server().await;
+    client.handshake("1234").await.unwrap();
+    let error = client
+        .expect_error("SET_ACTIVITY", json!({ "activity": {} }))
+        .await
+        .unwrap();
+    assert_eq!(error["code"], 4000);
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn empty_client_id() {
+    let (_events, handle, mut client) = server().await;
+    client
+        .send(&IpcMessage::Handshake(HandshakeMessage {
+            version: 1,
+            client_id: String::new(),
+        }))
+        .await
+        .unwrap();
+    let close = client.expect_close().await.unwrap();
+    assert_eq!(close.code, CloseCodes::InvalidClientID);
+    handle.shutdown().await;
+}
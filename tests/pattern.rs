@@ -0,0 +1,60 @@
+use arrpc_rs::pattern::Regex;
+
+fn replace(pattern: &str, text: &str, replacement: &str) -> String {
+    Regex::new(pattern).unwrap().replace_all(text, replacement)
+}
+
+#[test]
+fn replaces_every_match() {
+    assert_eq!(
+        replace(r"/\S+/", "Editing /home/me/secret/main.rs", ""),
+        "Editing main.rs"
+    );
+    assert_eq!(replace(r"\d+", "S01E02", "#"), "S#E#");
+    assert_eq!(replace("x*", "abc", "-"), "-a-b-c-");
+}
+
+#[test]
+fn groups_and_alternation() {
+    assert_eq!(
+        replace(r"(\w+) - (?:Episode|Ep\.?) \d+", "Show - Ep. 12", "$1"),
+        "Show"
+    );
+    assert_eq!(replace("(a)(b)?", "ac", "[$2${1}$$]"), "[a$]c");
+    assert_eq!(
+        replace(r"\bcat\b", "cat concat cat", "dog"),
+        "dog concat dog"
+    );
+    assert_eq!(replace("^a|b$", "aab", "_"), "_a_");
+}
+
+#[test]
+fn quantifiers() {
+    assert_eq!(replace("a{2,3}", "aaaaaaa", "x"), "xxa");
+    assert_eq!(replace("<.+?>", "<a><b>", "()"), "()()");
+    assert_eq!(replace("a{,", "a{,", "x"), "x");
+}
+
+#[test]
+fn classes_and_case() {
+    assert_eq!(replace("[^a-c-]", "abc-def", "."), "abc-...");
+    assert_eq!(replace(r"[\d_]", "a_1", ""), "a");
+    assert_eq!(
+        replace("(?i)nsfw", "Some NSFW title", "***"),
+        "Some *** title"
+    );
+    assert!(Regex::new("(?i)[a-z]+").unwrap().is_match("ÆBC"));
+}
+
+#[test]
+fn rejects_invalid_patterns() {
+    for pattern in ["(a", "a)", "*a", "[a", "a\\", r"\q", "[z-a]", "(?=a)"] {
+        assert!(Regex::new(pattern).is_err(), "{}", pattern);
+    }
+}
+
+#[test]
+fn gives_up_on_catastrophic_backtracking() {
+    let text = "a".repeat(64);
+    assert!(!Regex::new("(a|a)*b").unwrap().is_match(&text));
+}